@@ -0,0 +1,19 @@
+//! `src/ffi/mod.rs` の宣言から `include/fers.h` を作る。設定は `cbindgen.toml`
+
+use std::env;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/ffi/mod.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let dir = Path::new(&dir);
+    let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).unwrap();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(dir.join("src/ffi/mod.rs"))
+        .generate()
+        .expect("cbindgen can read src/ffi/mod.rs")
+        .write_to_file(dir.join("include/fers.h"));
+}
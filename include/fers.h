@@ -0,0 +1,186 @@
+/*
+ * fers: CASL2 assembler and COMET II emulator
+ *
+ * Link with the cdylib built by `cargo build --release` (libfers.so).
+ * A FersMachine must not be used from several threads at once.
+ *
+ * Generated from src/ffi by cbindgen in build.rs. Do not edit.
+ */
+
+#ifndef FERS_H
+#define FERS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#define FERS_OK 0
+
+#define FERS_ERROR -1
+
+/*
+ `fers_run` の結果
+ */
+#define FERS_HALTED 0
+
+#define FERS_BREAKPOINT 1
+
+#define FERS_LIMIT 2
+
+/*
+ `fers_get_register` などのレジスタ番号。0~7はGR0~GR7
+ */
+#define FERS_SP 8
+
+#define FERS_PR 9
+
+#define FERS_OF 10
+
+#define FERS_SF 11
+
+#define FERS_ZF 12
+
+typedef struct FersMachine FersMachine;
+
+/*
+ INで1行読む。`buffer` に書いたバイト数 (UTF-8) か、入力の終わりなら負の数を返す
+ */
+typedef int (*FersInputFn)(void *user, char *buffer, size_t capacity);
+
+/*
+ OUTの1行。`line` はNUL終端で、`len` はNULを含まないバイト数
+ */
+typedef void (*FersOutputFn)(void *user, const char *line, size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ ソースをアセンブルして読み込む。失敗したら `NULL` を返し、`error` にメッセージを書く
+
+ # Safety
+
+ `source` はNUL終端のUTF-8、`error` は `NULL` か `error_len` バイト書ける領域
+ */
+struct FersMachine *fers_load(const char *source,
+                              char *error,
+                              size_t error_len);
+
+/*
+ # Safety
+
+ `machine` は `fers_load` が返したものか `NULL`。このあとは使わないこと
+ */
+void fers_free(struct FersMachine *machine);
+
+/*
+ INで入力が必要になるたびに `read` を呼ぶ。`read` が `NULL` なら入力はいつも終わり
+
+ # Safety
+
+ `machine` は `fers_load` が返したもの。`user` はそのまま `read` に渡す
+ */
+void fers_set_input(struct FersMachine *machine,
+                    FersInputFn read,
+                    void *user);
+
+/*
+ OUTの1行ごとに `write` を呼ぶ
+
+ # Safety
+
+ `machine` は `fers_load` が返したもの。`user` はそのまま `write` に渡す
+ */
+void fers_set_output(struct FersMachine *machine, FersOutputFn write, void *user);
+
+/*
+ 1命令実行する。`FERS_OK` か `FERS_ERROR`
+
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+int fers_step(struct FersMachine *machine);
+
+/*
+ 停止かブレークポイントまで最大 `max_steps` 命令実行する。
+ `FERS_HALTED`, `FERS_BREAKPOINT`, `FERS_LIMIT` か `FERS_ERROR`
+
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+int fers_run(struct FersMachine *machine, uint64_t max_steps);
+
+/*
+ 最後に `FERS_ERROR` を返したときのメッセージ。次に実行するまで有効
+
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+const char *fers_last_error(const struct FersMachine *machine);
+
+/*
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+bool fers_halted(const struct FersMachine *machine);
+
+/*
+ レジスタかフラグの値。番号が正しくなければ0
+
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+uint16_t fers_get_register(const struct FersMachine *machine, int register_);
+
+/*
+ レジスタかフラグを書き換える。フラグは0以外で立つ。`FERS_OK` か `FERS_ERROR`
+
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+int fers_set_register(struct FersMachine *machine,
+                      int register_,
+                      uint16_t value);
+
+/*
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+uint16_t fers_read_memory(const struct FersMachine *machine, uint16_t address);
+
+/*
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+void fers_write_memory(struct FersMachine *machine, uint16_t address, uint16_t value);
+
+/*
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+void fers_add_breakpoint(struct FersMachine *machine, uint16_t address);
+
+/*
+ 消したら `true`
+
+ # Safety
+
+ `machine` は `fers_load` が返したもの
+ */
+bool fers_remove_breakpoint(struct FersMachine *machine, uint16_t address);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FERS_H */
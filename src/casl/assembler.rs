@@ -0,0 +1,665 @@
+//! `ast::Source` を機械語にする
+
+use super::ast::{Opecode, Operand, Source, Span, Value};
+use super::error::{Error, ErrorKind};
+use crate::core::console;
+use crate::core::machine::Machine;
+use crate::core::operations::{Operation1, Operation2, RegisterNumber};
+use crate::core::protection::{Protection, ProtectionMode, WordKind};
+use crate::core::shadow::Shadow;
+use std::io;
+
+/// アセンブルした結果
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Program {
+    /// 読み込む先頭の番地
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// 実行開始番地
+    pub entry: u16,
+    pub symbols: Vec<Symbol>,
+    /// 各語を出力したソースの行 (0始まり)
+    pub source_map: Vec<usize>,
+    /// 各語が命令かデータか
+    pub kinds: Vec<WordKind>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// 定義されたプログラムの名前 (STARTのラベル)
+    pub unit: String,
+    pub address: u16,
+    /// STARTのラベルは他のプログラムからも参照できる
+    pub global: bool,
+    pub span: Span,
+}
+
+impl Program {
+    /// その番地の語を出力した行
+    pub fn line_of(&self, address: u16) -> Option<usize> {
+        let index = address.checked_sub(self.origin)? as usize;
+        self.source_map.get(index).copied()
+    }
+
+    /// その行が出力した最初の語の番地
+    pub fn address_of_line(&self, line: usize) -> Option<u16> {
+        let index = self.source_map.iter().position(|&l| l == line)?;
+        Some(self.origin + index as u16)
+    }
+
+    /// 名前からラベルを探す。STARTのラベルを優先し、`UNIT.LABEL` の形でプログラムを指定できる
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        if let Some((unit, name)) = name.split_once('.') {
+            return self
+                .symbols
+                .iter()
+                .find(|s| s.unit == unit && s.name == name);
+        }
+        self.symbols
+            .iter()
+            .find(|s| s.global && s.name == name)
+            .or_else(|| self.symbols.iter().find(|s| s.name == name))
+    }
+
+    /// 番地を `LABEL+3` の形にする。手前にラベルがなければ `None`
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let symbol = self
+            .symbols
+            .iter()
+            .filter(|s| s.address <= address)
+            .max_by_key(|s| (s.address, !s.global))?;
+        Some(match address - symbol.address {
+            0 => symbol.name.clone(),
+            offset => format!("{}+{}", symbol.name, offset),
+        })
+    }
+
+    /// `Machine::init` で読める形 (ビッグエンディアンの語の列)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.words.iter().flat_map(|w| w.to_be_bytes()).collect()
+    }
+
+    /// プログラムを読み込んだMachine
+    pub fn load(&self) -> Machine {
+        let machine = Machine::init(&mut io::Cursor::new(self.to_bytes()))
+            .expect("reading from memory never fails");
+        machine.with_pr(self.entry)
+    }
+
+    /// `label` のサブルーチンをCALLした直後の状態で読み込む。
+    /// スタックが空なので、対応するRETで停止する
+    pub fn call(&self, label: &str) -> Option<Machine> {
+        let address = self.symbol(label)?.address;
+        Some(self.load().with_pr(address))
+    }
+
+    /// コードへの書き込みとデータの実行を `mode` で検査するMachine
+    pub fn load_protected(&self, mode: ProtectionMode) -> Machine {
+        self.load().with_protection(Protection {
+            mode,
+            origin: self.origin,
+            kinds: self.kinds.clone(),
+        })
+    }
+
+    /// 読み込んだ直後のシャドウメモリ。DS の領域だけ未初期化にする
+    pub fn shadow(&self, mode: ProtectionMode) -> Shadow {
+        let mut shadow = Shadow::new(mode);
+        for (i, &kind) in self.kinds.iter().enumerate() {
+            if kind != WordKind::Reserved {
+                shadow.initialize(self.origin.wrapping_add(i as u16));
+            }
+        }
+        shadow
+    }
+}
+
+/// リテラルはENDの位置に置く
+struct Literal {
+    value: Value,
+    uses: Vec<usize>,
+}
+
+/// START~ENDの間の状態
+struct Unit {
+    name: String,
+    /// STARTのラベルの `symbols` での位置
+    symbol: usize,
+    /// STARTのオペランドで指定した実行開始位置
+    entry: Option<(String, Span)>,
+    literals: Vec<Literal>,
+}
+
+/// あとでラベルの番地を入れる場所
+struct Fixup {
+    index: usize,
+    name: String,
+    unit: String,
+    span: Span,
+}
+
+struct Assembler {
+    origin: u16,
+    words: Vec<u16>,
+    source_map: Vec<usize>,
+    kinds: Vec<WordKind>,
+    symbols: Vec<Symbol>,
+    fixups: Vec<Fixup>,
+    errors: Vec<Error>,
+    unit: Option<Unit>,
+}
+
+/// 構文木を `origin` 番地に置く機械語にする。`Program::load` は `STACK_SIZE` 番地に読み込むので、
+/// それ以外の番地にしたものは自分でメモリに書き込むこと
+pub fn assemble_source_at(source: &Source, origin: u16) -> Result<Program, Vec<Error>> {
+    let mut assembler = Assembler {
+        origin,
+        words: Vec::new(),
+        source_map: Vec::new(),
+        kinds: Vec::new(),
+        symbols: Vec::new(),
+        fixups: Vec::new(),
+        errors: Vec::new(),
+        unit: None,
+    };
+
+    for line in source.lines.iter() {
+        let statement = match &line.statement {
+            Some(statement) => statement,
+            None => continue,
+        };
+        let error = |kind| Error::new(statement.opecode_span, kind);
+
+        match &statement.opecode {
+            Opecode::Start => {
+                if let Some(unit) = assembler.unit.take() {
+                    assembler.errors.push(error(ErrorKind::MissingEnd));
+                    assembler.end(unit, line.number);
+                }
+                let label = match &line.label {
+                    Some(label) => label,
+                    None => {
+                        assembler.errors.push(error(ErrorKind::StartWithoutLabel));
+                        continue;
+                    }
+                };
+                let entry = match statement.operands.as_slice() {
+                    [] => None,
+                    [Operand {
+                        value: Value::Label(name),
+                        span,
+                    }] => Some((name.clone(), *span)),
+                    _ => {
+                        assembler.errors.push(error(ErrorKind::WrongOperands(
+                            "START".to_string(),
+                            "an optional label",
+                        )));
+                        None
+                    }
+                };
+                if assembler
+                    .symbols
+                    .iter()
+                    .any(|s| s.global && s.name == label.name)
+                {
+                    assembler.errors.push(Error::new(
+                        label.span,
+                        ErrorKind::DuplicateLabel(label.name.clone()),
+                    ));
+                }
+                assembler.symbols.push(Symbol {
+                    name: label.name.clone(),
+                    unit: label.name.clone(),
+                    address: assembler.address(),
+                    global: true,
+                    span: label.span,
+                });
+                assembler.unit = Some(Unit {
+                    name: label.name.clone(),
+                    symbol: assembler.symbols.len() - 1,
+                    entry,
+                    literals: Vec::new(),
+                });
+            }
+            Opecode::End => {
+                if let Some(label) = &line.label {
+                    assembler
+                        .errors
+                        .push(Error::new(label.span, ErrorKind::LabelOnEnd));
+                }
+                match assembler.unit.take() {
+                    Some(unit) => assembler.end(unit, line.number),
+                    None => assembler.errors.push(error(ErrorKind::OutsideOfProgram)),
+                }
+            }
+            opecode => {
+                let unit = match &assembler.unit {
+                    Some(unit) => unit.name.clone(),
+                    None => {
+                        assembler.errors.push(error(ErrorKind::OutsideOfProgram));
+                        continue;
+                    }
+                };
+                if let Some(label) = &line.label {
+                    let duplicate = assembler
+                        .symbols
+                        .iter()
+                        .any(|s| s.unit == unit && s.name == label.name);
+                    if duplicate {
+                        assembler.errors.push(Error::new(
+                            label.span,
+                            ErrorKind::DuplicateLabel(label.name.clone()),
+                        ));
+                    } else {
+                        assembler.symbols.push(Symbol {
+                            name: label.name.clone(),
+                            unit,
+                            address: assembler.address(),
+                            global: false,
+                            span: label.span,
+                        });
+                    }
+                }
+                let start = assembler.words.len();
+                let result = assembler.statement(opecode, &statement.operands, line.number);
+                if let Err(kind) = result {
+                    assembler.errors.push(error(kind));
+                }
+                match opecode {
+                    Opecode::Ds => assembler.mark(start, WordKind::Reserved),
+                    Opecode::Dc => assembler.mark(start, WordKind::Data),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    if let Some(unit) = assembler.unit.take() {
+        let line = source.lines.last().map_or(0, |l| l.number);
+        let span = Span {
+            line,
+            start: 0,
+            end: 0,
+        };
+        assembler
+            .errors
+            .push(Error::new(span, ErrorKind::MissingEnd));
+        assembler.end(unit, line);
+    }
+    assembler.finish()
+}
+
+impl Assembler {
+    fn address(&self) -> u16 {
+        self.origin.wrapping_add(self.words.len() as u16)
+    }
+
+    fn emit(&mut self, word: u16, line: usize) {
+        self.words.push(word);
+        self.source_map.push(line);
+        self.kinds.push(WordKind::Code);
+    }
+
+    /// `start` 番目以降に出力した語の種類を `kind` にする
+    fn mark(&mut self, start: usize, kind: WordKind) {
+        for k in &mut self.kinds[start..] {
+            *k = kind;
+        }
+    }
+
+    fn current_unit(&self) -> &str {
+        self.unit.as_ref().map_or("", |u| u.name.as_str())
+    }
+
+    /// アドレスになるオペランドを出力する
+    fn emit_address(&mut self, operand: &Operand, line: usize) -> Result<(), ErrorKind> {
+        match &operand.value {
+            Value::Number(n) => self.emit(*n, line),
+            Value::Label(name) => {
+                self.fixups.push(Fixup {
+                    index: self.words.len(),
+                    name: name.clone(),
+                    unit: self.current_unit().to_string(),
+                    span: operand.span,
+                });
+                self.emit(0, line);
+            }
+            Value::Literal(value) => {
+                let index = self.words.len();
+                self.emit(0, line);
+                let unit = self.unit.as_mut().expect("statement is inside a unit");
+                match unit
+                    .literals
+                    .iter_mut()
+                    .find(|l| &l.value == value.as_ref())
+                {
+                    Some(literal) => literal.uses.push(index),
+                    None => unit.literals.push(Literal {
+                        value: value.as_ref().clone(),
+                        uses: vec![index],
+                    }),
+                }
+            }
+            value @ Value::Register(_) | value @ Value::Text(_) => {
+                Err(ErrorKind::InvalidOperand(value.to_string()))?
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(
+        &mut self,
+        opecode: &Opecode,
+        operands: &[Operand],
+        line: usize,
+    ) -> Result<(), ErrorKind> {
+        let values: Vec<&Value> = operands.iter().map(|o| &o.value).collect();
+        let wrong = |expected| {
+            Err(ErrorKind::WrongOperands(
+                opecode.name().to_string(),
+                expected,
+            ))
+        };
+
+        match opecode {
+            Opecode::Ds => match values.as_slice() {
+                [Value::Number(n)] => (0..*n).for_each(|_| self.emit(0, line)),
+                _ => wrong("a word count")?,
+            },
+            Opecode::Dc => {
+                if operands.is_empty() {
+                    wrong("constants")?;
+                }
+                for operand in operands {
+                    match &operand.value {
+                        Value::Text(text) => text
+                            .chars()
+                            .for_each(|c| self.emit(console::char_to_word(c), line)),
+                        _ => self.emit_address(operand, line)?,
+                    }
+                }
+            }
+            Opecode::In | Opecode::Out => {
+                let (buf, len) = match operands {
+                    [buf, len] => (buf, len),
+                    _ => return wrong("buffer and length labels"),
+                };
+                let svc = if opecode == &Opecode::In {
+                    console::SVC_IN
+                } else {
+                    console::SVC_OUT
+                };
+                // PUSH 0,GR1; PUSH 0,GR2; LAD GR1,buf; LAD GR2,len; SVC n; POP GR2; POP GR1
+                self.emit(0x7001, line);
+                self.emit(0, line);
+                self.emit(0x7002, line);
+                self.emit(0, line);
+                self.emit(0x1210, line);
+                self.emit_address(buf, line)?;
+                self.emit(0x1220, line);
+                self.emit_address(len, line)?;
+                self.emit(0xf000, line);
+                self.emit(svc, line);
+                self.emit(0x7120, line);
+                self.emit(0x7110, line);
+            }
+            Opecode::Rpush => {
+                if !operands.is_empty() {
+                    wrong("no operands")?;
+                }
+                for r in 1..=7 {
+                    self.emit(0x7000 | r, line);
+                    self.emit(0, line);
+                }
+            }
+            Opecode::Rpop => {
+                if !operands.is_empty() {
+                    wrong("no operands")?;
+                }
+                for r in (1..=7).rev() {
+                    self.emit(0x7100 | (r << 4), line);
+                }
+            }
+            Opecode::Machine(name) => self.machine(name, operands, line)?,
+            Opecode::Start | Opecode::End => unreachable!("handled by assemble_source_at"),
+        }
+        Ok(())
+    }
+
+    fn machine(&mut self, name: &str, operands: &[Operand], line: usize) -> Result<(), ErrorKind> {
+        use Operation1::*;
+
+        let op1 = Operation1::from_mnemonic(name);
+        let op2 = Operation2::from_mnemonic(name);
+        let word = |opcode: u8, r: RegisterNumber, x: RegisterNumber| {
+            ((opcode as u16) << 8) | ((r.0 as u16) << 4) | x.0 as u16
+        };
+        let values: Vec<&Value> = operands.iter().map(|o| &o.value).collect();
+        let index = |x: &Value| match x {
+            Value::Register(RegisterNumber(0)) => Err(ErrorKind::InvalidIndexRegister),
+            Value::Register(x) => Ok(*x),
+            other => Err(ErrorKind::InvalidOperand(other.to_string())),
+        };
+        let zero = RegisterNumber(0);
+
+        match (op1, values.as_slice()) {
+            (Some(o @ NoOperation), []) | (Some(o @ Return), []) => {
+                self.emit(word(o.opcode(), zero, zero), line);
+                return Ok(());
+            }
+            (Some(o @ Pop), [Value::Register(r)]) => {
+                self.emit(word(o.opcode(), *r, zero), line);
+                return Ok(());
+            }
+            (Some(o), [Value::Register(r1), Value::Register(r2)])
+                if !matches!(o, NoOperation | Return | Pop) =>
+            {
+                self.emit(word(o.opcode(), *r1, *r2), line);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if let Some(o) = op2 {
+            let (r, rest) = match (o.has_register(), operands) {
+                (true, [r, rest @ ..]) => match r.value {
+                    Value::Register(r) => (r, rest),
+                    _ => return Err(self.expected(op1, op2)),
+                },
+                (false, rest) => (zero, rest),
+                _ => return Err(self.expected(op1, op2)),
+            };
+            let (adr, x) = match rest {
+                [adr] => (adr, zero),
+                [adr, x] => (adr, index(&x.value)?),
+                _ => return Err(self.expected(op1, op2)),
+            };
+            self.emit(word(o.opcode(), r, x), line);
+            return self.emit_address(adr, line);
+        }
+
+        Err(self.expected(op1, op2))
+    }
+
+    /// オペランドの形が合わないときのエラー
+    fn expected(&self, op1: Option<Operation1>, op2: Option<Operation2>) -> ErrorKind {
+        let (name, expected) = match (op1, op2) {
+            (Some(o), Some(_)) => (o.mnemonic(), "r1,r2 or r,adr[,x]"),
+            (Some(o @ Operation1::Pop), None) => (o.mnemonic(), "r"),
+            (Some(o), None) => (o.mnemonic(), "no operands"),
+            (None, Some(o)) if o.has_register() => (o.mnemonic(), "r,adr[,x]"),
+            (None, Some(o)) => (o.mnemonic(), "adr[,x]"),
+            (None, None) => unreachable!("parser only accepts known mnemonics"),
+        };
+        ErrorKind::WrongOperands(name.to_string(), expected)
+    }
+
+    /// ENDに来たらリテラルを置いて実行開始位置を決める
+    fn end(&mut self, unit: Unit, line: usize) {
+        let start = self.words.len();
+        for literal in unit.literals.iter() {
+            let address = self.address();
+            for &index in literal.uses.iter() {
+                self.words[index] = address;
+            }
+            match &literal.value {
+                Value::Text(text) => text
+                    .chars()
+                    .for_each(|c| self.emit(console::char_to_word(c), line)),
+                Value::Number(n) => self.emit(*n, line),
+                _ => unreachable!("parser only accepts numbers and texts as literals"),
+            }
+        }
+        self.mark(start, WordKind::Data);
+
+        if let Some((name, span)) = &unit.entry {
+            let entry = self
+                .symbols
+                .iter()
+                .find(|s| s.unit == unit.name && &s.name == name)
+                .map(|s| s.address);
+            match entry {
+                Some(address) => self.symbols[unit.symbol].address = address,
+                None => self
+                    .errors
+                    .push(Error::new(*span, ErrorKind::UndefinedLabel(name.clone()))),
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<Program, Vec<Error>> {
+        for fixup in self.fixups.iter() {
+            let symbol = self
+                .symbols
+                .iter()
+                .find(|s| s.unit == fixup.unit && s.name == fixup.name)
+                .or_else(|| {
+                    self.symbols
+                        .iter()
+                        .find(|s| s.global && s.name == fixup.name)
+                });
+            match symbol {
+                Some(symbol) => self.words[fixup.index] = symbol.address,
+                None => self.errors.push(Error::new(
+                    fixup.span,
+                    ErrorKind::UndefinedLabel(fixup.name.clone()),
+                )),
+            }
+        }
+
+        if self.origin as usize + self.words.len() > 65536 {
+            let span = self.symbols.last().map_or(
+                Span {
+                    line: 0,
+                    start: 0,
+                    end: 0,
+                },
+                |s| s.span,
+            );
+            self.errors
+                .push(Error::new(span, ErrorKind::ProgramTooLarge));
+        }
+
+        let entry = match self.symbols.iter().find(|s| s.global) {
+            Some(symbol) => symbol.address,
+            None if self.errors.is_empty() => {
+                let span = Span {
+                    line: 0,
+                    start: 0,
+                    end: 0,
+                };
+                return Err(vec![Error::new(span, ErrorKind::EmptyProgram)]);
+            }
+            None => self.origin,
+        };
+
+        if !self.errors.is_empty() {
+            self.errors.sort_by_key(|e| e.span);
+            return Err(self.errors);
+        }
+
+        Ok(Program {
+            origin: self.origin,
+            words: self.words,
+            entry,
+            symbols: self.symbols,
+            source_map: self.source_map,
+            kinds: self.kinds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::casl::assemble;
+    use crate::casl::error::ErrorKind;
+
+    #[test]
+    fn encodes_instructions_and_literals() {
+        let program = assemble(
+            "MAIN  START
+      LD    GR1,=5
+      ADDA  GR1,GR2
+LOOP  JUMP  LOOP,GR3
+      RET
+      END",
+        )
+        .unwrap();
+        assert_eq!(
+            program.words,
+            vec![0x1010, 0x0106, 0x2412, 0x6403, 0x0103, 0x8100, 5]
+        );
+        assert_eq!(program.entry, 0x100);
+        assert_eq!(program.symbol("LOOP").unwrap().address, 0x103);
+        assert_eq!(program.line_of(0x103), Some(3));
+        assert_eq!(program.describe(0x104).as_deref(), Some("LOOP+1"));
+    }
+
+    #[test]
+    fn macros_and_units() {
+        let program = assemble(
+            "MAIN  START BEGIN
+BUF   DS    2
+BEGIN CALL  SUB
+      OUT   BUF,LEN
+      RET
+LEN   DC    2
+      END
+SUB   START
+LEN   DC    'A'
+      RPOP
+      END",
+        )
+        .unwrap();
+        assert_eq!(program.entry, 0x102);
+        // 同じ名前でもプログラムごとに別のラベル
+        assert_eq!(program.symbol("MAIN.LEN").unwrap().address, 0x111);
+        assert_eq!(program.symbol("SUB.LEN").unwrap().address, 0x112);
+        assert_eq!(&program.words[2..4], &[0x8000, 0x112]);
+        assert_eq!(&program.words[10..12], &[0x1220, 0x111]);
+        assert_eq!(program.words.len(), 0x13 + 7);
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let errors = assemble(
+            "MAIN  START
+      LD    GR1,NONE
+      LD    GR1,GR2,GR3
+      JUMP  0,GR0
+      END",
+        )
+        .unwrap_err();
+        let kinds: Vec<ErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ErrorKind::UndefinedLabel("NONE".to_string()),
+                ErrorKind::InvalidOperand("GR2".to_string()),
+                ErrorKind::InvalidIndexRegister,
+            ]
+        );
+    }
+}
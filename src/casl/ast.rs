@@ -1,31 +1,123 @@
+use crate::core::operations::RegisterNumber;
+use std::fmt;
+
+/// ソースコード上の範囲。行と列 (文字単位) は0始まり
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// カーソルが範囲内 (末尾の直後を含む) にあるか
+    pub fn contains(&self, line: usize, column: usize) -> bool {
+        self.line == line && self.start <= column && column <= self.end
+    }
+}
+
 /// ソースコード
-pub struct Source<'a>(Vec<Line<'a>>);
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Source {
+    pub lines: Vec<Line>,
+}
 
-pub enum Line<'a> {
-    OperationLine(OperationLine<'a>),
-    CommentLine(CommentLine<'a>),
+/// 1行。空行やコメントだけの行は `label` も `statement` も `None`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    /// 0始まりの行番号
+    pub number: usize,
+    pub label: Option<Label>,
+    pub statement: Option<Statement>,
+    pub comment: Option<String>,
 }
 
-pub struct CommentLine<'a> {
-    comment: &'a str,
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub name: String,
+    pub span: Span,
 }
 
-pub struct Label<'a>(&'a str);
-pub enum Opecode {}
-pub enum Operand {}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub opecode: Opecode,
+    pub opecode_span: Span,
+    pub operands: Vec<Operand>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Opecode {
+    /// プログラムの先頭。ラベルが必須
+    Start,
+    /// プログラムの終わり
+    End,
+    /// 領域確保
+    Ds,
+    /// 定数
+    Dc,
+    In,
+    Out,
+    Rpush,
+    Rpop,
+    /// 機械語命令のニーモニック
+    Machine(String),
+}
+
+impl Opecode {
+    /// アセンブラ命令とマクロ命令の名前
+    pub const DIRECTIVES: [(&'static str, Opecode); 8] = [
+        ("START", Opecode::Start),
+        ("END", Opecode::End),
+        ("DS", Opecode::Ds),
+        ("DC", Opecode::Dc),
+        ("IN", Opecode::In),
+        ("OUT", Opecode::Out),
+        ("RPUSH", Opecode::Rpush),
+        ("RPOP", Opecode::Rpop),
+    ];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Opecode::Machine(name) => name,
+            directive => {
+                Opecode::DIRECTIVES
+                    .iter()
+                    .find(|(_, d)| d == directive)
+                    .expect("every directive is listed")
+                    .0
+            }
+        }
+    }
+}
 
-pub enum OperationLine<'a> {
-    OperandLine(OperandLine<'a>),
-    NoOperandLine(NoOperandLine<'a>),
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operand {
+    pub value: Value,
+    pub span: Span,
 }
 
-pub struct OperandLine<'a> {
-    label: Option<Label<'a>>,
-    opecode: Opecode,
-    operand: Operand,
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Value {
+    /// GR0~GR7
+    Register(RegisterNumber),
+    /// 10進定数か16進定数 (`#FFFF`)
+    Number(u16),
+    /// 文字定数 (`'ABC'`)
+    Text(String),
+    Label(String),
+    /// リテラル (`=10`, `=#FFFF`, `='ABC'`)
+    Literal(Box<Value>),
 }
 
-pub struct NoOperandLine<'a> {
-    label: Option<Label<'a>>,
-    opecode: Opecode,
+impl fmt::Display for Value {
+    /// ソースに書く形にする
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Register(r) => write!(f, "GR{}", r.0),
+            Value::Number(n) => write!(f, "#{:04X}", n),
+            Value::Text(text) => write!(f, "'{}'", text.replace('\'', "''")),
+            Value::Label(name) => write!(f, "{}", name),
+            Value::Literal(value) => write!(f, "={}", value),
+        }
+    }
 }
@@ -0,0 +1,133 @@
+//! 機械語をCASL2の命令に戻す
+
+use super::Program;
+use crate::core::operations::{self, Operation1, Operation2, Word1, Word2};
+use crate::core::protection::WordKind;
+use itertools::Either;
+
+/// `words` の先頭の命令をCASL2の形にする。命令として読めなければ `DC`。
+/// 戻り値は (命令, 語数)
+pub fn disassemble(words: &[u16]) -> (String, usize) {
+    let word = match words.first() {
+        Some(&word) => word,
+        None => return (String::new(), 0),
+    };
+    let dc = (format!("DC    #{:04X}", word), 1);
+
+    match operations::ope(word) {
+        Ok(Either::Left(Word1 { operation, r1, r2 })) => {
+            let operands = match operation {
+                Operation1::NoOperation | Operation1::Return => String::new(),
+                Operation1::Pop => format!("GR{}", r1.0),
+                _ => format!("GR{},GR{}", r1.0, r2.0),
+            };
+            (line(operation.mnemonic(), &operands), 1)
+        }
+        Ok(Either::Right(Word2 { operation, r, x })) => {
+            let adr = match words.get(1) {
+                Some(&adr) => adr,
+                None => return dc,
+            };
+            let mut operands = Vec::new();
+            if operation.has_register() {
+                operands.push(format!("GR{}", r.0));
+            }
+            operands.push(match operation {
+                Operation2::SupervisorCall => adr.to_string(),
+                _ => format!("#{:04X}", adr),
+            });
+            if x.0 != 0 {
+                operands.push(format!("GR{}", x.0));
+            }
+            (line(operation.mnemonic(), &operands.join(",")), 2)
+        }
+        Err(_) => dc,
+    }
+}
+
+fn line(mnemonic: &str, operands: &str) -> String {
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{:<5} {}", mnemonic, operands)
+    }
+}
+
+/// プログラム全体を番地、語、ラベル、命令の表にする。
+/// DC とリテラルは1語ずつ `DC`、続いた DS の領域は1行にまとめる
+pub fn listing(program: &Program) -> String {
+    let mut text = String::new();
+    let mut index = 0;
+    while index < program.words.len() {
+        let address = program.origin.wrapping_add(index as u16);
+        let rest = &program.words[index..];
+        let kind = program.kinds.get(index).copied().unwrap_or(WordKind::Code);
+        let (instruction, len) = match kind {
+            WordKind::Code => disassemble(rest),
+            WordKind::Data => (format!("DC    #{:04X}", rest[0]), 1),
+            WordKind::Reserved => {
+                let len = program.kinds[index..]
+                    .iter()
+                    .take_while(|&&k| k == WordKind::Reserved)
+                    .count();
+                (format!("DS    {}", len), len)
+            }
+        };
+        let words = match kind {
+            WordKind::Reserved => String::new(),
+            _ => rest[..len]
+                .iter()
+                .map(|w| format!("{:04X}", w))
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        let label = program
+            .symbols
+            .iter()
+            .find(|s| s.address == address)
+            .map_or("", |s| s.name.as_str());
+        let line = format!(
+            "#{:04X}  {:<9}  {:<8} {}",
+            address, words, label, instruction
+        );
+        text.push_str(line.trim_end());
+        text.push('\n');
+        index += len;
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        assert_eq!(disassemble(&[0x2412]), ("ADDA  GR1,GR2".to_string(), 1));
+        assert_eq!(disassemble(&[0x8100]), ("RET".to_string(), 1));
+        assert_eq!(
+            disassemble(&[0x1013, 0x0120]),
+            ("LD    GR1,#0120,GR3".to_string(), 2)
+        );
+        assert_eq!(
+            disassemble(&[0x6400, 0x0100]),
+            ("JUMP  #0100".to_string(), 2)
+        );
+        assert_eq!(disassemble(&[0xf000, 2]), ("SVC   2".to_string(), 2));
+        assert_eq!(disassemble(&[0x1010]), ("DC    #1010".to_string(), 1));
+        assert_eq!(disassemble(&[0xff00]), ("DC    #FF00".to_string(), 1));
+    }
+
+    #[test]
+    fn program_listing() {
+        let program = crate::casl::assemble("MAIN START\n LD GR1,=5\n RET\nX DS 2\n END").unwrap();
+        assert_eq!(
+            listing(&program),
+            "#0100  1010 0105  MAIN     LD    GR1,#0105
+#0102  8100                RET
+#0103             X        DS    2
+#0105  0005                DC    #0005
+"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use super::ast::Span;
+
+/// アセンブルのエラー。どこで起きたかを持つ
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{}:{}: {kind}", .span.line + 1, .span.start + 1)]
+pub struct Error {
+    pub span: Span,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    #[error("Invalid label `{0}`")]
+    InvalidLabel(String),
+    #[error("Unknown instruction `{0}`")]
+    UnknownOpecode(String),
+    #[error("Invalid operand `{0}`")]
+    InvalidOperand(String),
+    #[error("Unterminated string")]
+    UnterminatedText,
+    #[error("{0} is out of range")]
+    NumberOutOfRange(String),
+    #[error("Missing instruction")]
+    MissingOpecode,
+    #[error("{0} expects {1}")]
+    WrongOperands(String, &'static str),
+    #[error("GR0 cannot be used as an index register")]
+    InvalidIndexRegister,
+    #[error("Label `{0}` is not defined")]
+    UndefinedLabel(String),
+    #[error("Label `{0}` is already defined")]
+    DuplicateLabel(String),
+    #[error("START needs a label")]
+    StartWithoutLabel,
+    #[error("END cannot have a label")]
+    LabelOnEnd,
+    #[error("Instruction outside of START and END")]
+    OutsideOfProgram,
+    #[error("START without END")]
+    MissingEnd,
+    #[error("No START in source")]
+    EmptyProgram,
+    #[error("Program does not fit in memory")]
+    ProgramTooLarge,
+}
+
+impl Error {
+    pub fn new(span: Span, kind: ErrorKind) -> Error {
+        Error { span, kind }
+    }
+}
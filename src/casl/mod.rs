@@ -1,4 +1,28 @@
-// mod _parser;
-mod ast;
-mod list;
-mod parser;
+pub mod assembler;
+pub mod ast;
+pub mod disasm;
+pub mod error;
+pub mod object;
+pub mod parser;
+
+pub use assembler::Program;
+pub use error::Error;
+
+/// ソースをアセンブルする。エラーはすべて返す
+pub fn assemble(source: &str) -> Result<Program, Vec<Error>> {
+    assemble_at(source, crate::core::machine::STACK_SIZE as u16)
+}
+
+/// `assemble` と同じだが、`origin` 番地に置くものとしてアセンブルする
+pub fn assemble_at(source: &str, origin: u16) -> Result<Program, Vec<Error>> {
+    let (ast, mut errors) = parser::parse(source);
+    match assembler::assemble_source_at(&ast, origin) {
+        Ok(program) if errors.is_empty() => Ok(program),
+        Ok(_) => Err(errors),
+        Err(more) => {
+            errors.extend(more);
+            errors.sort_by_key(|e| e.span);
+            Err(errors)
+        }
+    }
+}
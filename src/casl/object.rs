@@ -0,0 +1,155 @@
+//! オブジェクトファイル。アセンブルした結果を保存しておき、ソースなしで実行や逆アセンブルをする
+//!
+//! 数値はすべてビッグエンディアン
+//!
+//! | バイト数 | 内容 |
+//! |---|---|
+//! | 8 | `FERSOBJ\0` |
+//! | 2 | バージョン (1) |
+//! | 2 | 読み込む先頭の番地 |
+//! | 2 | 実行開始番地 |
+//! | 2 | 語数 n |
+//! | 2n | 語 |
+//! | n | 各語の種類 (0: 命令, 1: DC・リテラル, 2: DS) |
+//!
+//! ラベルとソースの行は含まない
+
+use super::Program;
+use crate::core::machine::STACK_SIZE;
+use crate::core::protection::WordKind;
+
+pub const MAGIC: &[u8; 8] = b"FERSOBJ\0";
+const VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ObjectError {
+    #[error("Not an object file")]
+    NotObject,
+    #[error("Unsupported object file version {0}")]
+    Version(u16),
+    #[error("Object file is truncated")]
+    Truncated,
+    #[error("Programs must be loaded at #{:04X}, not #{0:04X}", STACK_SIZE)]
+    Origin(u16),
+    #[error("Program of {0} words does not fit in memory")]
+    TooLong(usize),
+    #[error("Unknown word kind {0}")]
+    Kind(u8),
+}
+
+/// オブジェクトファイルの先頭か
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl Program {
+    pub fn to_object(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for n in [VERSION, self.origin, self.entry, self.words.len() as u16] {
+            bytes.extend(n.to_be_bytes());
+        }
+        bytes.extend(self.to_bytes());
+        bytes.extend(self.kinds.iter().map(|kind| match kind {
+            WordKind::Code => 0,
+            WordKind::Data => 1,
+            WordKind::Reserved => 2,
+        }));
+        bytes
+    }
+
+    /// オブジェクトファイルを読む。ラベルとソースの行は空になる
+    pub fn from_object(bytes: &[u8]) -> Result<Program, ObjectError> {
+        let body = bytes.strip_prefix(MAGIC).ok_or(ObjectError::NotObject)?;
+        let words: Vec<u16> = body
+            .chunks(2)
+            .map(|pair| match pair {
+                &[high, low] => u16::from_be_bytes([high, low]),
+                _ => 0,
+            })
+            .collect();
+        let header = words.get(..4).ok_or(ObjectError::Truncated)?;
+        let (version, origin, entry, len) = (header[0], header[1], header[2], header[3] as usize);
+        if version != VERSION {
+            return Err(ObjectError::Version(version));
+        }
+        if origin != STACK_SIZE as u16 {
+            return Err(ObjectError::Origin(origin));
+        }
+        if origin as usize + len > 0x10000 {
+            return Err(ObjectError::TooLong(len));
+        }
+        let kinds_start = 8 + len * 2;
+        if body.len() != kinds_start + len {
+            return Err(ObjectError::Truncated);
+        }
+        let kinds = body[kinds_start..]
+            .iter()
+            .map(|&kind| match kind {
+                0 => Ok(WordKind::Code),
+                1 => Ok(WordKind::Data),
+                2 => Ok(WordKind::Reserved),
+                _ => Err(ObjectError::Kind(kind)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Program {
+            origin,
+            words: words[4..4 + len].to_vec(),
+            entry,
+            symbols: Vec::new(),
+            source_map: Vec::new(),
+            kinds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+
+    #[test]
+    fn round_trip() {
+        let program = casl::assemble(
+            "MAIN  START BEGIN
+X     DS    2
+BEGIN LD    GR1,=3
+      RET
+      END",
+        )
+        .unwrap();
+        let bytes = program.to_object();
+        assert!(is_object(&bytes));
+        let read = Program::from_object(&bytes).unwrap();
+        assert_eq!(read.words, program.words);
+        assert_eq!(read.kinds, program.kinds);
+        assert_eq!(read.entry, 0x102);
+        assert_eq!(read.load(), program.load());
+
+        assert_eq!(
+            Program::from_object(&bytes[..bytes.len() - 1]),
+            Err(ObjectError::Truncated)
+        );
+        assert_eq!(
+            Program::from_object(b"MAIN START"),
+            Err(ObjectError::NotObject)
+        );
+    }
+
+    #[test]
+    fn too_long() {
+        let object = |len: u16| {
+            let mut bytes = MAGIC.to_vec();
+            for n in [VERSION, STACK_SIZE as u16, STACK_SIZE as u16, len] {
+                bytes.extend(n.to_be_bytes());
+            }
+            bytes.extend(vec![0; len as usize * 3]);
+            bytes
+        };
+        let fits = 0x10000 - STACK_SIZE;
+        assert!(Program::from_object(&object(fits as u16)).is_ok());
+        assert_eq!(
+            Program::from_object(&object(fits as u16 + 1)),
+            Err(ObjectError::TooLong(fits + 1))
+        );
+    }
+}
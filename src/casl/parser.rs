@@ -1,82 +1,315 @@
-#[derive(Clone)]
-struct Position {
-    line: usize,
-    column: usize,
+//! CASL2のソースを1行ずつ読んで `ast::Source` にする
+
+use super::ast::{Label, Line, Opecode, Operand, Source, Span, Statement, Value};
+use super::error::{Error, ErrorKind};
+use crate::core::operations::{Operation1, Operation2, RegisterNumber};
+
+/// ソース全体を読む。エラーのあった行は `Source` に含まれない
+pub fn parse(source: &str) -> (Source, Vec<Error>) {
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    for (number, text) in source.lines().enumerate() {
+        match parse_line(number, text) {
+            Ok(line) => lines.push(line),
+            Err(e) => errors.push(e),
+        }
+    }
+    (Source { lines }, errors)
 }
 
-#[derive(Clone)]
-struct ParserState<'a> {
-    lines: &'a [&'a str],
-    position: Position,
+/// ラベルとして使える名前か。先頭が英大文字で8文字以内、GR0~GR7は使えない
+pub fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    let head_ok = chars.next().is_some_and(|c| c.is_ascii_uppercase());
+    head_ok
+        && chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        && name.len() <= 8
+        && register(name).is_none()
+}
+
+/// `GR0`~`GR7` ならその番号
+pub fn register(name: &str) -> Option<RegisterNumber> {
+    match name.as_bytes() {
+        [b'G', b'R', n @ b'0'..=b'7'] => Some(RegisterNumber(n - b'0')),
+        _ => None,
+    }
 }
 
-struct ParserError {
-    label: String,
-    message: String,
-    position: Position,
+/// 命令の名前を解釈する
+pub fn opecode(name: &str) -> Option<Opecode> {
+    Opecode::DIRECTIVES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, o)| o.clone())
+        .or_else(|| {
+            let known = Operation1::from_mnemonic(name).is_some()
+                || Operation2::from_mnemonic(name).is_some();
+            if known {
+                Some(Opecode::Machine(name.to_string()))
+            } else {
+                None
+            }
+        })
 }
-type ParseResult<T> = Result<T, ParserError>;
 
-/// パーサコンビネータ
-struct Parser {
-    parse_fn: Box<dyn Fn(ParserState) -> Result<(), ()>>,
-    label: String,
+struct LineState {
+    number: usize,
+    chars: Vec<char>,
+    position: usize,
 }
 
-impl Position {
-    fn increment_line(&self) -> Position {
-        Position {
-            line: self.line + 1,
-            ..*self
+impl LineState {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+    fn span(&self, start: usize) -> Span {
+        Span {
+            line: self.number,
+            start,
+            end: self.position,
         }
     }
-    fn increment_column(&self) -> Position {
-        Position {
-            column: self.column + 1,
-            line: 0,
+    fn text(&self, start: usize) -> String {
+        self.chars[start..self.position].iter().collect()
+    }
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
         }
     }
-}
-
-impl ParserState<'_> {
-    /// 入力文字列の終端に来ているかどうか調べる
-    fn is_at_end_of_input(&self) -> bool {
-        self.position.line >= self.lines.len()
+    /// 空白かコメントの手前まで読む
+    fn word(&mut self) -> usize {
+        let start = self.position;
+        while self.peek().is_some_and(|c| !c.is_whitespace() && c != ';') {
+            self.position += 1;
+        }
+        start
     }
-    /// 現在の行を取得する．行の終端に到達した場合 `None`
-    fn get_current_line(&self) -> Option<&&str> {
-        self.lines.get(self.position.line)
+    /// オペランドを1つ読む。引用符の中の `,` や空白は区切りとみなさない
+    fn operand(&mut self) -> Result<usize, Error> {
+        let start = self.position;
+        let mut quoted = false;
+        while let Some(c) = self.peek() {
+            if !quoted && (c == ',' || c == ';' || c.is_whitespace()) {
+                break;
+            }
+            if c == '\'' {
+                quoted = !quoted;
+            }
+            self.position += 1;
+        }
+        if quoted {
+            Err(Error::new(self.span(start), ErrorKind::UnterminatedText))
+        } else {
+            Ok(start)
+        }
     }
-    /// 文字を取得して1文字進める
-    fn get_next_char(&self) -> (ParserState, Option<char>) {
-        let position_line = self.position.line;
-        if self.is_at_end_of_input() {
-            (self.clone(), None)
+    fn rest(&mut self) -> Option<String> {
+        if self.peek() == Some(';') {
+            let start = self.position;
+            self.position = self.chars.len();
+            Some(self.text(start))
         } else {
-            match self.get_current_line() {
-                Some(current_line) => {
-                    let position = self.position.increment_column();
-                    let ch = current_line.chars().nth(0).unwrap();
-                    (ParserState { position, ..*self }, Some(ch))
-                }
-                None => {
-                    let position = self.position.increment_line();
-                    (ParserState { position, ..*self }, Some('\n'))
-                }
+            None
+        }
+    }
+}
+
+fn parse_line(number: usize, text: &str) -> Result<Line, Error> {
+    let mut state = LineState {
+        number,
+        chars: text.chars().collect(),
+        position: 0,
+    };
+
+    let label = match state.peek() {
+        Some(c) if !c.is_whitespace() && c != ';' => {
+            let start = state.word();
+            let name = state.text(start);
+            if !is_label(&name) {
+                return Err(Error::new(state.span(start), ErrorKind::InvalidLabel(name)));
             }
+            Some(Label {
+                name,
+                span: state.span(start),
+            })
+        }
+        _ => None,
+    };
+
+    state.skip_whitespace();
+    if state.peek().is_none_or(|c| c == ';') {
+        if label.is_some() {
+            return Err(Error::new(
+                state.span(state.position),
+                ErrorKind::MissingOpecode,
+            ));
         }
+        return Ok(Line {
+            number,
+            label,
+            statement: None,
+            comment: state.rest(),
+        });
     }
+
+    let start = state.word();
+    let opecode_span = state.span(start);
+    let name = state.text(start);
+    let opecode =
+        opecode(&name).ok_or_else(|| Error::new(opecode_span, ErrorKind::UnknownOpecode(name)))?;
+
+    state.skip_whitespace();
+    let mut operands = Vec::new();
+    if state.peek().is_some_and(|c| c != ';') {
+        loop {
+            let start = state.operand()?;
+            let span = state.span(start);
+            let value = value(&state.text(start)).map_err(|kind| Error::new(span, kind))?;
+            operands.push(Operand { value, span });
+
+            if state.peek() != Some(',') {
+                break;
+            }
+            state.position += 1;
+            state.skip_whitespace();
+        }
+    }
+
+    state.skip_whitespace();
+    let comment = state.rest();
+    if state.peek().is_some() {
+        let start = state.word();
+        return Err(Error::new(
+            state.span(start),
+            ErrorKind::InvalidOperand(state.text(start)),
+        ));
+    }
+
+    Ok(Line {
+        number,
+        label,
+        statement: Some(Statement {
+            opecode,
+            opecode_span,
+            operands,
+        }),
+        comment,
+    })
+}
+
+/// オペランド1つを解釈する
+fn value(text: &str) -> Result<Value, ErrorKind> {
+    if let Some(rest) = text.strip_prefix('=') {
+        return match value(rest)? {
+            v @ Value::Number(_) | v @ Value::Text(_) => Ok(Value::Literal(Box::new(v))),
+            _ => Err(ErrorKind::InvalidOperand(text.to_string())),
+        };
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let inner = rest.strip_suffix('\'').ok_or(ErrorKind::UnterminatedText)?;
+        // 中の ' は '' と書く
+        if inner.replace("''", "").contains('\'') {
+            return Err(ErrorKind::InvalidOperand(text.to_string()));
+        }
+        return Ok(Value::Text(inner.replace("''", "'")));
+    }
+    if let Some(hex) = text.strip_prefix('#') {
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ErrorKind::InvalidOperand(text.to_string()));
+        }
+        return u16::from_str_radix(hex, 16)
+            .map(Value::Number)
+            .map_err(|_| ErrorKind::NumberOutOfRange(text.to_string()));
+    }
+    if text.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+        return match text.parse::<i64>() {
+            Ok(n) if (-32768..=65535).contains(&n) => Ok(Value::Number(n as u16)),
+            Ok(_) => Err(ErrorKind::NumberOutOfRange(text.to_string())),
+            Err(_) => Err(ErrorKind::InvalidOperand(text.to_string())),
+        };
+    }
+    if let Some(r) = register(text) {
+        return Ok(Value::Register(r));
+    }
+    if is_label(text) {
+        return Ok(Value::Label(text.to_string()));
+    }
+    Err(ErrorKind::InvalidOperand(text.to_string()))
 }
 
-// #[cfg(test)]
-// mod test {
-//     #[test]
-//     fn g() {
-//         ae
-//     }
-// }
-fn f() {
-    let a = Some(());
-    let b = Some(());
-    let c = Some(());
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn statement(text: &str) -> Statement {
+        parse_line(0, text).unwrap().statement.unwrap()
+    }
+
+    #[test]
+    fn line_with_label_and_comment() {
+        let line = parse_line(3, "LOOP  LD  GR1,DATA,GR2 ; load").unwrap();
+        assert_eq!(line.label.unwrap().name, "LOOP");
+        assert_eq!(line.comment.as_deref(), Some("; load"));
+        let statement = line.statement.unwrap();
+        assert_eq!(statement.opecode, Opecode::Machine("LD".to_string()));
+        assert_eq!(
+            statement
+                .operands
+                .iter()
+                .map(|o| o.value.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Value::Register(RegisterNumber(1)),
+                Value::Label("DATA".to_string()),
+                Value::Register(RegisterNumber(2)),
+            ]
+        );
+        assert_eq!(
+            statement.operands[1].span,
+            Span {
+                line: 3,
+                start: 14,
+                end: 18
+            }
+        );
+    }
+
+    #[test]
+    fn constants() {
+        let operands: Vec<Value> = statement("  DC 'It''s, ok',#00FF,-1,=3")
+            .operands
+            .into_iter()
+            .map(|o| o.value)
+            .collect();
+        assert_eq!(
+            operands,
+            vec![
+                Value::Text("It's, ok".to_string()),
+                Value::Number(0xff),
+                Value::Number(0xffff),
+                Value::Literal(Box::new(Value::Number(3))),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let kind = |text| parse_line(0, text).unwrap_err().kind;
+        assert_eq!(
+            kind("  FOO GR1"),
+            ErrorKind::UnknownOpecode("FOO".to_string())
+        );
+        assert_eq!(kind("gr  NOP"), ErrorKind::InvalidLabel("gr".to_string()));
+        assert_eq!(kind("  DC 'abc"), ErrorKind::UnterminatedText);
+        assert_eq!(
+            kind("  DC 70000"),
+            ErrorKind::NumberOutOfRange("70000".to_string())
+        );
+        assert_eq!(
+            kind("  LD GR1,GR2 x"),
+            ErrorKind::InvalidOperand("x".to_string())
+        );
+        assert_eq!(kind("ONLY"), ErrorKind::MissingOpecode);
+    }
 }
@@ -0,0 +1,90 @@
+//! 命令がどこを読み書きしたかの記録。ウォッチポイントで使う
+
+use super::machine::Machine;
+use super::operations::RegisterNumber;
+use super::register::Flag;
+use std::fmt;
+use std::rc::Rc;
+
+/// 読み書きされる場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cell {
+    Memory(u16),
+    Register(RegisterNumber),
+    Sp,
+    Flag(Flag),
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cell::Memory(address) => write!(f, "#{:04X}", address),
+            Cell::Register(r) => write!(f, "GR{}", r.0),
+            Cell::Sp => write!(f, "SP"),
+            Cell::Flag(flag) => write!(f, "{}", flag.name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// 1回の読み書き。読んだときは `old` と `new` は同じ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub cell: Cell,
+    pub kind: AccessKind,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl Access {
+    pub fn read(cell: Cell, value: u16) -> Access {
+        Access {
+            cell,
+            kind: AccessKind::Read,
+            old: value,
+            new: value,
+        }
+    }
+
+    pub fn write(cell: Cell, old: u16, new: u16) -> Access {
+        Access {
+            cell,
+            kind: AccessKind::Write,
+            old,
+            new,
+        }
+    }
+}
+
+impl Machine {
+    /// `cell` の今の値。フラグは0か1
+    pub fn cell(&self, cell: Cell) -> u16 {
+        match cell {
+            Cell::Memory(address) => self.mem.get(address).unwrap_or(0),
+            Cell::Register(r) => self.gr().get(r),
+            Cell::Sp => self.sp(),
+            Cell::Flag(Flag::Overflow) => self.of() as u16,
+            Cell::Flag(Flag::Sign) => self.sf() as u16,
+            Cell::Flag(Flag::Zero) => self.zf() as u16,
+        }
+    }
+
+    /// `cell` を `value` にしたMachineを返す。フラグは0以外で立つ
+    pub fn with_cell(&self, cell: Cell, value: u16) -> Machine {
+        let mut machine = self.clone();
+        match cell {
+            Cell::Memory(address) => machine.mem = Rc::new(self.mem.set(address, value)),
+            Cell::Register(r) => machine.gr = self.gr.set(r, value),
+            Cell::Sp => machine.sp = value,
+            Cell::Flag(Flag::Overflow) => machine.of = value != 0,
+            Cell::Flag(Flag::Sign) => machine.sf = value != 0,
+            Cell::Flag(Flag::Zero) => machine.zf = value != 0,
+        }
+        machine
+    }
+}
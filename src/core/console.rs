@@ -0,0 +1,54 @@
+//! SVC (IN/OUT) で使う入出力の状態。
+
+use std::collections::VecDeque;
+
+/// INで1回に読み込める最大の文字数
+pub const LINE_MAX: usize = 256;
+
+/// SVC 1 (IN) のアドレス
+pub const SVC_IN: u16 = 1;
+/// SVC 2 (OUT) のアドレス
+pub const SVC_OUT: u16 = 2;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Console {
+    /// まだ読み込まれていない入力行
+    pub input: VecDeque<String>,
+    /// OUTで出力された行
+    pub output: Vec<String>,
+}
+
+impl Console {
+    /// 入力を1行取り出したConsoleと取り出した行を返す
+    pub fn read_line(&self) -> (Console, Option<String>) {
+        let mut input = self.input.clone();
+        let line = input.pop_front();
+        (
+            Console {
+                input,
+                output: self.output.clone(),
+            },
+            line,
+        )
+    }
+
+    /// 1行出力したConsoleを返す
+    pub fn write_line(&self, line: String) -> Console {
+        let mut output = self.output.clone();
+        output.push(line);
+        Console {
+            input: self.input.clone(),
+            output,
+        }
+    }
+}
+
+/// メモリ上の1語を文字にする
+pub fn word_to_char(word: u16) -> char {
+    std::char::from_u32(word as u32).unwrap_or('?')
+}
+
+/// 文字をメモリ上の1語にする
+pub fn char_to_word(c: char) -> u16 {
+    c as u32 as u16
+}
@@ -0,0 +1,267 @@
+//! 命令のコスト (サイクル数) のモデルと、ステップ数・サイクル数の上限つきの実行
+//!
+//! コスト表はテキストで書ける。1行に1項目で、`;` から後ろはコメント
+//!
+//! ```text
+//! ; ニーモニックはその名前の命令すべて、#XX は命令コード1つ
+//! LD      2
+//! #14     1
+//! CALL    4
+//! default 1   ; 書いていない命令の1語あたり
+//! memory  1   ; メモリを1回読み書きするごと (命令の読み込みを除く)
+//! index   1   ; 指標レジスタで修飾したとき
+//! ```
+
+use super::access::{Access, Cell};
+use super::machine::{ExecError, Machine, StepError};
+use super::operations::{self, Operation1, Operation2, Word1, Word2};
+use itertools::Either;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostModel {
+    /// 命令コードごとの基本コスト
+    pub opcodes: HashMap<u8, u64>,
+    /// `opcodes` にない命令の1語あたりのコスト
+    pub per_word: u64,
+    /// メモリを1回読み書きするごとのコスト
+    pub memory: u64,
+    /// 指標レジスタで修飾したときのコスト
+    pub index: u64,
+}
+
+impl Default for CostModel {
+    /// 1語1サイクルで、メモリの読み書きと指標レジスタの修飾に1サイクルずつ
+    fn default() -> CostModel {
+        CostModel {
+            opcodes: HashMap::new(),
+            per_word: 1,
+            memory: 1,
+            index: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CostError {
+    #[error("Line {0}: expected `<instruction> <cost>`")]
+    Syntax(usize),
+    #[error("Line {0}: unknown instruction `{1}`")]
+    UnknownInstruction(usize, String),
+    #[error("Line {0}: invalid cost `{1}`")]
+    InvalidNumber(usize, String),
+}
+
+impl CostModel {
+    /// コスト表を読む。書いていないものは `Default` のまま
+    pub fn parse(text: &str) -> Result<CostModel, CostError> {
+        let mut model = CostModel::default();
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.split(';').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (name, cost) = match fields.as_slice() {
+                [] => continue,
+                [name, cost] => (*name, *cost),
+                _ => return Err(CostError::Syntax(line_number)),
+            };
+            let cost = cost
+                .parse::<u64>()
+                .map_err(|_| CostError::InvalidNumber(line_number, cost.to_string()))?;
+
+            match name {
+                "default" => model.per_word = cost,
+                "memory" => model.memory = cost,
+                "index" => model.index = cost,
+                _ => {
+                    let opcodes = opcodes_of(name).ok_or_else(|| {
+                        CostError::UnknownInstruction(line_number, name.to_string())
+                    })?;
+                    for opcode in opcodes {
+                        model.opcodes.insert(opcode, cost);
+                    }
+                }
+            }
+        }
+        Ok(model)
+    }
+
+    /// 実行した命令と、その命令が読み書きした場所からコストを求める
+    pub fn cost(&self, instruction: &Either<Word1, Word2>, accesses: &[Access]) -> u64 {
+        let memory = accesses
+            .iter()
+            .filter(|a| matches!(a.cell, Cell::Memory(_)))
+            .count() as u64;
+        self.cost_of(instruction, memory)
+    }
+
+    /// 実行した命令と、その命令がメモリを読み書きした回数からコストを求める
+    pub fn cost_of(&self, instruction: &Either<Word1, Word2>, memory: u64) -> u64 {
+        let (opcode, words, indexed) = match instruction {
+            Either::Left(word1) => (word1.operation.opcode(), 1, false),
+            Either::Right(word2) => (word2.operation.opcode(), 2, word2.x.0 != 0),
+        };
+        let base = self
+            .opcodes
+            .get(&opcode)
+            .copied()
+            .unwrap_or(self.per_word * words);
+        base + memory * self.memory + if indexed { self.index } else { 0 }
+    }
+}
+
+/// `LD` のようなニーモニックか `#14` のような命令コード
+fn opcodes_of(name: &str) -> Option<Vec<u8>> {
+    if let Some(hex) = name.strip_prefix('#') {
+        let opcode = u8::from_str_radix(hex, 16).ok()?;
+        let defined = Operation1::ALL.iter().any(|o| o.opcode() == opcode)
+            || Operation2::ALL.iter().any(|o| o.opcode() == opcode);
+        return if defined { Some(vec![opcode]) } else { None };
+    }
+    let opcodes: Vec<u8> = Operation1::from_mnemonic(name)
+        .map(|o| o.opcode())
+        .into_iter()
+        .chain(Operation2::from_mnemonic(name).map(|o| o.opcode()))
+        .collect();
+    if opcodes.is_empty() {
+        None
+    } else {
+        Some(opcodes)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MeterError {
+    #[error("{0}")]
+    StepError(#[from] StepError),
+    #[error("Step limit of {0} exceeded")]
+    StepLimit(u64),
+    #[error("Cycle limit of {0} exceeded")]
+    CycleLimit(u64),
+}
+
+/// ステップ数とサイクル数を数えながら実行する
+#[derive(Debug, Clone)]
+pub struct Meter {
+    pub model: CostModel,
+    pub steps: u64,
+    pub cycles: u64,
+    pub max_steps: Option<u64>,
+    /// 上限に達したあとの命令は実行しない。最後の命令の分だけ超えることがある
+    pub max_cycles: Option<u64>,
+}
+
+impl Meter {
+    pub fn new(model: CostModel) -> Meter {
+        Meter {
+            model,
+            steps: 0,
+            cycles: 0,
+            max_steps: None,
+            max_cycles: None,
+        }
+    }
+
+    /// 1命令実行して数える。読み書きした場所も返す
+    pub fn step(&mut self, machine: &Machine) -> Result<(Machine, Vec<Access>), MeterError> {
+        let instruction = self.begin(machine)?;
+        let (next, log) = machine.step_traced()?;
+        self.steps += 1;
+        self.cycles += self.model.cost(&instruction, &log);
+        Ok((next, log))
+    }
+
+    /// 上限を確かめて、`machine` が次に実行する命令を返す。
+    /// 自分で実行するときは、このあと `count` で数える
+    pub fn begin(&self, machine: &Machine) -> Result<Either<Word1, Word2>, MeterError> {
+        if let Some(max) = self.max_steps.filter(|&max| self.steps >= max) {
+            return Err(MeterError::StepLimit(max));
+        }
+        if let Some(max) = self.max_cycles.filter(|&max| self.cycles >= max) {
+            return Err(MeterError::CycleLimit(max));
+        }
+        let word = machine.mem.get(machine.pr()).map_err(StepError::from)?;
+        Ok(operations::ope(word).map_err(|e| StepError::from(ExecError::from(e)))?)
+    }
+
+    /// `instruction` を実行してメモリを `memory` 回読み書きした分を数える。増えたサイクル数を返す
+    pub fn count(&mut self, instruction: &Either<Word1, Word2>, memory: u64) -> u64 {
+        let cycles = self.model.cost_of(instruction, memory);
+        self.steps += 1;
+        self.cycles += cycles;
+        cycles
+    }
+
+    /// 停止するまで実行する
+    pub fn run(&mut self, mut machine: Machine) -> Result<Machine, MeterError> {
+        while !machine.is_halted() {
+            machine = self.step(&machine)?.0;
+        }
+        Ok(machine)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+
+    const SOURCE: &str = "MAIN  START
+      LAD   GR1,0
+      LD    GR2,X,GR1
+      ADDA  GR2,GR2
+      ST    GR2,X
+      RET
+X     DC    3
+      END";
+
+    #[test]
+    fn costs() {
+        let machine = casl::assemble(SOURCE).unwrap().load();
+        let mut meter = Meter::new(CostModel::default());
+        let machine = meter.run(machine).unwrap();
+        assert_eq!(machine.cell(Cell::Memory(0x108)), 6);
+        // LAD 2, LD 2+1+1 (読み込みと修飾), ADDA 1, ST 2+1, RET 1 (スタックが空なので停止する)
+        assert_eq!((meter.steps, meter.cycles), (5, 11));
+
+        let model = CostModel::parse("; 表\nLD 5\n#24 3 ; ADDA\nmemory 0\nindex 2\n").unwrap();
+        assert_eq!(model.opcodes[&0x10], 5);
+        assert_eq!(model.opcodes[&0x14], 5);
+        let mut meter = Meter::new(model);
+        meter.run(casl::assemble(SOURCE).unwrap().load()).unwrap();
+        // LAD 2, LD 5+2, ADDA 3, ST 2, RET 1
+        assert_eq!(meter.cycles, 15);
+
+        assert_eq!(
+            CostModel::parse("LD 1\nFOO 2"),
+            Err(CostError::UnknownInstruction(2, "FOO".to_string()))
+        );
+        assert_eq!(
+            CostModel::parse("#99 1").map(|_| ()),
+            Err(CostError::UnknownInstruction(1, "#99".to_string()))
+        );
+        assert_eq!(CostModel::parse("LD"), Err(CostError::Syntax(1)));
+        assert_eq!(
+            CostModel::parse("LD x"),
+            Err(CostError::InvalidNumber(1, "x".to_string()))
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let source = "MAIN START\nLOOP JUMP LOOP\n END";
+        let machine = casl::assemble(source).unwrap().load();
+        let mut meter = Meter::new(CostModel::default());
+        meter.max_steps = Some(100);
+        assert!(matches!(
+            meter.run(machine.clone()),
+            Err(MeterError::StepLimit(100))
+        ));
+        assert_eq!(meter.steps, 100);
+
+        let mut meter = Meter::new(CostModel::default());
+        meter.max_cycles = Some(7);
+        assert!(matches!(meter.run(machine), Err(MeterError::CycleLimit(7))));
+        assert_eq!(meter.cycles, 8);
+    }
+}
@@ -0,0 +1,74 @@
+//! 1命令の実行で起きたこと。エミュレータを埋め込んだ側が入出力や読み書きを追うのに使う
+
+use super::access::Access;
+use super::machine::{Machine, StepError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// レジスタ、フラグ、メモリの読み書き。順序は `Machine::step_traced` と同じ
+    Access(Access),
+    /// INで1行読んだ。入力の終わりを読んだなら `None`
+    Input(Option<String>),
+    /// OUTで1行出力した
+    Output(String),
+    /// スタックが空の状態でRETして停止した
+    Halted,
+}
+
+impl Machine {
+    /// 1命令実行して、起きたことを順に返す
+    pub fn step_events(&self) -> Result<(Machine, Vec<Event>), StepError> {
+        let input = self
+            .reads_input()
+            .then(|| self.console.input.front().cloned());
+        let (next, log) = self.step_traced()?;
+        let mut events: Vec<Event> = log.into_iter().map(Event::Access).collect();
+        events.extend(input.map(Event::Input));
+        events.extend(
+            next.output()[self.output().len()..]
+                .iter()
+                .cloned()
+                .map(Event::Output),
+        );
+        if next.is_halted() {
+            events.push(Event::Halted);
+        }
+        Ok((next, events))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+
+    #[test]
+    fn echo() {
+        let program = casl::assemble(
+            "MAIN  START
+      IN    BUF,LEN
+      OUT   BUF,LEN
+      RET
+BUF   DS    4
+LEN   DS    1
+      END",
+        )
+        .unwrap();
+        let mut machine = program.load().with_input(vec!["hi".to_string()]);
+        let mut events = Vec::new();
+        while !machine.is_halted() {
+            let (next, step) = machine.step_events().unwrap();
+            events.extend(step.into_iter().filter(|e| !matches!(e, Event::Access(_))));
+            machine = next;
+        }
+        assert_eq!(
+            events,
+            [
+                Event::Input(Some("hi".to_string())),
+                Event::Output("hi".to_string()),
+                Event::Halted
+            ]
+        );
+    }
+}
@@ -2,9 +2,14 @@ use itertools::Either;
 
 use crate::core::operations::Word1;
 
+use super::access::{Access, Cell};
+use super::console::{self, Console};
 use super::memory;
+use super::observer::MachineObserver;
 use super::operations::{Operation2, RegisterNumber, Word2};
-use super::register::GeneralRegister;
+use super::protection::{Protection, ProtectionMode, Violation};
+use super::register::{Flag, GeneralRegister};
+use super::shadow::Shadow;
 use super::utils::is_negative;
 use super::{memory::Memory, operations, operations::Operation1};
 use std::ops;
@@ -14,27 +19,28 @@ use std::{cmp, io};
 /// プログラム開始時に確保されるスタックの大きさ (ワード数)。
 pub const STACK_SIZE: usize = 256;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Machine {
     /// メモリ。`Rc` で共有したほうがメモリの節約になるのかもしれないという思いがある
-    pub mem: Rc<Memory>,
-    gr: GeneralRegister,
-    sp: u16,
-    pr: u16,
-    of: bool,
-    sf: bool,
-    zf: bool,
+    pub(crate) mem: Rc<Memory>,
+    pub(super) gr: GeneralRegister,
+    pub(super) sp: u16,
+    pub(super) pr: u16,
+    pub(super) of: bool,
+    pub(super) sf: bool,
+    pub(super) zf: bool,
     /// 前の命令。アドレス関係で2ワード読む場合に前の命令が何だったか保持するのに使う
-    previous_word: Option<Word2>,
-}
-
-impl Clone for Machine {
-    fn clone(&self) -> Self {
-        Machine {
-            mem: Rc::clone(&self.mem),
-            ..*self
-        }
-    }
+    pub(super) previous_word: Option<Word2>,
+    /// IN/OUTの入出力。メモリと同じく`Rc`で共有する
+    pub(crate) console: Rc<Console>,
+    /// スタックが空の状態でRETしたら停止する
+    pub(super) halted: bool,
+    /// コードとデータの区別。`None` なら検査しない
+    pub(super) protection: Option<Rc<Protection>>,
+    /// 書き込み済みの語とレジスタ。`None` なら検査しない
+    pub(super) shadow: Option<Rc<Shadow>>,
+    /// 警告にとどめた違反
+    pub(super) violations: Rc<Vec<Violation>>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -44,11 +50,9 @@ pub enum MachineInitError {
 }
 
 impl Machine {
-    pub fn init(stream: &mut impl io::Read) -> Result<Machine, MachineInitError> {
-        println!("v:{:X}", 3);
+    pub(crate) fn init(stream: &mut impl io::Read) -> Result<Machine, MachineInitError> {
         let mem = Rc::new(Memory::load_program(stream)?);
 
-        println!("v:{:X}", mem.0[STACK_SIZE]);
         Ok(Machine {
             mem,
             gr: GeneralRegister::new([0; 8]),
@@ -59,236 +63,418 @@ impl Machine {
             zf: false,
 
             previous_word: None,
+            console: Rc::new(Console::default()),
+            halted: false,
+            protection: None,
+            shadow: None,
+            violations: Rc::new(Vec::new()),
         })
     }
 }
 
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum ExecError {
     #[error("{0}")]
     OperationNotDefined(#[from] operations::NewError),
     #[error("{0}")]
     MemoryGetError(#[from] memory::GetError),
+    #[error("SVC {0} is not defined")]
+    SupervisorCallNotDefined(u16),
+    #[error("{0}")]
+    Protection(#[from] Violation),
 }
 
 impl Machine {
+    /// 実効アドレス。GR0は指標レジスタとして使えないので `x` が0なら `addr` そのまま
     fn get_effective_value(&self, x: RegisterNumber, addr: u16) -> u16 {
-        let (_, x) = self.gr.get_pair(x, x);
-        addr + x
+        if x.0 == 0 {
+            addr
+        } else {
+            addr.wrapping_add(self.gr.get(x))
+        }
+    }
+
+    /// メモリを読んで `log` に記録する
+    fn read(&self, address: u16, log: &mut Vec<Access>) -> Result<u16, memory::GetError> {
+        let value = self.mem.get(address)?;
+        log.push(Access::read(Cell::Memory(address), value));
+        Ok(value)
     }
 
-    fn exec(&self, word: u16) -> Result<Machine, ExecError> {
+    /// 1語実行する。読み書きしたメモリは `log` に記録する
+    fn exec(&self, word: u16, log: &mut Vec<Access>) -> Result<Machine, ExecError> {
         use Operation1::*;
 
         // 2ワード命令の2語目部分に来ている
         if let Some(Word2 { operation, r, x }) = self.previous_word {
-            use Operation2::*;
-            // TODO access2のエラー処理 たぶんエラー出る
-
-            let set_gr = |value| self.mod_gr(r, value);
-            let effective_addr = self.get_effective_value(x, word);
+            let machine = Machine {
+                previous_word: None,
+                ..self.clone()
+            };
+            return machine.exec_2(operation, r, x, word, log);
+        }
 
-            return Ok(match operation {
-                Load => {
-                    let mem_value = self.mem.get(effective_addr)?;
-                    set_gr(mem_value).set_sf_zf(mem_value)
-                }
-                Store => {
-                    let r = self.gr.get(r);
-
-                    let mut mem = self.mem.0.clone();
-                    mem[effective_addr as usize] = r;
-                    let mem = Rc::new(Memory(mem));
-                    Machine {
-                        mem,
-                        ..self.clone()
-                    }
-                }
+        // 1語目
+        Ok(match operations::ope(word)? {
+            Either::Left(Word1 { operation, r1, r2 }) => match operation {
+                NoOperation => self.clone(),
+                Load1 => self.load_1(r1, r2),
+                AddArithmetic1 => self.add_arithmetic_1(r1, r2),
+                SubtractArithmetic1 => self.subtract_arithmetic_1(r1, r2),
 
-                LoadAddress => set_gr(effective_addr),
+                AddLogical1 => self.add_logical_1(r1, r2),
+                SubtractLogical1 => self.subtract_logical_1(r1, r2),
+                And1 => self.and_1(r1, r2),
+                Or1 => self.or_1(r1, r2),
+                Xor1 => self.xor_1(r1, r2),
 
-                AddLogical => {
-                    let r_value = self.gr.get(r);
-                    let mem_value = self.mem.get(effective_addr)?;
+                CompareArithmetic => self.compare_arithmetic(r1, r2),
+                CompareLogical => self.compare_logical(r1, r2),
 
-                    let (r_value, of) = r_value.overflowing_add(mem_value);
+                Pop => self.pop(r1, log)?,
+                Return => self.return_(log)?,
+            },
+            Either::Right(t) => Machine {
+                previous_word: Some(t),
+                ..self.clone()
+            },
+        })
+    }
 
-                    Machine {
-                        of,
-                        ..set_gr(r_value).set_sf_zf(r_value)
-                    }
+    /// 2ワード命令を実行する。`word` は2語目 (アドレス部)
+    fn exec_2(
+        &self,
+        operation: Operation2,
+        r: RegisterNumber,
+        x: RegisterNumber,
+        word: u16,
+        log: &mut Vec<Access>,
+    ) -> Result<Machine, ExecError> {
+        use Operation2::*;
+
+        let set_gr = |value| self.mod_gr(r, value);
+        let effective_addr = self.get_effective_value(x, word);
+
+        Ok(match operation {
+            Load => {
+                let mem_value = self.read(effective_addr, log)?;
+                Machine {
+                    of: false,
+                    ..set_gr(mem_value).set_sf_zf(mem_value)
                 }
+            }
+            Store => self.store(effective_addr, self.gr.get(r), log),
 
-                SubtractLogical => {
-                    let r_value = self.gr.get(r);
-                    let mem_value = self.mem.get(effective_addr)?;
+            LoadAddress => set_gr(effective_addr),
 
-                    let (r_value, of) = r_value.overflowing_sub(mem_value);
+            AddLogical => {
+                let r_value = self.gr.get(r);
+                let mem_value = self.read(effective_addr, log)?;
 
-                    Machine {
-                        of,
-                        ..set_gr(r_value).set_sf_zf(r_value)
-                    }
+                let (r_value, of) = r_value.overflowing_add(mem_value);
+
+                Machine {
+                    of,
+                    ..set_gr(r_value).set_sf_zf(r_value)
                 }
+            }
 
-                AddArithmetic => {
-                    let r_value = self.gr.get(r);
-                    let mem_value = self.mem.get(effective_addr)?;
+            SubtractLogical => {
+                let r_value = self.gr.get(r);
+                let mem_value = self.read(effective_addr, log)?;
 
-                    let (r_value, of) = (r_value as i16).overflowing_add(mem_value as i16);
+                let (r_value, of) = r_value.overflowing_sub(mem_value);
 
-                    Machine {
-                        of,
-                        ..self.mod_gr(r, r_value as u16)
-                    }
+                Machine {
+                    of,
+                    ..set_gr(r_value).set_sf_zf(r_value)
                 }
-                SubtractArithmetic => {
-                    let r_value = self.gr.get(r);
-                    let mem_value = self.mem.get(effective_addr)?;
-
-                    let (r_value, of) = (r_value as i16).overflowing_sub(mem_value as i16);
+            }
 
-                    Machine {
-                        of,
-                        ..self.mod_gr(r, r_value as u16)
-                    }
-                }
-                Or => {
-                    let r_value = self.gr.get(r);
-                    let mem_value = self.mem.get(effective_addr)?;
+            AddArithmetic => {
+                let r_value = self.gr.get(r);
+                let mem_value = self.read(effective_addr, log)?;
 
-                    let r_value = r_value | mem_value;
+                let (r_value, of) = (r_value as i16).overflowing_add(mem_value as i16);
+                let r_value = r_value as u16;
 
-                    set_gr(r_value).set_sf_zf(r_value)
+                Machine {
+                    of,
+                    ..set_gr(r_value).set_sf_zf(r_value)
                 }
-                And => {
-                    let r_value = self.gr.get(r);
-                    let mem_value = self.mem.get(effective_addr)?;
+            }
+            SubtractArithmetic => {
+                let r_value = self.gr.get(r);
+                let mem_value = self.read(effective_addr, log)?;
 
-                    let r_value = r_value & mem_value;
+                let (r_value, of) = (r_value as i16).overflowing_sub(mem_value as i16);
+                let r_value = r_value as u16;
 
-                    set_gr(r_value).set_sf_zf(r_value)
+                Machine {
+                    of,
+                    ..set_gr(r_value).set_sf_zf(r_value)
                 }
-                Xor => {
-                    let r_value = self.gr.get(r);
-                    let mem_value = self.mem.get(effective_addr)?;
+            }
+            Or => {
+                let r_value = self.gr.get(r);
+                let mem_value = self.read(effective_addr, log)?;
 
-                    let r_value = r_value ^ mem_value;
+                let r_value = r_value | mem_value;
 
-                    set_gr(r_value).set_sf_zf(r_value)
+                Machine {
+                    of: false,
+                    ..set_gr(r_value).set_sf_zf(r_value)
                 }
+            }
+            And => {
+                let r_value = self.gr.get(r);
+                let mem_value = self.read(effective_addr, log)?;
 
-                JumpOnPlus => self.jump_to(x, word, self.sf == false && self.zf == false),
-                JumpOnMinus => self.jump_to(x, word, self.sf == true),
-                JumpOnNonZero => self.jump_to(x, word, self.zf == false),
-                JumpOnZero => self.jump_to(x, word, self.zf == true),
-                JumpOnOverflow => self.jump_to(x, word, self.of == true),
-                UnconditionalJump => self.jump_to(x, word, true),
-
-                Push => {
-                    let mut mem = self.mem.0.clone();
-
-                    let sp = self.sp - 1;
-                    mem[self.sp as usize] = effective_addr;
-
-                    let mem = Rc::new(Memory(mem));
-                    Machine {
-                        sp,
-                        mem,
-                        ..self.clone()
-                    }
-                }
+                let r_value = r_value & mem_value;
 
-                Call => {
-                    let mut mem = self.mem.0.clone();
+                Machine {
+                    of: false,
+                    ..set_gr(r_value).set_sf_zf(r_value)
+                }
+            }
+            Xor => {
+                let r_value = self.gr.get(r);
+                let mem_value = self.read(effective_addr, log)?;
 
-                    let sp = self.sp - 1;
-                    mem[sp as usize] = self.pr;
+                let r_value = r_value ^ mem_value;
 
-                    let mem = Rc::new(Memory(mem));
-                    let pr = effective_addr;
-                    Machine {
-                        sp,
-                        mem,
-                        pr,
-                        ..self.clone()
-                    }
+                Machine {
+                    of: false,
+                    ..set_gr(r_value).set_sf_zf(r_value)
                 }
-                _ => unimplemented!(),
-            });
-        }
-
-        // 1語目
-        Ok(match operations::ope(word)? {
-            Either::Left(Word1 { operation, r1, r2 }) => match operation {
-                NoOperation => self.clone(),
-                AddArithmetic1 => self.add_arithmetic_1(r1, r2),
-                SubtractArithmetic1 => self.subtract_arithmetic_1(r1, r2),
+            }
 
-                AddLogical1 => self.add_logical_1(r1, r2),
-                SubtractLogical1 => self.subtract_logical_1(r1, r2),
-                And1 => self.and_1(r1, r2),
-                Or1 => self.or_1(r1, r2),
-                Xor1 => self.xor_1(r1, r2),
+            CompareArithmetic => {
+                let r_value = self.gr.get(r) as i16;
+                let mem_value = self.read(effective_addr, log)? as i16;
+                self.compare(r_value, mem_value)
+            }
+            CompareLogical => {
+                let r_value = self.gr.get(r);
+                let mem_value = self.read(effective_addr, log)?;
+                self.compare(r_value, mem_value)
+            }
 
-                CompareArithmetic => self.compare_arithmetic(r1, r2),
-                CompareLogical => self.compare_logical(r1, r2),
+            ShiftLeftArithmetic => self.shift(r, effective_addr, |v| {
+                ((v & 0x8000) | ((v << 1) & 0x7fff), v & 0x4000 != 0)
+            }),
+            ShiftRightArithmetic => self.shift(r, effective_addr, |v| {
+                (((v as i16) >> 1) as u16, v & 1 != 0)
+            }),
+            ShiftLeftLogical => self.shift(r, effective_addr, |v| (v << 1, v & 0x8000 != 0)),
+            ShiftRightLogical => self.shift(r, effective_addr, |v| (v >> 1, v & 1 != 0)),
+
+            JumpOnPlus => self.jump_to(effective_addr, !self.sf && !self.zf),
+            JumpOnMinus => self.jump_to(effective_addr, self.sf),
+            JumpOnNonZero => self.jump_to(effective_addr, !self.zf),
+            JumpOnZero => self.jump_to(effective_addr, self.zf),
+            JumpOnOverflow => self.jump_to(effective_addr, self.of),
+            UnconditionalJump => self.jump_to(effective_addr, true),
+
+            Push => {
+                let sp = self.sp.wrapping_sub(1);
+                Machine {
+                    sp,
+                    ..self.store(sp, effective_addr, log)
+                }
+            }
 
-                Pop => self.pop(r1),
-                Return => self.return_(),
+            Call => {
+                let sp = self.sp.wrapping_sub(1);
+                Machine {
+                    sp,
+                    pr: effective_addr,
+                    ..self.store(sp, self.pr, log)
+                }
+            }
 
-                _ => unimplemented!(),
-            },
-            Either::Right(t) => Machine {
-                previous_word: Some(t),
-                ..self.clone()
+            SupervisorCall => match effective_addr {
+                console::SVC_IN => self.supervisor_in(log),
+                console::SVC_OUT => self.supervisor_out(log)?,
+                n => Err(ExecError::SupervisorCallNotDefined(n))?,
             },
         })
     }
 }
 
 impl Machine {
-    pub fn mem_info(&self) -> String {
-        let mem = self.mem.0;
-        let mem = &mem[..256];
-
-        format!("bytes: {:X?}, length: {}", mem, mem.len())
+    pub fn gr(&self) -> GeneralRegister {
+        self.gr
     }
-
-    /// PRが現在指示しているメモリの番地
-    pub fn pr_at(&self) -> String {
-        format!("{:X}", self.mem.get(self.pr).unwrap() as u16)
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+    pub fn pr(&self) -> u16 {
+        self.pr
+    }
+    pub fn of(&self) -> bool {
+        self.of
+    }
+    pub fn sf(&self) -> bool {
+        self.sf
+    }
+    pub fn zf(&self) -> bool {
+        self.zf
+    }
+    pub fn is_halted(&self) -> bool {
+        self.halted
     }
 
-    pub fn r_info(&self) -> String {
-        format!("GR: {:X?}\nPR: {:X}SP: {:X}", self.gr, self.pr, self.sp,)
+    /// PRを変更したMachineを返す
+    pub fn with_pr(&self, pr: u16) -> Machine {
+        Machine { pr, ..self.clone() }
     }
 }
 
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum StepError {
     #[error("{0}")]
     ExecError(#[from] ExecError),
     #[error("{0}")]
     MemoryGetError(#[from] memory::GetError),
+    #[error("Machine has already halted")]
+    Halted,
 }
 
 impl Machine {
+    /// 1語読んで実行する。PRは実行前に進める
     pub fn clock(&self) -> Result<Machine, StepError> {
+        self.clock_with(&mut ())
+    }
+
+    /// `clock` と同じだが、起きたことを `observer` に知らせる
+    pub fn clock_with(&self, observer: &mut impl MachineObserver) -> Result<Machine, StepError> {
+        self.clock_logged(&mut Vec::new(), observer)
+    }
+
+    /// `clock_with` と同じだが、読み書きしたメモリを `log` に記録する。命令の読み込みは含まない
+    fn clock_logged(
+        &self,
+        log: &mut Vec<Access>,
+        observer: &mut impl MachineObserver,
+    ) -> Result<Machine, StepError> {
+        if self.halted {
+            return Err(StepError::Halted);
+        }
         let word = self.mem.get(self.pr)?;
-        let machine = self.exec(word as u16)?;
+        let machine = Machine {
+            pr: self.pr.wrapping_add(1),
+            ..self.clone()
+        };
 
-        Ok(Machine {
-            pr: machine.pr + 16,
-            ..machine
-        })
+        let logged = log.len();
+        let mut machine = machine.exec(word, log)?;
+        if self.protection.is_none() && self.shadow.is_none() {
+            self.notify(word, &log[logged..], &machine, observer);
+            return Ok(machine);
+        }
+        // 違反は2語目を読んだときも命令の先頭番地で報告する
+        let start = self.previous_word.is_none();
+        let address = if start {
+            self.pr
+        } else {
+            self.pr.wrapping_sub(1)
+        };
+        let mut found = Vec::new();
+        if let Some(protection) = &self.protection {
+            if let Some(violation) = protection.check(address, start, &log[logged..]) {
+                found.push((protection.mode, violation));
+            }
+        }
+        // シャドウは命令を最後まで実行してから調べる
+        if let (Some(shadow), None) = (&self.shadow, machine.previous_word) {
+            let instruction = match self.previous_word {
+                Some(word2) => Either::Right(word2),
+                None => operations::ope(word).map_err(ExecError::from)?,
+            };
+            let (next, violations) = shadow.check(address, instruction, &log[logged..]);
+            // 変わらなければ共有したままにして、履歴にコピーを残さない
+            if next != **shadow {
+                machine.shadow = Some(Rc::new(next));
+            }
+            found.extend(violations.into_iter().map(|v| (shadow.mode, v)));
+        }
+        for (mode, violation) in found {
+            match mode {
+                ProtectionMode::Trap => return Err(ExecError::from(violation).into()),
+                ProtectionMode::Warn => {
+                    let mut violations = Vec::clone(&machine.violations);
+                    violations.push(violation);
+                    machine.violations = Rc::new(violations);
+                }
+            }
+        }
+        self.notify(word, &log[logged..], &machine, observer);
+        Ok(machine)
+    }
+
+    /// 1命令実行する。2ワード命令なら2語目まで読む
+    pub fn step(&self) -> Result<Machine, StepError> {
+        self.step_with(&mut ())
+    }
+
+    /// `step` と同じだが、起きたことを `observer` に知らせる
+    pub fn step_with(&self, observer: &mut impl MachineObserver) -> Result<Machine, StepError> {
+        let mut machine = self.clock_with(observer)?;
+        while machine.previous_word.is_some() {
+            machine = machine.clock_with(observer)?;
+        }
+        Ok(machine)
     }
+
+    /// `step` と同じだが、命令が読み書きした場所も返す。
+    /// メモリは実行しながら記録し、レジスタ・SP・フラグは命令の種類から求める
+    pub fn step_traced(&self) -> Result<(Machine, Vec<Access>), StepError> {
+        let instruction = match self.previous_word {
+            Some(word2) => Either::Right(word2),
+            None => operations::ope(self.mem.get(self.pr)?).map_err(ExecError::from)?,
+        };
+
+        let mut memory = Vec::new();
+        let mut machine = self.clock_logged(&mut memory, &mut ())?;
+        while machine.previous_word.is_some() {
+            machine = machine.clock_logged(&mut memory, &mut ())?;
+        }
+
+        let mut log = Vec::new();
+        let (reads, writes, flags) = register_effects(instruction);
+        log.extend(
+            reads
+                .iter()
+                .map(|&cell| Access::read(cell, self.cell(cell))),
+        );
+        log.extend(memory);
+        let writes = writes
+            .iter()
+            .chain(flags.iter())
+            .filter(|&&cell| !(cell == Cell::Sp && machine.halted));
+        log.extend(writes.map(|&cell| Access::write(cell, self.cell(cell), machine.cell(cell))));
+        Ok((machine, log))
+    }
+
     /// 指定してレジスタの値を変更したMachineを返す
     fn mod_gr(&self, r1: RegisterNumber, r1_value: u16) -> Machine {
         let gr = self.gr.set(r1, r1_value);
         Machine { gr, ..self.clone() }
     }
 
+    /// 指定した番地に書き込んだMachineを返す
+    fn store(&self, addr: u16, value: u16, log: &mut Vec<Access>) -> Machine {
+        let old = self.mem.get(addr).unwrap_or(0);
+        log.push(Access::write(Cell::Memory(addr), old, value));
+        Machine {
+            mem: Rc::new(self.mem.set(addr, value)),
+            ..self.clone()
+        }
+    }
+
     /// SF, ZFをセットしたMachineを返す
     fn set_sf_zf(&self, value: u16) -> Machine {
         let sf = is_negative(value);
@@ -302,11 +488,18 @@ impl Machine {
 }
 
 impl Machine {
+    fn load_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+        let r2_v = self.gr.get(r2);
+        Machine {
+            of: false,
+            ..self.mod_gr(r1, r2_v).set_sf_zf(r2_v)
+        }
+    }
+
     fn logical_1<F>(&self, r1: RegisterNumber, r2: RegisterNumber, f: F) -> Machine
     where
         F: FnOnce(u16, u16) -> (u16, bool),
     {
-        // TODO フラグレジスタ
         let (r1_v, r2_v) = self.gr.get_pair(r1, r2);
         let (r1_v, of) = f(r1_v, r2_v);
 
@@ -316,11 +509,11 @@ impl Machine {
         }
     }
 
-    pub fn add_logical_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn add_logical_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         self.logical_1(r1, r2, u16::overflowing_add)
     }
 
-    pub fn subtract_logical_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn subtract_logical_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         self.logical_1(r1, r2, u16::overflowing_sub)
     }
 
@@ -338,11 +531,11 @@ impl Machine {
         }
     }
 
-    pub fn add_arithmetic_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn add_arithmetic_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         self.arithmetic_1(r1, r2, i16::overflowing_add)
     }
 
-    pub fn subtract_arithmetic_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn subtract_arithmetic_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         self.arithmetic_1(r1, r2, i16::overflowing_sub)
     }
 
@@ -351,40 +544,38 @@ impl Machine {
         F: FnOnce(u16, u16) -> u16,
     {
         let (r1_v, r2_v) = self.gr.get_pair(r1, r2);
-        self.mod_gr(r1, f(r1_v, r2_v))
+        let r1_v = f(r1_v, r2_v);
+        Machine {
+            of: false,
+            ..self.mod_gr(r1, r1_v).set_sf_zf(r1_v)
+        }
     }
 
-    pub fn and_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn and_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         self.bit_1(r1, r2, ops::BitAnd::bitand)
     }
-    pub fn or_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn or_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         self.bit_1(r1, r2, ops::BitOr::bitor)
     }
-    pub fn xor_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn xor_1(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         self.bit_1(r1, r2, ops::BitXor::bitxor)
     }
 
-    pub fn pop(&self, r: RegisterNumber) -> Machine {
-        let r_value = self.mem.get(self.sp).unwrap();
-        let sp = self.sp + 1;
-        Machine {
+    fn pop(&self, r: RegisterNumber, log: &mut Vec<Access>) -> Result<Machine, ExecError> {
+        let r_value = self.read(self.sp, log)?;
+        let sp = self.sp.wrapping_add(1);
+        Ok(Machine {
             sp,
             ..self.mod_gr(r, r_value)
-        }
+        })
     }
 }
 
-#[derive(Debug, thiserror::Error)]
-#[error("MemoryAccessError")]
-pub struct MemoryAccessError(#[from] memory::GetError);
-
 // 2ワード命令の実装
 
 impl Machine {
     /// ジャンプする
-    fn jump_to(&self, x: RegisterNumber, addr: u16, cond: bool) -> Machine {
-        let effective_addr = self.get_effective_value(x, addr);
-
+    fn jump_to(&self, effective_addr: u16, cond: bool) -> Machine {
         if cond {
             Machine {
                 pr: effective_addr,
@@ -394,44 +585,303 @@ impl Machine {
             self.clone()
         }
     }
-    pub fn compare<T: cmp::Ord>(&self, a: T, b: T) -> Machine {
+    fn compare<T: cmp::Ord>(&self, a: T, b: T) -> Machine {
         let (sf, zf) = match a.cmp(&b) {
             cmp::Ordering::Greater => (false, false),
             cmp::Ordering::Equal => (false, true),
             cmp::Ordering::Less => (true, false),
         };
         Machine {
+            of: false,
             sf,
             zf,
             ..self.clone()
         }
     }
 
-    pub fn compare_arithmetic(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn compare_arithmetic(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         let (r1, r2) = self.gr.get_pair_arithmetic(r1, r2);
         self.compare(r1, r2)
     }
-    pub fn compare_logical(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
+    fn compare_logical(&self, r1: RegisterNumber, r2: RegisterNumber) -> Machine {
         let (r1, r2) = self.gr.get_pair(r1, r2);
         self.compare(r1, r2)
     }
 
-    fn return_(&self) -> Machine {
-        // TODO resultにする
-        let pr = self.mem.get(self.sp).unwrap();
-        let sp = self.sp + 1;
+    /// 1bitずつ `count` 回シフトする。`f` はシフト後の値と送り出されたビットを返す。
+    /// OFには最後に送り出されたビットが入る
+    fn shift<F>(&self, r: RegisterNumber, count: u16, f: F) -> Machine
+    where
+        F: Fn(u16) -> (u16, bool),
+    {
+        let (value, of) = (0..count.min(17)).fold((self.gr.get(r), false), |(v, _), _| f(v));
 
         Machine {
+            of,
+            ..self.mod_gr(r, value).set_sf_zf(value)
+        }
+    }
+
+    fn return_(&self, log: &mut Vec<Access>) -> Result<Machine, ExecError> {
+        if self.sp as usize == STACK_SIZE {
+            return Ok(Machine {
+                halted: true,
+                ..self.clone()
+            });
+        }
+        let pr = self.read(self.sp, log)?;
+        let sp = self.sp.wrapping_add(1);
+
+        Ok(Machine {
             sp,
             pr,
             ..self.clone()
+        })
+    }
+
+    /// SVC 1: GR1の番地から1行読み込み、GR2の番地に文字数を書く。入力がなければ文字数は-1
+    fn supervisor_in(&self, log: &mut Vec<Access>) -> Machine {
+        let [_, buf, len, ..] = self.gr.values();
+        let (console, line) = self.console.read_line();
+
+        let mut mem = self.mem.0;
+        let mut write = |address: u16, value: u16| {
+            log.push(Access::write(
+                Cell::Memory(address),
+                mem[address as usize],
+                value,
+            ));
+            mem[address as usize] = value;
+        };
+        match line {
+            Some(line) => {
+                let words: Vec<u16> = line
+                    .chars()
+                    .take(console::LINE_MAX)
+                    .map(console::char_to_word)
+                    .collect();
+                for (i, &word) in words.iter().enumerate() {
+                    write(buf.wrapping_add(i as u16), word);
+                }
+                write(len, words.len() as u16);
+            }
+            None => write(len, -1i16 as u16),
+        }
+
+        Machine {
+            mem: Rc::new(Memory(mem)),
+            console: Rc::new(console),
+            ..self.clone()
+        }
+    }
+
+    /// SVC 2: GR1の番地から (GR2の番地の値) 文字を1行として出力する
+    fn supervisor_out(&self, log: &mut Vec<Access>) -> Result<Machine, ExecError> {
+        let [_, buf, len, ..] = self.gr.values();
+        let len = self.read(len, log)?;
+        let line = (0..len)
+            .map(|i| {
+                self.read(buf.wrapping_add(i), log)
+                    .map(console::word_to_char)
+            })
+            .collect::<Result<String, _>>()?;
+
+        Ok(Machine {
+            console: Rc::new(self.console.write_line(line)),
+            ..self.clone()
+        })
+    }
+}
+
+/// 命令が読むレジスタ、書くレジスタ、書くフラグ
+pub(super) fn register_effects(
+    instruction: Either<Word1, Word2>,
+) -> (Vec<Cell>, Vec<Cell>, Vec<Cell>) {
+    use Operation1::*;
+
+    let mut reads = Vec::new();
+    let mut writes = Vec::new();
+    let flag_effect = match instruction {
+        Either::Left(Word1 { operation, r1, r2 }) => {
+            match operation {
+                NoOperation => {}
+                Load1 => {
+                    reads.push(Cell::Register(r2));
+                    writes.push(Cell::Register(r1));
+                }
+                CompareArithmetic | CompareLogical => {
+                    reads.extend([Cell::Register(r1), Cell::Register(r2)]);
+                }
+                Pop => {
+                    reads.push(Cell::Sp);
+                    writes.extend([Cell::Register(r1), Cell::Sp]);
+                }
+                Return => {
+                    reads.push(Cell::Sp);
+                    writes.push(Cell::Sp);
+                }
+                _ => {
+                    reads.extend([Cell::Register(r1), Cell::Register(r2)]);
+                    writes.push(Cell::Register(r1));
+                }
+            }
+            operation.flag_effect()
+        }
+        Either::Right(Word2 { operation, r, x }) => {
+            use Operation2::*;
+
+            if x.0 != 0 {
+                reads.push(Cell::Register(x));
+            }
+            reads.extend(operation.flags_read().iter().map(|&f| Cell::Flag(f)));
+            match operation {
+                Load | LoadAddress => writes.push(Cell::Register(r)),
+                Store | CompareArithmetic | CompareLogical => reads.push(Cell::Register(r)),
+                Push | Call => {
+                    reads.push(Cell::Sp);
+                    writes.push(Cell::Sp);
+                }
+                SupervisorCall => {
+                    reads.extend([
+                        Cell::Register(RegisterNumber(1)),
+                        Cell::Register(RegisterNumber(2)),
+                    ]);
+                }
+                _ if operation.has_register() => {
+                    reads.push(Cell::Register(r));
+                    writes.push(Cell::Register(r));
+                }
+                _ => {}
+            }
+            operation.flag_effect()
+        }
+    };
+    let flags = match flag_effect {
+        Some(_) => Flag::ALL.iter().map(|&f| Cell::Flag(f)).collect(),
+        None => Vec::new(),
+    };
+    (reads, writes, flags)
+}
+
+impl Machine {
+    /// 入力行を末尾に追加したMachineを返す
+    pub fn with_input<I: IntoIterator<Item = String>>(&self, lines: I) -> Machine {
+        let mut console = Console::clone(&self.console);
+        console.input.extend(lines);
+        Machine {
+            console: Rc::new(console),
+            ..self.clone()
+        }
+    }
+
+    /// これまでにOUTで出力された行
+    pub fn output(&self) -> &[String] {
+        &self.console.output
+    }
+
+    /// 次に完了する命令がINで、入力が残っていないか
+    pub fn needs_input(&self) -> bool {
+        self.console.input.is_empty() && self.reads_input()
+    }
+
+    /// 次に完了する命令がIN (SVC 1) か
+    pub fn reads_input(&self) -> bool {
+        let (Word2 { operation, x, .. }, addr) = match self.previous_word {
+            Some(word2) => (word2, self.mem.get(self.pr)),
+            None => match self.mem.get(self.pr).map(operations::ope) {
+                Ok(Ok(Either::Right(word2))) => (word2, self.mem.get(self.pr.wrapping_add(1))),
+                _ => return false,
+            },
+        };
+        match addr {
+            Ok(addr) => {
+                operation == Operation2::SupervisorCall
+                    && self.get_effective_value(x, addr) == console::SVC_IN
+            }
+            Err(_) => false,
         }
     }
 }
 
-// #[test]
-// fn test() -> Result<(), Box<dyn std::error::Error>> {
-//     let machine = Machine::init(&mut io::Cursor::new(vec![0x24]));
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn machine(words: &[u16]) -> Machine {
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        Machine::init(&mut io::Cursor::new(bytes)).unwrap()
+    }
+
+    fn run(machine: Machine) -> Machine {
+        let mut machine = machine;
+        while !machine.is_halted() {
+            machine = machine.step().unwrap();
+        }
+        machine
+    }
+
+    #[test]
+    fn add_arithmetic() {
+        // LAD GR1,5; LAD GR2,-7; ADDA GR1,GR2; RET
+        let m = run(machine(&[0x1210, 5, 0x1220, -7i16 as u16, 0x2412, 0x8100]));
+        assert_eq!(m.gr().values()[1], -2i16 as u16);
+        assert_eq!((m.of(), m.sf(), m.zf()), (false, true, false));
+    }
 
-//     Ok(())
-// }
+    #[test]
+    fn call_and_return() {
+        // CALL SUB; RET; SUB: LAD GR1,1; RET
+        let m = run(machine(&[0x8000, 0x103, 0x8100, 0x1210, 1, 0x8100]));
+        assert_eq!(m.gr().values()[1], 1);
+        assert_eq!(m.sp() as usize, STACK_SIZE);
+    }
+
+    #[test]
+    fn step_traced_reports_accesses() {
+        use crate::core::access::AccessKind;
+
+        // LAD GR2,1; ST GR1,#0110,GR2; CALL #0107; NOP; RET
+        let m = machine(&[0x1220, 1, 0x1112, 0x110, 0x8000, 0x107, 0x0000, 0x8100]);
+        let (m, _) = m.step_traced().unwrap();
+        let (m, log) = m.step_traced().unwrap();
+        assert_eq!(
+            log,
+            vec![
+                Access::read(Cell::Register(RegisterNumber(2)), 1),
+                Access::read(Cell::Register(RegisterNumber(1)), 0),
+                Access::write(Cell::Memory(0x111), 0, 0),
+            ]
+        );
+
+        let (_, log) = m.step_traced().unwrap();
+        let writes: Vec<_> = log
+            .iter()
+            .filter(|a| a.kind == AccessKind::Write)
+            .map(|a| (a.cell, a.old, a.new))
+            .collect();
+        assert_eq!(
+            writes,
+            vec![(Cell::Memory(0xff), 0, 0x106), (Cell::Sp, 0x100, 0xff)]
+        );
+    }
+
+    #[test]
+    fn shift_sets_overflow_to_last_bit() {
+        // LAD GR1,#C001; SLA GR1,1; RET
+        let m = run(machine(&[0x1210, 0xc001, 0x5010, 1, 0x8100]));
+        assert_eq!(m.gr().values()[1], 0x8002);
+        assert!(m.of());
+    }
+
+    #[test]
+    fn svc_in_out() {
+        // LAD GR1,#200; LAD GR2,#280; SVC 1; SVC 2; RET
+        let m = machine(&[0x1210, 0x200, 0x1220, 0x280, 0xf000, 1, 0xf000, 2, 0x8100]);
+        let m = m.step().unwrap().step().unwrap();
+        assert!(m.needs_input());
+
+        let m = run(m.with_input(vec!["CASL".to_string()]));
+        assert_eq!(m.mem.get(0x280).unwrap(), 4);
+        assert_eq!(m.output(), ["CASL"]);
+    }
+}
@@ -20,7 +20,7 @@ mod test {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Memory(pub [u16; 65536]);
 
 #[derive(Debug, thiserror::Error)]
@@ -34,7 +34,7 @@ impl Memory {
         let mut mem = [0; 65536];
 
         let mut buf = Vec::new();
-        let bytes = stream.read_to_end(&mut buf)?;
+        stream.read_to_end(&mut buf)?;
         buf.into_iter()
             .to_pairs()
             .map(u8u8_2_u16)
@@ -56,13 +56,19 @@ pub enum GetError {
 impl Memory {
     pub fn get(&self, index: u16) -> Result<u16, GetError> {
         let index = index as usize;
-        let Memory(raw) = *self;
+        let Memory(raw) = self;
         if index < raw.len() {
             Ok(raw[index])
         } else {
             Err(GetError::OutOfIndex(index))
         }
     }
+    /// 指定した番地の値を変更したMemoryを返す
+    pub fn set(&self, index: u16, value: u16) -> Memory {
+        let mut mem = self.0;
+        mem[index as usize] = value;
+        Memory(mem)
+    }
     pub fn info(&self) -> String {
         let mem = self.0;
         let stack = &mem[..machine::STACK_SIZE];
@@ -1,5 +1,15 @@
+pub mod access;
+pub mod console;
+pub mod cost;
+pub mod event;
 pub mod machine;
 pub mod memory;
+pub mod observer;
 pub mod operations;
+pub mod policy;
+pub mod protection;
 pub mod register;
+pub mod runner;
+pub mod shadow;
+pub mod snapshot;
 mod utils;
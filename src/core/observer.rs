@@ -0,0 +1,185 @@
+//! 実行中に起きたことを外から受け取るフック。`Machine::clock_with` に渡す
+//!
+//! 1語ごとに、語を実行し終えてから次の順で呼ぶ。
+//! 命令の読み込み、SVC、メモリの読み書き (実行した順)、レジスタの書き込み、フラグの変化、CALL/RET/停止。
+//! レジスタとフラグは2ワード命令なら2語目を実行したときに呼ぶ。
+//! エラーになった語については何も呼ばない
+
+use super::access::{Access, AccessKind, Cell};
+use super::machine::{register_effects, Machine};
+use super::operations::{self, Operation1, Operation2, Word2};
+use super::register::Flag;
+use itertools::Either;
+
+/// メソッドはすべて何もしないのがデフォルト。必要なものだけ実装する
+pub trait MachineObserver {
+    /// PRの指す `address` から1語読んだ。2ワード命令なら2回呼ぶ
+    fn fetch(&mut self, _address: u16, _word: u16) {}
+    /// SVC `number` を実行した
+    fn supervisor_call(&mut self, _number: u16) {}
+    fn memory_read(&mut self, _address: u16, _value: u16) {}
+    fn memory_written(&mut self, _address: u16, _old: u16, _new: u16) {}
+    /// GR0~GR7かSPに書き込んだ。同じ値を書いたときも呼ぶ
+    fn register_written(&mut self, _register: Cell, _old: u16, _new: u16) {}
+    /// フラグの値が変わった
+    fn flag_changed(&mut self, _flag: Flag, _value: bool) {}
+    /// `target` にCALLした。戻り先は `return_address`
+    fn call(&mut self, _target: u16, _return_address: u16) {}
+    /// RETで `address` に戻った
+    fn returned(&mut self, _address: u16) {}
+    /// スタックが空の状態でRETして停止した
+    fn halted(&mut self) {}
+}
+
+/// 何もしない
+impl MachineObserver for () {}
+
+impl Machine {
+    /// `self` の `pr` から `word` を読んで実行した結果が `next` で、そのとき読み書きしたメモリが `log`
+    pub(super) fn notify(
+        &self,
+        word: u16,
+        log: &[Access],
+        next: &Machine,
+        observer: &mut impl MachineObserver,
+    ) {
+        observer.fetch(self.pr, word);
+        if let Some(Word2 {
+            operation: Operation2::SupervisorCall,
+            x,
+            ..
+        }) = self.previous_word
+        {
+            let x = if x.0 == 0 { 0 } else { self.gr.get(x) };
+            observer.supervisor_call(word.wrapping_add(x));
+        }
+        for access in log {
+            if let Cell::Memory(address) = access.cell {
+                match access.kind {
+                    AccessKind::Read => observer.memory_read(address, access.new),
+                    AccessKind::Write => observer.memory_written(address, access.old, access.new),
+                }
+            }
+        }
+
+        // 2ワード命令の1語目なら命令はまだ終わっていない
+        if next.previous_word.is_some() {
+            return;
+        }
+        let instruction = match self.previous_word {
+            Some(word2) => Either::Right(word2),
+            None => match operations::ope(word) {
+                Ok(instruction) => instruction,
+                Err(_) => return,
+            },
+        };
+        let (_, writes, _) = register_effects(instruction);
+        for cell in writes {
+            if !(cell == Cell::Sp && next.halted) {
+                observer.register_written(cell, self.cell(cell), next.cell(cell));
+            }
+        }
+        for flag in Flag::ALL {
+            let (old, new) = (self.cell(Cell::Flag(flag)), next.cell(Cell::Flag(flag)));
+            if old != new {
+                observer.flag_changed(flag, new != 0);
+            }
+        }
+        match instruction {
+            Either::Right(Word2 {
+                operation: Operation2::Call,
+                ..
+            }) => observer.call(next.pr, self.pr.wrapping_add(1)),
+            Either::Left(word1) if word1.operation == Operation1::Return => {
+                if next.halted {
+                    observer.halted();
+                } else {
+                    observer.returned(next.pr);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl MachineObserver for Recorder {
+        fn supervisor_call(&mut self, number: u16) {
+            self.0.push(format!("SVC {}", number));
+        }
+        fn memory_written(&mut self, address: u16, _old: u16, new: u16) {
+            self.0.push(format!("#{:04X}<-{}", address, new));
+        }
+        fn register_written(&mut self, register: Cell, old: u16, new: u16) {
+            self.0.push(format!("{}:{}->{}", register, old, new));
+        }
+        fn flag_changed(&mut self, flag: Flag, value: bool) {
+            self.0.push(format!("{}={}", flag.name(), value as u8));
+        }
+        fn call(&mut self, target: u16, return_address: u16) {
+            self.0
+                .push(format!("CALL #{:04X} #{:04X}", target, return_address));
+        }
+        fn returned(&mut self, address: u16) {
+            self.0.push(format!("RET #{:04X}", address));
+        }
+        fn halted(&mut self) {
+            self.0.push("halted".to_string());
+        }
+    }
+
+    #[test]
+    fn events() {
+        let program = casl::assemble(
+            "MAIN  START
+      CALL  SUB
+      RET
+SUB   LAD   GR1,-1
+      RET
+      END",
+        )
+        .unwrap();
+        let mut machine = program.load();
+        let mut recorder = Recorder::default();
+        while !machine.is_halted() {
+            machine = machine.step_with(&mut recorder).unwrap();
+        }
+        assert_eq!(
+            recorder.0,
+            [
+                "#00FF<-258",
+                "SP:256->255",
+                "CALL #0103 #0102",
+                "GR1:0->65535",
+                "SP:255->256",
+                "RET #0102",
+                "halted"
+            ]
+        );
+    }
+
+    #[test]
+    fn fetch() {
+        #[derive(Default)]
+        struct Fetches(Vec<(u16, u16)>);
+        impl MachineObserver for Fetches {
+            fn fetch(&mut self, address: u16, word: u16) {
+                self.0.push((address, word));
+            }
+        }
+
+        let program = casl::assemble("MAIN START\n LD GR1,GR2\n LAD GR0,3\n RET\n END").unwrap();
+        let mut fetches = Fetches::default();
+        let machine = program.load().step_with(&mut fetches).unwrap();
+        let machine = machine.step_with(&mut fetches).unwrap();
+        assert_eq!(machine.pr(), 0x103);
+        assert_eq!(fetches.0, [(0x100, 0x1412), (0x101, 0x1200), (0x102, 3)]);
+    }
+}
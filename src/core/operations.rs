@@ -1,8 +1,9 @@
 //! 命令語の解釈とその処理の実行
 
+use super::register::Flag;
 use itertools::Either;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operation2 {
     /// r <- (実効アドレス)
     Load,
@@ -50,9 +51,13 @@ pub enum Operation2 {
 
     /// - フラグ維持
     Call,
+
+    /// - 実効アドレスで入出力などの機能を指定する
+    /// - フラグ維持
+    SupervisorCall,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operation1 {
     /// 何もしない
     NoOperation,
@@ -84,21 +89,19 @@ pub enum Operation1 {
 }
 
 pub fn ope(word: u16) -> Result<Either<Word1, Word2>, NewError> {
-    let (r1_r, r2_x) = RegisterNumber::new_pair(word);
-    Ok(Operation1::new(word).map_or(
-        Either::Right(Word2 {
+    let (r1_r, r2_x) = RegisterNumber::new_pair(word)?;
+    Ok(match Operation1::new(word) {
+        Ok(operation) => Either::Left(Word1 {
+            operation,
+            r1: r1_r,
+            r2: r2_x,
+        }),
+        Err(_) => Either::Right(Word2 {
             operation: Operation2::new(word)?,
             r: r1_r,
             x: r2_x,
         }),
-        |operation| {
-            Either::Left(Word1 {
-                operation,
-                r1: r1_r,
-                r2: r2_x,
-            })
-        },
-    ))
+    })
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -109,20 +112,47 @@ pub enum NewError {
     RegisterOutOfIndex(#[from] RegisterOutOfIndex),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Word1 {
     pub operation: Operation1,
     pub r1: RegisterNumber,
     pub r2: RegisterNumber,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Word2 {
     pub operation: Operation2,
     pub r: RegisterNumber,
     pub x: RegisterNumber,
 }
 
+impl Word2 {
+    /// 1語目のビット列に戻す
+    pub fn encode(&self) -> u16 {
+        ((self.operation.opcode() as u16) << 8) | ((self.r.0 as u16) << 4) | self.x.0 as u16
+    }
+}
+
 impl Operation1 {
+    pub const ALL: [Operation1; 13] = {
+        use Operation1::*;
+        [
+            NoOperation,
+            Load1,
+            AddArithmetic1,
+            SubtractArithmetic1,
+            AddLogical1,
+            SubtractLogical1,
+            And1,
+            Or1,
+            Xor1,
+            CompareArithmetic,
+            CompareLogical,
+            Pop,
+            Return,
+        ]
+    };
+
     pub fn new(word: u16) -> Result<Operation1, NewError> {
         use Operation1::*;
 
@@ -148,9 +178,98 @@ impl Operation1 {
             e => Err(NewError::OperationNotDefined(e))?,
         })
     }
+
+    /// 命令コード (上位8bit)
+    pub fn opcode(&self) -> u8 {
+        use Operation1::*;
+
+        match self {
+            NoOperation => 0x00,
+            Load1 => 0x14,
+            AddArithmetic1 => 0x24,
+            SubtractArithmetic1 => 0x25,
+            AddLogical1 => 0x26,
+            SubtractLogical1 => 0x27,
+
+            And1 => 0x34,
+            Or1 => 0x35,
+            Xor1 => 0x36,
+
+            CompareArithmetic => 0x44,
+            CompareLogical => 0x45,
+
+            Pop => 0x71,
+
+            Return => 0x81,
+        }
+    }
+
+    /// CASL2のニーモニック
+    pub fn mnemonic(&self) -> &'static str {
+        use Operation1::*;
+
+        match self {
+            NoOperation => "NOP",
+            Load1 => "LD",
+            AddArithmetic1 => "ADDA",
+            SubtractArithmetic1 => "SUBA",
+            AddLogical1 => "ADDL",
+            SubtractLogical1 => "SUBL",
+
+            And1 => "AND",
+            Or1 => "OR",
+            Xor1 => "XOR",
+
+            CompareArithmetic => "CPA",
+            CompareLogical => "CPL",
+
+            Pop => "POP",
+
+            Return => "RET",
+        }
+    }
+
+    /// ニーモニックから1語命令を探す
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Operation1> {
+        Operation1::ALL
+            .iter()
+            .copied()
+            .find(|o| o.mnemonic() == mnemonic)
+    }
 }
 
 impl Operation2 {
+    pub const ALL: [Operation2; 25] = {
+        use Operation2::*;
+        [
+            Load,
+            Store,
+            LoadAddress,
+            AddArithmetic,
+            SubtractArithmetic,
+            AddLogical,
+            SubtractLogical,
+            And,
+            Or,
+            Xor,
+            CompareArithmetic,
+            CompareLogical,
+            ShiftLeftArithmetic,
+            ShiftRightArithmetic,
+            ShiftLeftLogical,
+            ShiftRightLogical,
+            JumpOnMinus,
+            JumpOnNonZero,
+            JumpOnZero,
+            UnconditionalJump,
+            JumpOnPlus,
+            JumpOnOverflow,
+            Push,
+            Call,
+            SupervisorCall,
+        ]
+    };
+
     pub fn new(word: u16) -> Result<Operation2, NewError> {
         use Operation2::*;
 
@@ -172,8 +291,8 @@ impl Operation2 {
             0x4100 => CompareLogical,
 
             0x5000 => ShiftLeftArithmetic,
-            0x5100 => ShiftLeftLogical,
-            0x5200 => ShiftRightArithmetic,
+            0x5100 => ShiftRightArithmetic,
+            0x5200 => ShiftLeftLogical,
             0x5300 => ShiftRightLogical,
 
             0x6100 => JumpOnMinus,
@@ -186,32 +305,195 @@ impl Operation2 {
             0x7000 => Push,
             0x8000 => Call,
 
+            0xf000 => SupervisorCall,
+
             e => Err(NewError::OperationNotDefined(e))?,
         })
     }
+
+    /// 命令コード (1語目の上位8bit)
+    pub fn opcode(&self) -> u8 {
+        use Operation2::*;
+
+        match self {
+            Load => 0x10,
+            Store => 0x11,
+            LoadAddress => 0x12,
+
+            AddArithmetic => 0x20,
+            SubtractArithmetic => 0x21,
+            AddLogical => 0x22,
+            SubtractLogical => 0x23,
+
+            And => 0x30,
+            Or => 0x31,
+            Xor => 0x32,
+
+            CompareArithmetic => 0x40,
+            CompareLogical => 0x41,
+
+            ShiftLeftArithmetic => 0x50,
+            ShiftRightArithmetic => 0x51,
+            ShiftLeftLogical => 0x52,
+            ShiftRightLogical => 0x53,
+
+            JumpOnMinus => 0x61,
+            JumpOnNonZero => 0x62,
+            JumpOnZero => 0x63,
+            UnconditionalJump => 0x64,
+            JumpOnPlus => 0x65,
+            JumpOnOverflow => 0x66,
+
+            Push => 0x70,
+            Call => 0x80,
+
+            SupervisorCall => 0xf0,
+        }
+    }
+
+    /// CASL2のニーモニック
+    pub fn mnemonic(&self) -> &'static str {
+        use Operation2::*;
+
+        match self {
+            Load => "LD",
+            Store => "ST",
+            LoadAddress => "LAD",
+
+            AddArithmetic => "ADDA",
+            SubtractArithmetic => "SUBA",
+            AddLogical => "ADDL",
+            SubtractLogical => "SUBL",
+
+            And => "AND",
+            Or => "OR",
+            Xor => "XOR",
+
+            CompareArithmetic => "CPA",
+            CompareLogical => "CPL",
+
+            ShiftLeftArithmetic => "SLA",
+            ShiftRightArithmetic => "SRA",
+            ShiftLeftLogical => "SLL",
+            ShiftRightLogical => "SRL",
+
+            JumpOnMinus => "JMI",
+            JumpOnNonZero => "JNZ",
+            JumpOnZero => "JZE",
+            UnconditionalJump => "JUMP",
+            JumpOnPlus => "JPL",
+            JumpOnOverflow => "JOV",
+
+            Push => "PUSH",
+            Call => "CALL",
+
+            SupervisorCall => "SVC",
+        }
+    }
+
+    /// ニーモニックから2語命令を探す
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Operation2> {
+        Operation2::ALL
+            .iter()
+            .copied()
+            .find(|o| o.mnemonic() == mnemonic)
+    }
+
+    /// 汎用レジスタ r を取る命令か (取らないのはジャンプ, PUSH, CALL, SVC)
+    pub fn has_register(&self) -> bool {
+        use Operation2::*;
+
+        !matches!(
+            self,
+            JumpOnMinus
+                | JumpOnNonZero
+                | JumpOnZero
+                | UnconditionalJump
+                | JumpOnPlus
+                | JumpOnOverflow
+                | Push
+                | Call
+                | SupervisorCall
+        )
+    }
+}
+
+/// 命令がフラグをどう変えるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagEffect {
+    /// OF, SF, ZFを結果に応じて設定する
+    Set,
+    /// OFは0にし、SF, ZFを結果に応じて設定する
+    OverflowCleared,
+}
+
+impl Operation1 {
+    /// フラグを変えない命令ならNone
+    pub fn flag_effect(&self) -> Option<FlagEffect> {
+        use Operation1::*;
+
+        match self {
+            AddArithmetic1 | SubtractArithmetic1 | AddLogical1 | SubtractLogical1 => {
+                Some(FlagEffect::Set)
+            }
+            Load1 | And1 | Or1 | Xor1 | CompareArithmetic | CompareLogical => {
+                Some(FlagEffect::OverflowCleared)
+            }
+            NoOperation | Pop | Return => None,
+        }
+    }
 }
 
-/// GRのインデックスのペア。R1 <- f (R1, R2) みたいな演算で使う。
-/// `new` で範囲内 (R <= 7) であることを保証しているので
-/// これを使うときは範囲外アクセスを気にして `Result` を使う必要はない。
-#[derive(Debug, Clone, Copy)]
-pub struct TwoRegisters {
-    r1: u16,
-    r2: u16,
+impl Operation2 {
+    /// フラグを変えない命令ならNone
+    pub fn flag_effect(&self) -> Option<FlagEffect> {
+        use Operation2::*;
+
+        match self {
+            AddArithmetic | SubtractArithmetic | AddLogical | SubtractLogical => {
+                Some(FlagEffect::Set)
+            }
+            ShiftLeftArithmetic | ShiftRightArithmetic | ShiftLeftLogical | ShiftRightLogical => {
+                Some(FlagEffect::Set)
+            }
+            Load | And | Or | Xor | CompareArithmetic | CompareLogical => {
+                Some(FlagEffect::OverflowCleared)
+            }
+            _ => None,
+        }
+    }
+
+    /// 条件ジャンプが見るフラグ
+    pub fn flags_read(&self) -> &'static [Flag] {
+        use Operation2::*;
+
+        match self {
+            JumpOnPlus => &[Flag::Sign, Flag::Zero],
+            JumpOnMinus => &[Flag::Sign],
+            JumpOnNonZero | JumpOnZero => &[Flag::Zero],
+            JumpOnOverflow => &[Flag::Overflow],
+            _ => &[],
+        }
+    }
 }
 
 /// GRの番号．0~7の保証付き．
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct RegisterNumber(pub u8);
 
 impl RegisterNumber {
-    pub fn new(n: u16) -> RegisterNumber {
-        RegisterNumber(n as u8)
+    pub fn new(n: u16) -> Result<RegisterNumber, RegisterOutOfIndex> {
+        if n <= 7 {
+            Ok(RegisterNumber(n as u8))
+        } else {
+            Err(RegisterOutOfIndex(n))
+        }
     }
-    pub fn new_pair(word: u16) -> (RegisterNumber, RegisterNumber) {
-        let r1_r = (word & 0x00f0) >> 1;
+    /// 1語目の r/r1 と x/r2 の部分を取り出す
+    pub fn new_pair(word: u16) -> Result<(RegisterNumber, RegisterNumber), RegisterOutOfIndex> {
+        let r1_r = (word & 0x00f0) >> 4;
         let r2_x = word & 0x000f;
-        (RegisterNumber::new(r1_r), RegisterNumber::new(r2_x))
+        Ok((RegisterNumber::new(r1_r)?, RegisterNumber::new(r2_x)?))
     }
 }
 
@@ -219,27 +501,36 @@ impl RegisterNumber {
 #[error("{0:X} is out of range for general register")]
 pub struct RegisterOutOfIndex(u16);
 
-impl TwoRegisters {
-    pub fn get_pair(&self) -> (&u16, &u16) {
-        let Self { r1, r2 } = self;
-        (r1, r2)
-    }
-}
-
 #[cfg(test)]
 mod test {
-    use std::{error, io};
-
-    use crate::core::machine::Machine;
+    use super::*;
+
+    #[test]
+    fn ope_two_word() {
+        let Word2 { operation, r, x } = ope(0x1213).unwrap().right().unwrap();
+        assert_eq!(operation, Operation2::LoadAddress);
+        assert_eq!((r, x), (RegisterNumber(1), RegisterNumber(3)));
+        assert_eq!(Word2 { operation, r, x }.encode(), 0x1213);
+    }
 
-    // TODO テスト書く
-    // #[test]
-    // fn add_arithmetic_1() -> Result<(), Box<dyn error::Error>> {
-    //     let vec = vec![0x00, 0x24, 0x12];
-    //     let mut bytes = io::Cursor::new(vec);
-    //     let machine = Machine::init(&mut bytes);
-    //     // assert_eq!()
+    #[test]
+    fn ope_one_word() {
+        let Word1 { operation, r1, r2 } = ope(0x2412).unwrap().left().unwrap();
+        assert_eq!(operation, Operation1::AddArithmetic1);
+        assert_eq!((r1, r2), (RegisterNumber(1), RegisterNumber(2)));
+        assert!(ope(0x1218).is_err());
+        assert!(ope(0xff00).is_err());
+    }
 
-    //     Ok(())
-    // }
+    #[test]
+    fn mnemonic_round_trip() {
+        for o in Operation1::ALL.iter() {
+            assert_eq!(Operation1::new((o.opcode() as u16) << 8).unwrap(), *o);
+            assert_eq!(Operation1::from_mnemonic(o.mnemonic()), Some(*o));
+        }
+        for o in Operation2::ALL.iter() {
+            assert_eq!(Operation2::new((o.opcode() as u16) << 8).unwrap(), *o);
+            assert_eq!(Operation2::from_mnemonic(o.mnemonic()), Some(*o));
+        }
+    }
 }
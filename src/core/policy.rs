@@ -0,0 +1,247 @@
+//! 信用できないプログラムを動かすときの制限。自動採点などで使う
+
+use super::access::{AccessKind, Cell};
+use super::machine::{Machine, StepError};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// 制限。`None` は制限なし
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunPolicy {
+    pub max_steps: Option<u64>,
+    /// OUTで出力するバイト数 (UTF-8、行末の改行を1バイトと数える)
+    pub max_output_bytes: Option<usize>,
+    /// INを実行する回数。入力の終わりを読むのも1回
+    pub max_input_reads: Option<usize>,
+    /// 書き込んでよい番地の範囲。スタックも含めること
+    pub writable: Option<Vec<RangeInclusive<u16>>>,
+    /// 実行にかけてよい実時間
+    pub time_limit: Option<Duration>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("{0}")]
+    StepError(#[from] StepError),
+    #[error("Step limit of {0} exceeded")]
+    StepLimit(u64),
+    #[error("Output limit of {0} bytes exceeded")]
+    OutputLimit(usize),
+    #[error("Input limit of {0} reads exceeded")]
+    InputLimit(usize),
+    #[error("Write to #{address:04X} at #{pr:04X} is not allowed")]
+    ForbiddenWrite { pr: u16, address: u16 },
+    #[error("Time limit of {0:?} exceeded")]
+    Timeout(Duration),
+}
+
+/// `Machine::run_with_policy` の結果
+#[derive(Debug)]
+pub struct Run {
+    /// 停止したか、制限に引っかかった命令の直前の状態
+    pub machine: Machine,
+    pub steps: u64,
+    pub result: Result<(), PolicyError>,
+}
+
+impl RunPolicy {
+    fn writable(&self, address: u16) -> bool {
+        match &self.writable {
+            Some(ranges) => ranges.iter().any(|range| range.contains(&address)),
+            None => true,
+        }
+    }
+}
+
+/// これまでの実行量を数えながら1命令ずつ実行する
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    policy: RunPolicy,
+    started: Instant,
+    steps: u64,
+    input_reads: usize,
+    output_bytes: usize,
+}
+
+impl Sandbox {
+    /// 実時間はここから測る
+    pub fn new(policy: RunPolicy) -> Sandbox {
+        Sandbox {
+            policy,
+            started: Instant::now(),
+            steps: 0,
+            input_reads: 0,
+            output_bytes: 0,
+        }
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// 1命令実行する。制限を破る命令は実行せずエラーを返す
+    pub fn step(&mut self, machine: &Machine) -> Result<Machine, PolicyError> {
+        let policy = &self.policy;
+        if let Some(max) = policy.max_steps.filter(|&max| self.steps >= max) {
+            return Err(PolicyError::StepLimit(max));
+        }
+        if let Some(limit) = policy.time_limit.filter(|&l| self.started.elapsed() >= l) {
+            return Err(PolicyError::Timeout(limit));
+        }
+        let reads_input = machine.reads_input();
+        if let Some(max) = policy.max_input_reads {
+            if reads_input && self.input_reads >= max {
+                return Err(PolicyError::InputLimit(max));
+            }
+        }
+
+        let (next, log) = machine.step_traced()?;
+        let forbidden = log.iter().find_map(|access| match access.cell {
+            Cell::Memory(address)
+                if access.kind == AccessKind::Write && !policy.writable(address) =>
+            {
+                Some(address)
+            }
+            _ => None,
+        });
+        if let Some(address) = forbidden {
+            return Err(PolicyError::ForbiddenWrite {
+                pr: machine.pr(),
+                address,
+            });
+        }
+        let output_bytes: usize = next.output()[machine.output().len()..]
+            .iter()
+            .map(|line| line.len() + 1)
+            .sum();
+        if let Some(max) = policy
+            .max_output_bytes
+            .filter(|&max| self.output_bytes + output_bytes > max)
+        {
+            return Err(PolicyError::OutputLimit(max));
+        }
+
+        self.steps += 1;
+        self.input_reads += reads_input as usize;
+        self.output_bytes += output_bytes;
+        Ok(next)
+    }
+}
+
+impl Machine {
+    /// `policy` の制限の中で停止するまで実行する
+    pub fn run_with_policy(&self, policy: &RunPolicy) -> Run {
+        let mut sandbox = Sandbox::new(policy.clone());
+        let mut machine = self.clone();
+        while !machine.is_halted() {
+            match sandbox.step(&machine) {
+                Ok(next) => machine = next,
+                Err(e) => {
+                    return Run {
+                        machine,
+                        steps: sandbox.steps,
+                        result: Err(e),
+                    }
+                }
+            }
+        }
+        Run {
+            machine,
+            steps: sandbox.steps,
+            result: Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+
+    /// 入力を1行ずつ読んでそのまま出力する。空行で終わる
+    const ECHO: &str = "MAIN  START
+LOOP  IN    BUF,LEN
+      LD    GR1,LEN
+      JZE   FIN
+      JMI   FIN
+      OUT   BUF,LEN
+      JUMP  LOOP
+FIN   RET
+BUF   DS    256
+LEN   DS    1
+      END";
+
+    fn echo(lines: &[&str]) -> Machine {
+        casl::assemble(ECHO)
+            .unwrap()
+            .load()
+            .with_input(lines.iter().map(|l| l.to_string()))
+    }
+
+    #[test]
+    fn limits() {
+        let machine = echo(&["abc", "de", ""]);
+        let run = machine.run_with_policy(&RunPolicy::default());
+        assert!(run.result.is_ok());
+        assert_eq!(run.machine.output(), ["abc", "de"]);
+
+        let policy = RunPolicy {
+            max_steps: Some(10),
+            ..RunPolicy::default()
+        };
+        let run = machine.run_with_policy(&policy);
+        assert!(matches!(run.result, Err(PolicyError::StepLimit(10))));
+        assert_eq!(run.steps, 10);
+
+        // "abc\n" は4バイト
+        let policy = RunPolicy {
+            max_output_bytes: Some(6),
+            ..RunPolicy::default()
+        };
+        let run = machine.run_with_policy(&policy);
+        assert!(matches!(run.result, Err(PolicyError::OutputLimit(6))));
+        assert_eq!(run.machine.output(), ["abc"]);
+
+        let policy = RunPolicy {
+            max_input_reads: Some(2),
+            ..RunPolicy::default()
+        };
+        let run = machine.run_with_policy(&policy);
+        assert!(matches!(run.result, Err(PolicyError::InputLimit(2))));
+        assert!(run.machine.reads_input());
+
+        let policy = RunPolicy {
+            time_limit: Some(Duration::from_secs(0)),
+            ..RunPolicy::default()
+        };
+        let run = machine.run_with_policy(&policy);
+        assert!(matches!(run.result, Err(PolicyError::Timeout(_))));
+        assert_eq!(run.steps, 0);
+    }
+
+    #[test]
+    fn writable_ranges() {
+        let program = casl::assemble(ECHO).unwrap();
+        let buf = program.symbol("BUF").unwrap().address;
+        let len = program.symbol("LEN").unwrap().address;
+        let machine = program.load().with_input(vec!["x".to_string()]);
+
+        // スタックとBUF, LEN
+        let policy = RunPolicy {
+            writable: Some(vec![0..=0xFF, buf..=len]),
+            ..RunPolicy::default()
+        };
+        assert!(machine.run_with_policy(&policy).result.is_ok());
+
+        // LENに書けない
+        let policy = RunPolicy {
+            writable: Some(vec![0..=0xFF, buf..=len - 1]),
+            ..RunPolicy::default()
+        };
+        let run = machine.run_with_policy(&policy);
+        match run.result {
+            Err(PolicyError::ForbiddenWrite { address, .. }) => assert_eq!(address, len),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! コード領域への書き込みと、データ領域の実行を見つける
+//!
+//! どの語がコードでどの語がデータかはアセンブラの出力から決める。
+//! プログラムの外 (スタックや空き領域) はどちらでもなく、検査しない
+
+use super::access::{Access, AccessKind, Cell};
+use super::machine::Machine;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordKind {
+    /// 命令 (マクロ命令が展開したものを含む)
+    Code,
+    /// DC, リテラル
+    Data,
+    /// DS で確保した領域
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectionMode {
+    /// 記録して実行を続ける
+    Warn,
+    /// その語の実行をエラーにする
+    Trap,
+}
+
+/// 読み込んだプログラムの各語の種類
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Protection {
+    pub mode: ProtectionMode,
+    /// `kinds[0]` の番地
+    pub origin: u16,
+    pub kinds: Vec<WordKind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Violation {
+    #[error("Write to code at #{address:04X} (PR=#{pr:04X})")]
+    WriteToCode { pr: u16, address: u16 },
+    #[error("Executing data at #{0:04X}")]
+    ExecuteData(u16),
+    /// `shadow` で見つける
+    #[error("Read of uninitialized #{address:04X} (PR=#{pr:04X})")]
+    UninitializedMemory { pr: u16, address: u16 },
+    #[error("Read of uninitialized GR{register} (PR=#{pr:04X})")]
+    UninitializedRegister { pr: u16, register: u8 },
+}
+
+impl Protection {
+    pub fn kind(&self, address: u16) -> Option<WordKind> {
+        let index = address.checked_sub(self.origin)? as usize;
+        self.kinds.get(index).copied()
+    }
+
+    /// 1語の実行の違反。`start` は命令の1語目か
+    pub(super) fn check(&self, pr: u16, start: bool, log: &[Access]) -> Option<Violation> {
+        if start && matches!(self.kind(pr), Some(WordKind::Data | WordKind::Reserved)) {
+            return Some(Violation::ExecuteData(pr));
+        }
+        log.iter().find_map(|access| match access.cell {
+            Cell::Memory(address)
+                if access.kind == AccessKind::Write
+                    && self.kind(address) == Some(WordKind::Code) =>
+            {
+                Some(Violation::WriteToCode { pr, address })
+            }
+            _ => None,
+        })
+    }
+}
+
+impl Machine {
+    /// コードとデータの区別を付けたMachineを返す
+    pub fn with_protection(&self, protection: Protection) -> Machine {
+        Machine {
+            protection: Some(Rc::new(protection)),
+            ..self.clone()
+        }
+    }
+
+    pub fn protection(&self) -> Option<&Protection> {
+        self.protection.as_deref()
+    }
+
+    /// `ProtectionMode::Warn` で見つかった違反。古い順
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+    use crate::core::machine::{ExecError, StepError};
+
+    fn run(source: &str, mode: ProtectionMode) -> (Machine, Option<StepError>) {
+        let mut machine = casl::assemble(source).unwrap().load_protected(mode);
+        while !machine.is_halted() {
+            match machine.step() {
+                Ok(next) => machine = next,
+                Err(e) => return (machine, Some(e)),
+            }
+        }
+        (machine, None)
+    }
+
+    #[test]
+    fn kinds() {
+        let program =
+            casl::assemble("MAIN START\n LD GR1,=5\n OUT X,L\n RET\nX DC 'A'\nL DS 1\n END")
+                .unwrap();
+        let count = |kind| program.kinds.iter().filter(|&&k| k == kind).count();
+        // X とリテラル
+        assert_eq!(count(WordKind::Data), 2);
+        // L
+        assert_eq!(count(WordKind::Reserved), 1);
+        assert_eq!(program.kinds.len(), 2 + 12 + 1 + 3);
+    }
+
+    #[test]
+    fn trap_and_warn() {
+        // 2語目のアドレスを書き換えてしまう
+        let write = "MAIN  START
+      LAD   GR1,1
+      ST    GR1,MAIN,GR1
+      RET
+      END";
+        let (machine, error) = run(write, ProtectionMode::Trap);
+        assert!(matches!(
+            error,
+            Some(StepError::ExecError(ExecError::Protection(
+                Violation::WriteToCode { address: 0x101, .. }
+            )))
+        ));
+        // 書き込む前の状態のまま
+        assert_eq!(machine.cell(Cell::Memory(0x101)), 1);
+
+        let (machine, error) = run(write, ProtectionMode::Warn);
+        assert!(error.is_none());
+        assert_eq!(
+            machine.violations(),
+            [Violation::WriteToCode {
+                pr: 0x102,
+                address: 0x101
+            }]
+        );
+
+        // データに飛び込む
+        let execute = "MAIN  START
+      JUMP  X
+X     DC    #8100
+      END";
+        let (_, error) = run(execute, ProtectionMode::Trap);
+        assert!(matches!(
+            error,
+            Some(StepError::ExecError(ExecError::Protection(
+                Violation::ExecuteData(0x102)
+            )))
+        ));
+        let (machine, _) = run(execute, ProtectionMode::Warn);
+        assert_eq!(machine.violations(), [Violation::ExecuteData(0x102)]);
+    }
+}
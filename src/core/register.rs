@@ -1,17 +1,12 @@
-use super::operations::{RegisterNumber, TwoRegisters};
+use super::operations::RegisterNumber;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct GeneralRegister([u16; 8]);
 
 impl GeneralRegister {
     pub fn new(gr: [u16; 8]) -> GeneralRegister {
         GeneralRegister(gr)
     }
-    /// インデックスレジスタ
-    pub fn index(&self, two: TwoRegisters) -> IndexRegister {
-        let (_, &num_of_index_register) = two.get_pair();
-        IndexRegister(self.0[num_of_index_register as usize])
-    }
 
     pub fn get(&self, r: RegisterNumber) -> u16 {
         self.0[r.0 as usize]
@@ -31,16 +26,34 @@ impl GeneralRegister {
         gr[r1.0 as usize] = r1_value;
         GeneralRegister(gr)
     }
+
+    /// GR0~GR7の値をまとめて取り出す
+    pub fn values(&self) -> [u16; 8] {
+        self.0
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct IndexRegister(u16);
+/// フラグレジスタ (FR) の各ビット
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flag {
+    Overflow,
+    Sign,
+    Zero,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 3] = [Flag::Overflow, Flag::Sign, Flag::Zero];
 
-impl IndexRegister {
-    pub fn new(gr: u16) -> IndexRegister {
-        IndexRegister(gr)
+    /// `OF`, `SF`, `ZF`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::Overflow => "OF",
+            Flag::Sign => "SF",
+            Flag::Zero => "ZF",
+        }
     }
-    pub fn value(&self) -> u16 {
-        self.0
+
+    pub fn from_name(name: &str) -> Option<Flag> {
+        Flag::ALL.iter().copied().find(|f| f.name() == name)
     }
 }
@@ -0,0 +1,135 @@
+//! ブレークポイントで止まりながら実行する。INとOUTは `Io` で差し替える
+
+use super::machine::{Machine, StepError};
+use std::collections::BTreeSet;
+
+/// INとOUTの行き先
+pub trait Io {
+    /// INで読む1行。`None` なら入力の終わり
+    fn read_line(&mut self) -> Option<String>;
+    fn write_line(&mut self, line: &str);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Halted,
+    /// ブレークポイントの番地の命令を実行する前
+    Breakpoint,
+    /// ステップ数の上限に達した
+    StepLimit,
+}
+
+impl Stop {
+    /// JavaScriptに返す名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Stop::Halted => "halted",
+            Stop::Breakpoint => "breakpoint",
+            Stop::StepLimit => "limit",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Runner {
+    pub machine: Machine,
+    pub breakpoints: BTreeSet<u16>,
+}
+
+impl Runner {
+    pub fn new(machine: Machine) -> Runner {
+        Runner {
+            machine,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// 1命令実行する。入力が空でINを実行するなら先に `io` から1行読む
+    pub fn step(&mut self, io: &mut impl Io) -> Result<(), StepError> {
+        if self.machine.needs_input() {
+            if let Some(line) = io.read_line() {
+                self.machine = self.machine.with_input(Some(line));
+            }
+        }
+        let next = self.machine.step()?;
+        for line in &next.output()[self.machine.output().len()..] {
+            io.write_line(line);
+        }
+        self.machine = next;
+        Ok(())
+    }
+
+    /// 停止するか、ブレークポイントに着くまで最大 `max_steps` 命令実行する。
+    /// いまの位置のブレークポイントでは止まらない
+    pub fn run(&mut self, max_steps: u64, io: &mut impl Io) -> Result<Stop, StepError> {
+        for i in 0..max_steps {
+            if self.machine.is_halted() {
+                return Ok(Stop::Halted);
+            }
+            if i > 0 && self.breakpoints.contains(&self.machine.pr()) {
+                return Ok(Stop::Breakpoint);
+            }
+            self.step(io)?;
+        }
+        Ok(if self.machine.is_halted() {
+            Stop::Halted
+        } else {
+            Stop::StepLimit
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+
+    #[derive(Default)]
+    struct Lines {
+        input: Vec<String>,
+        output: Vec<String>,
+    }
+
+    impl Io for Lines {
+        fn read_line(&mut self) -> Option<String> {
+            if self.input.is_empty() {
+                None
+            } else {
+                Some(self.input.remove(0))
+            }
+        }
+        fn write_line(&mut self, line: &str) {
+            self.output.push(line.to_string());
+        }
+    }
+
+    #[test]
+    fn breakpoints_and_io() {
+        let program = casl::assemble(
+            "MAIN  START
+LOOP  IN    BUF,LEN
+      LD    GR1,LEN
+      JMI   FIN
+      OUT   BUF,LEN
+      JUMP  LOOP
+FIN   RET
+BUF   DS    16
+LEN   DS    1
+      END",
+        )
+        .unwrap();
+        let mut runner = Runner::new(program.load());
+        let fin = program.symbol("FIN").unwrap().address;
+        runner.breakpoints.insert(fin);
+        let mut io = Lines {
+            input: vec!["hello".to_string(), "wasm".to_string()],
+            ..Lines::default()
+        };
+
+        assert_eq!(runner.run(5, &mut io).unwrap(), Stop::StepLimit);
+        assert_eq!(runner.run(1000, &mut io).unwrap(), Stop::Breakpoint);
+        assert_eq!(runner.machine.pr(), fin);
+        assert_eq!(io.output, ["hello", "wasm"]);
+        assert_eq!(runner.run(1000, &mut io).unwrap(), Stop::Halted);
+    }
+}
@@ -0,0 +1,244 @@
+//! 一度も書き込んでいない語やレジスタを読んだことを見つける
+//!
+//! 読み込んだ直後は命令、DC、リテラルだけを書き込み済みとし、DS の領域とスタック、
+//! 空き領域、すべてのGRは未初期化から始める。SPは検査しない。
+//! PUSH と POP は書き込み済みかどうかをそのまま運ぶので、RPUSH/RPOP では報告しない
+
+use super::access::{Access, AccessKind, Cell};
+use super::machine::{self, Machine};
+use super::operations::{Operation1, Operation2, RegisterNumber, Word1, Word2};
+use super::protection::{ProtectionMode, Violation};
+use itertools::Either;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shadow {
+    pub mode: ProtectionMode,
+    /// 番地ごとに1ビット。立っていれば書き込み済み
+    pub(super) memory: Vec<u64>,
+    /// GR0からGR7まで1ビットずつ
+    pub(super) registers: u8,
+}
+
+impl Shadow {
+    /// メモリもレジスタもすべて未初期化
+    pub fn new(mode: ProtectionMode) -> Shadow {
+        Shadow {
+            mode,
+            memory: vec![0; (1 << 16) / 64],
+            registers: 0,
+        }
+    }
+
+    pub fn initialize(&mut self, address: u16) {
+        self.set(address, true);
+    }
+
+    pub fn is_initialized(&self, address: u16) -> bool {
+        self.memory[address as usize / 64] & 1 << (address % 64) != 0
+    }
+
+    pub fn register_initialized(&self, r: RegisterNumber) -> bool {
+        self.registers & 1 << r.0 != 0
+    }
+
+    fn set(&mut self, address: u16, initialized: bool) {
+        let bits = &mut self.memory[address as usize / 64];
+        if initialized {
+            *bits |= 1 << (address % 64);
+        } else {
+            *bits &= !(1 << (address % 64));
+        }
+    }
+
+    fn set_register(&mut self, r: RegisterNumber, initialized: bool) {
+        if initialized {
+            self.registers |= 1 << r.0;
+        } else {
+            self.registers &= !(1 << r.0);
+        }
+    }
+
+    /// 実行を終えた命令 `instruction` と、その命令のメモリの読み書き `log` から
+    /// 更新したシャドウと違反を返す。メモリの違反は1命令につき最初の1つだけ
+    pub(super) fn check(
+        &self,
+        pr: u16,
+        instruction: Either<Word1, Word2>,
+        log: &[Access],
+    ) -> (Shadow, Vec<Violation>) {
+        use Operation1::*;
+
+        let mut next = self.clone();
+        let mut violations = Vec::new();
+        let popped = matches!(instruction, Either::Left(Word1 { operation: Pop, .. }));
+        let pushed = match instruction {
+            Either::Right(Word2 {
+                operation: Operation2::Push,
+                x,
+                ..
+            }) => Some(x.0 == 0 || self.register_initialized(x)),
+            _ => None,
+        };
+        // XOR GR1,GR1 のように、値によらず結果が決まる
+        let cleared = matches!(
+            instruction,
+            Either::Left(Word1 {
+                operation: Xor1 | SubtractArithmetic1 | SubtractLogical1,
+                r1,
+                r2,
+            }) if r1 == r2
+        );
+
+        let (reads, writes, _) = machine::register_effects(instruction);
+        if pushed.is_none() && !cleared {
+            for cell in reads {
+                if let Cell::Register(r) = cell {
+                    if !self.register_initialized(r) {
+                        violations.push(Violation::UninitializedRegister { pr, register: r.0 });
+                    }
+                }
+            }
+        }
+
+        let mut stack = true;
+        let mut reported = false;
+        for access in log {
+            let address = match access.cell {
+                Cell::Memory(address) => address,
+                _ => continue,
+            };
+            match access.kind {
+                AccessKind::Read if popped => stack = self.is_initialized(address),
+                AccessKind::Read => {
+                    if !reported && !self.is_initialized(address) {
+                        violations.push(Violation::UninitializedMemory { pr, address });
+                        reported = true;
+                    }
+                }
+                AccessKind::Write => next.set(address, pushed.unwrap_or(true)),
+            }
+        }
+
+        for cell in writes {
+            if let Cell::Register(r) = cell {
+                next.set_register(r, !popped || stack);
+            }
+        }
+        (next, violations)
+    }
+}
+
+impl Machine {
+    /// 未初期化の読み込みを `shadow` で検査するMachineを返す
+    pub fn with_shadow(&self, shadow: Shadow) -> Machine {
+        Machine {
+            shadow: Some(Rc::new(shadow)),
+            ..self.clone()
+        }
+    }
+
+    pub fn shadow(&self) -> Option<&Shadow> {
+        self.shadow.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+    use crate::core::machine::{ExecError, StepError};
+
+    fn run(source: &str, input: &[&str], mode: ProtectionMode) -> (Machine, Option<StepError>) {
+        let program = casl::assemble(source).unwrap();
+        let mut machine = program
+            .load()
+            .with_shadow(program.shadow(mode))
+            .with_input(input.iter().map(|l| l.to_string()));
+        while !machine.is_halted() {
+            match machine.step() {
+                Ok(next) => machine = next,
+                Err(e) => return (machine, Some(e)),
+            }
+        }
+        (machine, None)
+    }
+
+    #[test]
+    fn memory() {
+        // X は読む前に書いていない。Y は書いてから読む
+        let source = "MAIN  START
+      LD    GR1,X
+      ST    GR1,Y
+      LD    GR2,Y
+      RET
+X     DS    1
+Y     DS    1
+      END";
+        let (machine, error) = run(source, &[], ProtectionMode::Warn);
+        assert!(error.is_none());
+        assert_eq!(
+            machine.violations(),
+            [Violation::UninitializedMemory {
+                pr: 0x100,
+                address: 0x107
+            }]
+        );
+
+        let (machine, error) = run(source, &[], ProtectionMode::Trap);
+        assert!(matches!(
+            error,
+            Some(StepError::ExecError(ExecError::Protection(
+                Violation::UninitializedMemory { address: 0x107, .. }
+            )))
+        ));
+        assert_eq!(machine.pr(), 0x100);
+    }
+
+    #[test]
+    fn registers() {
+        let (machine, _) = run(
+            "MAIN START\n ADDA GR1,GR2\n RET\n END",
+            &[],
+            ProtectionMode::Warn,
+        );
+        assert_eq!(
+            machine.violations(),
+            [
+                Violation::UninitializedRegister {
+                    pr: 0x100,
+                    register: 1
+                },
+                Violation::UninitializedRegister {
+                    pr: 0x100,
+                    register: 2
+                },
+            ]
+        );
+
+        // 退避して戻すだけなら報告しない。戻したあとも未初期化のまま
+        let (machine, _) = run(
+            "MAIN START\n XOR GR3,GR3\n RPUSH\n RPOP\n RET\n END",
+            &[],
+            ProtectionMode::Warn,
+        );
+        assert_eq!(machine.violations(), []);
+        let shadow = machine.shadow().unwrap();
+        assert!(!shadow.register_initialized(RegisterNumber(1)));
+        assert!(shadow.register_initialized(RegisterNumber(3)));
+    }
+
+    #[test]
+    fn input_initializes() {
+        let source = "MAIN  START
+      IN    BUF,LEN
+      OUT   BUF,LEN
+      RET
+BUF   DS    8
+LEN   DS    1
+      END";
+        let (machine, error) = run(source, &["ab"], ProtectionMode::Trap);
+        assert!(error.is_none());
+        assert_eq!(machine.output(), ["ab"]);
+    }
+}
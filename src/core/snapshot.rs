@@ -0,0 +1,528 @@
+//! 実行中の `Machine` の状態を保存・復元する。
+//!
+//! 形式 (数値はすべてビッグエンディアン):
+//!
+//! | 内容 | 大きさ |
+//! | --- | --- |
+//! | マジック `FERSSNAP` | 8 byte |
+//! | バージョン (現在 2。1 も読める) | u16 |
+//! | GR0~GR7, SP, PR | u16 × 10 |
+//! | フラグ (bit0: OF, bit1: SF, bit2: ZF, bit3: 停止済み) | u8 |
+//! | 読みかけの2ワード命令の1語目があれば 1, なければ 0 | u8 |
+//! | 読みかけの命令の1語目 (なければ 0) | u16 |
+//! | 0でない値が続く区間の数 | u32 |
+//! | 区間ごとに開始番地, 語数, 値の列 | u16, u16, u16 × 語数 |
+//! | 未読の入力行数, 各行の (バイト数 u32, UTF-8) | u32, ... |
+//! | 出力済みの行数, 各行の (バイト数 u32, UTF-8) | u32, ... |
+//!
+//! バージョン2ではこの後に続く:
+//!
+//! | 内容 | 大きさ |
+//! | --- | --- |
+//! | コードとデータの区別のモード (0: なし, 1: 警告, 2: 停止) | u8 |
+//! | モードが0でなければ、先頭の番地, 語数, 語ごとの種類 (0: 命令, 1: DC, 2: DS) | u16, u32, u8 × 語数 |
+//! | 警告にとどめた違反の数 | u32 |
+//! | 違反ごとに種類 (0: コードへの書き込み, 1: データの実行, 2: 未初期化の語, 3: 未初期化のGR), PR, 番地かGRの番号 | u8, u16, u16 |
+//! | 未初期化の読み込みの検査のモード (0: なし, 1: 警告, 2: 停止) | u8 |
+//! | モードが0でなければ、書き込み済みの語 (番地0から64語ずつ) と GR0~GR7 (bit0がGR0) | u64 × 1024, u8 |
+
+use super::console::Console;
+use super::machine::Machine;
+use super::memory::Memory;
+use super::operations::{self, Word2};
+use super::protection::{Protection, ProtectionMode, Violation, WordKind};
+use super::register::GeneralRegister;
+use super::shadow::Shadow;
+use itertools::Either;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+const MAGIC: &[u8; 8] = b"FERSSNAP";
+const VERSION: u16 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("{0}")]
+    IOError(#[from] io::Error),
+    #[error("Not a snapshot")]
+    BadMagic,
+    #[error("Snapshot version {0} is not supported")]
+    UnsupportedVersion(u16),
+    #[error("Pending word {0:X} is not the first word of a two-word operation")]
+    InvalidPendingWord(u16),
+    #[error("Memory run at {0:X} overflows the address space")]
+    InvalidMemoryRun(u16),
+    #[error("{0}")]
+    InvalidText(#[from] std::string::FromUtf8Error),
+    #[error("Protection mode {0} is not defined")]
+    InvalidProtectionMode(u8),
+    #[error("Word kind {0} is not defined")]
+    InvalidWordKind(u8),
+    #[error("Violation kind {0} is not defined")]
+    InvalidViolation(u8),
+}
+
+impl Machine {
+    /// 状態を書き出す
+    pub fn save_snapshot(&self, stream: &mut impl io::Write) -> Result<(), SnapshotError> {
+        stream.write_all(MAGIC)?;
+        write_u16(stream, VERSION)?;
+
+        for value in self.gr.values().iter() {
+            write_u16(stream, *value)?;
+        }
+        write_u16(stream, self.sp)?;
+        write_u16(stream, self.pr)?;
+
+        let flags = [self.of, self.sf, self.zf, self.halted]
+            .iter()
+            .enumerate()
+            .fold(0u8, |acc, (i, &flag)| acc | ((flag as u8) << i));
+        stream.write_all(&[flags])?;
+
+        match self.previous_word {
+            Some(word2) => {
+                stream.write_all(&[1])?;
+                write_u16(stream, word2.encode())?;
+            }
+            None => {
+                stream.write_all(&[0])?;
+                write_u16(stream, 0)?;
+            }
+        }
+
+        let runs = memory_runs(&self.mem);
+        write_u32(stream, runs.len() as u32)?;
+        for (start, words) in runs {
+            write_u16(stream, start)?;
+            write_u16(stream, words.len() as u16)?;
+            for word in words {
+                write_u16(stream, *word)?;
+            }
+        }
+
+        write_lines(stream, self.console.input.iter())?;
+        write_lines(stream, self.console.output.iter())?;
+
+        match &self.protection {
+            Some(protection) => {
+                stream.write_all(&[mode_to_u8(Some(protection.mode))])?;
+                write_u16(stream, protection.origin)?;
+                write_u32(stream, protection.kinds.len() as u32)?;
+                let kinds: Vec<u8> = protection.kinds.iter().map(|&k| kind_to_u8(k)).collect();
+                stream.write_all(&kinds)?;
+            }
+            None => stream.write_all(&[mode_to_u8(None)])?,
+        }
+        write_u32(stream, self.violations.len() as u32)?;
+        for &violation in self.violations.iter() {
+            let (kind, pr, value) = match violation {
+                Violation::WriteToCode { pr, address } => (0, pr, address),
+                Violation::ExecuteData(pr) => (1, pr, 0),
+                Violation::UninitializedMemory { pr, address } => (2, pr, address),
+                Violation::UninitializedRegister { pr, register } => (3, pr, register as u16),
+            };
+            stream.write_all(&[kind])?;
+            write_u16(stream, pr)?;
+            write_u16(stream, value)?;
+        }
+
+        match &self.shadow {
+            Some(shadow) => {
+                stream.write_all(&[mode_to_u8(Some(shadow.mode))])?;
+                for bits in &shadow.memory {
+                    stream.write_all(&bits.to_be_bytes())?;
+                }
+                stream.write_all(&[shadow.registers])?;
+            }
+            None => stream.write_all(&[mode_to_u8(None)])?,
+        }
+        Ok(())
+    }
+
+    /// `save_snapshot` で書き出した状態を読み込む
+    pub fn load_snapshot(stream: &mut impl io::Read) -> Result<Machine, SnapshotError> {
+        let mut magic = [0; 8];
+        stream.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = read_u16(stream)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut gr = [0; 8];
+        for value in gr.iter_mut() {
+            *value = read_u16(stream)?;
+        }
+        let sp = read_u16(stream)?;
+        let pr = read_u16(stream)?;
+
+        let flags = read_u8(stream)?;
+        let flag = |i: u8| flags & (1 << i) != 0;
+
+        let has_previous_word = read_u8(stream)? != 0;
+        let word = read_u16(stream)?;
+        let previous_word = if has_previous_word {
+            Some(decode_word2(word)?)
+        } else {
+            None
+        };
+
+        let mut mem = Memory([0; 65536]);
+        for _ in 0..read_u32(stream)? {
+            let start = read_u16(stream)?;
+            let len = read_u16(stream)?;
+            if start as usize + len as usize > mem.0.len() {
+                return Err(SnapshotError::InvalidMemoryRun(start));
+            }
+            for i in 0..len {
+                mem.0[(start + i) as usize] = read_u16(stream)?;
+            }
+        }
+
+        let console = Console {
+            input: read_lines(stream)?.into_iter().collect(),
+            output: read_lines(stream)?,
+        };
+
+        let (protection, violations, shadow) = if version >= 2 {
+            (
+                read_protection(stream)?,
+                read_violations(stream)?,
+                read_shadow(stream)?,
+            )
+        } else {
+            (None, Vec::new(), None)
+        };
+
+        Ok(Machine {
+            mem: Rc::new(mem),
+            gr: GeneralRegister::new(gr),
+            sp,
+            pr,
+            of: flag(0),
+            sf: flag(1),
+            zf: flag(2),
+            previous_word,
+            console: Rc::new(console),
+            halted: flag(3),
+            protection: protection.map(Rc::new),
+            shadow: shadow.map(Rc::new),
+            violations: Rc::new(violations),
+        })
+    }
+}
+
+fn decode_word2(word: u16) -> Result<Word2, SnapshotError> {
+    match operations::ope(word) {
+        Ok(Either::Right(word2)) => Ok(word2),
+        _ => Err(SnapshotError::InvalidPendingWord(word)),
+    }
+}
+
+fn mode_to_u8(mode: Option<ProtectionMode>) -> u8 {
+    match mode {
+        None => 0,
+        Some(ProtectionMode::Warn) => 1,
+        Some(ProtectionMode::Trap) => 2,
+    }
+}
+
+fn read_mode(stream: &mut impl io::Read) -> Result<Option<ProtectionMode>, SnapshotError> {
+    match read_u8(stream)? {
+        0 => Ok(None),
+        1 => Ok(Some(ProtectionMode::Warn)),
+        2 => Ok(Some(ProtectionMode::Trap)),
+        n => Err(SnapshotError::InvalidProtectionMode(n)),
+    }
+}
+
+fn kind_to_u8(kind: WordKind) -> u8 {
+    match kind {
+        WordKind::Code => 0,
+        WordKind::Data => 1,
+        WordKind::Reserved => 2,
+    }
+}
+
+fn read_protection(stream: &mut impl io::Read) -> Result<Option<Protection>, SnapshotError> {
+    let mode = match read_mode(stream)? {
+        Some(mode) => mode,
+        None => return Ok(None),
+    };
+    let origin = read_u16(stream)?;
+    let mut kinds = Vec::new();
+    for _ in 0..read_u32(stream)? {
+        kinds.push(match read_u8(stream)? {
+            0 => WordKind::Code,
+            1 => WordKind::Data,
+            2 => WordKind::Reserved,
+            n => return Err(SnapshotError::InvalidWordKind(n)),
+        });
+    }
+    Ok(Some(Protection {
+        mode,
+        origin,
+        kinds,
+    }))
+}
+
+fn read_violations(stream: &mut impl io::Read) -> Result<Vec<Violation>, SnapshotError> {
+    let mut violations = Vec::new();
+    for _ in 0..read_u32(stream)? {
+        let kind = read_u8(stream)?;
+        let pr = read_u16(stream)?;
+        let value = read_u16(stream)?;
+        violations.push(match kind {
+            0 => Violation::WriteToCode { pr, address: value },
+            1 => Violation::ExecuteData(pr),
+            2 => Violation::UninitializedMemory { pr, address: value },
+            3 => Violation::UninitializedRegister {
+                pr,
+                register: value as u8,
+            },
+            n => return Err(SnapshotError::InvalidViolation(n)),
+        });
+    }
+    Ok(violations)
+}
+
+fn read_shadow(stream: &mut impl io::Read) -> Result<Option<Shadow>, SnapshotError> {
+    let mut shadow = match read_mode(stream)? {
+        Some(mode) => Shadow::new(mode),
+        None => return Ok(None),
+    };
+    for bits in shadow.memory.iter_mut() {
+        let mut buf = [0; 8];
+        stream.read_exact(&mut buf)?;
+        *bits = u64::from_be_bytes(buf);
+    }
+    shadow.registers = read_u8(stream)?;
+    Ok(Some(shadow))
+}
+
+/// 0でない値が続く区間を (開始番地, 値の列) で列挙する。1区間は最大 `u16::MAX` 語
+fn memory_runs(mem: &Memory) -> Vec<(u16, &[u16])> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    for (i, &word) in mem.0.iter().enumerate() {
+        match start {
+            Some(s) if word == 0 || i - s == u16::MAX as usize => {
+                runs.push((s as u16, &mem.0[s..i]));
+                start = if word == 0 { None } else { Some(i) };
+            }
+            None if word != 0 => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        runs.push((s as u16, &mem.0[s..]));
+    }
+    runs
+}
+
+fn write_u16(stream: &mut impl io::Write, value: u16) -> io::Result<()> {
+    stream.write_all(&value.to_be_bytes())
+}
+
+fn write_u32(stream: &mut impl io::Write, value: u32) -> io::Result<()> {
+    stream.write_all(&value.to_be_bytes())
+}
+
+fn write_lines<'a>(
+    stream: &mut impl io::Write,
+    lines: impl ExactSizeIterator<Item = &'a String>,
+) -> io::Result<()> {
+    write_u32(stream, lines.len() as u32)?;
+    for line in lines {
+        write_u32(stream, line.len() as u32)?;
+        stream.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u8(stream: &mut impl io::Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    stream.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(stream: &mut impl io::Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut impl io::Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_lines(stream: &mut impl io::Read) -> Result<Vec<String>, SnapshotError> {
+    let count = read_u32(stream)?;
+    let mut lines = Vec::new();
+    for _ in 0..count {
+        let len = read_u32(stream)?;
+        let mut buf = Vec::new();
+        stream.by_ref().take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        lines.push(String::from_utf8(buf)?);
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn machine(words: &[u16]) -> Machine {
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        Machine::init(&mut io::Cursor::new(bytes)).unwrap()
+    }
+
+    fn round_trip(machine: &Machine) -> Machine {
+        let mut buf = Vec::new();
+        machine.save_snapshot(&mut buf).unwrap();
+        Machine::load_snapshot(&mut io::Cursor::new(buf)).unwrap()
+    }
+
+    #[test]
+    fn resume_after_restore() {
+        // LAD GR1,#200; LAD GR2,#280; SVC 1; PUSH 3; SVC 2; POP GR3; RET
+        let words = [
+            0x1210, 0x200, 0x1220, 0x280, 0xf000, 1, 0x7000, 3, 0xf000, 2, 0x7130, 0x8100,
+        ];
+        let m = machine(&words).with_input(vec!["ab".to_string(), "rest".to_string()]);
+
+        let mut whole = m.clone();
+        while !whole.is_halted() {
+            whole = whole.clock().unwrap();
+        }
+
+        // 2ワード命令の途中で止めても再開できる
+        let mut paused = m;
+        for _ in 0..7 {
+            paused = paused.clock().unwrap();
+        }
+        assert!(paused.previous_word.is_some());
+        let mut resumed = round_trip(&paused);
+        assert_eq!(resumed, paused);
+        while !resumed.is_halted() {
+            resumed = resumed.clock().unwrap();
+        }
+
+        assert_eq!(resumed, whole);
+        assert_eq!(round_trip(&whole), whole);
+    }
+
+    #[test]
+    fn protection_and_violations() {
+        // 2語目のアドレスを書き換えるので警告が残る
+        let source = "MAIN  START
+      LAD   GR1,1
+      ST    GR1,MAIN,GR1
+      RET
+      END";
+        let mut m = crate::casl::assemble(source)
+            .unwrap()
+            .load_protected(ProtectionMode::Warn);
+        m = m.step().unwrap().step().unwrap();
+        assert_eq!(m.violations().len(), 1);
+
+        let restored = round_trip(&m);
+        assert_eq!(restored, m);
+        assert_eq!(restored.step().unwrap(), m.step().unwrap());
+    }
+
+    #[test]
+    fn shadow() {
+        // X はDSなので未初期化のまま読む
+        let source = "MAIN  START
+      LAD   GR1,1
+      ST    GR1,Y
+      LD    GR2,X
+      RET
+X     DS    1
+Y     DS    1
+      END";
+        let program = crate::casl::assemble(source).unwrap();
+        let mut m = program
+            .load()
+            .with_shadow(program.shadow(ProtectionMode::Warn));
+        m = m.step().unwrap().step().unwrap();
+        let y = program.symbol("Y").unwrap().address;
+        assert!(m.shadow().unwrap().is_initialized(y));
+
+        let restored = round_trip(&m);
+        assert_eq!(restored, m);
+        let next = restored.step().unwrap();
+        assert_eq!(next.violations().len(), 1);
+        assert_eq!(next, m.step().unwrap());
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let mut v1 = Vec::new();
+        v1.extend_from_slice(MAGIC);
+        v1.extend_from_slice(&1u16.to_be_bytes());
+        // GR0~GR7, SP, PR
+        for value in [0u16, 0, 0, 0, 0, 0, 0, 0, 0x100, 0x100] {
+            v1.extend_from_slice(&value.to_be_bytes());
+        }
+        // フラグ, 読みかけの命令なし
+        v1.extend_from_slice(&[0, 0, 0, 0]);
+        // #0100 に RET だけ
+        v1.extend_from_slice(&1u32.to_be_bytes());
+        for value in [0x100u16, 1, 0x8100] {
+            v1.extend_from_slice(&value.to_be_bytes());
+        }
+        // 入力と出力
+        v1.extend_from_slice(&[0; 8]);
+
+        let restored = Machine::load_snapshot(&mut io::Cursor::new(v1)).unwrap();
+        assert_eq!(restored, machine(&[0x8100]));
+    }
+
+    #[test]
+    fn only_nonzero_runs_are_stored() {
+        let mem = Memory([0; 65536]).set(3, 1).set(4, 2).set(9, 3);
+        let runs = memory_runs(&mem);
+        assert_eq!(runs, vec![(3, &[1, 2][..]), (9, &[3][..])]);
+
+        let full = Memory([1; 65536]);
+        let runs = memory_runs(&full);
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[1], (0xffff, &[1][..]));
+    }
+
+    #[test]
+    fn rejects_broken_input() {
+        let mut buf = Vec::new();
+        machine(&[0x8100]).save_snapshot(&mut buf).unwrap();
+
+        let mut bad_magic = buf.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            Machine::load_snapshot(&mut io::Cursor::new(bad_magic)),
+            Err(SnapshotError::BadMagic)
+        ));
+
+        let mut future = buf.clone();
+        future[9] = 3;
+        assert!(matches!(
+            Machine::load_snapshot(&mut io::Cursor::new(future)),
+            Err(SnapshotError::UnsupportedVersion(3))
+        ));
+
+        buf.truncate(buf.len() - 1);
+        assert!(matches!(
+            Machine::load_snapshot(&mut io::Cursor::new(buf)),
+            Err(SnapshotError::IOError(_))
+        ));
+    }
+}
@@ -9,9 +9,9 @@ mod test {
 
     #[test]
     fn test_is_negative() {
-        assert_eq!(is_negative(0x8000), true);
-        assert_eq!(is_negative(-123i16 as u16), true);
-        assert_eq!(is_negative(32768), true);
-        assert_eq!(is_negative(32767), false);
+        assert!(is_negative(0x8000));
+        assert!(is_negative(-123i16 as u16));
+        assert!(is_negative(32768));
+        assert!(!is_negative(32767));
     }
 }
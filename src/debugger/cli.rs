@@ -0,0 +1,563 @@
+//! 端末で使うデバッガ
+
+use super::command::{self, Command, Format, Location};
+use super::watch::{Hit, WatchKind};
+use super::{BreakpointError, Debugger, Goal, Stop};
+use crate::casl::disasm::disassemble;
+use crate::core::access::{AccessKind, Cell};
+use crate::core::console;
+use std::io::{self, BufRead, Write};
+
+/// `input` からコマンドを読んで実行する。INの入力も `input` から読む
+pub fn run(
+    debugger: &mut Debugger,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> io::Result<()> {
+    let mut session = Session {
+        debugger,
+        printed: 0,
+        warned: 0,
+    };
+    let mut last = None;
+    session.show_location(output)?;
+
+    loop {
+        write!(output, "(fers) ")?;
+        output.flush()?;
+        let line = match read_line(input)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+
+        // 空行なら前のコマンドを繰り返す
+        let command = if line.trim().is_empty() {
+            match &last {
+                Some(command) => Command::clone(command),
+                None => continue,
+            }
+        } else {
+            match command::parse(&line) {
+                Ok(command) => command,
+                Err(e) => {
+                    writeln!(output, "{}", e)?;
+                    continue;
+                }
+            }
+        };
+        last = Some(command.clone());
+
+        if !session.execute(command, input, output)? {
+            return Ok(());
+        }
+    }
+}
+
+pub(crate) fn read_line(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+}
+
+struct Session<'a> {
+    debugger: &'a mut Debugger,
+    /// 表示済みのOUTの行数
+    printed: usize,
+    /// 表示済みのコード・データの違反の数
+    warned: usize,
+}
+
+impl Session<'_> {
+    /// コマンドを実行する。終了するなら `false`
+    fn execute(
+        &mut self,
+        command: Command,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<bool> {
+        match command {
+            Command::Step(n) => self.repeat(n, |_| Goal::Step, input, output)?,
+            Command::Next(n) => self.repeat(n, Debugger::next_goal, input, output)?,
+            Command::Finish => self.repeat(1, Debugger::finish_goal, input, output)?,
+            Command::Continue => self.repeat(1, |_| Goal::Continue, input, output)?,
+            Command::ReverseStep(n) => {
+                for _ in 0..n {
+                    if let Stop::HistoryStart = self.debugger.step_back() {
+                        writeln!(output, "No more history")?;
+                        break;
+                    }
+                }
+                self.rewind_output();
+                self.show_location(output)?;
+            }
+            Command::ReverseContinue => {
+                match self.debugger.reverse_continue() {
+                    Stop::Breakpoint(id) => write!(output, "Breakpoint {}, ", id)?,
+                    _ => writeln!(output, "Reached the start of history")?,
+                }
+                self.rewind_output();
+                self.show_location(output)?;
+            }
+            Command::Who(place) => match self.debugger.resolve_place(&place) {
+                Some(cell) => self.who(cell, output)?,
+                None => writeln!(output, "No label {:?}", place)?,
+            },
+            Command::Break {
+                location,
+                condition,
+            } => {
+                if let Some(address) = self.resolve(&location, output)? {
+                    let id = self.debugger.add_breakpoint(address);
+                    match self.debugger.set_condition(id, condition.as_deref()) {
+                        Ok(()) => writeln!(
+                            output,
+                            "Breakpoint {} at {}",
+                            id,
+                            self.debugger.describe(address)
+                        )?,
+                        Err(e) => {
+                            self.debugger.delete_breakpoint(id);
+                            writeln!(output, "{}", e)?;
+                        }
+                    }
+                }
+            }
+            Command::Condition(id, condition) => {
+                let set = self.debugger.set_condition(id, condition.as_deref());
+                self.report(set, output)?;
+            }
+            Command::Ignore(id, count) => {
+                let set = self.debugger.set_ignore(id, count);
+                self.report(set, output)?;
+            }
+            Command::Log { location, message } => {
+                if let Some(address) = self.resolve(&location, output)? {
+                    let id = self.debugger.add_breakpoint(address);
+                    match self.debugger.set_message(id, Some(&message)) {
+                        Ok(()) => writeln!(
+                            output,
+                            "Logpoint {} at {}",
+                            id,
+                            self.debugger.describe(address)
+                        )?,
+                        Err(e) => {
+                            self.debugger.delete_breakpoint(id);
+                            writeln!(output, "{}", e)?;
+                        }
+                    }
+                }
+            }
+            Command::Watch { place, len, kind } => match self.debugger.resolve_place(&place) {
+                Some(cell) => {
+                    let id = self.debugger.add_watchpoint(cell, len, kind);
+                    writeln!(output, "Watchpoint {}: {}", id, self.cell_range(cell, len))?;
+                }
+                None => writeln!(output, "No label {:?}", place)?,
+            },
+            Command::Delete(id) => {
+                if !self.debugger.delete_breakpoint(id) {
+                    writeln!(output, "No breakpoint {}", id)?;
+                }
+            }
+            Command::InfoBreakpoints => {
+                if self.debugger.breakpoints().is_empty() && self.debugger.watchpoints().is_empty()
+                {
+                    writeln!(output, "No breakpoints")?;
+                }
+                for b in self.debugger.breakpoints() {
+                    write!(output, "{:<3} {}", b.id, self.debugger.describe(b.address))?;
+                    if let Some((condition, _)) = &b.condition {
+                        write!(output, " if {}", condition)?;
+                    }
+                    if let Some((message, _)) = &b.message {
+                        write!(output, " log {:?}", message)?;
+                    }
+                    if b.hits > 0 {
+                        write!(output, " (hit {} times)", b.hits)?;
+                    }
+                    if b.ignore > 0 {
+                        write!(output, " (ignore next {})", b.ignore)?;
+                    }
+                    writeln!(output)?;
+                }
+                for w in self.debugger.watchpoints() {
+                    let kind = match w.kind {
+                        WatchKind::Read => "read",
+                        WatchKind::Write => "write",
+                        WatchKind::Change => "change",
+                    };
+                    writeln!(
+                        output,
+                        "{:<3} {} {}",
+                        w.id,
+                        kind,
+                        self.cell_range(w.cell, w.len)
+                    )?;
+                }
+            }
+            Command::InfoRegisters => self.show_registers(output)?,
+            Command::InfoHistory => {
+                let history = self.debugger.history();
+                writeln!(
+                    output,
+                    "{} instructions recorded (limit {})",
+                    history.len(),
+                    history.limit()
+                )?;
+            }
+            Command::Examine {
+                count,
+                format,
+                location,
+            } => {
+                if let Some(address) = self.resolve(&location, output)? {
+                    self.examine(address, count, format, output)?;
+                }
+            }
+            Command::Backtrace => self.backtrace(output)?,
+            Command::List(location) => {
+                let address = match location {
+                    Some(location) => self.resolve(&location, output)?,
+                    None => Some(self.debugger.machine().pr()),
+                };
+                if let Some(address) = address {
+                    self.list(address, output)?;
+                }
+            }
+            Command::Help => writeln!(output, "{}", command::HELP)?,
+            Command::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn resolve(&self, location: &Location, output: &mut impl Write) -> io::Result<Option<u16>> {
+        let address = self.debugger.resolve(location);
+        if address.is_none() {
+            writeln!(output, "No label {:?}", location)?;
+        }
+        Ok(address)
+    }
+
+    /// `goal` まで実行するのを `n` 回繰り返す。入力待ちになったら `input` から1行読む
+    fn repeat(
+        &mut self,
+        n: usize,
+        goal: impl Fn(&Debugger) -> Goal,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        for _ in 0..n {
+            let goal = goal(self.debugger);
+            let stop = loop {
+                match self.debugger.resume(goal) {
+                    Stop::NeedsInput => {
+                        self.flush_output(output)?;
+                        write!(output, "input> ")?;
+                        output.flush()?;
+                        match read_line(input)? {
+                            Some(line) => self.debugger.push_input(line),
+                            None => self.debugger.close_input(),
+                        }
+                    }
+                    stop => break stop,
+                }
+            };
+            self.flush_output(output)?;
+            match stop {
+                Stop::Done => {}
+                Stop::Breakpoint(id) => {
+                    write!(output, "Breakpoint {}, ", id)?;
+                    return self.show_location(output);
+                }
+                Stop::Watchpoint(hits) => {
+                    for hit in hits {
+                        self.show_hit(hit, output)?;
+                    }
+                    return self.show_location(output);
+                }
+                Stop::Halted => return writeln!(output, "Program halted"),
+                Stop::Error(e) => {
+                    writeln!(output, "Error: {}", e)?;
+                    return self.show_location(output);
+                }
+                Stop::NeedsInput => unreachable!("handled above"),
+                Stop::HistoryStart => unreachable!("only returned when running backward"),
+            }
+        }
+        self.show_location(output)
+    }
+
+    fn report(
+        &self,
+        result: Result<(), BreakpointError>,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => writeln!(output, "{}", e),
+        }
+    }
+
+    /// OUTで新しく出力された行、ログポイントの出力、コード・データの違反を表示する
+    fn flush_output(&mut self, output: &mut impl Write) -> io::Result<()> {
+        for log in self.debugger.take_logs() {
+            writeln!(output, "{}", log)?;
+        }
+        let lines = self.debugger.machine().output();
+        for line in &lines[self.printed..] {
+            writeln!(output, "{}", line)?;
+        }
+        self.printed = lines.len();
+        let violations = self.debugger.machine().violations();
+        for violation in &violations[self.warned..] {
+            writeln!(output, "warning: {}", violation)?;
+        }
+        self.warned = violations.len();
+        Ok(())
+    }
+
+    /// 巻き戻して出力が減ったら表示済みの行数も戻す
+    fn rewind_output(&mut self) {
+        let machine = self.debugger.machine();
+        self.printed = self.printed.min(machine.output().len());
+        self.warned = self.warned.min(machine.violations().len());
+    }
+
+    fn instruction_at(&self, address: u16) -> String {
+        let mem = &self.debugger.machine().mem;
+        let words: Vec<u16> = (0..2)
+            .filter_map(|i| mem.get(address.wrapping_add(i)).ok())
+            .collect();
+        disassemble(&words).0
+    }
+
+    fn show_location(&self, output: &mut impl Write) -> io::Result<()> {
+        let machine = self.debugger.machine();
+        if machine.is_halted() {
+            return writeln!(output, "Program halted");
+        }
+        let pr = machine.pr();
+        let instruction = self.instruction_at(pr);
+        writeln!(output, "{}: {}", self.debugger.describe(pr), instruction)?;
+
+        let line = self.debugger.program().line_of(pr);
+        if let Some(text) = line.and_then(|l| self.debugger.source_line(l)) {
+            writeln!(output, "{:>5}  {}", line.unwrap_or(0) + 1, text)?;
+        }
+        Ok(())
+    }
+
+    fn show_registers(&self, output: &mut impl Write) -> io::Result<()> {
+        let machine = self.debugger.machine();
+        for (i, value) in machine.gr().values().iter().enumerate() {
+            let separator = if i % 4 == 3 { "\n" } else { "  " };
+            write!(
+                output,
+                "GR{} #{:04X} {:>6}{}",
+                i, value, *value as i16, separator
+            )?;
+        }
+        writeln!(
+            output,
+            "SP  #{:04X}  PR  #{:04X}  OF {}  SF {}  ZF {}",
+            machine.sp(),
+            machine.pr(),
+            machine.of() as u8,
+            machine.sf() as u8,
+            machine.zf() as u8
+        )
+    }
+
+    fn examine(
+        &self,
+        address: u16,
+        count: usize,
+        format: Format,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let mem = &self.debugger.machine().mem;
+        for row in 0..count.div_ceil(8) {
+            let start = address.wrapping_add((row * 8) as u16);
+            write!(output, "{}:", self.debugger.describe(start))?;
+            for i in 0..(count - row * 8).min(8) {
+                let value = mem.get(start.wrapping_add(i as u16)).unwrap_or(0);
+                match format {
+                    Format::Hex => write!(output, " #{:04X}", value)?,
+                    Format::Signed => write!(output, " {}", value as i16)?,
+                    Format::Unsigned => write!(output, " {}", value)?,
+                    Format::Char => write!(output, " {:?}", console::word_to_char(value))?,
+                }
+            }
+            writeln!(output)?;
+        }
+        Ok(())
+    }
+
+    /// メモリなら `#0105 (X)` の形にする
+    fn cell_name(&self, cell: Cell) -> String {
+        match cell {
+            Cell::Memory(address) => self.debugger.describe(address),
+            cell => cell.to_string(),
+        }
+    }
+
+    fn cell_range(&self, cell: Cell, len: u16) -> String {
+        match cell {
+            Cell::Memory(start) if len > 1 => format!(
+                "{} .. #{:04X}",
+                self.cell_name(cell),
+                start.wrapping_add(len - 1)
+            ),
+            cell => self.cell_name(cell),
+        }
+    }
+
+    fn show_hit(&self, hit: Hit, output: &mut impl Write) -> io::Result<()> {
+        let Hit { id, pr, access } = hit;
+        let what = match access.kind {
+            AccessKind::Read => format!("read #{:04X}", access.old),
+            AccessKind::Write => format!("#{:04X} -> #{:04X}", access.old, access.new),
+        };
+        writeln!(
+            output,
+            "Watchpoint {}: {} {} by {}",
+            id,
+            self.cell_name(access.cell),
+            what,
+            self.debugger.describe(pr)
+        )
+    }
+
+    fn who(&self, cell: Cell, output: &mut impl Write) -> io::Result<()> {
+        let change = match self.debugger.last_change(cell) {
+            Some(change) => change,
+            None if self.debugger.history().is_empty() => {
+                return writeln!(output, "No history yet")
+            }
+            None => {
+                return writeln!(
+                    output,
+                    "Not written in the last {} instructions",
+                    self.debugger.history().len()
+                )
+            }
+        };
+        writeln!(
+            output,
+            "{}: {}  #{:04X} -> #{:04X}  ({} instructions ago)",
+            self.debugger.describe(change.pr),
+            self.instruction_at(change.pr),
+            change.old,
+            change.new,
+            change.ago
+        )
+    }
+
+    fn backtrace(&self, output: &mut impl Write) -> io::Result<()> {
+        let pcs = std::iter::once(self.debugger.machine().pr()).chain(
+            self.debugger
+                .frames()
+                .iter()
+                .rev()
+                .map(|f| f.return_address.wrapping_sub(2)),
+        );
+        for (i, pc) in pcs.enumerate() {
+            writeln!(output, "#{:<2} {}", i, self.debugger.describe(pc))?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, address: u16, output: &mut impl Write) -> io::Result<()> {
+        let program = self.debugger.program();
+        let center = match program.line_of(address) {
+            Some(line) => line,
+            None => return writeln!(output, "No source for {}", self.debugger.describe(address)),
+        };
+        let current = program.line_of(self.debugger.machine().pr());
+        let first = center.saturating_sub(5);
+        let last = (center + 5).min(self.debugger.source_len().saturating_sub(1));
+        for line in first..=last {
+            let marker = if Some(line) == current { "=>" } else { "  " };
+            let text = self.debugger.source_line(line).unwrap_or("");
+            writeln!(output, "{} {:>4}  {}", marker, line + 1, text)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl::assemble;
+
+    #[test]
+    fn scripted_session() {
+        let source = "MAIN  START
+      IN    BUF,LEN
+      CALL  ECHO
+      RET
+ECHO  OUT   BUF,LEN
+      RET
+BUF   DS    8
+LEN   DS    1
+      END";
+        let mut debugger = Debugger::new(assemble(source).unwrap(), source);
+        let mut input = io::Cursor::new("b ECHO\nc\nhi\nbt\nx/2c BUF\nc\n");
+        let mut output = Vec::new();
+        run(&mut debugger, &mut input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("Breakpoint 1 at #010F (ECHO)"));
+        assert!(output.contains("input> Breakpoint 1, #010F (ECHO): PUSH  #0000,GR1"));
+        assert!(output.contains("#0  #010F (ECHO)\n#1  #010C (MAIN+12)"));
+        assert!(output.contains("#011C (BUF): 'h' 'i'"));
+        assert!(output.contains("hi\nProgram halted"));
+    }
+
+    #[test]
+    fn rewind() {
+        let source = "MAIN  START
+      LAD   GR1,5
+      ST    GR1,X
+      RET
+X     DS    1
+      END";
+        let mut debugger = Debugger::new(assemble(source).unwrap(), source);
+        let mut input = io::Cursor::new("c\nwho X\nwho GR2\nrs 2\ni r\nrs 5\nwatch X\nc\n");
+        let mut output = Vec::new();
+        run(&mut debugger, &mut input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output
+            .contains("#0102 (MAIN+2): ST    GR1,#0105  #0000 -> #0005  (2 instructions ago)"));
+        assert!(output.contains("Not written in the last 3 instructions"));
+        assert!(output.contains("#0102 (MAIN+2): ST    GR1,#0105\n"));
+        assert!(output.contains("GR1 #0005"));
+        assert!(output.contains("No more history\n#0100 (MAIN): LAD   GR1,#0005"));
+        assert!(output.contains("Watchpoint 1: #0105 (X)\n"));
+        assert!(output.contains("Watchpoint 1: #0105 (X) #0000 -> #0005 by #0102 (MAIN+2)"));
+    }
+
+    #[test]
+    fn code_write_warning() {
+        // 添字を間違えて命令を書き換える
+        let source = "MAIN  START
+      LAD   GR2,-3
+      ST    GR2,X,GR2
+      RET
+X     DS    1
+      END";
+        let mut debugger = Debugger::new(assemble(source).unwrap(), source);
+        let mut input = io::Cursor::new(
+            "c
+",
+        );
+        let mut output = Vec::new();
+        run(&mut debugger, &mut input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("warning: Write to code at #0102 (PR=#0102)\nProgram halted"));
+    }
+}
@@ -0,0 +1,374 @@
+//! デバッガのコマンド。gdbに似せている
+
+use super::watch::WatchKind;
+use crate::casl::parser;
+use crate::core::operations::RegisterNumber;
+use crate::core::register::Flag;
+
+/// 番地の指定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Address(u16),
+    Label(String),
+}
+
+/// 値の出どころを調べる場所
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Place {
+    Memory(Location),
+    Register(RegisterNumber),
+    Sp,
+    Flag(Flag),
+}
+
+/// メモリを表示するときの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Hex,
+    Signed,
+    Unsigned,
+    Char,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step(usize),
+    Next(usize),
+    Finish,
+    Continue,
+    ReverseStep(usize),
+    ReverseContinue,
+    Who(Place),
+    /// `break LOC if 式`
+    Break {
+        location: Location,
+        condition: Option<String>,
+    },
+    /// 条件を付け替える。`None` なら外す
+    Condition(usize, Option<String>),
+    Ignore(usize, usize),
+    /// 止まらずにメッセージを出すブレークポイント
+    Log {
+        location: Location,
+        message: String,
+    },
+    /// `place` から `len` 語を見張る
+    Watch {
+        place: Place,
+        len: u16,
+        kind: WatchKind,
+    },
+    Delete(usize),
+    InfoBreakpoints,
+    InfoRegisters,
+    InfoHistory,
+    Examine {
+        count: usize,
+        format: Format,
+        location: Location,
+    },
+    Backtrace,
+    List(Option<Location>),
+    Quit,
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("Unknown command `{0}`. Try `help`")]
+    UnknownCommand(String),
+    #[error("Invalid argument `{0}`")]
+    InvalidArgument(String),
+    #[error("`{0}` needs an argument")]
+    MissingArgument(String),
+}
+
+pub const HELP: &str = "\
+step [n]     (s)  execute n instructions
+next [n]     (n)  like step, but run CALL to its return
+finish            run until the current subroutine returns
+continue     (c)  run until a breakpoint or halt
+reverse-step [n]  (rs) undo n instructions
+reverse-continue  (rc) run backward to the previous breakpoint
+who PLACE         show the last instruction that wrote PLACE
+break LOC [if EXPR]  (b)  set a breakpoint at a label or address
+condition ID [EXPR]  stop at breakpoint ID only when EXPR is true
+ignore ID N       do not stop at breakpoint ID the next N times
+log LOC MESSAGE   print MESSAGE at LOC without stopping, e.g. GR1={GR1} X={[X]:x}
+watch PLACE [N]   stop when PLACE (N words from PLACE) changes
+wwatch PLACE [N]  stop when PLACE is written
+rwatch PLACE [N]  stop when PLACE is read
+delete ID    (d)  delete a breakpoint or watchpoint
+info breakpoints  (i b)
+info registers    (i r)
+info history      (i h) number of instructions that can be undone
+x/NF LOC          examine N words at LOC, F is x (hex), d (signed), u (unsigned) or c (char)
+backtrace    (bt) show CALL frames
+list [LOC]   (l)  show source around PR or LOC
+quit         (q)
+LOC is a label (LOOP, MAIN.LOOP), #hex, 0xhex or decimal address
+PLACE is LOC, GR0-GR7, SP, OF, SF or ZF
+EXPR is like `GR1 == 10 && ZF`, `[BUF+1] < 0`, `SP != #0100`";
+
+/// `#0100`, `0x100`, `256` または ラベル
+pub fn location(text: &str) -> Result<Location, ParseError> {
+    let invalid = || ParseError::InvalidArgument(text.to_string());
+    let hex = text.strip_prefix('#').or_else(|| text.strip_prefix("0x"));
+    if let Some(hex) = hex {
+        return u16::from_str_radix(hex, 16)
+            .map(Location::Address)
+            .map_err(|_| invalid());
+    }
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        return text.parse().map(Location::Address).map_err(|_| invalid());
+    }
+    let is_label = text.split('.').all(crate::casl::parser::is_label);
+    if is_label {
+        Ok(Location::Label(text.to_string()))
+    } else {
+        Err(invalid())
+    }
+}
+
+/// `GR0`~`GR7`, `SP`, `OF`/`SF`/`ZF` またはメモリの番地
+pub fn place(text: &str) -> Result<Place, ParseError> {
+    if let Some(r) = parser::register(text) {
+        return Ok(Place::Register(r));
+    }
+    if text == "SP" {
+        return Ok(Place::Sp);
+    }
+    if let Some(flag) = Flag::from_name(text) {
+        return Ok(Place::Flag(flag));
+    }
+    location(text).map(Place::Memory)
+}
+
+/// 先頭から `skip` 語を除いた残り
+fn rest(line: &str, skip: usize) -> Option<&str> {
+    let mut rest = line.trim_start();
+    for _ in 0..skip {
+        let end = rest.find(char::is_whitespace)?;
+        rest = rest[end..].trim_start();
+    }
+    let rest = rest.trim_end();
+    if rest.is_empty() {
+        None
+    } else {
+        Some(rest)
+    }
+}
+
+fn id(text: &str) -> Result<usize, ParseError> {
+    text.parse()
+        .map_err(|_| ParseError::InvalidArgument(text.to_string()))
+}
+
+fn count(argument: Option<&str>) -> Result<usize, ParseError> {
+    match argument {
+        None => Ok(1),
+        Some(n) => n
+            .parse()
+            .map_err(|_| ParseError::InvalidArgument(n.to_string())),
+    }
+}
+
+/// `x/8x` の `/8x` の部分
+fn examine_format(text: &str) -> Result<(usize, Format), ParseError> {
+    let invalid = || ParseError::InvalidArgument(text.to_string());
+    let spec = match text.strip_prefix('/') {
+        Some(spec) => spec,
+        None if text.is_empty() => return Ok((1, Format::Hex)),
+        None => return Err(invalid()),
+    };
+    let digits = spec.chars().take_while(char::is_ascii_digit).count();
+    let (n, f) = spec.split_at(digits);
+    let n = if n.is_empty() {
+        1
+    } else {
+        n.parse().map_err(|_| invalid())?
+    };
+    let format = match f {
+        "" | "x" => Format::Hex,
+        "d" => Format::Signed,
+        "u" => Format::Unsigned,
+        "c" => Format::Char,
+        _ => return Err(invalid()),
+    };
+    Ok((n, format))
+}
+
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let argument = words.next();
+    let second = words.next();
+    let required = || argument.ok_or_else(|| ParseError::MissingArgument(name.to_string()));
+
+    Ok(match name {
+        "s" | "step" => Command::Step(count(argument)?),
+        "n" | "next" => Command::Next(count(argument)?),
+        "finish" => Command::Finish,
+        "c" | "continue" => Command::Continue,
+        "rs" | "reverse-step" => Command::ReverseStep(count(argument)?),
+        "rc" | "reverse-continue" => Command::ReverseContinue,
+        "who" => Command::Who(place(required()?)?),
+        "b" | "break" => {
+            let location = location(required()?)?;
+            let condition = match second {
+                None => None,
+                Some("if") => Some(
+                    rest(line, 3)
+                        .ok_or_else(|| ParseError::MissingArgument("if".to_string()))?
+                        .to_string(),
+                ),
+                Some(other) => return Err(ParseError::InvalidArgument(other.to_string())),
+            };
+            Command::Break {
+                location,
+                condition,
+            }
+        }
+        "condition" => Command::Condition(id(required()?)?, rest(line, 2).map(String::from)),
+        "ignore" => {
+            let count = second.ok_or_else(|| ParseError::MissingArgument(name.to_string()))?;
+            Command::Ignore(
+                id(required()?)?,
+                count
+                    .parse()
+                    .map_err(|_| ParseError::InvalidArgument(count.to_string()))?,
+            )
+        }
+        "log" => Command::Log {
+            location: location(required()?)?,
+            message: rest(line, 2)
+                .ok_or_else(|| ParseError::MissingArgument(name.to_string()))?
+                .to_string(),
+        },
+        "watch" | "wwatch" | "rwatch" => {
+            let kind = match name {
+                "watch" => WatchKind::Change,
+                "wwatch" => WatchKind::Write,
+                _ => WatchKind::Read,
+            };
+            let place = place(required()?)?;
+            let len = match second {
+                None => 1,
+                Some(n) => match (&place, n.parse()) {
+                    (Place::Memory(_), Ok(n)) if n > 0 => n,
+                    _ => return Err(ParseError::InvalidArgument(n.to_string())),
+                },
+            };
+            Command::Watch { place, len, kind }
+        }
+        "d" | "delete" => Command::Delete(id(required()?)?),
+        "i" | "info" => match required()? {
+            "b" | "breakpoints" => Command::InfoBreakpoints,
+            "r" | "registers" => Command::InfoRegisters,
+            "h" | "history" => Command::InfoHistory,
+            other => return Err(ParseError::InvalidArgument(other.to_string())),
+        },
+        "bt" | "backtrace" => Command::Backtrace,
+        "l" | "list" => Command::List(argument.map(location).transpose()?),
+        "q" | "quit" => Command::Quit,
+        "h" | "help" => Command::Help,
+        x if x == "x" || x.starts_with("x/") => {
+            let (count, format) = examine_format(&x[1..])?;
+            Command::Examine {
+                count,
+                format,
+                location: location(required()?)?,
+            }
+        }
+        other => return Err(ParseError::UnknownCommand(other.to_string())),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("s"), Ok(Command::Step(1)));
+        assert_eq!(parse("next 3"), Ok(Command::Next(3)));
+        assert_eq!(
+            parse("b MAIN.LOOP"),
+            Ok(Command::Break {
+                location: Location::Label("MAIN.LOOP".to_string()),
+                condition: None
+            })
+        );
+        assert_eq!(
+            parse("b LOOP if GR1 == 10 &&  ZF "),
+            Ok(Command::Break {
+                location: Location::Label("LOOP".to_string()),
+                condition: Some("GR1 == 10 &&  ZF".to_string())
+            })
+        );
+        assert_eq!(parse("condition 2"), Ok(Command::Condition(2, None)));
+        assert_eq!(parse("ignore 1 5"), Ok(Command::Ignore(1, 5)));
+        assert_eq!(
+            parse("log #0102 i={GR1}"),
+            Ok(Command::Log {
+                location: Location::Address(0x102),
+                message: "i={GR1}".to_string()
+            })
+        );
+        assert_eq!(
+            parse("x/4c #0110"),
+            Ok(Command::Examine {
+                count: 4,
+                format: Format::Char,
+                location: Location::Address(0x110)
+            })
+        );
+        assert_eq!(
+            parse("x 0x20"),
+            Ok(Command::Examine {
+                count: 1,
+                format: Format::Hex,
+                location: Location::Address(0x20)
+            })
+        );
+        assert_eq!(parse("i r"), Ok(Command::InfoRegisters));
+        assert_eq!(parse("rs 2"), Ok(Command::ReverseStep(2)));
+        assert_eq!(
+            parse("who GR3"),
+            Ok(Command::Who(Place::Register(RegisterNumber(3))))
+        );
+        assert_eq!(parse("who SP"), Ok(Command::Who(Place::Sp)));
+        assert_eq!(
+            parse("rwatch BUF 8"),
+            Ok(Command::Watch {
+                place: Place::Memory(Location::Label("BUF".to_string())),
+                len: 8,
+                kind: WatchKind::Read
+            })
+        );
+        assert_eq!(
+            parse("watch ZF"),
+            Ok(Command::Watch {
+                place: Place::Flag(Flag::Zero),
+                len: 1,
+                kind: WatchKind::Change
+            })
+        );
+        assert_eq!(
+            parse("watch GR1 2"),
+            Err(ParseError::InvalidArgument("2".to_string()))
+        );
+        assert_eq!(
+            parse("who #0110"),
+            Ok(Command::Who(Place::Memory(Location::Address(0x110))))
+        );
+        assert_eq!(
+            parse("break"),
+            Err(ParseError::MissingArgument("break".to_string()))
+        );
+        assert_eq!(
+            parse("x/4q A"),
+            Err(ParseError::InvalidArgument("/4q".to_string()))
+        );
+    }
+}
@@ -0,0 +1,430 @@
+//! ブレークポイントの条件式とログの書式
+//!
+//! ```text
+//! or    := and ('||' and)*
+//! and   := not ('&&' not)*
+//! not   := '!' not | cmp
+//! cmp   := sum (('==' | '!=' | '<' | '<=' | '>' | '>=') sum)?
+//! sum   := unary (('+' | '-') unary)*
+//! unary := '-' unary | atom
+//! atom  := 数 | GR0~GR7 | SP | PR | OF | SF | ZF | ラベル | '[' or ']' | '(' or ')'
+//! ```
+//!
+//! 値はすべて16bitで、足し算・引き算は桁あふれを無視する。
+//! 大小比較は符号つき (CPAと同じ)。ラベルはその番地、`[式]` はその番地の内容になる。
+//! 真偽は0以外が真で、比較や `&&` の結果は1か0
+
+use crate::casl::parser;
+use crate::core::access::Cell;
+use crate::core::machine::Machine;
+use crate::core::register::Flag;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("Unexpected `{0}` in expression")]
+    Unexpected(String),
+    #[error("Unexpected end of expression")]
+    UnexpectedEnd,
+    #[error("Invalid number `{0}`")]
+    InvalidNumber(String),
+    #[error("No label `{0}`")]
+    UndefinedLabel(String),
+    #[error("Unclosed `{{` in message")]
+    UnclosedBrace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(u16),
+    Cell(Cell),
+    Pr,
+    /// `[式]`
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn eval(&self, machine: &Machine) -> u16 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Cell(cell) => machine.cell(*cell),
+            Expr::Pr => machine.pr(),
+            Expr::Memory(address) => machine.cell(Cell::Memory(address.eval(machine))),
+            Expr::Not(e) => (e.eval(machine) == 0) as u16,
+            Expr::Neg(e) => e.eval(machine).wrapping_neg(),
+            Expr::Binary(op, a, b) => {
+                let a = a.eval(machine);
+                // `&&` と `||` は右辺を評価しなくても結果が決まる
+                match op {
+                    BinaryOp::Or if a != 0 => return 1,
+                    BinaryOp::And if a == 0 => return 0,
+                    _ => {}
+                }
+                let b = b.eval(machine);
+                let (sa, sb) = (a as i16, b as i16);
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (b != 0) as u16,
+                    BinaryOp::Eq => (a == b) as u16,
+                    BinaryOp::Ne => (a != b) as u16,
+                    BinaryOp::Lt => (sa < sb) as u16,
+                    BinaryOp::Le => (sa <= sb) as u16,
+                    BinaryOp::Gt => (sa > sb) as u16,
+                    BinaryOp::Ge => (sa >= sb) as u16,
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Sub => a.wrapping_sub(b),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, machine: &Machine) -> bool {
+        self.eval(machine) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 16] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "!", "+", "-", "[", "]", "(", ")", ".",
+];
+
+fn number(text: &str) -> Result<u16, ParseError> {
+    let invalid = || ParseError::InvalidNumber(text.to_string());
+    match text.strip_prefix('#').or_else(|| text.strip_prefix("0x")) {
+        Some(hex) => u16::from_str_radix(hex, 16).map_err(|_| invalid()),
+        None => text.parse().map_err(|_| invalid()),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let word_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '#'))
+            .unwrap_or(rest.len());
+        if word_len > 0 {
+            let word = &rest[..word_len];
+            let token = if word.starts_with(|c: char| c.is_ascii_digit() || c == '#') {
+                Token::Number(number(word)?)
+            } else {
+                Token::Name(word.to_string())
+            };
+            tokens.push(token);
+            rest = &rest[word_len..];
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(**s))
+                .ok_or_else(|| ParseError::Unexpected(rest.chars().next().unwrap().to_string()))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a, F> {
+    tokens: Vec<Token>,
+    position: usize,
+    label: &'a F,
+}
+
+impl<F: Fn(&str) -> Option<u16>> Parser<'_, F> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, ParseError> {
+        let token = self.peek().cloned().ok_or(ParseError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn binary(
+        &mut self,
+        operators: &[(&str, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Expr, ParseError>,
+        repeat: bool,
+    ) -> Result<Expr, ParseError> {
+        let mut left = operand(self)?;
+        'outer: loop {
+            for &(symbol, op) in operators {
+                if self.eat(symbol) {
+                    left = Expr::Binary(op, Box::new(left), Box::new(operand(self)?));
+                    if !repeat {
+                        return Ok(left);
+                    }
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&[("||", BinaryOp::Or)], Self::and, true)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&[("&&", BinaryOp::And)], Self::not, true)
+    }
+
+    fn not(&mut self) -> Result<Expr, ParseError> {
+        if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.not()?)))
+        } else {
+            self.compare()
+        }
+    }
+
+    fn compare(&mut self) -> Result<Expr, ParseError> {
+        use BinaryOp::*;
+
+        let operators = [
+            ("==", Eq),
+            ("!=", Ne),
+            ("<=", Le),
+            (">=", Ge),
+            ("<", Lt),
+            (">", Gt),
+        ];
+        self.binary(&operators, Self::sum, false)
+    }
+
+    fn sum(&mut self) -> Result<Expr, ParseError> {
+        self.binary(
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+            Self::unary,
+            true,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, ParseError> {
+        match self.next()? {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Symbol("(") => {
+                let e = self.or()?;
+                self.close(")")?;
+                Ok(e)
+            }
+            Token::Symbol("[") => {
+                let e = self.or()?;
+                self.close("]")?;
+                Ok(Expr::Memory(Box::new(e)))
+            }
+            Token::Symbol(s) => Err(ParseError::Unexpected(s.to_string())),
+            Token::Name(name) => self.name(name),
+        }
+    }
+
+    fn name(&mut self, name: String) -> Result<Expr, ParseError> {
+        if let Some(r) = parser::register(&name) {
+            return Ok(Expr::Cell(Cell::Register(r)));
+        }
+        if let Some(flag) = Flag::from_name(&name) {
+            return Ok(Expr::Cell(Cell::Flag(flag)));
+        }
+        match name.as_str() {
+            "SP" => return Ok(Expr::Cell(Cell::Sp)),
+            "PR" => return Ok(Expr::Pr),
+            _ => {}
+        }
+        // `MAIN.LOOP` の形のラベル
+        let mut name = name;
+        while self.eat(".") {
+            match self.next()? {
+                Token::Name(part) => {
+                    name.push('.');
+                    name.push_str(&part);
+                }
+                token => return Err(ParseError::Unexpected(format!("{:?}", token))),
+            }
+        }
+        (self.label)(&name)
+            .map(Expr::Number)
+            .ok_or(ParseError::UndefinedLabel(name))
+    }
+
+    fn close(&mut self, symbol: &str) -> Result<(), ParseError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(token) => Err(ParseError::Unexpected(format!("{:?}", token))),
+                None => Err(ParseError::UnexpectedEnd),
+            }
+        }
+    }
+}
+
+/// 式を解釈する。ラベルは `label` で番地にする
+pub fn parse(text: &str, label: &impl Fn(&str) -> Option<u16>) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+        label,
+    };
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(Token::Name(name)) => Err(ParseError::Unexpected(name.clone())),
+        Some(Token::Number(n)) => Err(ParseError::Unexpected(n.to_string())),
+        Some(Token::Symbol(s)) => Err(ParseError::Unexpected(s.to_string())),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    /// `{式}` は符号つき10進、`{式:x}` は16進
+    Value {
+        expr: Expr,
+        hex: bool,
+    },
+}
+
+/// ログポイントの書式。`GR1={GR1} X={[X]:x}` のように `{}` の中に式を書く
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pieces: Vec<Piece>,
+}
+
+impl Message {
+    pub fn parse(text: &str, label: &impl Fn(&str) -> Option<u16>) -> Result<Message, ParseError> {
+        let mut pieces = Vec::new();
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                pieces.push(Piece::Text(rest[..open].to_string()));
+            }
+            let close = rest[open..].find('}').ok_or(ParseError::UnclosedBrace)? + open;
+            let inner = &rest[open + 1..close];
+            let (inner, hex) = match inner.strip_suffix(":x") {
+                Some(inner) => (inner, true),
+                None => (inner, false),
+            };
+            pieces.push(Piece::Value {
+                expr: parse(inner, label)?,
+                hex,
+            });
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Text(rest.to_string()));
+        }
+        Ok(Message { pieces })
+    }
+
+    pub fn format(&self, machine: &Machine) -> String {
+        self.pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.clone(),
+                Piece::Value { expr, hex: true } => format!("#{:04X}", expr.eval(machine)),
+                Piece::Value { expr, hex: false } => (expr.eval(machine) as i16).to_string(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl::assemble;
+
+    fn machine() -> (Machine, impl Fn(&str) -> Option<u16>) {
+        let program = assemble(
+            "MAIN  START
+      LAD   GR1,10
+      LD    GR2,X
+      RET
+X     DC    -3
+      END",
+        )
+        .unwrap();
+        let mut machine = program.load();
+        for _ in 0..2 {
+            machine = machine.step().unwrap();
+        }
+        let label = move |name: &str| program.symbol(name).map(|s| s.address);
+        (machine, label)
+    }
+
+    #[test]
+    fn evaluate() {
+        let (m, label) = machine();
+        let eval = |text| parse(text, &label).unwrap().eval(&m);
+        assert_eq!(eval("GR1 == 10 && SF"), 1);
+        assert_eq!(eval("GR1 == 10 && ZF"), 0);
+        assert_eq!(eval("GR2 < 0 || [0]"), 1);
+        assert_eq!(eval("[X] == -3"), 1);
+        assert_eq!(eval("[MAIN.X + 1 - 1] + GR1"), 7);
+        assert_eq!(eval("!(PR > #0100)"), 0);
+        assert_eq!(eval("SP - 1"), 0xff);
+    }
+
+    #[test]
+    fn errors() {
+        let (_, label) = machine();
+        assert_eq!(parse("GR1 ==", &label), Err(ParseError::UnexpectedEnd));
+        assert_eq!(
+            parse("Y == 1", &label),
+            Err(ParseError::UndefinedLabel("Y".to_string()))
+        );
+        assert_eq!(
+            parse("GR1 GR2", &label),
+            Err(ParseError::Unexpected("GR2".to_string()))
+        );
+        assert_eq!(
+            Message::parse("{GR1", &label),
+            Err(ParseError::UnclosedBrace)
+        );
+    }
+
+    #[test]
+    fn message() {
+        let (m, label) = machine();
+        let message = Message::parse("GR1={GR1} X={[X]:x}.", &label).unwrap();
+        assert_eq!(message.format(&m), "GR1=10 X=#FFFD.");
+    }
+}
@@ -0,0 +1,597 @@
+//! Debug Adapter Protocol のサーバー。エディタからソースの行単位でデバッグできるようにする
+//!
+//! `launch` の引数は `program` (.casのパス)、`stopOnEntry`、`input` (INで読む行の配列)。
+//! `input` を読み切ったあとのINは入力の終わり (-1) になる。
+//! OUTの出力は `output` イベントの `stdout` で、ログポイントは `console` で送る
+
+use super::condition;
+use super::{Debugger, Goal, Stop};
+use crate::casl::{self, ast::Opecode, parser};
+use crate::core::access::Cell;
+use crate::core::operations::RegisterNumber;
+use crate::core::register::Flag;
+use crate::utils::message::{read_message, write_message};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const THREAD_ID: u64 = 1;
+const REGISTERS: u64 = 1;
+const FLAGS: u64 = 2;
+const DATA: u64 = 3;
+
+/// ラベルの付いたDS/DCの領域
+struct DataArea {
+    name: String,
+    address: u16,
+    len: usize,
+}
+
+pub struct Server {
+    seq: u64,
+    debugger: Option<Debugger>,
+    path: String,
+    data: Vec<DataArea>,
+    stop_on_entry: bool,
+    /// 送り済みのOUTの行数
+    sent: usize,
+    /// 送るメッセージ。`handle` のたびに取り出す
+    outgoing: Vec<Value>,
+}
+
+fn string_arg<'a>(arguments: &'a Value, key: &str) -> Option<&'a str> {
+    arguments.get(key).and_then(Value::as_str)
+}
+
+/// `#0005 (5)` の形
+fn word(value: u16) -> String {
+    format!("#{:04X} ({})", value, value as i16)
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            seq: 0,
+            debugger: None,
+            path: String::new(),
+            data: Vec::new(),
+            stop_on_entry: false,
+            sent: 0,
+            outgoing: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        self.outgoing.push(message);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    /// リクエストを1つ処理して、送るべきレスポンスとイベントを返す
+    pub fn handle(&mut self, request: &Value) -> Vec<Value> {
+        let command = request["command"].as_str().unwrap_or("").to_string();
+        let arguments = request.get("arguments").cloned().unwrap_or(Value::Null);
+        let response = |result: Result<Value, String>| {
+            let mut response = json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": result.is_ok(),
+            });
+            match result {
+                Ok(body) => response["body"] = body,
+                Err(message) => response["message"] = json!(message),
+            }
+            response
+        };
+
+        // 実行するリクエストは先に返事をしてから実行する
+        let run = match command.as_str() {
+            "continue" => Some(Run::Continue),
+            "next" => Some(Run::Next),
+            "stepIn" => Some(Run::StepIn),
+            "stepOut" => Some(Run::StepOut),
+            _ => None,
+        };
+        if let Some(run) = run {
+            let result = match self.debugger {
+                Some(_) => Ok(json!({ "allThreadsContinued": true })),
+                None => Err("Not launched".to_string()),
+            };
+            let ok = result.is_ok();
+            self.send(response(result));
+            if ok {
+                self.run(run);
+            }
+            return std::mem::take(&mut self.outgoing);
+        }
+
+        let result = match command.as_str() {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsHitConditionalBreakpoints": true,
+                "supportsLogPoints": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" => self.launch(&arguments),
+            "setBreakpoints" => self.set_breakpoints(&arguments),
+            "configurationDone" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS, "expensive": false },
+                { "name": "Data", "variablesReference": DATA, "expensive": false },
+            ]})),
+            "variables" => self.variables(&arguments),
+            "evaluate" => self.evaluate(&arguments),
+            "disconnect" => Ok(Value::Null),
+            other => Err(format!("Unsupported request `{}`", other)),
+        };
+        let ok = result.is_ok();
+        self.send(response(result));
+
+        match command.as_str() {
+            "initialize" => self.event("initialized", json!({})),
+            "configurationDone" if ok && self.stop_on_entry => {
+                self.event(
+                    "stopped",
+                    json!({ "reason": "entry", "threadId": THREAD_ID }),
+                );
+            }
+            "configurationDone" if ok && self.debugger.is_some() => self.run(Run::Continue),
+            _ => {}
+        }
+        std::mem::take(&mut self.outgoing)
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = string_arg(arguments, "program").ok_or("`program` is required")?;
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        self.launch_source(path, &source, arguments)
+    }
+
+    fn launch_source(
+        &mut self,
+        path: &str,
+        source: &str,
+        arguments: &Value,
+    ) -> Result<Value, String> {
+        let program = casl::assemble(source).map_err(|errors| {
+            errors
+                .iter()
+                .map(|e| format!("{}:{}", path, e))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })?;
+
+        // ラベルの付いたDS/DCを変数として見せる
+        let (ast, _) = parser::parse(source);
+        self.data = ast
+            .lines
+            .iter()
+            .filter(|line| {
+                matches!(
+                    line.statement.as_ref().map(|s| &s.opecode),
+                    Some(Opecode::Ds) | Some(Opecode::Dc)
+                )
+            })
+            .filter_map(|line| {
+                let label = line.label.as_ref()?;
+                let symbol = program
+                    .symbols
+                    .iter()
+                    .find(|s| s.span.line == line.number && s.name == label.name)?;
+                let len = program
+                    .source_map
+                    .iter()
+                    .filter(|&&l| l == line.number)
+                    .count();
+                Some(DataArea {
+                    name: label.name.clone(),
+                    address: symbol.address,
+                    len,
+                })
+            })
+            .collect();
+
+        let mut debugger = Debugger::new(program, source);
+        if let Some(lines) = arguments.get("input").and_then(Value::as_array) {
+            for line in lines.iter().filter_map(Value::as_str) {
+                debugger.push_input(line.to_string());
+            }
+        }
+        debugger.close_input();
+        self.debugger = Some(debugger);
+        self.path = path.to_string();
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.sent = 0;
+        Ok(Value::Null)
+    }
+
+    fn debugger(&self) -> Result<&Debugger, String> {
+        self.debugger
+            .as_ref()
+            .ok_or_else(|| "Not launched".to_string())
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let debugger = self.debugger.as_mut().ok_or("Not launched")?;
+        let ids: Vec<usize> = debugger.breakpoints().iter().map(|b| b.id).collect();
+        for id in ids {
+            debugger.delete_breakpoint(id);
+        }
+
+        let requested = arguments["breakpoints"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let mut breakpoints = Vec::new();
+        for b in requested {
+            let line = b["line"].as_u64().unwrap_or(0) as usize;
+            let address = line
+                .checked_sub(1)
+                .and_then(|l| debugger.program().address_of_line(l));
+            let address = match address {
+                Some(address) => address,
+                None => {
+                    breakpoints.push(json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "No instruction on this line",
+                    }));
+                    continue;
+                }
+            };
+            let id = debugger.add_breakpoint(address);
+            let mut set = debugger.set_condition(id, string_arg(&b, "condition"));
+            if set.is_ok() {
+                set = debugger.set_message(id, string_arg(&b, "logMessage"));
+            }
+            if let (true, Some(count)) = (set.is_ok(), string_arg(&b, "hitCondition")) {
+                // 「N回目で止まる」なので N-1 回は無視する
+                match count.trim().parse::<usize>() {
+                    Ok(n) => set = debugger.set_ignore(id, n.saturating_sub(1)),
+                    Err(_) => {
+                        debugger.delete_breakpoint(id);
+                        breakpoints.push(json!({
+                            "verified": false,
+                            "line": line,
+                            "message": format!("Invalid hit count `{}`", count),
+                        }));
+                        continue;
+                    }
+                }
+            }
+            breakpoints.push(match set {
+                Ok(()) => json!({ "id": id, "verified": true, "line": line }),
+                Err(e) => {
+                    debugger.delete_breakpoint(id);
+                    json!({ "verified": false, "line": line, "message": e.to_string() })
+                }
+            });
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&self) -> Result<Value, String> {
+        let debugger = self.debugger()?;
+        let program = debugger.program();
+        // 一番内側から順に、(今いる番地, そのサブルーチンの先頭)
+        let mut frames = vec![(
+            debugger.machine().pr(),
+            debugger.frames().last().map_or(program.entry, |f| f.entry),
+        )];
+        let calls = debugger.frames();
+        for (i, frame) in calls.iter().enumerate().rev() {
+            let entry = match i {
+                0 => program.entry,
+                _ => calls[i - 1].entry,
+            };
+            frames.push((frame.return_address.wrapping_sub(2), entry));
+        }
+
+        let frames: Vec<Value> = frames
+            .into_iter()
+            .enumerate()
+            .map(|(id, (pc, entry))| {
+                let name = program
+                    .describe(entry)
+                    .unwrap_or_else(|| format!("#{:04X}", entry));
+                let line = program.line_of(pc).map_or(0, |l| l + 1);
+                json!({
+                    "id": id,
+                    "name": name,
+                    "source": { "path": self.path },
+                    "line": line,
+                    "column": 1,
+                    "instructionPointerReference": format!("#{:04X}", pc),
+                })
+            })
+            .collect();
+        let total = frames.len();
+        Ok(json!({ "stackFrames": frames, "totalFrames": total }))
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Value, String> {
+        let debugger = self.debugger()?;
+        let machine = debugger.machine();
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<Value> = match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS) => (0..8)
+                .map(|n| {
+                    let value = machine.gr().get(RegisterNumber(n));
+                    variable(format!("GR{}", n), word(value))
+                })
+                .chain(std::iter::once(variable(
+                    "SP".to_string(),
+                    word(machine.sp()),
+                )))
+                .chain(std::iter::once(variable(
+                    "PR".to_string(),
+                    word(machine.pr()),
+                )))
+                .collect(),
+            Some(FLAGS) => Flag::ALL
+                .iter()
+                .map(|&f| {
+                    variable(
+                        f.name().to_string(),
+                        machine.cell(Cell::Flag(f)).to_string(),
+                    )
+                })
+                .collect(),
+            Some(DATA) => self
+                .data
+                .iter()
+                .map(|area| {
+                    let words: Vec<String> = (0..area.len.min(16))
+                        .map(|i| {
+                            format!(
+                                "#{:04X}",
+                                machine.cell(Cell::Memory(area.address.wrapping_add(i as u16)))
+                            )
+                        })
+                        .collect();
+                    let value = match area.len {
+                        1 => word(machine.cell(Cell::Memory(area.address))),
+                        n if n > 16 => format!("[{} ...] ({} words)", words.join(" "), n),
+                        _ => format!("[{}]", words.join(" ")),
+                    };
+                    variable(format!("{} (#{:04X})", area.name, area.address), value)
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// ウォッチやホバーの式。ブレークポイントの条件と同じ書き方
+    fn evaluate(&self, arguments: &Value) -> Result<Value, String> {
+        let debugger = self.debugger()?;
+        let expression = string_arg(arguments, "expression").unwrap_or("");
+        let program = debugger.program();
+        let expr = condition::parse(expression, &|name| program.symbol(name).map(|s| s.address))
+            .map_err(|e| e.to_string())?;
+        Ok(json!({
+            "result": word(expr.eval(debugger.machine())),
+            "variablesReference": 0,
+        }))
+    }
+
+    fn run(&mut self, run: Run) {
+        let debugger = self.debugger.as_mut().expect("checked before running");
+        let start_line = debugger.program().line_of(debugger.machine().pr());
+        let depth = debugger.frames().len();
+        let stop = loop {
+            let goal = match run {
+                Run::Continue => Goal::Continue,
+                Run::Next => debugger.next_goal(),
+                Run::StepIn => Goal::Step,
+                Run::StepOut => debugger.finish_goal(),
+            };
+            let stop = debugger.resume(goal);
+            // IN/OUTのように1行が複数の命令になることがあるので、行が変わるまで進める
+            let same_line = debugger.program().line_of(debugger.machine().pr()) == start_line
+                && debugger.frames().len() == depth;
+            match (&run, &stop) {
+                (Run::Next, Stop::Done) | (Run::StepIn, Stop::Done) if same_line => continue,
+                _ => break stop,
+            }
+        };
+
+        self.forward_output();
+        let (reason, description) = match stop {
+            Stop::Done => ("step", None),
+            Stop::Breakpoint(_) => ("breakpoint", None),
+            Stop::Watchpoint(_) => ("data breakpoint", None),
+            Stop::Error(e) => ("exception", Some(e.to_string())),
+            Stop::Halted => {
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+                return;
+            }
+            Stop::NeedsInput | Stop::HistoryStart => unreachable!("input is closed at launch"),
+        };
+        let mut body =
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+            body["text"] = json!(description);
+        }
+        self.event("stopped", body);
+    }
+
+    /// OUTとログポイントの出力を `output` イベントにする
+    fn forward_output(&mut self) {
+        let debugger = self.debugger.as_mut().expect("launched");
+        let logs = debugger.take_logs();
+        let lines = debugger.machine().output()[self.sent..].to_vec();
+        self.sent = debugger.machine().output().len();
+        for log in logs {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": format!("{}\n", log) }),
+            );
+        }
+        for line in lines {
+            self.event(
+                "output",
+                json!({ "category": "stdout", "output": format!("{}\n", line) }),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    Continue,
+    Next,
+    StepIn,
+    StepOut,
+}
+
+/// `disconnect` か入力の終わりまでリクエストを処理する
+pub fn run(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut server = Server::new();
+    while let Some(request) = read_message(input)? {
+        for message in server.handle(&request) {
+            write_message(output, &message)?;
+        }
+        if request["command"] == "disconnect" {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &str = "MAIN  START
+      LAD   GR1,3
+      CALL  SUB
+      OUT   MSG,LEN
+      RET
+SUB   ST    GR1,X
+      RET
+X     DS    1
+MSG   DC    'ok'
+LEN   DC    2
+      END";
+
+    fn request(server: &mut Server, command: &str, arguments: Value) -> Vec<Value> {
+        server.handle(
+            &json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }),
+        )
+    }
+
+    fn launched() -> Server {
+        let mut server = Server::new();
+        request(&mut server, "initialize", json!({}));
+        server
+            .launch_source("test.cas", SOURCE, &json!({ "stopOnEntry": true }))
+            .unwrap();
+        server
+    }
+
+    fn events(messages: &[Value]) -> Vec<&str> {
+        messages
+            .iter()
+            .filter_map(|m| m["event"].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn breakpoints_stack_and_variables() {
+        let mut s = launched();
+        let reply = request(
+            &mut s,
+            "setBreakpoints",
+            json!({ "source": { "path": "test.cas" }, "breakpoints": [{ "line": 6 }, { "line": 1 }] }),
+        );
+        assert_eq!(reply[0]["body"]["breakpoints"][0]["verified"], true);
+        assert_eq!(reply[0]["body"]["breakpoints"][1]["verified"], false);
+
+        let reply = request(&mut s, "configurationDone", json!({}));
+        assert_eq!(events(&reply), vec!["stopped"]);
+        assert_eq!(reply[1]["body"]["reason"], "entry");
+        let reply = request(&mut s, "continue", json!({ "threadId": 1 }));
+        assert_eq!(reply[1]["body"]["reason"], "breakpoint");
+
+        let reply = request(&mut s, "stackTrace", json!({ "threadId": 1 }));
+        let frames = &reply[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "SUB");
+        assert_eq!(frames[0]["line"], 6);
+        assert_eq!(frames[1]["name"], "MAIN");
+        assert_eq!(frames[1]["line"], 3);
+
+        let reply = request(
+            &mut s,
+            "variables",
+            json!({ "variablesReference": REGISTERS }),
+        );
+        assert_eq!(reply[0]["body"]["variables"][1]["value"], "#0003 (3)");
+        request(&mut s, "next", json!({ "threadId": 1 }));
+        let reply = request(&mut s, "variables", json!({ "variablesReference": DATA }));
+        let data = &reply[0]["body"]["variables"];
+        assert_eq!(data[0]["name"], "X (#0114)");
+        assert_eq!(data[0]["value"], "#0003 (3)");
+        assert_eq!(data[1]["value"], "[#006F #006B]");
+
+        let reply = request(&mut s, "evaluate", json!({ "expression": "[X] + GR1" }));
+        assert_eq!(reply[0]["body"]["result"], "#0006 (6)");
+    }
+
+    #[test]
+    fn step_out_and_output() {
+        let mut s = launched();
+        request(
+            &mut s,
+            "setBreakpoints",
+            json!({ "breakpoints": [{ "line": 6 }] }),
+        );
+        request(&mut s, "configurationDone", json!({}));
+        request(&mut s, "continue", json!({}));
+        let reply = request(&mut s, "stepOut", json!({}));
+        assert_eq!(reply[1]["body"]["reason"], "step");
+        let reply = request(&mut s, "stackTrace", json!({}));
+        assert_eq!(reply[0]["body"]["stackFrames"][0]["line"], 4);
+
+        // OUTは1行で複数の命令だが1回で次の行に進む
+        let reply = request(&mut s, "next", json!({}));
+        assert_eq!(reply[1]["body"]["output"], "ok\n");
+        assert_eq!(events(&reply), vec!["output", "stopped"]);
+        let reply = request(&mut s, "continue", json!({}));
+        assert_eq!(events(&reply), vec!["exited", "terminated"]);
+    }
+
+    #[test]
+    fn framing() {
+        let input: String = [
+            r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#,
+            r#"{"seq":2,"type":"request","command":"disconnect"}"#,
+        ]
+        .iter()
+        .map(|body| format!("Content-Length: {}\r\n\r\n{}", body.len(), body))
+        .collect();
+        let mut output = Vec::new();
+        run(&mut io::Cursor::new(input), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Content-Length: "));
+        assert!(output.contains(r#""event":"initialized""#));
+        assert!(output.contains(r#""command":"disconnect""#));
+    }
+}
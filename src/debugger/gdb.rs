@@ -0,0 +1,625 @@
+//! GDBのリモートシリアルプロトコル (RSP) で `Machine` を操作できるようにする
+//!
+//! - レジスタは GR0~GR7, SP, PR, FR の順に番号0~10で、それぞれ16bitのビッグエンディアン。
+//!   FRは OF, SF, ZF をこの順に上位から並べた3bit
+//! - アドレスはバイト単位で、語の番地の2倍。1語はビッグエンディアンの2バイト
+//! - OUTで出力した行は停止の返事の前に `O` パケットで送る
+//! - INで読む行は `monitor input 文字列` で追加する。`monitor eof` で入力を終える
+//!
+//! 停止理由は ステップ・ブレークポイントが `S05`、中断と入力待ちが `S02`、
+//! 実行時エラーが `S04`、RETでの停止が `W00`
+//!
+//! レジスタの名前と大きさは `qXfer:features:read` で `target.xml` として渡す。
+//! 動作を確かめているのはこのモジュールのテストにある素のRSPクライアントだけ。
+//! 配布されているGDBにはCOMET IIのアーキテクチャがないので、`target.xml` を読んでも
+//! 逆アセンブルやビッグエンディアンのレジスタの解釈はできない
+
+use crate::core::access::Cell;
+use crate::core::machine::Machine;
+use crate::core::operations::RegisterNumber;
+use crate::core::register::Flag;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// GR0~GR7, SP, PR, FR
+pub const REGISTER_COUNT: usize = 11;
+
+/// 中断 (Ctrl-C) が来ていないか確かめる間隔 (命令数)
+const POLL_INTERVAL: usize = 1024;
+
+/// `m` で一度に読めるバイト数。メモリ全体の大きさ
+const MAX_READ: usize = 0x20000;
+
+/// `qXfer:features:read` で渡すレジスタの説明。番号は `REGISTER_COUNT` と同じ順
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.fers.comet2.core">
+    <flags id="fr_flags" size="2">
+      <field name="zf" start="0" end="0"/>
+      <field name="sf" start="1" end="1"/>
+      <field name="of" start="2" end="2"/>
+    </flags>
+    <reg name="gr0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="gr1" bitsize="16" type="int16"/>
+    <reg name="gr2" bitsize="16" type="int16"/>
+    <reg name="gr3" bitsize="16" type="int16"/>
+    <reg name="gr4" bitsize="16" type="int16"/>
+    <reg name="gr5" bitsize="16" type="int16"/>
+    <reg name="gr6" bitsize="16" type="int16"/>
+    <reg name="gr7" bitsize="16" type="int16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pr" bitsize="16" type="code_ptr"/>
+    <reg name="fr" bitsize="16" type="fr_flags"/>
+  </feature>
+</target>
+"#;
+
+/// 実行が止まった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    Trap,
+    Interrupted,
+    Error,
+    Halted,
+}
+
+impl StopReason {
+    fn reply(self) -> &'static str {
+        match self {
+            StopReason::Trap => "S05",
+            StopReason::Interrupted => "S02",
+            StopReason::Error => "S04",
+            StopReason::Halted => "W00",
+        }
+    }
+}
+
+/// パケットへの返事
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Packet(String),
+    /// `O` パケットで送る出力と停止の返事
+    Stopped {
+        output: Vec<String>,
+        reply: String,
+    },
+    /// 返事をしないで接続を閉じる
+    Close,
+}
+
+pub struct Stub {
+    machine: Machine,
+    breakpoints: BTreeSet<u16>,
+    /// 送り済みのOUTの行数
+    sent: usize,
+    input_closed: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// `addr,length` の形
+fn range(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((number(address)?, number(length)?))
+}
+
+/// バイト単位のアドレスを語の番地にする
+fn word_address(address: usize) -> Option<u16> {
+    u16::try_from(address / 2).ok()
+}
+
+fn ok() -> Reply {
+    Reply::Packet("OK".to_string())
+}
+
+fn error() -> Reply {
+    Reply::Packet("E01".to_string())
+}
+
+fn unsupported() -> Reply {
+    Reply::Packet(String::new())
+}
+
+/// `qXfer:features:read:target.xml:offset,length`。続きがあれば `m`、最後なら `l`
+fn features(annex: &str) -> Reply {
+    let window = annex
+        .strip_prefix("target.xml:")
+        .and_then(range)
+        .filter(|&(offset, _)| offset <= TARGET_XML.len());
+    match window {
+        Some((offset, length)) => {
+            let end = offset.saturating_add(length).min(TARGET_XML.len());
+            let marker = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            Reply::Packet(format!("{}{}", marker, &TARGET_XML[offset..end]))
+        }
+        None => error(),
+    }
+}
+
+impl Stub {
+    pub fn new(machine: Machine) -> Stub {
+        Stub {
+            machine,
+            breakpoints: BTreeSet::new(),
+            sent: 0,
+            input_closed: false,
+        }
+    }
+
+    fn register(&self, n: usize) -> Option<u16> {
+        let m = &self.machine;
+        Some(match n {
+            0..=7 => m.gr().get(RegisterNumber(n as u8)),
+            8 => m.sp(),
+            9 => m.pr(),
+            10 => (m.of() as u16) << 2 | (m.sf() as u16) << 1 | m.zf() as u16,
+            _ => return None,
+        })
+    }
+
+    fn set_register(&mut self, n: usize, value: u16) -> Option<()> {
+        let m = &self.machine;
+        self.machine = match n {
+            0..=7 => m.with_cell(Cell::Register(RegisterNumber(n as u8)), value),
+            8 => m.with_cell(Cell::Sp, value),
+            9 => m.with_pr(value),
+            10 => m
+                .with_cell(Cell::Flag(Flag::Overflow), value & 4)
+                .with_cell(Cell::Flag(Flag::Sign), value & 2)
+                .with_cell(Cell::Flag(Flag::Zero), value & 1),
+            _ => return None,
+        };
+        Some(())
+    }
+
+    fn read_byte(&self, address: usize) -> Option<u8> {
+        let word = self.machine.mem.get(word_address(address)?).ok()?;
+        Some(word.to_be_bytes()[address % 2])
+    }
+
+    fn write_byte(&mut self, address: usize, byte: u8) -> Option<()> {
+        let cell = Cell::Memory(word_address(address)?);
+        let mut bytes = self.machine.cell(cell).to_be_bytes();
+        bytes[address % 2] = byte;
+        self.machine = self.machine.with_cell(cell, u16::from_be_bytes(bytes));
+        Some(())
+    }
+
+    /// 1パケットを処理する。`interrupted` は実行中に中断が来たかを調べる
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Reply {
+        let (command, rest) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" if self.machine.is_halted() => Reply::Packet("W00".to_string()),
+            "?" => Reply::Packet("S05".to_string()),
+            "g" => {
+                let bytes: Vec<u8> = (0..REGISTER_COUNT)
+                    .flat_map(|n| self.register(n).unwrap_or(0).to_be_bytes())
+                    .collect();
+                Reply::Packet(hex(&bytes))
+            }
+            "G" => match unhex(rest) {
+                Some(bytes) if bytes.len() == REGISTER_COUNT * 2 => {
+                    for (n, pair) in bytes.chunks(2).enumerate() {
+                        self.set_register(n, u16::from_be_bytes([pair[0], pair[1]]));
+                    }
+                    ok()
+                }
+                _ => error(),
+            },
+            "p" => match number(rest).and_then(|n| self.register(n)) {
+                Some(value) => Reply::Packet(hex(&value.to_be_bytes())),
+                None => error(),
+            },
+            "P" => {
+                let set = rest.split_once('=').and_then(|(n, value)| {
+                    let value = unhex(value).filter(|v| v.len() == 2)?;
+                    self.set_register(number(n)?, u16::from_be_bytes([value[0], value[1]]))
+                });
+                set.map_or_else(error, |_| ok())
+            }
+            "m" => {
+                let bytes = range(rest).and_then(|(address, length)| {
+                    if length > MAX_READ {
+                        return None;
+                    }
+                    (address..address.checked_add(length)?)
+                        .map(|a| self.read_byte(a))
+                        .collect::<Option<Vec<u8>>>()
+                });
+                bytes.map_or_else(error, |bytes| Reply::Packet(hex(&bytes)))
+            }
+            "M" => {
+                let written = rest.split_once(':').and_then(|(range_text, data)| {
+                    let (address, length) = range(range_text)?;
+                    let data = unhex(data).filter(|d| d.len() == length)?;
+                    for (i, byte) in data.into_iter().enumerate() {
+                        self.write_byte(address.checked_add(i)?, byte)?;
+                    }
+                    Some(())
+                });
+                written.map_or_else(error, |_| ok())
+            }
+            "s" | "c" => {
+                if !rest.is_empty() {
+                    match number(rest).and_then(word_address) {
+                        Some(address) => self.machine = self.machine.with_pr(address),
+                        None => return error(),
+                    }
+                }
+                let reason = self.resume(command == "s", interrupted);
+                self.stopped(reason)
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", rest),
+            "H" | "T" => ok(),
+            "k" => Reply::Close,
+            "D" => ok(),
+            "q" => self.query(rest),
+            _ => unsupported(),
+        }
+    }
+
+    /// `Z0,addr,kind` / `z0,addr,kind`。ソフトウェアブレークポイントだけ
+    fn breakpoint(&mut self, insert: bool, rest: &str) -> Reply {
+        let mut fields = rest.split(',');
+        let (kind, address) = (fields.next(), fields.next().map(number));
+        match (kind, address) {
+            (Some("0"), Some(address)) => {
+                let Some(address) = address.and_then(word_address) else {
+                    return error();
+                };
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                ok()
+            }
+            _ => unsupported(),
+        }
+    }
+
+    fn query(&mut self, rest: &str) -> Reply {
+        if rest.starts_with("Supported") {
+            return Reply::Packet("PacketSize=1000;qXfer:features:read+".to_string());
+        }
+        if let Some(annex) = rest.strip_prefix("Xfer:features:read:") {
+            return features(annex);
+        }
+        match rest {
+            "Attached" => Reply::Packet("1".to_string()),
+            "C" => Reply::Packet("QC1".to_string()),
+            "fThreadInfo" => Reply::Packet("m1".to_string()),
+            "sThreadInfo" => Reply::Packet("l".to_string()),
+            _ => match rest.strip_prefix("Rcmd,").and_then(unhex) {
+                Some(command) => self.monitor(&String::from_utf8_lossy(&command)),
+                None => unsupported(),
+            },
+        }
+    }
+
+    /// `monitor` コマンド
+    fn monitor(&mut self, command: &str) -> Reply {
+        if let Some(line) = command.strip_prefix("input ") {
+            self.machine = self.machine.with_input(Some(line.to_string()));
+            ok()
+        } else if command == "eof" {
+            self.input_closed = true;
+            ok()
+        } else {
+            let message = "monitor commands: input TEXT, eof\n";
+            Reply::Packet(hex(message.as_bytes()))
+        }
+    }
+
+    fn resume(&mut self, single: bool, interrupted: &mut dyn FnMut() -> bool) -> StopReason {
+        let mut count: usize = 0;
+        loop {
+            if self.machine.is_halted() {
+                return StopReason::Halted;
+            }
+            // 入力がないINは実行せずに止まる。`monitor input` してから再開する
+            if self.machine.needs_input() && !self.input_closed {
+                return StopReason::Interrupted;
+            }
+            match self.machine.step() {
+                Ok(machine) => self.machine = machine,
+                Err(_) => return StopReason::Error,
+            }
+            if self.machine.is_halted() {
+                return StopReason::Halted;
+            }
+            if single || self.breakpoints.contains(&self.machine.pr()) {
+                return StopReason::Trap;
+            }
+            count += 1;
+            if count.is_multiple_of(POLL_INTERVAL) && interrupted() {
+                return StopReason::Interrupted;
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: StopReason) -> Reply {
+        let output = self.machine.output()[self.sent..].to_vec();
+        self.sent = self.machine.output().len();
+        Reply::Stopped {
+            output,
+            reply: reason.reply().to_string(),
+        }
+    }
+}
+
+/// パケットの読み書き
+struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// 次のパケットの中身。中断 (0x03) なら `?` を返す
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(0x03) => return Ok(Some("?".to_string())),
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok());
+            let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if expected == Some(actual) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+
+    /// 実行中に0x03が届いていないか、待たずに調べる
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let interrupted = matches!(self.stream.peek(&mut byte), Ok(1) if byte[0] == 0x03);
+        if interrupted {
+            let _ = self.stream.read(&mut byte);
+        }
+        let _ = self.stream.set_nonblocking(false);
+        interrupted
+    }
+}
+
+/// `listener` で1つ接続を受けて、切れるまで相手をする。最後の状態を返す
+pub fn serve(listener: &TcpListener, machine: Machine) -> io::Result<Machine> {
+    let (stream, _) = listener.accept()?;
+    let mut connection = Connection { stream };
+    let mut stub = Stub::new(machine);
+
+    while let Some(packet) = connection.read_packet()? {
+        let reply = {
+            let connection = &mut connection;
+            stub.handle(&packet, &mut || connection.interrupted())
+        };
+        match reply {
+            Reply::Packet(data) => connection.write_packet(&data)?,
+            Reply::Stopped { output, reply } => {
+                for line in output {
+                    let data = format!("O{}", hex(format!("{}\n", line).as_bytes()));
+                    connection.write_packet(&data)?;
+                }
+                connection.write_packet(&reply)?;
+            }
+            Reply::Close => break,
+        }
+        if packet == "D" {
+            break;
+        }
+    }
+    Ok(stub.machine)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl::assemble;
+    use std::thread;
+
+    const SOURCE: &str = "MAIN  START
+      LAD   GR1,1
+      ST    GR1,LEN
+      OUT   X,LEN
+      RET
+X     DC    'hi'
+LEN   DC    2
+      END";
+
+    fn stub() -> Stub {
+        Stub::new(assemble(SOURCE).unwrap().load())
+    }
+
+    fn packet(stub: &mut Stub, data: &str) -> Reply {
+        stub.handle(data, &mut || false)
+    }
+
+    fn text(data: &str) -> Reply {
+        Reply::Packet(data.to_string())
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut s = stub();
+        // PRは #0100 なので10番目 (PR) が 0100
+        let g = match packet(&mut s, "g") {
+            Reply::Packet(g) => g,
+            reply => panic!("{:?}", reply),
+        };
+        assert_eq!(&g[32..44], "010001000000");
+        assert_eq!(packet(&mut s, "P1=1234"), text("OK"));
+        assert_eq!(packet(&mut s, "p1"), text("1234"));
+        assert_eq!(packet(&mut s, "Pa=0005"), text("OK"));
+        assert_eq!(
+            (s.machine.of(), s.machine.sf(), s.machine.zf()),
+            (true, false, true)
+        );
+        // LAD GR1,1 は #1210 #0001
+        assert_eq!(packet(&mut s, "m200,4"), text("12100001"));
+        assert_eq!(packet(&mut s, "M203,1:07"), text("OK"));
+        assert_eq!(packet(&mut s, "m202,2"), text("0007"));
+        assert_eq!(packet(&mut s, "p20"), text("E01"));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut s = stub();
+        assert_eq!(packet(&mut s, "mffffffffffffffff,2"), text("E01"));
+        assert_eq!(packet(&mut s, "m0,20001"), text("E01"));
+        assert_eq!(packet(&mut s, "m1fffe,2"), text("0000"));
+        assert_eq!(packet(&mut s, "m1fffe,3"), text("E01"));
+        assert_eq!(packet(&mut s, "Mffffffffffffffff,2:0000"), text("E01"));
+        assert_eq!(packet(&mut s, "s20000"), text("E01"));
+        assert_eq!(packet(&mut s, "c20000"), text("E01"));
+        assert_eq!(packet(&mut s, "Z0,20000,2"), text("E01"));
+        assert_eq!(s.machine.pr(), 0x100);
+        assert!(s.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn target_description() {
+        let mut s = stub();
+        let supported = packet(&mut s, "qSupported:multiprocess+;xmlRegisters=i386");
+        assert!(matches!(supported, Reply::Packet(p) if p.contains("qXfer:features:read+")));
+
+        let mut xml = String::new();
+        loop {
+            let request = format!("qXfer:features:read:target.xml:{:x},40", xml.len());
+            let chunk = match packet(&mut s, &request) {
+                Reply::Packet(chunk) => chunk,
+                reply => panic!("{:?}", reply),
+            };
+            let (marker, data) = chunk.split_at(1);
+            xml.push_str(data);
+            if marker == "l" {
+                break;
+            }
+            assert_eq!((marker, data.len()), ("m", 0x40));
+        }
+        assert_eq!(xml, TARGET_XML);
+        assert_eq!(xml.matches("<reg ").count(), REGISTER_COUNT);
+        assert_eq!(xml.matches("bitsize=\"16\"").count(), REGISTER_COUNT);
+        assert_eq!(
+            packet(&mut s, "qXfer:features:read:other.xml:0,40"),
+            text("E01")
+        );
+    }
+
+    #[test]
+    fn step_breakpoint_and_output() {
+        let mut s = stub();
+        assert_eq!(
+            packet(&mut s, "s"),
+            Reply::Stopped {
+                output: vec![],
+                reply: "S05".to_string()
+            }
+        );
+        assert_eq!(packet(&mut s, "p1"), text("0001"));
+        // RET (#0110) で止まる
+        assert_eq!(packet(&mut s, "Z0,220,2"), text("OK"));
+        assert_eq!(
+            packet(&mut s, "c"),
+            Reply::Stopped {
+                output: vec!["h".to_string()],
+                reply: "S05".to_string()
+            }
+        );
+        assert_eq!(packet(&mut s, "z0,220,2"), text("OK"));
+        assert_eq!(
+            packet(&mut s, "c"),
+            Reply::Stopped {
+                output: vec![],
+                reply: "W00".to_string()
+            }
+        );
+        assert_eq!(packet(&mut s, "?"), text("W00"));
+    }
+
+    #[test]
+    fn over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // Machineはスレッドをまたげないので向こうで作る
+        let server = thread::spawn(move || {
+            let machine = assemble(SOURCE).unwrap().load();
+            serve(&listener, machine).unwrap().pr()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut exchange = |data: &str| {
+            let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(stream, "${}#{:02x}", data, checksum).unwrap();
+            let mut received = Vec::new();
+            let mut byte = [0];
+            // `+` と、`#` の後の2文字まで
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                received.push(byte[0]);
+                if byte[0] == b'#' {
+                    let mut checksum = [0; 2];
+                    stream.read_exact(&mut checksum).unwrap();
+                    received.extend_from_slice(&checksum);
+                    break;
+                }
+            }
+            String::from_utf8(received).unwrap()
+        };
+        assert_eq!(exchange("qAttached"), "+$1#31");
+        assert_eq!(exchange("p9"), "+$0100#c1");
+        assert_eq!(exchange("s"), "+$S05#b8");
+        stream.write_all(b"$k#6b").unwrap();
+        assert_eq!(server.join().unwrap(), 0x102);
+    }
+}
@@ -0,0 +1,122 @@
+//! 実行した命令の前の状態を覚えておいて巻き戻せるようにする
+
+use super::Frame;
+use crate::core::access::{Access, AccessKind, Cell};
+use crate::core::machine::Machine;
+use crate::core::memory::Memory;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// 覚えておく命令数の既定値。メモリは命令ごとに書いた語の前の値しか残さない
+pub const DEFAULT_LIMIT: usize = 1000;
+
+/// 1命令を実行する直前の状態
+#[derive(Debug, Clone)]
+struct Entry {
+    /// メモリだけは `History::blank` に差し替えてあり、`writes` から戻す
+    machine: Machine,
+    frames: Vec<Frame>,
+    /// その命令が書いたメモリとレジスタ。同じ値を書いたものも含む
+    writes: Vec<Access>,
+}
+
+/// `Cell` に書いた命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    /// 書いた命令の番地
+    pub pr: u16,
+    pub old: u16,
+    pub new: u16,
+    /// 何命令前か。直前の命令なら1
+    pub ago: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    entries: VecDeque<Entry>,
+    limit: usize,
+    /// 積んだ状態のメモリの代わりに置いておく
+    blank: Rc<Memory>,
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History {
+            entries: VecDeque::new(),
+            limit,
+            blank: Rc::new(Memory([0; 65536])),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// 命令を実行する前の状態と、その命令の読み書き `log` を積む
+    pub fn push(&mut self, mut machine: Machine, frames: Vec<Frame>, log: &[Access]) {
+        let writes = log
+            .iter()
+            .filter(|access| access.kind == AccessKind::Write)
+            .copied()
+            .collect();
+        machine.mem = Rc::clone(&self.blank);
+        self.entries.push_back(Entry {
+            machine,
+            frames,
+            writes,
+        });
+        self.truncate();
+    }
+
+    /// 最後に積んだ状態を取り出す。メモリは `current` に書いた値を戻して作る
+    pub fn pop(&mut self, current: &Machine) -> Option<(Machine, Vec<Frame>)> {
+        let mut entry = self.entries.pop_back()?;
+        let mut mem = Rc::clone(&current.mem);
+        for access in entry.writes.iter().rev() {
+            if let Cell::Memory(address) = access.cell {
+                Rc::make_mut(&mut mem).0[address as usize] = access.old;
+            }
+        }
+        entry.machine.mem = mem;
+        Some((entry.machine, entry.frames))
+    }
+
+    /// 最後に `cell` に書いた命令。同じ値を書いた場合も含む
+    pub fn last_change(&self, cell: Cell) -> Option<Change> {
+        self.entries
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(i, entry)| {
+                let access = entry
+                    .writes
+                    .iter()
+                    .rev()
+                    .find(|access| access.cell == cell)?;
+                Some(Change {
+                    pr: entry.machine.pr(),
+                    old: access.old,
+                    new: access.new,
+                    ago: i + 1,
+                })
+            })
+    }
+
+    fn truncate(&mut self) {
+        while self.entries.len() > self.limit {
+            self.entries.pop_front();
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(DEFAULT_LIMIT)
+    }
+}
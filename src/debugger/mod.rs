@@ -0,0 +1,606 @@
+//! ステップ実行のデバッガ。画面まわりは `cli` にある
+
+pub mod cli;
+pub mod command;
+pub mod condition;
+pub mod dap;
+pub mod gdb;
+pub mod history;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tui;
+pub mod watch;
+
+use crate::casl::Program;
+use crate::core::access::Cell;
+use crate::core::machine::{Machine, StepError};
+use crate::core::operations::{self, Operation1, Operation2, Word1, Word2};
+use crate::core::protection::ProtectionMode;
+use condition::{Expr, Message};
+use history::{Change, History};
+use itertools::Either;
+use watch::{Hit, WatchKind, Watchpoint};
+
+/// CALLで積まれた呼び出し
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// 呼ばれた番地
+    pub entry: u16,
+    /// CALLの次の命令の番地
+    pub return_address: u16,
+    /// 呼び出した直後のSP
+    pub sp: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub address: u16,
+    /// 式とその元の文字列。真のときだけ止まる
+    pub condition: Option<(String, Expr)>,
+    /// 残りこの回数は止まらない
+    pub ignore: usize,
+    /// 条件を満たして到達した回数
+    pub hits: usize,
+    /// ログポイントなら止まらずにこれを出力する
+    pub message: Option<(String, Message)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BreakpointError {
+    #[error("No breakpoint {0}")]
+    NotFound(usize),
+    #[error("{0}")]
+    Parse(#[from] condition::ParseError),
+}
+
+/// どこまで実行するか。入力待ちで止まったら同じ `Goal` でもう一度 `resume` する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Goal {
+    /// 1命令
+    Step,
+    /// CALLの次の命令に戻ってくるまで
+    Return { address: u16, sp: u16 },
+    /// 呼び出しの深さが `depth` より浅くなるまで
+    Finish { depth: usize },
+    /// ブレークポイントか停止まで
+    Continue,
+}
+
+/// 実行が止まった理由
+#[derive(Debug)]
+pub enum Stop {
+    Done,
+    Breakpoint(usize),
+    /// 直前の命令がウォッチポイントに引っかかった
+    Watchpoint(Vec<Hit>),
+    Halted,
+    /// INを実行しようとしたが入力がない。何も実行していない
+    NeedsInput,
+    Error(StepError),
+    /// 巻き戻そうとしたが履歴が残っていない
+    HistoryStart,
+}
+
+pub struct Debugger {
+    program: Program,
+    source: Vec<String>,
+    machine: Machine,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    /// ブレークポイントとウォッチポイントで共通の番号
+    next_breakpoint: usize,
+    frames: Vec<Frame>,
+    history: History,
+    /// ログポイントが出力して、まだ取り出されていない行
+    logs: Vec<String>,
+    /// 入力が終わったらINは入力待ちにならずに-1を返す
+    input_closed: bool,
+}
+
+impl Breakpoint {
+    fn condition_holds(&self, machine: &Machine) -> bool {
+        match &self.condition {
+            Some((_, expr)) => expr.is_true(machine),
+            None => true,
+        }
+    }
+}
+
+/// コードの書き換え、データの実行、未初期化の読み込みを警告するMachine
+fn load(program: &Program) -> Machine {
+    program
+        .load_protected(ProtectionMode::Warn)
+        .with_shadow(program.shadow(ProtectionMode::Warn))
+}
+
+impl Debugger {
+    pub fn new(program: Program, source: &str) -> Debugger {
+        Debugger {
+            machine: load(&program),
+            program,
+            source: source.lines().map(String::from).collect(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_breakpoint: 1,
+            frames: Vec::new(),
+            history: History::default(),
+            logs: Vec::new(),
+            input_closed: false,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+    /// ソースの行 (0始まり)
+    pub fn source_line(&self, line: usize) -> Option<&str> {
+        self.source.get(line).map(String::as_str)
+    }
+    pub fn source_len(&self) -> usize {
+        self.source.len()
+    }
+    /// 呼び出し中のフレーム。最後が一番内側
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// INで読む行を追加する
+    pub fn push_input(&mut self, line: String) {
+        self.machine = self.machine.with_input(Some(line));
+    }
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// ラベル (`LOOP`, `MAIN.LOOP`) か番地を解釈する
+    pub fn resolve(&self, location: &command::Location) -> Option<u16> {
+        match location {
+            command::Location::Address(address) => Some(*address),
+            command::Location::Label(name) => self.program.symbol(name).map(|s| s.address),
+        }
+    }
+
+    /// `who` の対象を解釈する
+    pub fn resolve_place(&self, place: &command::Place) -> Option<Cell> {
+        match place {
+            command::Place::Memory(location) => self.resolve(location).map(Cell::Memory),
+            command::Place::Register(r) => Some(Cell::Register(*r)),
+            command::Place::Sp => Some(Cell::Sp),
+            command::Place::Flag(flag) => Some(Cell::Flag(*flag)),
+        }
+    }
+
+    /// 番地を `#0103 (LOOP+1)` の形にする
+    pub fn describe(&self, address: u16) -> String {
+        match self.program.describe(address) {
+            Some(label) => format!("#{:04X} ({})", address, label),
+            None => format!("#{:04X}", address),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) -> usize {
+        let id = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            address,
+            condition: None,
+            ignore: 0,
+            hits: 0,
+            message: None,
+        });
+        id
+    }
+
+    fn breakpoint_mut(&mut self, id: usize) -> Result<&mut Breakpoint, BreakpointError> {
+        self.breakpoints
+            .iter_mut()
+            .find(|b| b.id == id)
+            .ok_or(BreakpointError::NotFound(id))
+    }
+
+    /// 式の中のラベルを番地にする
+    fn label_resolver(&self) -> impl Fn(&str) -> Option<u16> + '_ {
+        move |name| self.program.symbol(name).map(|s| s.address)
+    }
+
+    /// 条件を付ける。`None` なら外す
+    pub fn set_condition(&mut self, id: usize, text: Option<&str>) -> Result<(), BreakpointError> {
+        let condition = match text {
+            Some(text) => Some((
+                text.to_string(),
+                condition::parse(text, &self.label_resolver())?,
+            )),
+            None => None,
+        };
+        self.breakpoint_mut(id)?.condition = condition;
+        Ok(())
+    }
+
+    /// 次の `count` 回は止まらないようにする
+    pub fn set_ignore(&mut self, id: usize, count: usize) -> Result<(), BreakpointError> {
+        self.breakpoint_mut(id)?.ignore = count;
+        Ok(())
+    }
+
+    /// ログポイントにする。`None` なら普通のブレークポイントに戻す
+    pub fn set_message(&mut self, id: usize, text: Option<&str>) -> Result<(), BreakpointError> {
+        let message = match text {
+            Some(text) => Some((
+                text.to_string(),
+                Message::parse(text, &self.label_resolver())?,
+            )),
+            None => None,
+        };
+        self.breakpoint_mut(id)?.message = message;
+        Ok(())
+    }
+
+    /// ログポイントが出力した行を取り出す
+    pub fn take_logs(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.logs)
+    }
+
+    /// `cell` から `len` 語 (メモリ以外は1) を見張る
+    pub fn add_watchpoint(&mut self, cell: Cell, len: u16, kind: WatchKind) -> usize {
+        let id = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        self.watchpoints.push(Watchpoint {
+            id,
+            cell,
+            len,
+            kind,
+        });
+        id
+    }
+
+    /// ブレークポイントかウォッチポイントを消す
+    pub fn delete_breakpoint(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        self.breakpoints.len() + self.watchpoints.len() != len
+    }
+
+    /// PRの位置にある命令
+    pub fn current_instruction(&self) -> Option<Either<Word1, Word2>> {
+        let word = self.machine.mem.get(self.machine.pr()).ok()?;
+        operations::ope(word).ok()
+    }
+
+    /// CALLなら戻ってくるまで、それ以外は1命令
+    pub fn next_goal(&self) -> Goal {
+        match self.current_instruction() {
+            Some(Either::Right(Word2 {
+                operation: Operation2::Call,
+                ..
+            })) => Goal::Return {
+                address: self.machine.pr().wrapping_add(2),
+                sp: self.machine.sp(),
+            },
+            _ => Goal::Step,
+        }
+    }
+
+    /// 今のサブルーチンからRETするまで
+    pub fn finish_goal(&self) -> Goal {
+        Goal::Finish {
+            depth: self.frames.len(),
+        }
+    }
+
+    /// `goal` に着くかブレークポイントに当たるまで実行する
+    pub fn resume(&mut self, goal: Goal) -> Stop {
+        loop {
+            if let Err(stop) = self.advance() {
+                return stop;
+            }
+            if let Some(id) = self.hit_breakpoint() {
+                return Stop::Breakpoint(id);
+            }
+            let reached = match goal {
+                Goal::Step => true,
+                Goal::Return { address, sp } => {
+                    self.machine.pr() == address && self.machine.sp() == sp
+                }
+                Goal::Finish { depth } => self.frames.len() < depth,
+                Goal::Continue => false,
+            };
+            if reached {
+                return Stop::Done;
+            }
+        }
+    }
+
+    /// 1命令戻す
+    pub fn step_back(&mut self) -> Stop {
+        match self.history.pop(&self.machine) {
+            Some((machine, frames)) => {
+                self.machine = machine;
+                self.frames = frames;
+                Stop::Done
+            }
+            None => Stop::HistoryStart,
+        }
+    }
+
+    /// 前のブレークポイントまで戻る。なければ履歴の最初まで戻る
+    pub fn reverse_continue(&mut self) -> Stop {
+        if let Stop::HistoryStart = self.step_back() {
+            return Stop::HistoryStart;
+        }
+        loop {
+            // 戻るときは回数やログポイントは気にしない
+            let machine = &self.machine;
+            if let Some(b) = self.breakpoints.iter().find(|b| {
+                b.message.is_none() && b.address == machine.pr() && b.condition_holds(machine)
+            }) {
+                return Stop::Breakpoint(b.id);
+            }
+            if let Stop::HistoryStart = self.step_back() {
+                return Stop::HistoryStart;
+            }
+        }
+    }
+
+    /// 履歴の中で最後に `cell` に書いた命令
+    pub fn last_change(&self, cell: Cell) -> Option<Change> {
+        self.history.last_change(cell)
+    }
+
+    /// PRの位置のブレークポイントを数えて、止まるべきものがあればその番号
+    fn hit_breakpoint(&mut self) -> Option<usize> {
+        let machine = &self.machine;
+        let mut stop = None;
+        for b in &mut self.breakpoints {
+            if b.address != machine.pr() || !b.condition_holds(machine) {
+                continue;
+            }
+            b.hits += 1;
+            if b.ignore > 0 {
+                b.ignore -= 1;
+            } else if let Some((_, message)) = &b.message {
+                self.logs.push(message.format(machine));
+            } else if stop.is_none() {
+                stop = Some(b.id);
+            }
+        }
+        stop
+    }
+
+    /// 1命令実行してCALL/RETを追いかける
+    fn advance(&mut self) -> Result<(), Stop> {
+        if self.machine.is_halted() {
+            return Err(Stop::Halted);
+        }
+        if self.machine.needs_input() && !self.input_closed {
+            return Err(Stop::NeedsInput);
+        }
+
+        let pr = self.machine.pr();
+        let instruction = self.current_instruction();
+        let (machine, log) = self.machine.step_traced().map_err(Stop::Error)?;
+        let frames = self.frames.clone();
+
+        match instruction {
+            Some(Either::Right(Word2 {
+                operation: Operation2::Call,
+                ..
+            })) => self.frames.push(Frame {
+                entry: machine.pr(),
+                return_address: pr.wrapping_add(2),
+                sp: machine.sp(),
+            }),
+            Some(Either::Left(Word1 {
+                operation: Operation1::Return,
+                ..
+            })) => {
+                self.frames.pop();
+            }
+            _ => {}
+        }
+
+        let previous = std::mem::replace(&mut self.machine, machine);
+        self.history.push(previous, frames, &log);
+        let hits = watch::hits(&self.watchpoints, pr, &log);
+        if !hits.is_empty() {
+            Err(Stop::Watchpoint(hits))
+        } else if self.machine.is_halted() {
+            Err(Stop::Halted)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl::assemble;
+    use crate::core::operations::RegisterNumber;
+
+    const SOURCE: &str = "MAIN  START
+      LAD   GR1,1
+      CALL  SUB
+      LAD   GR2,2
+      RET
+SUB   LAD   GR3,3
+      CALL  SUB2
+      RET
+SUB2  LAD   GR4,4
+      RET
+      END";
+
+    fn debugger() -> Debugger {
+        Debugger::new(assemble(SOURCE).unwrap(), SOURCE)
+    }
+
+    #[test]
+    fn next_steps_over_call() {
+        let mut d = debugger();
+        assert!(matches!(d.resume(Goal::Step), Stop::Done));
+        assert!(matches!(d.resume(d.next_goal()), Stop::Done));
+        assert_eq!(
+            d.machine().pr(),
+            d.program().symbol("MAIN").unwrap().address + 4
+        );
+        assert_eq!(d.machine().gr().values()[4], 4);
+        assert!(d.frames().is_empty());
+    }
+
+    #[test]
+    fn finish_and_breakpoints() {
+        let mut d = debugger();
+        let sub2 = d.program().symbol("SUB2").unwrap().address;
+        let id = d.add_breakpoint(sub2);
+        assert!(matches!(d.resume(Goal::Continue), Stop::Breakpoint(i) if i == id));
+        assert_eq!(d.frames().len(), 2);
+        assert_eq!(d.frames()[1].entry, sub2);
+
+        assert!(matches!(d.resume(d.finish_goal()), Stop::Done));
+        assert_eq!(d.frames().len(), 1);
+        assert!(d.delete_breakpoint(id));
+        assert!(matches!(d.resume(Goal::Continue), Stop::Halted));
+    }
+
+    #[test]
+    fn step_back_and_reverse_continue() {
+        let mut d = debugger();
+        let sub2 = d.program().symbol("SUB2").unwrap().address;
+        let id = d.add_breakpoint(sub2);
+        assert!(matches!(d.resume(Goal::Continue), Stop::Breakpoint(_)));
+        assert!(matches!(d.resume(Goal::Continue), Stop::Halted));
+        assert!(d.machine().is_halted());
+
+        assert!(matches!(d.reverse_continue(), Stop::Breakpoint(i) if i == id));
+        assert_eq!(d.machine().pr(), sub2);
+        assert_eq!(d.frames().len(), 2);
+        assert_eq!(d.machine().gr().values()[4], 0);
+
+        assert!(matches!(d.step_back(), Stop::Done));
+        assert_eq!(d.frames().len(), 1);
+        assert!(matches!(d.reverse_continue(), Stop::HistoryStart));
+        assert_eq!(d.machine(), &load(d.program()));
+        assert!(matches!(d.step_back(), Stop::HistoryStart));
+    }
+
+    #[test]
+    fn last_change() {
+        let source = "MAIN  START
+      LAD   GR1,3
+      ST    GR1,X
+      LAD   GR1,0
+      ST    GR1,Y
+      RET
+X     DS    1
+Y     DS    1
+      END";
+        let mut d = Debugger::new(assemble(source).unwrap(), source);
+        assert!(matches!(d.resume(Goal::Continue), Stop::Halted));
+        let main = d.program().symbol("MAIN").unwrap().address;
+        let x = d.program().symbol("X").unwrap().address;
+        let y = d.program().symbol("Y").unwrap().address;
+
+        let change = d.last_change(Cell::Memory(x)).unwrap();
+        assert_eq!((change.pr, change.old, change.new), (main + 2, 0, 3));
+        assert_eq!(change.ago, 4);
+        let change = d.last_change(Cell::Register(RegisterNumber(1))).unwrap();
+        assert_eq!((change.pr, change.old, change.new), (main + 4, 3, 0));
+        // 同じ値を書いても見つかる
+        let change = d.last_change(Cell::Memory(y)).unwrap();
+        assert_eq!((change.pr, change.old, change.new), (main + 6, 0, 0));
+        assert_eq!(change.ago, 2);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let source = "MAIN  START
+      LAD   GR1,3
+      ST    GR1,X
+      ST    GR1,X
+      LD    GR2,X
+      RET
+X     DS    1
+      END";
+        let mut d = Debugger::new(assemble(source).unwrap(), source);
+        let main = d.program().symbol("MAIN").unwrap().address;
+        let x = d.program().symbol("X").unwrap().address;
+        let change = d.add_watchpoint(Cell::Memory(x), 1, WatchKind::Change);
+        let read = d.add_watchpoint(Cell::Memory(x), 1, WatchKind::Read);
+
+        match d.resume(Goal::Continue) {
+            Stop::Watchpoint(hits) => {
+                assert_eq!(hits.len(), 1);
+                assert_eq!((hits[0].id, hits[0].pr), (change, main + 2));
+                assert_eq!((hits[0].access.old, hits[0].access.new), (0, 3));
+            }
+            stop => panic!("{:?}", stop),
+        }
+        // 同じ値を書いても変化ではない
+        match d.resume(Goal::Continue) {
+            Stop::Watchpoint(hits) => assert_eq!((hits[0].id, hits[0].pr), (read, main + 6)),
+            stop => panic!("{:?}", stop),
+        }
+        assert!(matches!(d.resume(Goal::Continue), Stop::Halted));
+    }
+
+    #[test]
+    fn conditions_ignore_counts_and_logpoints() {
+        let source = "MAIN  START
+      LAD   GR1,0
+LOOP  LAD   GR1,1,GR1
+      CPA   GR1,=5
+      JMI   LOOP
+      RET
+      END";
+        let mut d = Debugger::new(assemble(source).unwrap(), source);
+        let lp = d.program().symbol("LOOP").unwrap().address;
+        let id = d.add_breakpoint(lp);
+        d.set_condition(id, Some("GR1 >= 2")).unwrap();
+        d.set_ignore(id, 1).unwrap();
+        let log = d.add_breakpoint(lp);
+        d.set_message(log, Some("GR1={GR1}")).unwrap();
+        assert_eq!(
+            d.set_condition(id, Some("NOPE")),
+            Err(BreakpointError::Parse(
+                condition::ParseError::UndefinedLabel("NOPE".to_string())
+            ))
+        );
+        assert_eq!(d.set_ignore(9, 1), Err(BreakpointError::NotFound(9)));
+
+        assert!(matches!(d.resume(Goal::Continue), Stop::Breakpoint(i) if i == id));
+        assert_eq!(d.machine().gr().values()[1], 3);
+        assert_eq!(d.take_logs(), vec!["GR1=0", "GR1=1", "GR1=2", "GR1=3"]);
+        assert_eq!(d.breakpoints()[0].hits, 2);
+        assert!(matches!(d.resume(Goal::Continue), Stop::Breakpoint(i) if i == id));
+        assert_eq!(d.machine().gr().values()[1], 4);
+    }
+
+    #[test]
+    fn stops_for_input() {
+        let source = "MAIN START
+     IN   BUF,LEN
+     RET
+BUF  DS   4
+LEN  DS   1
+     END";
+        let mut d = Debugger::new(assemble(source).unwrap(), source);
+        assert!(matches!(d.resume(Goal::Continue), Stop::NeedsInput));
+        d.push_input("ab".to_string());
+        assert!(matches!(d.resume(Goal::Continue), Stop::Halted));
+        let len = d.program().symbol("LEN").unwrap().address;
+        assert_eq!(d.machine().mem.get(len).unwrap(), 2);
+    }
+}
@@ -0,0 +1,481 @@
+//! 端末全体を使うデバッガ。ソース、レジスタ、スタック、メモリ、出力を並べて表示する
+//!
+//! キー: `s` ステップ、`n` ステップオーバー、`f` RETまで、`c` 続行、`u` 1命令戻す、
+//! `b` カーソル行のブレークポイントを切り替え、`↑`/`↓` カーソル、`PgUp`/`PgDn` メモリ、
+//! `m` メモリをカーソル行の番地に合わせる、`q` 終了。
+//! INで入力待ちになったら1行入力してEnter、Ctrl-Dで入力の終わり
+
+use super::{Debugger, Goal, Stop};
+use crate::casl::disasm::disassemble;
+use crate::core::access::Cell;
+use crate::core::machine::{Machine, STACK_SIZE};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::Frame;
+use std::io;
+
+/// メモリ表示の1行の語数
+const MEMORY_COLUMNS: u16 = 8;
+
+fn changed() -> Style {
+    Style::default()
+        .fg(Color::Yellow)
+        .add_modifier(Modifier::BOLD)
+}
+
+pub struct App {
+    debugger: Debugger,
+    /// 直前のコマンドを実行する前の状態。変わったところを強調するのに使う
+    previous: Machine,
+    /// ソースのカーソル行 (0始まり)
+    cursor: usize,
+    /// メモリ表示の先頭の番地
+    memory: u16,
+    status: String,
+    /// 入力待ちなら、続けるつもりだった `Goal` と入力中の行
+    input: Option<(Goal, String)>,
+    quit: bool,
+}
+
+impl App {
+    pub fn new(debugger: Debugger) -> App {
+        let machine = debugger.machine().clone();
+        let mut app = App {
+            cursor: 0,
+            memory: debugger.program().origin,
+            previous: machine,
+            debugger,
+            status: "s:step n:next f:finish c:continue u:back b:breakpoint q:quit".to_string(),
+            input: None,
+            quit: false,
+        };
+        app.follow();
+        app
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    /// カーソルをPRの行に合わせる
+    fn follow(&mut self) {
+        let pr = self.debugger.machine().pr();
+        if let Some(line) = self.debugger.program().line_of(pr) {
+            self.cursor = line;
+        }
+    }
+
+    pub fn handle(&mut self, key: KeyEvent) {
+        if let Some((goal, line)) = &mut self.input {
+            let goal = *goal;
+            match key.code {
+                KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.input = None;
+                    self.debugger.close_input();
+                    self.resume(goal);
+                }
+                KeyCode::Char(c) => line.push(c),
+                KeyCode::Backspace => {
+                    line.pop();
+                }
+                KeyCode::Enter => {
+                    let line = std::mem::take(line);
+                    self.input = None;
+                    self.debugger.push_input(line);
+                    self.resume(goal);
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Char('s') => self.execute(Goal::Step),
+            KeyCode::Char('n') => self.execute(self.debugger.next_goal()),
+            KeyCode::Char('f') => self.execute(self.debugger.finish_goal()),
+            KeyCode::Char('c') => self.execute(Goal::Continue),
+            KeyCode::Char('u') => {
+                self.previous = self.debugger.machine().clone();
+                self.status = match self.debugger.step_back() {
+                    Stop::HistoryStart => "No more history".to_string(),
+                    _ => String::new(),
+                };
+                self.follow();
+            }
+            KeyCode::Char('b') => self.toggle_breakpoint(),
+            KeyCode::Up | KeyCode::Char('k') => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.cursor = (self.cursor + 1).min(self.debugger.source_len().saturating_sub(1))
+            }
+            KeyCode::PageUp => self.memory = self.memory.wrapping_sub(MEMORY_COLUMNS * 8),
+            KeyCode::PageDown => self.memory = self.memory.wrapping_add(MEMORY_COLUMNS * 8),
+            KeyCode::Char('m') => {
+                if let Some(address) = self.debugger.program().address_of_line(self.cursor) {
+                    self.memory = address;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn execute(&mut self, goal: Goal) {
+        if self.debugger.machine().is_halted() {
+            self.status = "Program halted".to_string();
+            return;
+        }
+        self.previous = self.debugger.machine().clone();
+        self.resume(goal);
+    }
+
+    fn resume(&mut self, goal: Goal) {
+        self.status = match self.debugger.resume(goal) {
+            Stop::Done => String::new(),
+            Stop::Breakpoint(id) => format!("Breakpoint {}", id),
+            Stop::Watchpoint(hits) => {
+                let ids: Vec<String> = hits.iter().map(|hit| hit.id.to_string()).collect();
+                format!("Watchpoint {}", ids.join(", "))
+            }
+            Stop::Halted => "Program halted".to_string(),
+            Stop::NeedsInput => {
+                self.input = Some((goal, String::new()));
+                "Input (Enter to send, Ctrl-D for end of input)".to_string()
+            }
+            Stop::Error(e) => format!("Error: {}", e),
+            Stop::HistoryStart => unreachable!("only returned when running backward"),
+        };
+        self.follow();
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        let address = match self.debugger.program().address_of_line(self.cursor) {
+            Some(address) => address,
+            None => {
+                self.status = "No code on this line".to_string();
+                return;
+            }
+        };
+        let existing = self
+            .debugger
+            .breakpoints()
+            .iter()
+            .find(|b| b.address == address)
+            .map(|b| b.id);
+        self.status = match existing {
+            Some(id) => {
+                self.debugger.delete_breakpoint(id);
+                format!("Deleted breakpoint {}", id)
+            }
+            None => {
+                let id = self.debugger.add_breakpoint(address);
+                format!("Breakpoint {} at {}", id, self.debugger.describe(address))
+            }
+        };
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [top, memory, bottom] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(10),
+            Constraint::Length(8),
+        ])
+        .areas(frame.area());
+        let [source, side] =
+            Layout::horizontal([Constraint::Min(30), Constraint::Length(28)]).areas(top);
+        let [registers, stack] =
+            Layout::vertical([Constraint::Length(14), Constraint::Min(3)]).areas(side);
+        let [output, status] =
+            Layout::vertical([Constraint::Min(2), Constraint::Length(1)]).areas(bottom);
+
+        self.draw_source(frame, source);
+        self.draw_registers(frame, registers);
+        self.draw_stack(frame, stack);
+        self.draw_memory(frame, memory);
+        self.draw_output(frame, output);
+        let status_line = match &self.input {
+            Some((_, line)) => format!("input> {}", line),
+            None => self.status.clone(),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+
+    fn draw_source(&self, frame: &mut Frame, area: Rect) {
+        let machine = self.debugger.machine();
+        let program = self.debugger.program();
+        let current = if machine.is_halted() {
+            None
+        } else {
+            program.line_of(machine.pr())
+        };
+        let title = match current {
+            Some(_) => {
+                let pr = machine.pr();
+                let words = [
+                    machine.cell(Cell::Memory(pr)),
+                    machine.cell(Cell::Memory(pr.wrapping_add(1))),
+                ];
+                format!(
+                    " {}: {} ",
+                    self.debugger.describe(pr),
+                    disassemble(&words).0
+                )
+            }
+            None => " Source ".to_string(),
+        };
+
+        // カーソル行が見える位置から表示する
+        let height = area.height.saturating_sub(2) as usize;
+        let first = self.cursor.saturating_sub(height / 2);
+        let lines = (first..self.debugger.source_len())
+            .take(height)
+            .map(|i| {
+                let address = program.address_of_line(i);
+                let breakpoint = address.is_some_and(|address| {
+                    self.debugger
+                        .breakpoints()
+                        .iter()
+                        .any(|b| b.address == address)
+                });
+                let mut style = Style::default();
+                if Some(i) == current {
+                    style = style.bg(Color::Blue).fg(Color::White);
+                }
+                if i == self.cursor {
+                    style = style.add_modifier(Modifier::UNDERLINED);
+                }
+                Line::from(vec![
+                    Span::styled(
+                        if breakpoint { "●" } else { " " },
+                        Style::default().fg(Color::Red),
+                    ),
+                    Span::styled(
+                        format!(
+                            "{} {:>5}  {}",
+                            if Some(i) == current { ">" } else { " " },
+                            address.map_or(String::new(), |a| format!("#{:04X}", a)),
+                            self.debugger.source_line(i).unwrap_or("")
+                        ),
+                        style,
+                    ),
+                ])
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_registers(&self, frame: &mut Frame, area: Rect) {
+        let machine = self.debugger.machine();
+        let previous = &self.previous;
+        let row = |name: String, value: u16, old: u16| {
+            let style = if value != old {
+                changed()
+            } else {
+                Style::default()
+            };
+            Line::styled(
+                format!("{:<4} #{:04X} {:>6}", name, value, value as i16),
+                style,
+            )
+        };
+        let mut lines: Vec<Line> = machine
+            .gr()
+            .values()
+            .iter()
+            .zip(previous.gr().values().iter())
+            .enumerate()
+            .map(|(i, (&value, &old))| row(format!("GR{}", i), value, old))
+            .collect();
+        lines.push(row("SP".to_string(), machine.sp(), previous.sp()));
+        lines.push(row("PR".to_string(), machine.pr(), previous.pr()));
+        let flags = [
+            ("OF", machine.of(), previous.of()),
+            ("SF", machine.sf(), previous.sf()),
+            ("ZF", machine.zf(), previous.zf()),
+        ];
+        let mut spans = vec![Span::raw("FR  ")];
+        for (name, value, old) in flags {
+            let style = if value != old {
+                changed()
+            } else {
+                Style::default()
+            };
+            spans.push(Span::styled(format!(" {}={}", name, value as u8), style));
+        }
+        lines.push(Line::from(spans));
+        if machine.is_halted() {
+            lines.push(Line::styled("halted", Style::default().fg(Color::Red)));
+        }
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Registers ")),
+            area,
+        );
+    }
+
+    fn draw_stack(&self, frame: &mut Frame, area: Rect) {
+        let machine = self.debugger.machine();
+        let lines: Vec<Line> = (machine.sp() as usize..STACK_SIZE)
+            .map(|address| {
+                let value = machine.cell(Cell::Memory(address as u16));
+                let label = self.debugger.program().describe(value).unwrap_or_default();
+                Line::raw(format!("#{:04X} #{:04X} {}", address, value, label))
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Stack ")),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let machine = self.debugger.machine();
+        let rows = area.height.saturating_sub(2);
+        let lines: Vec<Line> = (0..rows)
+            .map(|row| {
+                let start = self.memory.wrapping_add(row * MEMORY_COLUMNS);
+                let mut spans = vec![Span::raw(format!("#{:04X} ", start))];
+                for column in 0..MEMORY_COLUMNS {
+                    let address = start.wrapping_add(column);
+                    let value = machine.cell(Cell::Memory(address));
+                    let mut style = Style::default();
+                    if value != self.previous.cell(Cell::Memory(address)) {
+                        style = changed();
+                    }
+                    if address == machine.pr() {
+                        style = style.add_modifier(Modifier::REVERSED);
+                    }
+                    spans.push(Span::raw(" "));
+                    spans.push(Span::styled(format!("{:04X}", value), style));
+                }
+                Line::from(spans)
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Memory ")),
+            area,
+        );
+    }
+
+    fn draw_output(&self, frame: &mut Frame, area: Rect) {
+        let output = self.debugger.machine().output();
+        let height = area.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = output[output.len().saturating_sub(height)..]
+            .iter()
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Output ")),
+            area,
+        );
+    }
+}
+
+/// 端末を全画面にして `q` が押されるまで動かす
+pub fn run(debugger: Debugger) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut app = App::new(debugger);
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| app.draw(frame)) {
+            break Err(e);
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => app.handle(key),
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+        if app.should_quit() {
+            break Ok(());
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    const ECHO: &str = "MAIN  START
+      IN    BUF,LEN
+      OUT   BUF,LEN
+      RET
+BUF   DS    4
+LEN   DS    1
+      END";
+
+    fn app() -> App {
+        let program = casl::assemble(ECHO).unwrap();
+        App::new(Debugger::new(program, ECHO))
+    }
+
+    fn press(app: &mut App, code: KeyCode) {
+        app.handle(KeyEvent::new(code, KeyModifiers::NONE));
+    }
+
+    /// 画面を文字だけにする
+    fn screen(app: &App) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(90, 40)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer
+            .content
+            .chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn input_and_output() {
+        let mut app = app();
+        assert!(screen(&app).contains("> #0100        IN    BUF,LEN"));
+        press(&mut app, KeyCode::Char('c'));
+        assert!(screen(&app).contains("input> "));
+        for c in "hi".chars() {
+            press(&mut app, KeyCode::Char(c));
+        }
+        press(&mut app, KeyCode::Enter);
+        let screen = screen(&app);
+        assert!(screen.contains("│hi "));
+        assert!(screen.contains("Program halted"));
+        assert!(app.debugger().machine().is_halted());
+    }
+
+    #[test]
+    fn breakpoint_at_cursor() {
+        let mut app = app();
+        press(&mut app, KeyCode::Down);
+        press(&mut app, KeyCode::Down);
+        press(&mut app, KeyCode::Char('b'));
+        assert!(screen(&app).contains("●  #0118        RET"));
+        app.debugger.close_input();
+        press(&mut app, KeyCode::Char('c'));
+        assert_eq!(app.debugger().machine().pr(), 0x118);
+        assert_eq!(app.status, "Breakpoint 1");
+        press(&mut app, KeyCode::Char('b'));
+        assert!(app.debugger().breakpoints().is_empty());
+    }
+
+    #[test]
+    fn stack_after_push() {
+        let mut app = app();
+        press(&mut app, KeyCode::Char('s'));
+        let screen = screen(&app);
+        assert!(screen.contains("SP   #00FF    255"));
+        assert!(screen.contains("#00FF #0000"));
+        assert!(screen.contains("(MAIN+2): PUSH  #0000,GR2"));
+        assert_eq!(app.previous.sp(), 0x100);
+    }
+}
@@ -0,0 +1,99 @@
+//! ウォッチポイント。命令が読み書きした場所 (`Access`) と突き合わせる
+
+use crate::core::access::{Access, AccessKind, Cell};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    /// 読んだら止まる
+    Read,
+    /// 書いたら止まる。同じ値を書いても止まる
+    Write,
+    /// 書いて値が変わったら止まる
+    Change,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub id: usize,
+    /// メモリなら範囲の先頭
+    pub cell: Cell,
+    /// メモリの語数。レジスタやフラグなら1
+    pub len: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn covers(&self, cell: Cell) -> bool {
+        match (self.cell, cell) {
+            (Cell::Memory(start), Cell::Memory(address)) => address.wrapping_sub(start) < self.len,
+            (watched, cell) => watched == cell,
+        }
+    }
+
+    fn triggered_by(&self, access: &Access) -> bool {
+        let kind = match self.kind {
+            WatchKind::Read => access.kind == AccessKind::Read,
+            WatchKind::Write => access.kind == AccessKind::Write,
+            WatchKind::Change => access.kind == AccessKind::Write && access.old != access.new,
+        };
+        kind && self.covers(access.cell)
+    }
+}
+
+/// ウォッチポイントに引っかかった読み書き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+    pub id: usize,
+    /// 読み書きした命令の番地
+    pub pr: u16,
+    pub access: Access,
+}
+
+/// `pr` の命令が行った読み書き `log` のうち、ウォッチポイントに引っかかったもの
+pub fn hits(watchpoints: &[Watchpoint], pr: u16, log: &[Access]) -> Vec<Hit> {
+    log.iter()
+        .flat_map(|access| {
+            watchpoints
+                .iter()
+                .filter(move |w| w.triggered_by(access))
+                .map(move |w| Hit {
+                    id: w.id,
+                    pr,
+                    access: *access,
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::operations::RegisterNumber;
+
+    #[test]
+    fn kinds_and_ranges() {
+        let watch = |id, cell, len, kind| Watchpoint {
+            id,
+            cell,
+            len,
+            kind,
+        };
+        let watchpoints = [
+            watch(1, Cell::Memory(0x110), 4, WatchKind::Read),
+            watch(2, Cell::Memory(0x110), 4, WatchKind::Change),
+            watch(3, Cell::Register(RegisterNumber(1)), 1, WatchKind::Write),
+        ];
+        let log = [
+            Access::read(Cell::Memory(0x113), 7),
+            Access::read(Cell::Memory(0x114), 7),
+            Access::write(Cell::Memory(0x110), 3, 3),
+            Access::write(Cell::Register(RegisterNumber(1)), 3, 3),
+            Access::write(Cell::Memory(0x111), 0, 1),
+        ];
+        let ids: Vec<usize> = hits(&watchpoints, 0x100, &log)
+            .iter()
+            .map(|h| h.id)
+            .collect();
+        assert_eq!(ids, vec![1, 3, 2]);
+    }
+}
@@ -0,0 +1,330 @@
+//! C から使うAPI。宣言の `include/fers.h` は `build.rs` がここから cbindgen で作る
+//!
+//! `FersMachine` は中身を見せないハンドルで、`fers_load` で作って `fers_free` で捨てる。
+//! 1つのハンドルを複数のスレッドから同時に使ってはいけない
+
+use crate::casl;
+use crate::core::access::Cell;
+use crate::core::console::LINE_MAX;
+use crate::core::operations::RegisterNumber;
+use crate::core::register::Flag;
+use crate::core::runner::{Io, Runner, Stop};
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+/// INで1行読む。`buffer` に書いたバイト数 (UTF-8) か、入力の終わりなら負の数を返す
+pub type InputFn =
+    Option<unsafe extern "C" fn(user: *mut c_void, buffer: *mut c_char, capacity: usize) -> c_int>;
+/// OUTの1行。`line` はNUL終端で、`len` はNULを含まないバイト数
+pub type OutputFn =
+    Option<unsafe extern "C" fn(user: *mut c_void, line: *const c_char, len: usize)>;
+
+pub const FERS_OK: c_int = 0;
+pub const FERS_ERROR: c_int = -1;
+
+/// `fers_run` の結果
+pub const FERS_HALTED: c_int = 0;
+pub const FERS_BREAKPOINT: c_int = 1;
+pub const FERS_LIMIT: c_int = 2;
+
+/// `fers_get_register` などのレジスタ番号。0~7はGR0~GR7
+pub const FERS_SP: c_int = 8;
+pub const FERS_PR: c_int = 9;
+pub const FERS_OF: c_int = 10;
+pub const FERS_SF: c_int = 11;
+pub const FERS_ZF: c_int = 12;
+
+pub struct FersMachine {
+    runner: Runner,
+    input: (InputFn, *mut c_void),
+    output: (OutputFn, *mut c_void),
+    /// 最後のエラー。`fers_last_error` で返す
+    error: CString,
+}
+
+struct Callbacks {
+    input: (InputFn, *mut c_void),
+    output: (OutputFn, *mut c_void),
+}
+
+impl Io for Callbacks {
+    fn read_line(&mut self) -> Option<String> {
+        let (read, user) = self.input;
+        let read = read?;
+        // 全角文字でもLINE_MAX文字入るように
+        let mut buffer = vec![0u8; LINE_MAX * 4];
+        let len = unsafe { read(user, buffer.as_mut_ptr() as *mut c_char, buffer.len()) };
+        if len < 0 {
+            return None;
+        }
+        buffer.truncate((len as usize).min(LINE_MAX * 4));
+        Some(String::from_utf8_lossy(&buffer).into_owned())
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let (Some(write), user) = self.output {
+            let line = CString::new(line.replace('\0', "")).expect("NUL is removed");
+            unsafe { write(user, line.as_ptr(), line.as_bytes().len()) };
+        }
+    }
+}
+
+/// 書き込める長さに切り詰めて、NUL終端で `buffer` に書く
+unsafe fn write_message(message: &str, buffer: *mut c_char, capacity: usize) {
+    if buffer.is_null() || capacity == 0 {
+        return;
+    }
+    let len = message.len().min(capacity - 1);
+    ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buffer, len);
+    *buffer.add(len) = 0;
+}
+
+fn cell(register: c_int) -> Option<Cell> {
+    Some(match register {
+        0..=7 => Cell::Register(RegisterNumber(register as u8)),
+        FERS_SP => Cell::Sp,
+        FERS_OF => Cell::Flag(Flag::Overflow),
+        FERS_SF => Cell::Flag(Flag::Sign),
+        FERS_ZF => Cell::Flag(Flag::Zero),
+        _ => return None,
+    })
+}
+
+/// ソースをアセンブルして読み込む。失敗したら `NULL` を返し、`error` にメッセージを書く
+///
+/// # Safety
+///
+/// `source` はNUL終端のUTF-8、`error` は `NULL` か `error_len` バイト書ける領域
+#[no_mangle]
+pub unsafe extern "C" fn fers_load(
+    source: *const c_char,
+    error: *mut c_char,
+    error_len: usize,
+) -> *mut FersMachine {
+    if source.is_null() {
+        write_message("source is NULL", error, error_len);
+        return ptr::null_mut();
+    }
+    let source = match CStr::from_ptr(source).to_str() {
+        Ok(source) => source,
+        Err(e) => {
+            write_message(&e.to_string(), error, error_len);
+            return ptr::null_mut();
+        }
+    };
+    match casl::assemble(source) {
+        Ok(program) => Box::into_raw(Box::new(FersMachine {
+            runner: Runner::new(program.load()),
+            input: (None, ptr::null_mut()),
+            output: (None, ptr::null_mut()),
+            error: CString::default(),
+        })),
+        Err(errors) => {
+            let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            write_message(&messages.join("\n"), error, error_len);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// # Safety
+///
+/// `machine` は `fers_load` が返したものか `NULL`。このあとは使わないこと
+#[no_mangle]
+pub unsafe extern "C" fn fers_free(machine: *mut FersMachine) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+/// INで入力が必要になるたびに `read` を呼ぶ。`read` が `NULL` なら入力はいつも終わり
+///
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの。`user` はそのまま `read` に渡す
+#[no_mangle]
+pub unsafe extern "C" fn fers_set_input(
+    machine: *mut FersMachine,
+    read: InputFn,
+    user: *mut c_void,
+) {
+    if let Some(machine) = machine.as_mut() {
+        machine.input = (read, user);
+    }
+}
+
+/// OUTの1行ごとに `write` を呼ぶ
+///
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの。`user` はそのまま `write` に渡す
+#[no_mangle]
+pub unsafe extern "C" fn fers_set_output(
+    machine: *mut FersMachine,
+    write: OutputFn,
+    user: *mut c_void,
+) {
+    if let Some(machine) = machine.as_mut() {
+        machine.output = (write, user);
+    }
+}
+
+impl FersMachine {
+    fn callbacks(&self) -> Callbacks {
+        Callbacks {
+            input: self.input,
+            output: self.output,
+        }
+    }
+
+    fn fail(&mut self, message: String) -> c_int {
+        self.error = CString::new(message.replace('\0', "")).expect("NUL is removed");
+        FERS_ERROR
+    }
+}
+
+/// 1命令実行する。`FERS_OK` か `FERS_ERROR`
+///
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_step(machine: *mut FersMachine) -> c_int {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return FERS_ERROR,
+    };
+    let mut io = machine.callbacks();
+    match machine.runner.step(&mut io) {
+        Ok(()) => FERS_OK,
+        Err(e) => machine.fail(e.to_string()),
+    }
+}
+
+/// 停止かブレークポイントまで最大 `max_steps` 命令実行する。
+/// `FERS_HALTED`, `FERS_BREAKPOINT`, `FERS_LIMIT` か `FERS_ERROR`
+///
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_run(machine: *mut FersMachine, max_steps: u64) -> c_int {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return FERS_ERROR,
+    };
+    let mut io = machine.callbacks();
+    match machine.runner.run(max_steps, &mut io) {
+        Ok(Stop::Halted) => FERS_HALTED,
+        Ok(Stop::Breakpoint) => FERS_BREAKPOINT,
+        Ok(Stop::StepLimit) => FERS_LIMIT,
+        Err(e) => machine.fail(e.to_string()),
+    }
+}
+
+/// 最後に `FERS_ERROR` を返したときのメッセージ。次に実行するまで有効
+///
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_last_error(machine: *const FersMachine) -> *const c_char {
+    match machine.as_ref() {
+        Some(machine) => machine.error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_halted(machine: *const FersMachine) -> bool {
+    machine
+        .as_ref()
+        .is_none_or(|machine| machine.runner.machine.is_halted())
+}
+
+/// レジスタかフラグの値。番号が正しくなければ0
+///
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_get_register(machine: *const FersMachine, register: c_int) -> u16 {
+    let machine = match machine.as_ref() {
+        Some(machine) => &machine.runner.machine,
+        None => return 0,
+    };
+    match register {
+        FERS_PR => machine.pr(),
+        _ => cell(register).map_or(0, |cell| machine.cell(cell)),
+    }
+}
+
+/// レジスタかフラグを書き換える。フラグは0以外で立つ。`FERS_OK` か `FERS_ERROR`
+///
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_set_register(
+    machine: *mut FersMachine,
+    register: c_int,
+    value: u16,
+) -> c_int {
+    let machine = match machine.as_mut() {
+        Some(machine) => &mut machine.runner.machine,
+        None => return FERS_ERROR,
+    };
+    *machine = match (register, cell(register)) {
+        (FERS_PR, _) => machine.with_pr(value),
+        (_, Some(cell)) => machine.with_cell(cell, value),
+        (_, None) => return FERS_ERROR,
+    };
+    FERS_OK
+}
+
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_read_memory(machine: *const FersMachine, address: u16) -> u16 {
+    machine.as_ref().map_or(0, |machine| {
+        machine.runner.machine.cell(Cell::Memory(address))
+    })
+}
+
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_write_memory(machine: *mut FersMachine, address: u16, value: u16) {
+    if let Some(machine) = machine.as_mut() {
+        let runner = &mut machine.runner;
+        runner.machine = runner.machine.with_cell(Cell::Memory(address), value);
+    }
+}
+
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_add_breakpoint(machine: *mut FersMachine, address: u16) {
+    if let Some(machine) = machine.as_mut() {
+        machine.runner.breakpoints.insert(address);
+    }
+}
+
+/// 消したら `true`
+///
+/// # Safety
+///
+/// `machine` は `fers_load` が返したもの
+#[no_mangle]
+pub unsafe extern "C" fn fers_remove_breakpoint(machine: *mut FersMachine, address: u16) -> bool {
+    machine
+        .as_mut()
+        .is_some_and(|machine| machine.runner.breakpoints.remove(&address))
+}
@@ -0,0 +1,325 @@
+//! 試験対策の自動採点。テストケースのファイルの入力で実行して、出力と停止時の値を比べる
+
+pub mod spec;
+
+pub use spec::{Case, Spec, SpecError};
+
+use crate::casl::Program;
+use crate::core::access::Cell;
+use crate::core::machine::Machine;
+use crate::core::policy::PolicyError;
+use crate::debugger::command::{Location, Place};
+use std::fmt;
+
+/// 1つのケースで期待と違ったところ
+#[derive(Debug)]
+pub enum Failure {
+    /// 停止する前にエラーになったか、制限に引っかかった
+    Error(PolicyError),
+    Output {
+        expected: Vec<String>,
+        actual: Vec<String>,
+    },
+    Value {
+        /// ファイルに書いた場所。配列の2つ目以降なら `BUF+1` の形
+        name: String,
+        expected: u16,
+        actual: u16,
+    },
+    UnknownLabel(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Error(e) => write!(f, "{}", e),
+            Failure::Output { expected, actual } => {
+                write!(f, "output differs (- expected, + actual):")?;
+                for line in diff(expected, actual) {
+                    write!(f, "\n    {}", line)?;
+                }
+                Ok(())
+            }
+            Failure::Value {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: expected #{:04X} ({}), got #{:04X} ({})",
+                name, expected, *expected as i16, actual, *actual as i16
+            ),
+            Failure::UnknownLabel(label) => write!(f, "No label {}", label),
+        }
+    }
+}
+
+/// 行単位の差分。共通の行は `  `、期待だけの行は `- `、実際だけの行は `+ ` を付ける
+fn diff(expected: &[String], actual: &[String]) -> Vec<String> {
+    // lcs[i][j] は expected[i..] と actual[j..] の最長共通部分列の長さ
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub name: String,
+    pub steps: u64,
+    pub failures: Vec<Failure>,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// ファイルに書いた場所と値を、1語ずつの (名前, 場所, 値) にする。配列の2つ目以降は `BUF+1` の形
+fn cells(
+    program: &Program,
+    entries: &[(String, Place, Vec<u16>)],
+) -> Result<Vec<(String, Cell, u16)>, Failure> {
+    let mut cells = Vec::new();
+    for (name, place, values) in entries {
+        let cell = match place {
+            Place::Memory(Location::Address(address)) => Cell::Memory(*address),
+            Place::Memory(Location::Label(label)) => match program.symbol(label) {
+                Some(symbol) => Cell::Memory(symbol.address),
+                None => return Err(Failure::UnknownLabel(label.clone())),
+            },
+            Place::Register(r) => Cell::Register(*r),
+            Place::Sp => Cell::Sp,
+            Place::Flag(flag) => Cell::Flag(*flag),
+        };
+        for (i, &value) in values.iter().enumerate() {
+            cells.push(match cell {
+                Cell::Memory(address) if i > 0 => (
+                    format!("{}+{}", name, i),
+                    Cell::Memory(address.wrapping_add(i as u16)),
+                    value,
+                ),
+                _ => (name.clone(), cell, value),
+            });
+        }
+    }
+    Ok(cells)
+}
+
+/// 実行する前の状態。`call` があればそのサブルーチンから始める
+fn prepare(program: &Program, case: &Case) -> Result<Machine, Failure> {
+    let machine = match &case.call {
+        Some(label) => program
+            .call(label)
+            .ok_or_else(|| Failure::UnknownLabel(label.clone()))?,
+        None => program.load(),
+    };
+    let machine = cells(program, &case.set)?
+        .into_iter()
+        .fold(machine, |machine, (_, cell, value)| {
+            machine.with_cell(cell, value)
+        });
+    Ok(machine.with_input(case.input.iter().cloned()))
+}
+
+/// `case` の入力で `program` を実行して採点する
+pub fn grade(program: &Program, case: &Case) -> Outcome {
+    let mut outcome = Outcome {
+        name: case.name.clone(),
+        steps: 0,
+        failures: Vec::new(),
+    };
+    let machine = match prepare(program, case) {
+        Ok(machine) => machine,
+        Err(failure) => {
+            outcome.failures.push(failure);
+            return outcome;
+        }
+    };
+    let run = machine.run_with_policy(&case.policy);
+    let machine = run.machine;
+    outcome.steps = run.steps;
+    let failures = &mut outcome.failures;
+    let halted = run.result.is_ok();
+    if let Err(e) = run.result {
+        failures.push(Failure::Error(e));
+    }
+    if let Some(expected) = &case.output {
+        if machine.output() != expected.as_slice() {
+            failures.push(Failure::Output {
+                expected: expected.clone(),
+                actual: machine.output().to_vec(),
+            });
+        }
+    }
+
+    // 停止しなかったなら値を比べても意味がない
+    if halted {
+        match cells(program, &case.expect) {
+            Ok(cells) => failures.extend(cells.into_iter().filter_map(|(name, cell, expected)| {
+                let actual = machine.cell(cell);
+                (actual != expected).then_some(Failure::Value {
+                    name,
+                    expected,
+                    actual,
+                })
+            })),
+            Err(failure) => failures.push(failure),
+        }
+    }
+    outcome
+}
+
+/// ケースごとに `PASS`/`FAIL` と違ったところ、最後に集計
+pub fn report(outcomes: &[Outcome]) -> String {
+    let mut text = String::new();
+    for outcome in outcomes {
+        let result = if outcome.passed() { "PASS" } else { "FAIL" };
+        text += &format!("{} {} ({} steps)\n", result, outcome.name, outcome.steps);
+        for failure in &outcome.failures {
+            text += &format!("  {}\n", failure);
+        }
+    }
+    let passed = outcomes.iter().filter(|o| o.passed()).count();
+    text += &format!(
+        "{} cases, {} passed, {} failed\n",
+        outcomes.len(),
+        passed,
+        outcomes.len() - passed
+    );
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+
+    /// 1行読んで、文字数をGR1に入れて、そのまま出力する
+    const ECHO: &str = "MAIN  START
+      IN    BUF,LEN
+      LD    GR1,LEN
+      OUT   BUF,LEN
+      RET
+BUF   DS    8
+LEN   DS    1
+      END";
+
+    #[test]
+    fn report() {
+        let program = casl::assemble(ECHO).unwrap();
+        let spec = Spec::parse(
+            r#"
+[[case]]
+name = "pass"
+input = ["abc"]
+output = ["abc"]
+[case.expect]
+GR1 = 3
+BUF = [97, 98, 99]
+
+[[case]]
+name = "fail"
+input = ["abd"]
+output = ["x", "abc"]
+[case.expect]
+GR1 = 2
+BUF = [97, 98, 99]
+
+[[case]]
+name = "limit"
+max_steps = 3
+"#,
+        )
+        .unwrap();
+        let outcomes: Vec<Outcome> = spec.cases.iter().map(|c| grade(&program, c)).collect();
+        assert_eq!(
+            super::report(&outcomes),
+            "PASS pass (16 steps)
+FAIL fail (16 steps)
+  output differs (- expected, + actual):
+    - x
+    - abc
+    + abd
+  BUF+2: expected #0063 (99), got #0064 (100)
+  GR1: expected #0002 (2), got #0003 (3)
+FAIL limit (3 steps)
+  Step limit of 3 exceeded
+3 cases, 1 passed, 2 failed
+"
+        );
+    }
+
+    #[test]
+    fn subroutine() {
+        // GR1の番地からGR2語の和をGR0に入れる
+        let program = casl::assemble(
+            "SUM   START
+      PUSH  0,GR1
+      LD    GR0,=0
+LOOP  LD    GR2,GR2
+      JZE   FIN
+      ADDA  GR0,0,GR1
+      LAD   GR1,1,GR1
+      LAD   GR2,-1,GR2
+      JUMP  LOOP
+FIN   POP   GR1
+      RET
+      END",
+        )
+        .unwrap();
+        let spec = Spec::parse(
+            r##"
+[[case]]
+call = "SUM"
+[case.set]
+GR1 = "#0200"
+GR2 = 3
+"#0200" = [1, 2, -4]
+[case.expect]
+GR0 = -1
+GR1 = "#0200"
+SP = 256
+
+[[case]]
+call = "NOPE"
+"##,
+        )
+        .unwrap();
+        let outcomes: Vec<Outcome> = spec.cases.iter().map(|c| grade(&program, c)).collect();
+        assert!(outcomes[0].passed(), "{}", super::report(&outcomes));
+        assert_eq!(outcomes[1].failures[0].to_string(), "No label NOPE");
+    }
+
+    #[test]
+    fn line_diff() {
+        let lines = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            diff(&lines("a b c d"), &lines("a c x d")),
+            ["  a", "- b", "  c", "+ x", "  d"]
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! テストケースのファイル (TOML)
+//!
+//! ```toml
+//! max_steps = 100000        # すべてのケースの既定。省略すると DEFAULT_MAX_STEPS
+//!
+//! [[case]]
+//! name = "two lines"
+//! input = ["abc", "de"]     # 読み切ったあとのINは入力の終わり
+//! output = ["abc", "de"]    # 省略すると比べない
+//! max_steps = 1000          # max_steps, max_output_bytes, time_limit_ms はケースごとに上書きできる
+//!
+//! [case.expect]             # 停止したときの値。場所はデバッガの `who` と同じ書き方
+//! GR1 = -1
+//! ZF = false
+//! LEN = "#FFFF"
+//! BUF = [97, 98, 99]        # 配列なら続く番地
+//! ```
+//!
+//! サブルーチンだけを試すときは `call` でラベルを指定する。対応するRETで停止する
+//!
+//! ```toml
+//! [[case]]
+//! call = "SUM"
+//!
+//! [case.set]                # 呼ぶ前に書き込む値。書き方は expect と同じ
+//! GR1 = "#0200"
+//! "#0200" = [1, 2, 3]
+//! GR2 = 3
+//!
+//! [case.expect]
+//! GR0 = 6
+//! ```
+
+use crate::core::policy::RunPolicy;
+use crate::debugger::command::{self, Place};
+use std::time::Duration;
+use toml::{Table, Value};
+
+/// 止まらないプログラムを打ち切るまでのステップ数の既定
+pub const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spec {
+    pub cases: Vec<Case>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    pub name: String,
+    pub input: Vec<String>,
+    /// `None` なら出力は比べない
+    pub output: Option<Vec<String>>,
+    /// 実行を始めるサブルーチン。`None` ならプログラムの先頭から
+    pub call: Option<String>,
+    /// 実行する前に書き込む値。書き方は `expect` と同じ
+    pub set: Vec<(String, Place, Vec<u16>)>,
+    /// (書いたときの文字列, 場所, 値)。名前の順。メモリなら値は続く番地の分だけある
+    pub expect: Vec<(String, Place, Vec<u16>)>,
+    pub policy: RunPolicy,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpecError {
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("{at}: {message}")]
+    Invalid { at: String, message: String },
+}
+
+fn invalid(at: &str, message: impl Into<String>) -> SpecError {
+    SpecError::Invalid {
+        at: at.to_string(),
+        message: message.into(),
+    }
+}
+
+/// 負の数は2の補数、文字列は `#FFFF` の形、真偽値は1か0
+fn word(value: &Value, at: &str) -> Result<u16, SpecError> {
+    match value {
+        Value::Integer(n) if (-32768..=65535).contains(n) => Ok(*n as u16),
+        Value::Boolean(b) => Ok(*b as u16),
+        Value::String(s) => s
+            .strip_prefix('#')
+            .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| invalid(at, format!("`{}` is not a word like #FFFF", s))),
+        _ => Err(invalid(at, "expected a word")),
+    }
+}
+
+fn lines(value: &Value, at: &str) -> Result<Vec<String>, SpecError> {
+    let strings = value.as_array().and_then(|array| {
+        array
+            .iter()
+            .map(|v| v.as_str().map(String::from))
+            .collect::<Option<Vec<_>>>()
+    });
+    strings.ok_or_else(|| invalid(at, "expected an array of strings"))
+}
+
+fn integer(value: &Value, at: &str) -> Result<u64, SpecError> {
+    match value.as_integer() {
+        Some(n) if n >= 0 => Ok(n as u64),
+        _ => Err(invalid(at, "expected a non-negative integer")),
+    }
+}
+
+/// 制限のキーなら `policy` に入れて `true` を返す
+fn limit(policy: &mut RunPolicy, key: &str, value: &Value, at: &str) -> Result<bool, SpecError> {
+    match key {
+        "max_steps" => policy.max_steps = Some(integer(value, at)?),
+        "max_output_bytes" => policy.max_output_bytes = Some(integer(value, at)? as usize),
+        "time_limit_ms" => policy.time_limit = Some(Duration::from_millis(integer(value, at)?)),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn values(table: &Value, at: &str) -> Result<Vec<(String, Place, Vec<u16>)>, SpecError> {
+    let table = table
+        .as_table()
+        .ok_or_else(|| invalid(at, "expected a table"))?;
+    table
+        .iter()
+        .map(|(key, value)| {
+            let at = format!("{}.{}", at, key);
+            let place = command::place(key).map_err(|e| invalid(&at, e.to_string()))?;
+            let values = match (value, &place) {
+                (Value::Array(values), Place::Memory(_)) => values
+                    .iter()
+                    .map(|v| word(v, &at))
+                    .collect::<Result<_, _>>()?,
+                (Value::Array(_), _) => return Err(invalid(&at, "only memory takes an array")),
+                (value, _) => vec![word(value, &at)?],
+            };
+            Ok((key.clone(), place, values))
+        })
+        .collect()
+}
+
+fn case(value: &Value, index: usize, defaults: &RunPolicy) -> Result<Case, SpecError> {
+    let at = format!("case {}", index + 1);
+    let table = value
+        .as_table()
+        .ok_or_else(|| invalid(&at, "expected a table"))?;
+    let mut case = Case {
+        name: at.clone(),
+        input: Vec::new(),
+        output: None,
+        call: None,
+        set: Vec::new(),
+        expect: Vec::new(),
+        policy: defaults.clone(),
+    };
+    for (key, value) in table {
+        let at = format!("{}.{}", at, key);
+        match key.as_str() {
+            "name" => {
+                case.name = value
+                    .as_str()
+                    .ok_or_else(|| invalid(&at, "expected a string"))?
+                    .to_string()
+            }
+            "input" => case.input = lines(value, &at)?,
+            "output" => case.output = Some(lines(value, &at)?),
+            "call" => {
+                let label = value
+                    .as_str()
+                    .ok_or_else(|| invalid(&at, "expected a label"))?;
+                case.call = Some(label.to_string())
+            }
+            "set" => case.set = values(value, &at)?,
+            "expect" => case.expect = values(value, &at)?,
+            _ if limit(&mut case.policy, key, value, &at)? => {}
+            _ => return Err(invalid(&at, "unknown key")),
+        }
+    }
+    Ok(case)
+}
+
+impl Spec {
+    pub fn parse(text: &str) -> Result<Spec, SpecError> {
+        let table: Table = text.parse()?;
+        let mut defaults = RunPolicy {
+            max_steps: Some(DEFAULT_MAX_STEPS),
+            ..RunPolicy::default()
+        };
+        let mut cases = &Vec::new();
+        for (key, value) in &table {
+            match key.as_str() {
+                "case" => {
+                    cases = value
+                        .as_array()
+                        .ok_or_else(|| invalid(key, "expected [[case]] tables"))?
+                }
+                _ if limit(&mut defaults, key, value, key)? => {}
+                _ => return Err(invalid(key, "unknown key")),
+            }
+        }
+        let cases = cases
+            .iter()
+            .enumerate()
+            .map(|(i, value)| case(value, i, &defaults))
+            .collect::<Result<Vec<_>, _>>()?;
+        if cases.is_empty() {
+            return Err(invalid("case", "no [[case]] in the file"));
+        }
+        Ok(Spec { cases })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::operations::RegisterNumber;
+    use crate::debugger::command::Location;
+
+    #[test]
+    fn parse() {
+        let spec = Spec::parse(
+            r##"
+max_steps = 500
+
+[[case]]
+input = ["a"]
+output = ["a"]
+
+[[case]]
+name = "empty"
+max_steps = 10
+[case.expect]
+GR1 = -1
+ZF = true
+BUF = [1, "#FFFF"]
+"##,
+        )
+        .unwrap();
+        assert_eq!(spec.cases[0].name, "case 1");
+        assert_eq!(spec.cases[0].policy.max_steps, Some(500));
+        assert_eq!(spec.cases[0].output, Some(vec!["a".to_string()]));
+        let case = &spec.cases[1];
+        assert_eq!(case.policy.max_steps, Some(10));
+        assert_eq!(case.output, None);
+        assert_eq!(case.call, None);
+        assert_eq!(
+            case.expect,
+            [
+                (
+                    "BUF".to_string(),
+                    Place::Memory(Location::Label("BUF".to_string())),
+                    vec![1, 0xFFFF]
+                ),
+                (
+                    "GR1".to_string(),
+                    Place::Register(RegisterNumber(1)),
+                    vec![0xFFFF]
+                ),
+                (
+                    "ZF".to_string(),
+                    Place::Flag(crate::core::register::Flag::Zero),
+                    vec![1]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| Spec::parse(text).unwrap_err().to_string();
+        assert_eq!(error("[[case]]\nouput = []"), "case 1.ouput: unknown key");
+        assert_eq!(
+            error("[[case]]\n[case.expect]\nGR1 = [1]"),
+            "case 1.expect.GR1: only memory takes an array"
+        );
+        assert_eq!(
+            error("[[case]]\n[case.expect]\nGR1 = 70000"),
+            "case 1.expect.GR1: expected a word"
+        );
+        assert_eq!(error("max_steps = 1"), "case: no [[case]] in the file");
+    }
+}
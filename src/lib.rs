@@ -1,139 +1,68 @@
-// pub mod operations;
-// use itertools::{self, Itertools};
-// use operations::TwoLengthOperation;
-// mod interpreter;
-// mod memory;
-// mod parser;
-
-use std::{error, fmt, io};
-
-trait Register {
-    fn isIndexRegister(&self) -> bool;
-}
-
-struct GeneralRegister([i16; 8]);
-
-struct StackPointer(u16);
-
-struct ProgramRegister(u16);
-
-struct IndexRegister {
-    value: i16,
-}
-
-struct FlagRegister(i16, i16, i16);
-
-impl Register for IndexRegister {
-    fn isIndexRegister(&self) -> bool {
-        true
-    }
-}
-
-// pub struct Machine {
-//     pub mem: Memory,
-//     gr: [i16; 8],
-//     sp: i16,
-//     pr: i16,
-//     of: bool,
-//     sf: bool,
-//     zf: bool,
-//     second_word: Option<Box<dyn TwoLengthOperation>>,
-// }
-
-#[derive(Debug)]
-struct UnknownOperationError(u16);
-
-impl fmt::Display for UnknownOperationError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Unknown operation: {}", self.0)
-    }
-}
-
-impl error::Error for UnknownOperationError {}
-
-// #[derive(Debug)]
-// pub enum MachineStepError {
-//     UnknownOperation(u16),
-//     MemoryOutOfIndex(usize),
-// }
-
-// impl fmt::Display for MachineStepError {
-//     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-//         use MachineStepError::*;
-//         match self {
-//             MemoryOutOfIndex(n) => write!(f, "Memory out of index: {}", n),
-//             UnknownOperation(n) => write!(f, "Unknown operation: {}", n),
-//         }
-//     }
-// }
-// impl error::Error for MachineStepError {}
-
-// #[derive(Debug)]
-// pub enum MachineInitError {
-//     IOError(io::Error),
-//     OutOfMemory(usize),
-// }
-
-// impl fmt::Display for MachineInitError {
-//     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-//         use MachineInitError::*;
-//         match self {
-//             IOError(e) => e.fmt(f),
-//             OutOfMemory(size) => write!(f, "Out of memory: {}", size),
-//         }
-//     }
-// }
-
-// impl error::Error for MachineInitError {}
-
-// impl Machine {
-//     pub fn init(stream: &mut impl io::Read) -> Result<Machine, MachineInitError> {
-//         let mem = Memory::load_program(stream)
-//             .map_err(|MemoryInitError(e)| MachineInitError::IOError(e))?;
-//         Ok(Machine {
-//             mem,
-//             gr: [0; 8],
-//             sp: 0,
-//             pr: 0,
-//             of: false,
-//             sf: false,
-//             zf: false,
-
-//             second_word: None,
-//         })
-//     }
-//     /// ワードを読んで命令を実行する
-//     fn exec(self, word: u16) -> Result<Machine, UnknownOperationError> {
-//         match word & 0x1100 {
-//             0 => Ok(Machine { ..self }), // NOP
-//             0x1000 => Ok(Machine { ..self }),
-//             e => Err(UnknownOperationError(e)),
-//         }
-//     }
-
-//     fn is_register_valid(num: u16) {}
-
-//     pub fn mem_info(&self) -> String {
-//         let Memory(mem) = self.mem;
-
-//         format!("bytes: {:X?}, length: {}", mem, mem.len())
-//     }
-
-//     /// PRが現在指示しているメモリの番地
-//     pub fn pr_at(&self) -> String {
-//         format!("{:X}", self.mem.get(self.pr as usize).unwrap() as u16)
-//     }
-
-//     pub fn clock(self) -> Result<Machine, MachineStepError> {
-//         use MachineStepError::*;
-//         let word = self.mem.get(self.pr as usize)?;
-//         let machine = self
-//             .exec(word as u16)
-//             .map_err(|UnknownOperationError(o)| UnknownOperation(o))?;
-
-//         Ok(Machine {
-//             pr: machine.pr + 16,
-//             ..machine
-//         })
-//     }
-// }
+//! CASL2 のアセンブラと COMET II のエミュレータ
+//!
+//! よく使うものはトップレベルに置いてある。`Machine` は値で、実行するたびに新しい `Machine` を返す。
+//! メモリと入出力は `Rc` で共有しているので、古い状態を取っておくのも安い
+//!
+//! ```
+//! use fers::{assemble, Event};
+//!
+//! let program = assemble("MAIN  START
+//!       OUT   MSG,LEN
+//!       RET
+//! MSG   DC    'Hello'
+//! LEN   DC    5
+//!       END").unwrap();
+//! let mut machine = program.load();
+//! let mut output = Vec::new();
+//! while !machine.is_halted() {
+//!     let (next, events) = machine.step_events().unwrap();
+//!     for event in events {
+//!         if let Event::Output(line) = event {
+//!             output.push(line);
+//!         }
+//!     }
+//!     machine = next;
+//! }
+//! assert_eq!(output, ["Hello"]);
+//! ```
+
+// 下のモジュールは `fers` コマンドとテストから使うために見せているだけで、
+// 使ってよいのは最後の re-export だけ
+#[doc(hidden)]
+pub mod casl;
+#[doc(hidden)]
+pub mod core;
+#[doc(hidden)]
+pub mod debugger;
+mod ffi;
+#[doc(hidden)]
+pub mod grade;
+#[doc(hidden)]
+pub mod lsp;
+#[doc(hidden)]
+pub mod profile;
+#[doc(hidden)]
+pub mod repl;
+#[doc(hidden)]
+pub mod trace;
+mod utils;
+#[cfg(feature = "wasm")]
+#[doc(hidden)]
+pub mod wasm;
+
+pub use crate::casl::assembler::Symbol;
+pub use crate::casl::ast::Span;
+pub use crate::casl::error::ErrorKind as AssembleErrorKind;
+pub use crate::casl::object::ObjectError;
+pub use crate::casl::{assemble, Error as AssembleError, Program};
+pub use crate::core::access::{Access, AccessKind, Cell};
+pub use crate::core::event::Event;
+pub use crate::core::machine::{ExecError, Machine, StepError};
+pub use crate::core::memory::GetError as MemoryError;
+pub use crate::core::observer::MachineObserver;
+pub use crate::core::operations::{NewError as DecodeError, RegisterNumber, RegisterOutOfIndex};
+pub use crate::core::policy::{PolicyError, Run, RunPolicy};
+pub use crate::core::protection::{Protection, ProtectionMode, Violation, WordKind};
+pub use crate::core::register::{Flag, GeneralRegister};
+pub use crate::core::shadow::Shadow;
+pub use crate::core::snapshot::SnapshotError;
@@ -0,0 +1,422 @@
+//! エディタに見せる情報をソースから求める。位置はすべて0始まりの行と文字数
+
+use crate::casl::ast::{Opecode, Source, Span, Value};
+use crate::casl::{self, parser, Error, Program};
+use crate::core::operations::{FlagEffect, Operation1, Operation2};
+
+/// START~ENDの1つのプログラム
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unit {
+    pub name: String,
+    /// STARTのラベル
+    pub span: Span,
+    /// STARTの行からENDの行まで (ENDがなければ最後の行まで)
+    pub first_line: usize,
+    pub last_line: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelKind {
+    Unit,
+    Code,
+    /// DS
+    Area,
+    /// DC
+    Constant,
+}
+
+/// ラベルの定義
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    /// 属するプログラムの名前。START より前なら空
+    pub unit: String,
+    pub span: Span,
+    pub kind: LabelKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionKind {
+    Mnemonic,
+    Register,
+    Label,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    pub detail: String,
+}
+
+pub struct Analysis {
+    source: Source,
+    errors: Vec<Error>,
+    program: Option<Program>,
+    units: Vec<Unit>,
+    definitions: Vec<Definition>,
+}
+
+impl Analysis {
+    pub fn new(text: &str) -> Analysis {
+        let (source, _) = parser::parse(text);
+        let (program, errors) = match casl::assemble(text) {
+            Ok(program) => (Some(program), Vec::new()),
+            Err(errors) => (None, errors),
+        };
+
+        let mut units: Vec<Unit> = Vec::new();
+        let mut definitions = Vec::new();
+        let mut unit = String::new();
+        for line in &source.lines {
+            let opecode = line.statement.as_ref().map(|s| &s.opecode);
+            if let (Some(Opecode::Start), Some(label)) = (opecode, &line.label) {
+                unit = label.name.clone();
+                units.push(Unit {
+                    name: unit.clone(),
+                    span: label.span,
+                    first_line: line.number,
+                    last_line: usize::MAX,
+                });
+            }
+            if let Some(label) = &line.label {
+                let kind = match opecode {
+                    Some(Opecode::Start) => LabelKind::Unit,
+                    Some(Opecode::Ds) => LabelKind::Area,
+                    Some(Opecode::Dc) => LabelKind::Constant,
+                    _ => LabelKind::Code,
+                };
+                definitions.push(Definition {
+                    name: label.name.clone(),
+                    unit: unit.clone(),
+                    span: label.span,
+                    kind,
+                });
+            }
+            if let Some(Opecode::End) = opecode {
+                if let Some(u) = units.last_mut().filter(|u| u.last_line == usize::MAX) {
+                    u.last_line = line.number;
+                }
+                unit = String::new();
+            }
+        }
+        let last = text.lines().count().saturating_sub(1);
+        for u in units.iter_mut().filter(|u| u.last_line == usize::MAX) {
+            u.last_line = last;
+        }
+
+        Analysis {
+            source,
+            errors,
+            program,
+            units,
+            definitions,
+        }
+    }
+
+    /// 構文エラーとアセンブルのエラー
+    pub fn diagnostics(&self) -> &[Error] {
+        &self.errors
+    }
+
+    pub fn units(&self) -> &[Unit] {
+        &self.units
+    }
+
+    /// `unit` の中のラベル (STARTのラベルを除く)
+    pub fn labels_of<'a>(&'a self, unit: &'a str) -> impl Iterator<Item = &'a Definition> + 'a {
+        self.definitions
+            .iter()
+            .filter(move |d| d.unit == unit && d.kind != LabelKind::Unit)
+    }
+
+    fn unit_at(&self, line: usize) -> &str {
+        self.units
+            .iter()
+            .find(|u| u.first_line <= line && line <= u.last_line)
+            .map_or("", |u| u.name.as_str())
+    }
+
+    /// 同じプログラムのラベル、なければ他のプログラムのSTARTのラベル
+    fn resolve(&self, name: &str, unit: &str) -> Option<&Definition> {
+        self.definitions
+            .iter()
+            .find(|d| d.unit == unit && d.name == name)
+            .or_else(|| {
+                self.definitions
+                    .iter()
+                    .find(|d| d.kind == LabelKind::Unit && d.name == name)
+            })
+    }
+
+    /// ラベルの定義と参照をすべて (名前, 場所, 行の属するプログラム) で
+    fn occurrences(&self) -> impl Iterator<Item = (&str, Span, &str)> + '_ {
+        self.source.lines.iter().flat_map(move |line| {
+            let unit = self.unit_at(line.number);
+            let label = line.label.iter().map(|l| (l.name.as_str(), l.span));
+            let operands = line.statement.iter().flat_map(|s| {
+                s.operands.iter().filter_map(|o| match &o.value {
+                    Value::Label(name) => Some((name.as_str(), o.span)),
+                    _ => None,
+                })
+            });
+            label
+                .chain(operands)
+                .map(move |(name, span)| (name, span, unit))
+        })
+    }
+
+    /// カーソル位置のラベルが指す定義
+    pub fn definition(&self, line: usize, column: usize) -> Option<&Definition> {
+        let (name, _, unit) = self
+            .occurrences()
+            .find(|(_, span, _)| span.contains(line, column))?;
+        self.resolve(name, unit)
+    }
+
+    /// カーソル位置のラベルと同じ定義を指す場所すべて
+    pub fn references(&self, line: usize, column: usize, declaration: bool) -> Vec<Span> {
+        let target = match self.definition(line, column) {
+            Some(d) => d,
+            None => return Vec::new(),
+        };
+        self.occurrences()
+            .filter(|&(name, span, unit)| {
+                (declaration || span != target.span) && self.resolve(name, unit) == Some(target)
+            })
+            .map(|(_, span, _)| span)
+            .collect()
+    }
+
+    /// カーソル位置の命令かラベルの説明 (Markdown) とその範囲
+    pub fn hover(&self, line: usize, column: usize) -> Option<(String, Span)> {
+        let source_line = self.source.lines.iter().find(|l| l.number == line)?;
+        if let Some(statement) = &source_line.statement {
+            if statement.opecode_span.contains(line, column) {
+                return Some((describe_opecode(&statement.opecode), statement.opecode_span));
+            }
+        }
+        let definition = self.definition(line, column)?;
+        let (_, span, _) = self
+            .occurrences()
+            .find(|(_, span, _)| span.contains(line, column))?;
+        let address = self.program.as_ref().and_then(|p| {
+            p.symbols
+                .iter()
+                .find(|s| s.span == definition.span)
+                .map(|s| s.address)
+        });
+        let mut text = format!("**{}**", definition.name);
+        if !definition.unit.is_empty() && definition.kind != LabelKind::Unit {
+            text.push_str(&format!(" in {}", definition.unit));
+        }
+        if let Some(address) = address {
+            text.push_str(&format!(" = `#{:04X}`", address));
+        }
+        Some((text, span))
+    }
+
+    /// `prefix` はカーソルより前のその行の文字列
+    pub fn completions(&self, line: usize, prefix: &str) -> Vec<Completion> {
+        if !in_operands(prefix) {
+            return mnemonics();
+        }
+        let unit = self.unit_at(line);
+        let registers = (0..8).map(|n| Completion {
+            label: format!("GR{}", n),
+            kind: CompletionKind::Register,
+            detail: "general register".to_string(),
+        });
+        let labels = self
+            .definitions
+            .iter()
+            .filter(|d| d.unit == unit || d.kind == LabelKind::Unit)
+            .map(|d| Completion {
+                label: d.name.clone(),
+                kind: CompletionKind::Label,
+                detail: format!("label (line {})", d.span.line + 1),
+            });
+        registers.chain(labels).collect()
+    }
+}
+
+/// オペコードより後ろにいるか
+fn in_operands(prefix: &str) -> bool {
+    let fields = prefix.split_whitespace().count();
+    let ends_with_space = prefix.ends_with(char::is_whitespace);
+    // 行頭が空白ならラベルがない
+    let opecode_field = if prefix.starts_with(char::is_whitespace) {
+        1
+    } else {
+        2
+    };
+    fields > opecode_field || (fields == opecode_field && ends_with_space)
+}
+
+fn mnemonics() -> Vec<Completion> {
+    let mut items: Vec<Completion> = Vec::new();
+    let machine = Operation2::ALL
+        .iter()
+        .map(|o| o.mnemonic())
+        .chain(Operation1::ALL.iter().map(|o| o.mnemonic()));
+    for name in machine {
+        if items.iter().all(|i| i.label != name) {
+            items.push(Completion {
+                label: name.to_string(),
+                kind: CompletionKind::Mnemonic,
+                detail: "instruction".to_string(),
+            });
+        }
+    }
+    items.extend(Opecode::DIRECTIVES.iter().map(|(name, _)| Completion {
+        label: name.to_string(),
+        kind: CompletionKind::Mnemonic,
+        detail: "assembler instruction / macro".to_string(),
+    }));
+    items
+}
+
+fn flag_text(effect: Option<FlagEffect>) -> &'static str {
+    match effect {
+        Some(FlagEffect::Set) => "OF, SF, ZF are set by the result",
+        Some(FlagEffect::OverflowCleared) => "OF is cleared, SF and ZF are set by the result",
+        None => "flags are unchanged",
+    }
+}
+
+/// 命令の形式、機械語、フラグの変化
+fn describe_opecode(opecode: &Opecode) -> String {
+    let name = opecode.name();
+    let directive = match opecode {
+        Opecode::Start => Some("START [entry]: beginning of a program. The label is required"),
+        Opecode::End => Some("END: end of a program. Literals are placed here"),
+        Opecode::Ds => Some("DS n: reserve n words"),
+        Opecode::Dc => Some("DC const[,const...]: define constants (decimal, #hex, 'text' or label)"),
+        Opecode::In => Some("IN buf,len: read a line into buf and its length into len (SVC 1). Registers are preserved"),
+        Opecode::Out => Some("OUT buf,len: write len characters from buf as a line (SVC 2). Registers are preserved"),
+        Opecode::Rpush => Some("RPUSH: push GR1~GR7"),
+        Opecode::Rpop => Some("RPOP: pop GR7~GR1"),
+        Opecode::Machine(_) => None,
+    };
+    if let Some(text) = directive {
+        return format!("**{}** (macro / assembler instruction)\n\n{}", name, text);
+    }
+
+    let mut forms = Vec::new();
+    if let Some(o) = Operation2::from_mnemonic(name) {
+        let (operands, register) = if o.has_register() {
+            ("r,adr[,x]", "r")
+        } else {
+            ("adr[,x]", "0")
+        };
+        let mut form = format!(
+            "- `{} {}` — `#{:02X}{}x adr` (2 words), {}",
+            name,
+            operands,
+            o.opcode(),
+            register,
+            flag_text(o.flag_effect())
+        );
+        if !o.flags_read().is_empty() {
+            let flags: Vec<&str> = o.flags_read().iter().map(|f| f.name()).collect();
+            form.push_str(&format!("; branches on {}", flags.join(", ")));
+        }
+        forms.push(form);
+    }
+    if let Some(o) = Operation1::from_mnemonic(name) {
+        let (operands, registers) = match o {
+            Operation1::NoOperation | Operation1::Return => ("", "00"),
+            Operation1::Pop => (" r", "r0"),
+            _ => (" r1,r2", "r1r2"),
+        };
+        forms.push(format!(
+            "- `{}{}` — `#{:02X}{}` (1 word), {}",
+            name,
+            operands,
+            o.opcode(),
+            registers,
+            flag_text(o.flag_effect())
+        ));
+    }
+    if forms.is_empty() {
+        return format!("**{}**: unknown instruction", name);
+    }
+    format!("**{}**\n\n{}", name, forms.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &str = "MAIN  START
+      LD    GR1,X
+LOOP  SUBA  GR1,=1
+      JPL   LOOP
+      CALL  SUB
+      RET
+X     DC    3
+      END
+SUB   START
+LOOP  RET
+      END";
+
+    #[test]
+    fn definitions_and_references() {
+        let a = Analysis::new(SOURCE);
+        assert!(a.diagnostics().is_empty());
+        // 4行目の LOOP は同じプログラムの3行目の LOOP
+        let d = a.definition(3, 12).unwrap();
+        assert_eq!((d.span.line, d.unit.as_str()), (2, "MAIN"));
+        // CALL SUB は別のプログラムのSTART
+        assert_eq!(a.definition(4, 13).unwrap().span.line, 8);
+
+        let lines: Vec<usize> = a.references(2, 0, true).iter().map(|s| s.line).collect();
+        assert_eq!(lines, vec![2, 3]);
+        assert_eq!(a.references(9, 0, false), vec![]);
+    }
+
+    #[test]
+    fn hover() {
+        let a = Analysis::new(SOURCE);
+        let (text, span) = a.hover(1, 7).unwrap();
+        assert_eq!(span.start, 6);
+        assert!(text.contains("`LD r,adr[,x]` — `#10rx adr` (2 words), OF is cleared"));
+        assert!(text.contains("`LD r1,r2` — `#14r1r2` (1 word)"));
+        let (text, _) = a.hover(3, 6).unwrap();
+        assert!(text.contains(
+            "`JPL adr[,x]` — `#650x adr` (2 words), flags are unchanged; branches on SF, ZF"
+        ));
+        let (text, _) = a.hover(1, 16).unwrap();
+        assert_eq!(text, "**X** in MAIN = `#0109`");
+    }
+
+    #[test]
+    fn completions_and_units() {
+        let a = Analysis::new(SOURCE);
+        assert!(a
+            .completions(1, "      L")
+            .iter()
+            .any(|c| c.label == "LAD" && c.kind == CompletionKind::Mnemonic));
+        let operands = a.completions(1, "LOOP  LD    ");
+        assert!(operands.iter().any(|c| c.label == "GR7"));
+        assert!(operands.iter().any(|c| c.label == "X"));
+        assert!(operands.iter().any(|c| c.label == "SUB"));
+
+        let units: Vec<_> = a
+            .units()
+            .iter()
+            .map(|u| (u.name.as_str(), u.first_line, u.last_line))
+            .collect();
+        assert_eq!(units, vec![("MAIN", 0, 7), ("SUB", 8, 10)]);
+        let kinds: Vec<_> = a.labels_of("MAIN").map(|d| d.kind).collect();
+        assert_eq!(kinds, vec![LabelKind::Code, LabelKind::Constant]);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let a = Analysis::new("MAIN START\n  LD GR9,X\n  END");
+        let messages: Vec<String> = a.diagnostics().iter().map(|e| e.kind.to_string()).collect();
+        assert!(!messages.is_empty());
+        assert_eq!(a.diagnostics()[0].span.line, 1);
+    }
+}
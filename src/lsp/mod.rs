@@ -0,0 +1,407 @@
+//! CASL2 の Language Server。標準入出力で JSON-RPC をやりとりする
+//!
+//! 診断 (アセンブルのエラー)、定義へのジャンプ、参照の検索、命令の説明、
+//! 補完、ドキュメントシンボルに対応する。文書は毎回まるごと受け取る
+
+pub mod analysis;
+
+use crate::casl::ast::Span;
+use crate::utils::message::{read_message, write_message};
+use analysis::{Analysis, CompletionKind, LabelKind};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// LSPの位置はUTF-16の単位で数えるので、文字数と変換する
+fn to_utf16(line: &str, column: usize) -> usize {
+    line.chars().take(column).map(char::len_utf16).sum()
+}
+
+fn from_utf16(line: &str, column: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.chars().enumerate() {
+        if units >= column {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.chars().count()
+}
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+impl Document {
+    fn new(text: String) -> Document {
+        let analysis = Analysis::new(&text);
+        Document { text, analysis }
+    }
+
+    fn line(&self, line: usize) -> &str {
+        self.text.lines().nth(line).unwrap_or("")
+    }
+
+    fn range(&self, span: Span) -> Value {
+        let line = self.line(span.line);
+        json!({
+            "start": { "line": span.line, "character": to_utf16(line, span.start) },
+            "end": { "line": span.line, "character": to_utf16(line, span.end) },
+        })
+    }
+
+    /// `position` を (行, 文字数) にする
+    fn position(&self, position: &Value) -> Option<(usize, usize)> {
+        let line = position.get("line")?.as_u64()? as usize;
+        let character = position.get("character")?.as_u64()? as usize;
+        Some((line, from_utf16(self.line(line), character)))
+    }
+}
+
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// `exit` を受け取ったか
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// メッセージを1つ処理して、送るべきレスポンスと通知を返す
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(method, params),
+        };
+        let result = match method {
+            // shutdown のあとは exit しか受け付けない
+            _ if self.shutdown => Err((INVALID_REQUEST, "Server is shut down".to_string())),
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "fers" },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.completion(params),
+            "textDocument/documentSymbol" => self.symbols(params),
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method `{}`", method))),
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        vec![response]
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents
+                    .insert(uri.clone(), Document::new(text.to_string()));
+                vec![self.diagnostics(&uri)]
+            }
+            "textDocument/didChange" => {
+                // 全文同期なので最後の変更が文書全体
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                match text {
+                    Some(text) => {
+                        self.documents
+                            .insert(uri.clone(), Document::new(text.to_string()));
+                        vec![self.diagnostics(&uri)]
+                    }
+                    None => Vec::new(),
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })]
+            }
+            "exit" => {
+                self.exited = true;
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn diagnostics(&self, uri: &str) -> Value {
+        let document = &self.documents[uri];
+        let diagnostics: Vec<Value> = document
+            .analysis
+            .diagnostics()
+            .iter()
+            .map(|e| {
+                json!({
+                    "range": document.range(e.span),
+                    "severity": 1,
+                    "source": "fers",
+                    "message": e.kind.to_string(),
+                })
+            })
+            .collect();
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })
+    }
+
+    /// 文書とカーソル位置 (文字数)
+    fn cursor<'a>(
+        &'a self,
+        params: &'a Value,
+    ) -> Result<(&'a str, &'a Document, usize, usize), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let document = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("Unknown document `{}`", uri)))?;
+        let (line, column) = document
+            .position(&params["position"])
+            .ok_or_else(|| (INVALID_PARAMS, "Invalid position".to_string()))?;
+        Ok((uri, document, line, column))
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (uri, document, line, column) = self.cursor(params)?;
+        Ok(match document.analysis.definition(line, column) {
+            Some(d) => json!({ "uri": uri, "range": document.range(d.span) }),
+            None => Value::Null,
+        })
+    }
+
+    fn references(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (uri, document, line, column) = self.cursor(params)?;
+        let declaration = params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(true);
+        let locations: Vec<Value> = document
+            .analysis
+            .references(line, column, declaration)
+            .into_iter()
+            .map(|span| json!({ "uri": uri, "range": document.range(span) }))
+            .collect();
+        Ok(json!(locations))
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (_, document, line, column) = self.cursor(params)?;
+        Ok(match document.analysis.hover(line, column) {
+            Some((text, span)) => json!({
+                "contents": { "kind": "markdown", "value": text },
+                "range": document.range(span),
+            }),
+            None => Value::Null,
+        })
+    }
+
+    fn completion(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (_, document, line, column) = self.cursor(params)?;
+        let prefix: String = document.line(line).chars().take(column).collect();
+        let items: Vec<Value> = document
+            .analysis
+            .completions(line, &prefix)
+            .into_iter()
+            .map(|c| {
+                let kind = match c.kind {
+                    CompletionKind::Mnemonic => 14,
+                    CompletionKind::Register => 6,
+                    CompletionKind::Label => 18,
+                };
+                json!({ "label": c.label, "kind": kind, "detail": c.detail })
+            })
+            .collect();
+        Ok(json!(items))
+    }
+
+    fn symbols(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let document = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("Unknown document `{}`", uri)))?;
+        let symbols: Vec<Value> = document
+            .analysis
+            .units()
+            .iter()
+            .map(|unit| {
+                let children: Vec<Value> = document
+                    .analysis
+                    .labels_of(&unit.name)
+                    .map(|d| {
+                        let kind = match d.kind {
+                            LabelKind::Area => 13,
+                            LabelKind::Constant => 14,
+                            LabelKind::Code | LabelKind::Unit => 12,
+                        };
+                        json!({
+                            "name": d.name,
+                            "kind": kind,
+                            "range": document.range(d.span),
+                            "selectionRange": document.range(d.span),
+                        })
+                    })
+                    .collect();
+                let end = document.line(unit.last_line).chars().count();
+                json!({
+                    "name": unit.name,
+                    "kind": 2,
+                    "range": {
+                        "start": { "line": unit.first_line, "character": 0 },
+                        "end": {
+                            "line": unit.last_line,
+                            "character": to_utf16(document.line(unit.last_line), end),
+                        },
+                    },
+                    "selectionRange": document.range(unit.span),
+                    "children": children,
+                })
+            })
+            .collect();
+        Ok(json!(symbols))
+    }
+}
+
+/// `exit` か入力の終わりまでメッセージを処理する
+pub fn run(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut server = Server::new();
+    while let Some(message) = read_message(input)? {
+        for reply in server.handle(&message) {
+            write_message(output, &reply)?;
+        }
+        if server.exited() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(server: &mut Server, text: &str) -> Vec<Value> {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///a.cas", "languageId": "casl2", "version": 1, "text": text } },
+        }))
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        let mut replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": "file:///a.cas" },
+                "position": { "line": line, "character": character },
+                "context": { "includeDeclaration": true },
+            },
+        }));
+        replies.remove(0)["result"].take()
+    }
+
+    #[test]
+    fn diagnostics_and_navigation() {
+        let mut server = Server::new();
+        let replies = open(&mut server, "MAIN START\n  LD GR1,Y\n  RET\nX DC 1\n  END");
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({ "line": 1, "character": 9 })
+        );
+        assert_eq!(diagnostics[0]["message"], "Label `Y` is not defined");
+
+        // 全角のコメントがあってもUTF-16で位置を合わせる
+        let replies = open(
+            &mut server,
+            "; ＣＡＳＬ\nMAIN START\n  LD GR1,X ; ｘ\n  RET\nX DC 1\n  END",
+        );
+        assert_eq!(replies[0]["params"]["diagnostics"], json!([]));
+        let definition = request(&mut server, "textDocument/definition", 2, 9);
+        assert_eq!(
+            definition["range"]["start"],
+            json!({ "line": 4, "character": 0 })
+        );
+        let references = request(&mut server, "textDocument/references", 4, 0);
+        assert_eq!(references.as_array().unwrap().len(), 2);
+        let hover = request(&mut server, "textDocument/hover", 2, 3);
+        assert!(hover["contents"]["value"]
+            .as_str()
+            .unwrap()
+            .starts_with("**LD**"));
+
+        let symbols = request(&mut server, "textDocument/documentSymbol", 0, 0);
+        assert_eq!(symbols[0]["name"], "MAIN");
+        assert_eq!(symbols[0]["children"][0]["name"], "X");
+        assert_eq!(symbols[0]["children"][0]["kind"], 14);
+    }
+
+    #[test]
+    fn lifecycle() {
+        let mut input = Vec::new();
+        for message in &[
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ] {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        run(&mut io::Cursor::new(input), &mut output).unwrap();
+
+        let mut output = io::Cursor::new(output);
+        let initialize = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(initialize["result"]["capabilities"]["hoverProvider"], true);
+        let unknown = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+        let shutdown = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(shutdown["result"], Value::Null);
+        assert!(read_message(&mut output).unwrap().is_none());
+    }
+}
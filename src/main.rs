@@ -1,21 +1,439 @@
-use std::{error::Error, thread::sleep, time::Duration};
-mod casl;
-mod core;
-mod lib;
-mod utils;
-
-use crate::core::machine::Machine;
-
-fn main() -> Result<(), Box<dyn Error>> {
-    // let mut code = std::fs::File::open("sample")?;
-    let mut code = std::io::Cursor::new(vec![0x20, 0x01]);
-    let machine = Machine::init(&mut code)?;
-    println!("{}", machine.r_info());
-    println!("{}", machine.mem.0[0]);
-    machine.clock()?;
-
-    println!("{}", machine.r_info());
-    println!("{}", machine.mem.0[0]);
-    println!("{}", machine.mem.info());
+use std::io::{BufRead, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io, process};
+
+use fers::core::cost::{CostModel, Meter, MeterError};
+use fers::debugger::Debugger;
+use fers::{casl, debugger, grade, lsp, profile, repl, trace, Machine, Program};
+
+const USAGE: &str = "usage: fers asm <source.cas> [-o <program.obj>]
+       fers run <source.cas|program.obj> [--input <file>] [--trace]
+       fers disasm <source.cas|program.obj>
+       fers test <source.cas|program.obj> <cases.toml>
+       fers trace <json|binary> <source.cas>
+       fers profile <table|listing|collapsed|heat> <source.cas>
+       fers cycles <costs.txt|default> <source.cas>
+       fers diff [--effects] <left.trace> <right.trace>
+       fers debug <source.cas>
+       fers tui <source.cas>
+       fers gdb <port> <source.cas>
+       fers repl
+       fers dap
+       fers lsp
+
+exit status: 0 success, 1 invalid source, object, costs, cases or trace, 2 usage error,
+             3 runtime error, 4 did not halt, 5 test cases failed or traces differ,
+             6 I/O error";
+
+/// 終了コード
+const EXIT_INVALID: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_RUNTIME: i32 = 3;
+const EXIT_NOT_HALTED: i32 = 4;
+const EXIT_MISMATCH: i32 = 5;
+const EXIT_IO: i32 = 6;
+
+/// 止まらないプログラムを打ち切るまでのステップ数
+const MAX_STEPS: u64 = 10_000_000;
+
+/// コマンドが失敗した理由。表示してから `exit_code` で終わる
+#[derive(Debug, thiserror::Error)]
+enum Failure {
+    /// 標準入出力やソケット
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{path}: {error}")]
+    File { path: String, error: io::Error },
+    /// オブジェクトファイル、コスト表、テストケース、トレースの中身が正しくない
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    Runtime(String),
+    /// ステップ数などの上限まで実行しても停止しなかった
+    #[error("{0}")]
+    NotHalted(String),
+    /// テストケースが失敗したか、トレースが食い違った
+    #[error("{0}")]
+    Mismatch(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Io(_) | Failure::File { .. } => EXIT_IO,
+            Failure::Invalid(_) => EXIT_INVALID,
+            Failure::Runtime(_) => EXIT_RUNTIME,
+            Failure::NotHalted(_) => EXIT_NOT_HALTED,
+            Failure::Mismatch(_) => EXIT_MISMATCH,
+        }
+    }
+
+    fn file(path: &str) -> impl FnOnce(io::Error) -> Failure + '_ {
+        move |error| Failure::File {
+            path: path.to_string(),
+            error,
+        }
+    }
+
+    fn meter(path: &str, e: MeterError) -> Failure {
+        match e {
+            MeterError::StepError(_) => Failure::Runtime(format!("{}: {}", path, e)),
+            MeterError::StepLimit(_) | MeterError::CycleLimit(_) => {
+                Failure::NotHalted(format!("{}: {}", path, e))
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = command(&args) {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+fn command(args: &[String]) -> Result<(), Failure> {
+    let (command, rest) = args.split_first().unwrap_or_else(|| usage());
+    match (command.as_str(), rest) {
+        ("asm", rest) => assemble_file(rest),
+        ("run", rest) => run(rest),
+        ("disasm", [path]) => {
+            print!("{}", casl::disasm::listing(&read_program(path)?));
+            Ok(())
+        }
+        ("test", [path, cases]) => test(path, cases),
+        ("trace", [format, path]) => match trace::Format::from_name(format) {
+            Some(format) => trace_program(path, format),
+            None => usage(),
+        },
+        ("profile", [report, path]) => match report.as_str() {
+            "table" | "listing" | "collapsed" | "heat" => profile_program(report, path),
+            _ => usage(),
+        },
+        ("cycles", [costs, path]) => cycles(costs, path),
+        ("diff", [left, right]) => diff_traces(left, right, trace::diff::Mode::Steps),
+        ("diff", [flag, left, right]) if flag == "--effects" => {
+            diff_traces(left, right, trace::diff::Mode::Effects)
+        }
+        ("debug", [path]) => {
+            let (source, program) = read_source(path)?;
+            let mut debugger = Debugger::new(program, &source);
+            let stdin = io::stdin();
+            debugger::cli::run(&mut debugger, &mut stdin.lock(), &mut io::stdout())?;
+            Ok(())
+        }
+        ("tui", [path]) => {
+            let (source, program) = read_source(path)?;
+            debugger::tui::run(Debugger::new(program, &source))?;
+            Ok(())
+        }
+        ("gdb", [port, path]) => {
+            let port = port.parse::<u16>().unwrap_or_else(|_| usage());
+            let (_, program) = read_source(path)?;
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+            debugger::gdb::serve(&listener, program.load())?;
+            Ok(())
+        }
+        ("repl", []) => {
+            let stdin = io::stdin();
+            repl::run(&mut stdin.lock(), &mut io::stdout())?;
+            Ok(())
+        }
+        // エディタとは標準入出力でやりとりする
+        ("dap", []) => {
+            let stdin = io::stdin();
+            debugger::dap::run(&mut stdin.lock(), &mut io::stdout())?;
+            Ok(())
+        }
+        ("lsp", []) => {
+            let stdin = io::stdin();
+            lsp::run(&mut stdin.lock(), &mut io::stdout())?;
+            Ok(())
+        }
+        _ => usage(),
+    }
+}
+
+/// アセンブルする。エラーは1行に1つずつ並べる
+fn assemble(path: &str, source: &str) -> Result<Program, Failure> {
+    casl::assemble(source).map_err(|errors| {
+        let lines: Vec<String> = errors.iter().map(|e| format!("{}:{}", path, e)).collect();
+        Failure::Invalid(lines.join("\n"))
+    })
+}
+
+/// ソースを読んでアセンブルする
+fn read_source(path: &str) -> Result<(String, Program), Failure> {
+    let source = fs::read_to_string(path).map_err(Failure::file(path))?;
+    let program = assemble(path, &source)?;
+    Ok((source, program))
+}
+
+/// オブジェクトファイルか、ソースをアセンブルしたもの
+fn read_program(path: &str) -> Result<Program, Failure> {
+    let bytes = fs::read(path).map_err(Failure::file(path))?;
+    if casl::object::is_object(&bytes) {
+        return Program::from_object(&bytes)
+            .map_err(|e| Failure::Invalid(format!("{}: {}", path, e)));
+    }
+    let source =
+        String::from_utf8(bytes).map_err(|e| Failure::Invalid(format!("{}: {}", path, e)))?;
+    assemble(path, &source)
+}
+
+/// INで読む行。全部を先に読まず、INを実行するときに1行ずつ読む
+struct Input {
+    lines: io::Lines<Box<dyn BufRead>>,
+}
+
+impl Input {
+    fn stdin() -> Input {
+        Input {
+            lines: (Box::new(io::stdin().lock()) as Box<dyn BufRead>).lines(),
+        }
+    }
+
+    fn file(path: &str) -> Result<Input, Failure> {
+        let file = fs::File::open(path).map_err(Failure::file(path))?;
+        Ok(Input {
+            lines: (Box::new(io::BufReader::new(file)) as Box<dyn BufRead>).lines(),
+        })
+    }
+
+    /// 次に実行するINの入力がなければ1行読んで渡す
+    fn feed(&mut self, machine: Machine) -> Result<Machine, Failure> {
+        if !machine.needs_input() {
+            return Ok(machine);
+        }
+        match self.lines.next() {
+            Some(line) => Ok(machine.with_input(Some(line?))),
+            None => Ok(machine),
+        }
+    }
+}
+
+/// `fers asm`。出力先を指定しなければ拡張子を `.obj` にする
+fn assemble_file(args: &[String]) -> Result<(), Failure> {
+    let (path, output) = match args {
+        [path] => (path, Path::new(path).with_extension("obj")),
+        [path, flag, output] | [flag, output, path] if flag == "-o" => {
+            (path, PathBuf::from(output))
+        }
+        _ => usage(),
+    };
+    let (_, program) = read_source(path)?;
+    fs::write(&output, program.to_object()).map_err(Failure::file(&output.to_string_lossy()))
+}
+
+/// `fers run`。OUTは標準出力へ、トレースはJSONで標準エラー出力へ書く
+fn run(args: &[String]) -> Result<(), Failure> {
+    let mut path = None;
+    let mut input = None;
+    let mut tracing = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => input = Some(args.next().unwrap_or_else(|| usage())),
+            "--trace" => tracing = true,
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let program = read_program(path)?;
+    let mut input = match input {
+        Some(file) => Input::file(file)?,
+        None => Input::stdin(),
+    };
+
+    let mut machine = program.load();
+    let stderr = io::stderr();
+    let mut writer = if tracing {
+        Some(trace::Writer::new(stderr.lock(), trace::Format::Json))
+    } else {
+        None
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut steps = 0;
+    let result = loop {
+        if machine.is_halted() {
+            break Ok(());
+        }
+        if steps == MAX_STEPS {
+            let message = format!("{}: Step limit of {} exceeded", path, MAX_STEPS);
+            break Err(Failure::NotHalted(message));
+        }
+        steps += 1;
+        machine = input.feed(machine)?;
+        let next = match writer.as_mut() {
+            Some(writer) => match trace::Record::step(&machine, steps) {
+                Ok((next, record)) => {
+                    writer.write(&record)?;
+                    Ok(next)
+                }
+                Err(e) => Err(e),
+            },
+            None => machine.step(),
+        };
+        match next {
+            Ok(next) => {
+                for line in &next.output()[machine.output().len()..] {
+                    writeln!(stdout, "{}", line)?;
+                }
+                machine = next;
+            }
+            Err(e) => break Err(Failure::Runtime(format!("{}: {}", path, e))),
+        }
+    };
+    stdout.flush()?;
+    if let Some(writer) = writer.as_mut() {
+        writer.flush()?;
+    }
+    result
+}
+
+/// `fers trace`。標準入力を入力として実行し、トレースを標準出力に書く
+fn trace_program(path: &str, format: trace::Format) -> Result<(), Failure> {
+    let (_, program) = read_source(path)?;
+    let mut input = Input::stdin();
+    let stdout = io::stdout();
+    let mut writer = trace::Writer::new(io::BufWriter::new(stdout.lock()), format);
+    let mut machine = program.load();
+    let mut steps = 0;
+    let result = loop {
+        if machine.is_halted() {
+            break Ok(());
+        }
+        if steps == MAX_STEPS {
+            let message = format!("{}: Step limit of {} exceeded", path, MAX_STEPS);
+            break Err(Failure::NotHalted(message));
+        }
+        steps += 1;
+        machine = input.feed(machine)?;
+        match trace::Record::step(&machine, steps) {
+            Ok((next, record)) => {
+                writer.write(&record)?;
+                machine = next;
+            }
+            Err(e) => break Err(Failure::Runtime(format!("{}: {}", path, e))),
+        }
+    };
+    writer.flush()?;
+    result
+}
+
+/// `fers profile`。標準入力を入力として実行し、集計を標準出力に書く。OUTは標準エラー出力へ。
+/// 途中で止まっても、そこまでの集計を書いてから失敗する
+fn profile_program(report: &str, path: &str) -> Result<(), Failure> {
+    let (source, program) = read_source(path)?;
+    let mut input = Input::stdin();
+    let mut machine = program.load();
+    let mut profiler = profile::Profiler::new(&machine, CostModel::default());
+    profiler.meter().max_steps = Some(MAX_STEPS);
+    let mut result = Ok(());
+    while !machine.is_halted() {
+        machine = input.feed(machine)?;
+        let next = profiler.step(&machine);
+        for line in &next.as_ref().unwrap_or(&machine).output()[machine.output().len()..] {
+            eprintln!("{}", line);
+        }
+        match next {
+            Ok(next) => machine = next,
+            Err(e) => {
+                result = Err(Failure::meter(path, e));
+                break;
+            }
+        }
+    }
+    let profile = profiler.profile();
+    print!(
+        "{}",
+        match report {
+            "table" => profile::report::table(profile, &program),
+            "listing" => profile::report::listing(profile, &program, &source),
+            "collapsed" => profile::report::collapsed(profile, &program),
+            _ => profile::report::heat(profile, &program),
+        }
+    );
+    result
+}
+
+/// `fers cycles`。標準入力を入力として実行し、ステップ数とサイクル数を標準エラー出力に書く
+fn cycles(costs: &str, path: &str) -> Result<(), Failure> {
+    let model = if costs == "default" {
+        CostModel::default()
+    } else {
+        let text = fs::read_to_string(costs).map_err(Failure::file(costs))?;
+        CostModel::parse(&text).map_err(|e| Failure::Invalid(format!("{}: {}", costs, e)))?
+    };
+    let (_, program) = read_source(path)?;
+    let mut input = Input::stdin();
+    let mut machine = program.load();
+    let mut meter = Meter::new(model);
+    meter.max_steps = Some(MAX_STEPS);
+    let result = loop {
+        if machine.is_halted() {
+            break Ok(());
+        }
+        machine = input.feed(machine)?;
+        match meter.step(&machine) {
+            Ok((next, _)) => {
+                for line in &next.output()[machine.output().len()..] {
+                    println!("{}", line);
+                }
+                machine = next;
+            }
+            Err(e) => break Err(Failure::meter(path, e)),
+        }
+    };
+    eprintln!("{} steps, {} cycles", meter.steps, meter.cycles);
+    result
+}
+
+/// すべてのケースで採点して結果を表示する。失敗があれば `Failure::Mismatch`
+fn test(path: &str, cases: &str) -> Result<(), Failure> {
+    let program = read_program(path)?;
+    let text = fs::read_to_string(cases).map_err(Failure::file(cases))?;
+    let spec =
+        grade::Spec::parse(&text).map_err(|e| Failure::Invalid(format!("{}: {}", cases, e)))?;
+    let outcomes: Vec<grade::Outcome> = spec
+        .cases
+        .iter()
+        .map(|case| grade::grade(&program, case))
+        .collect();
+    print!("{}", grade::report(&outcomes));
+    let failed = outcomes.iter().filter(|o| !o.passed()).count();
+    if failed > 0 {
+        let message = format!("{}: {} of {} cases failed", cases, failed, outcomes.len());
+        return Err(Failure::Mismatch(message));
+    }
     Ok(())
 }
+
+/// 食い違いがあれば表示して `Failure::Mismatch`
+fn diff_traces(left: &str, right: &str, mode: trace::diff::Mode) -> Result<(), Failure> {
+    let read = |path: &str| -> Result<Vec<trace::Record>, Failure> {
+        let file = fs::File::open(path).map_err(Failure::file(path))?;
+        trace::read(&mut io::BufReader::new(file))
+            .map_err(|e| Failure::Invalid(format!("{}: {}", path, e)))
+    };
+    let (left_records, right_records) = (read(left)?, read(right)?);
+    if let Some(divergence) = trace::diff::diff(&left_records, &right_records, mode) {
+        print!(
+            "{}",
+            trace::diff::render(&left_records, &right_records, &divergence, 5)
+        );
+        let message = format!("{} and {} differ", left, right);
+        return Err(Failure::Mismatch(message));
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(EXIT_USAGE);
+}
@@ -0,0 +1,262 @@
+//! 命令ごとの実行回数、サブルーチンごとのサイクル数、メモリの読み書きの回数を数える
+//!
+//! サイクル数は `CostModel` で数える。読み書きと呼び出しは `MachineObserver` で受け取る
+
+pub mod report;
+
+use crate::core::cost::{CostModel, Meter, MeterError};
+use crate::core::machine::Machine;
+use crate::core::observer::MachineObserver;
+use std::collections::BTreeMap;
+
+/// 1つの番地を読み書きした回数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Heat {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// サブルーチン1つ分の集計
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subroutine {
+    /// 先頭番地 (CALLの飛び先)
+    pub entry: u16,
+    pub calls: u64,
+    /// 呼んだサブルーチンの中も含めたサイクル数
+    pub inclusive: u64,
+    /// 自分の中だけのサイクル数
+    pub exclusive: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// 命令の番地ごとの実行回数
+    pub counts: BTreeMap<u16, u64>,
+    /// 番地ごとの読み書きの回数。命令の読み込みは含まない
+    pub heat: BTreeMap<u16, Heat>,
+    /// 呼び出しの列 (サブルーチンの先頭番地。外側から) ごとの、そこで直接使ったサイクル数
+    pub stacks: BTreeMap<Vec<u16>, u64>,
+    /// サブルーチンの先頭番地ごとの呼ばれた回数。実行開始位置は1回
+    pub calls: BTreeMap<u16, u64>,
+}
+
+impl Profile {
+    /// 全体のステップ数
+    pub fn steps(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// 全体のサイクル数
+    pub fn total(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// inclusive の多い順。再帰していても1つの呼び出しの列では1回だけ数える
+    pub fn subroutines(&self) -> Vec<Subroutine> {
+        let mut subroutines: Vec<Subroutine> = self
+            .calls
+            .iter()
+            .map(|(&entry, &calls)| {
+                let mut inclusive = 0;
+                let mut exclusive = 0;
+                for (stack, &cycles) in &self.stacks {
+                    if stack.contains(&entry) {
+                        inclusive += cycles;
+                    }
+                    if stack.last() == Some(&entry) {
+                        exclusive += cycles;
+                    }
+                }
+                Subroutine {
+                    entry,
+                    calls,
+                    inclusive,
+                    exclusive,
+                }
+            })
+            .collect();
+        subroutines.sort_by_key(|s| (std::cmp::Reverse(s.inclusive), s.entry));
+        subroutines
+    }
+}
+
+/// 実行中の命令で数えたもの。命令が最後まで実行できてから `Profile` に足す
+#[derive(Debug, Default)]
+struct Pending {
+    heat: BTreeMap<u16, Heat>,
+    /// メモリを読み書きした回数
+    accesses: u64,
+    /// CALLの飛び先
+    call: Option<u16>,
+    returned: bool,
+}
+
+/// 1命令ずつ実行しながら数える
+pub struct Profiler {
+    meter: Meter,
+    profile: Profile,
+    /// いま実行中のサブルーチンの先頭番地の列
+    stack: Vec<u16>,
+    pending: Pending,
+}
+
+impl Profiler {
+    /// `machine` のPRを実行開始位置とする
+    pub fn new(machine: &Machine, model: CostModel) -> Profiler {
+        let mut profile = Profile::default();
+        profile.calls.insert(machine.pr(), 1);
+        Profiler {
+            meter: Meter::new(model),
+            profile,
+            stack: vec![machine.pr()],
+            pending: Pending::default(),
+        }
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// 全体のステップ数とサイクル数。上限もここで決める
+    pub fn meter(&mut self) -> &mut Meter {
+        &mut self.meter
+    }
+
+    /// 1命令実行して数える。CALLは呼んだ側、RETは呼ばれた側のサイクルにする。
+    /// 途中で失敗した命令は数えない
+    pub fn step(&mut self, machine: &Machine) -> Result<Machine, MeterError> {
+        let instruction = self.meter.begin(machine)?;
+        self.pending = Pending::default();
+        let next = machine.step_with(self)?;
+        let pending = std::mem::take(&mut self.pending);
+
+        let cycles = self.meter.count(&instruction, pending.accesses);
+        *self.profile.counts.entry(machine.pr()).or_insert(0) += 1;
+        // CALLとRETの前の列に数える
+        *self.profile.stacks.entry(self.stack.clone()).or_insert(0) += cycles;
+        for (address, heat) in pending.heat {
+            let total = self.profile.heat.entry(address).or_default();
+            total.reads += heat.reads;
+            total.writes += heat.writes;
+        }
+        if let Some(target) = pending.call {
+            *self.profile.calls.entry(target).or_insert(0) += 1;
+            self.stack.push(target);
+        }
+        if pending.returned && self.stack.len() > 1 {
+            self.stack.pop();
+        }
+        Ok(next)
+    }
+}
+
+impl MachineObserver for Profiler {
+    fn memory_read(&mut self, address: u16, _value: u16) {
+        self.pending.heat.entry(address).or_default().reads += 1;
+        self.pending.accesses += 1;
+    }
+
+    fn memory_written(&mut self, address: u16, _old: u16, _new: u16) {
+        self.pending.heat.entry(address).or_default().writes += 1;
+        self.pending.accesses += 1;
+    }
+
+    fn call(&mut self, target: u16, _return_address: u16) {
+        self.pending.call = Some(target);
+    }
+
+    fn returned(&mut self, _address: u16) {
+        self.pending.returned = true;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::casl;
+    use crate::core::protection::ProtectionMode;
+
+    /// SUB を3回呼び、SUB は INNER を1回呼ぶ
+    pub const SOURCE: &str = "MAIN  START
+      LAD   GR2,3
+LOOP  CALL  SUB
+      SUBA  GR2,=1
+      JNZ   LOOP
+      RET
+X     DS    1
+SUB   LD    GR1,X
+      CALL  INNER
+      RET
+INNER ST    GR1,X
+      RET
+      END";
+
+    pub fn profile() -> (casl::Program, Profile) {
+        let program = casl::assemble(SOURCE).unwrap();
+        let mut machine = program.load();
+        // サイクル数を命令の語数にする
+        let model = CostModel {
+            memory: 0,
+            index: 0,
+            ..CostModel::default()
+        };
+        let mut profiler = Profiler::new(&machine, model);
+        while !machine.is_halted() {
+            machine = profiler.step(&machine).unwrap();
+        }
+        (program, profiler.profile().clone())
+    }
+
+    #[test]
+    fn counts_and_cycles() {
+        let (program, profile) = profile();
+        let address = |name: &str| program.symbol(name).unwrap().address;
+        assert_eq!(profile.counts[&address("LOOP")], 3);
+        assert_eq!(profile.counts[&address("INNER")], 3);
+        assert_eq!(profile.calls[&address("SUB")], 3);
+
+        // MAIN: LAD + (CALL + SUBA + JNZ) × 3 = 2 + 18, RET 1
+        // SUB: (LD + CALL + RET) × 3 = 15, INNER: (ST + RET) × 3 = 9
+        let subroutines = profile.subroutines();
+        let summary: Vec<_> = subroutines
+            .iter()
+            .map(|s| (s.entry, s.calls, s.inclusive, s.exclusive))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (address("MAIN"), 1, 45, 21),
+                (address("SUB"), 3, 24, 15),
+                (address("INNER"), 3, 9, 9),
+            ]
+        );
+        assert_eq!((profile.steps(), profile.total()), (26, 45));
+
+        let x = profile.heat[&address("X")];
+        assert_eq!((x.reads, x.writes), (3, 3));
+    }
+
+    #[test]
+    fn failing_instruction() {
+        // 2つ目のLDが未初期化のYを読んで止まる
+        let program = casl::assemble(
+            "MAIN  START
+      LD    GR1,X
+      LD    GR2,Y
+      RET
+X     DC    1
+Y     DS    1
+      END",
+        )
+        .unwrap();
+        let mut machine = program.load();
+        machine = machine.with_shadow(program.shadow(ProtectionMode::Trap));
+        let mut profiler = Profiler::new(&machine, CostModel::default());
+        machine = profiler.step(&machine).unwrap();
+        let before = profiler.profile().clone();
+        assert!(profiler.step(&machine).is_err());
+        assert_eq!(profiler.profile(), &before);
+        assert_eq!(profiler.meter().cycles, before.total());
+        assert_eq!(profiler.meter().steps, before.steps());
+    }
+}
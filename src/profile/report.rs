@@ -0,0 +1,134 @@
+//! `Profile` を人やツールが読める形にする
+
+use super::Profile;
+use crate::casl::Program;
+
+/// ラベルがあれば `LABEL+n`、なければ `#XXXX`
+fn name(program: &Program, address: u16) -> String {
+    program
+        .describe(address)
+        .unwrap_or_else(|| format!("#{:04X}", address))
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// サブルーチンごとの表
+pub fn table(profile: &Profile, program: &Program) -> String {
+    let total = profile.total();
+    let mut text = format!("{} steps, {} cycles\n", profile.steps(), total);
+    text.push_str(&format!(
+        "{:>10} {:>7} {:>10} {:>7} {:>8}  subroutine\n",
+        "inclusive", "%", "exclusive", "%", "calls"
+    ));
+    for s in profile.subroutines() {
+        text.push_str(&format!(
+            "{:>10} {:>6.1}% {:>10} {:>6.1}% {:>8}  {}\n",
+            s.inclusive,
+            percent(s.inclusive, total),
+            s.exclusive,
+            percent(s.exclusive, total),
+            s.calls,
+            name(program, s.entry)
+        ));
+    }
+    text
+}
+
+/// ソースの各行に実行回数と、その行の領域を読み書きした回数を付ける
+pub fn listing(profile: &Profile, program: &Program, source: &str) -> String {
+    let lines = source.lines().count();
+    let mut counts = vec![0; lines];
+    let mut heat = vec![(0, 0); lines];
+    for (&address, &count) in &profile.counts {
+        if let Some(line) = program.line_of(address).filter(|&l| l < lines) {
+            counts[line] += count;
+        }
+    }
+    for (&address, h) in &profile.heat {
+        if let Some(line) = program.line_of(address).filter(|&l| l < lines) {
+            heat[line].0 += h.reads;
+            heat[line].1 += h.writes;
+        }
+    }
+
+    let mut text = format!("{:>8} {:>13}  source\n", "count", "reads/writes");
+    for (i, line) in source.lines().enumerate() {
+        let count = match counts[i] {
+            0 => String::new(),
+            n => n.to_string(),
+        };
+        let access = match heat[i] {
+            (0, 0) => String::new(),
+            (r, w) => format!("{}/{}", r, w),
+        };
+        text.push_str(&format!("{:>8} {:>13}  {}\n", count, access, line));
+    }
+    text
+}
+
+/// flamegraph.pl などで読める `MAIN;SUB;INNER 9` の形
+pub fn collapsed(profile: &Profile, program: &Program) -> String {
+    profile
+        .stacks
+        .iter()
+        .map(|(stack, cycles)| {
+            let names: Vec<String> = stack.iter().map(|&a| name(program, a)).collect();
+            format!("{} {}\n", names.join(";"), cycles)
+        })
+        .collect()
+}
+
+/// よく読み書きされた番地の順
+pub fn heat(profile: &Profile, program: &Program) -> String {
+    let mut addresses: Vec<_> = profile.heat.iter().collect();
+    addresses.sort_by_key(|(&address, h)| (std::cmp::Reverse(h.reads + h.writes), address));
+    let mut text = format!(
+        "{:>7}  {:<12} {:>8} {:>8}\n",
+        "address", "label", "reads", "writes"
+    );
+    for (&address, h) in addresses {
+        let label = program.describe(address).unwrap_or_default();
+        text.push_str(&format!(
+            "  #{:04X}  {:<12} {:>8} {:>8}\n",
+            address, label, h.reads, h.writes
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::{profile, SOURCE};
+    use super::*;
+
+    #[test]
+    fn reports() {
+        let (program, profile) = profile();
+        let table = table(&profile, &program);
+        assert!(table.starts_with("26 steps, 45 cycles\n"));
+        assert_eq!(
+            table.lines().nth(3).unwrap(),
+            "        24   53.3%         15   33.3%        3  SUB"
+        );
+        assert_eq!(
+            collapsed(&profile, &program),
+            "MAIN 21\nMAIN;SUB 15\nMAIN;SUB;INNER 9\n"
+        );
+
+        let listing = listing(&profile, &program, SOURCE);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[3], "       3                LOOP  CALL  SUB");
+        assert_eq!(lines[7], "                   3/3  X     DS    1");
+
+        let heat = heat(&profile, &program);
+        assert!(heat
+            .lines()
+            .any(|l| l == "  #0109  X                   3        3"));
+    }
+}
@@ -0,0 +1,439 @@
+//! 命令を1つ入力するたびにアセンブルして実行する。授業で命令の動きを見せるのに使う
+//!
+//! 入力した命令は `SCRATCH` 番地からの作業領域に置いて実行し、書き換わったレジスタ、フラグ、
+//! メモリを表示する。`:` で始まる行はREPLのコマンド
+
+use crate::casl::ast::{Opecode, Value};
+use crate::casl::error::ErrorKind;
+use crate::casl::{self, parser};
+use crate::core::access::{AccessKind, Cell};
+use crate::core::machine::{Machine, StepError};
+use crate::core::protection::WordKind;
+use crate::debugger::cli::read_line;
+use crate::debugger::command::{self, Location, ParseError, Place};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+/// 入力した命令を置く番地。ここから後ろはデータに使わないこと
+pub const SCRATCH: u16 = 0xFF00;
+/// 1行の実行で打ち切るまでのステップ数
+const MAX_STEPS: usize = 1000;
+
+pub const HELP: &str = "\
+LAD GR1,5           assemble and execute an instruction (IN, OUT, RPUSH, RPOP too)
+:set PLACE VALUE    set GR0-GR7, SP, OF, SF, ZF or a memory word
+:poke LOC VALUE...  write words starting at LOC
+:label NAME LOC     define a label to use in instructions
+:labels             list labels
+:regs               show registers
+:x LOC [N]          show N words at LOC
+:reset              start over with a fresh machine
+:help
+:quit
+LOC is a label, #hex or decimal address. VALUE is #hex or a decimal number";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplError {
+    #[error("{0}")]
+    Parse(#[from] ParseError),
+    #[error("Unknown command `:{0}`. Try :help")]
+    UnknownCommand(String),
+    #[error("Label `{0}` is not defined")]
+    UndefinedLabel(String),
+    #[error("{0}")]
+    Assemble(ErrorKind),
+    #[error("Only instructions can be executed. Use :poke to write data")]
+    NotInstruction,
+    #[error("{0}")]
+    Step(#[from] StepError),
+    #[error("Instruction did not finish in {0} steps")]
+    StepLimit(usize),
+    #[error("Machine has halted. Use :reset to start over")]
+    Halted,
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// 命令を実行していくMachineと、定義したラベル
+#[derive(Debug, Clone)]
+pub struct Repl {
+    machine: Machine,
+    labels: BTreeMap<String, u16>,
+}
+
+impl Default for Repl {
+    /// メモリもレジスタも0で、スタックは空
+    fn default() -> Repl {
+        Repl {
+            machine: Machine::init(&mut io::empty()).expect("reading from memory never fails"),
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+/// `input` から1行ずつ読んで実行する。INの入力も `input` から読む
+pub fn run(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut repl = Repl::default();
+    writeln!(output, "Type an instruction such as `LAD GR1,5`, or :help")?;
+    loop {
+        write!(output, "casl> ")?;
+        output.flush()?;
+        let line = match read_line(input)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let line = line.trim();
+        let result = match line.strip_prefix(':') {
+            Some(command) => repl.command(command, output),
+            None if line.is_empty() => continue,
+            None => repl.execute(line, input, output).map(|()| true),
+        };
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(ReplError::Io(e)) => return Err(e),
+            Err(e) => writeln!(output, "{}", e)?,
+        }
+    }
+}
+
+/// `#FFFF`, `65535` または `-1`
+fn number(text: &str) -> Result<u16, ParseError> {
+    let invalid = || ParseError::InvalidArgument(text.to_string());
+    match text.strip_prefix('#') {
+        Some(hex) => u16::from_str_radix(hex, 16).map_err(|_| invalid()),
+        None => match text.parse::<i32>() {
+            Ok(n) if (-0x8000..=0xFFFF).contains(&n) => Ok(n as u16),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+/// 文字単位の列をバイト単位の位置にする
+fn byte_index(text: &str, column: usize) -> usize {
+    text.char_indices()
+        .nth(column)
+        .map_or(text.len(), |(index, _)| index)
+}
+
+impl Repl {
+    fn resolve(&self, location: &Location) -> Result<u16, ReplError> {
+        match location {
+            Location::Address(address) => Ok(*address),
+            Location::Label(name) => self
+                .labels
+                .get(name)
+                .copied()
+                .ok_or_else(|| ReplError::UndefinedLabel(name.clone())),
+        }
+    }
+
+    /// 番地にラベルがあれば `#0200 (X)`
+    fn describe(&self, cell: Cell) -> String {
+        let label = match cell {
+            Cell::Memory(address) => self.labels.iter().find(|(_, &a)| a == address),
+            _ => None,
+        };
+        match label {
+            Some((name, _)) => format!("{} ({})", cell, name),
+            None => cell.to_string(),
+        }
+    }
+
+    /// `:` を除いたコマンドを実行する。終了するなら `false`
+    pub fn command(&mut self, line: &str, output: &mut impl Write) -> Result<bool, ReplError> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        match (name, args.as_slice()) {
+            ("set", [place, value]) => {
+                let cell = match command::place(place)? {
+                    Place::Memory(location) => Cell::Memory(self.resolve(&location)?),
+                    Place::Register(r) => Cell::Register(r),
+                    Place::Sp => Cell::Sp,
+                    Place::Flag(flag) => Cell::Flag(flag),
+                };
+                self.machine = self.machine.with_cell(cell, number(value)?);
+            }
+            ("poke", [location, values @ ..]) if !values.is_empty() => {
+                let start = self.resolve(&command::location(location)?)?;
+                let values = values
+                    .iter()
+                    .map(|v| number(v))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (i, value) in values.into_iter().enumerate() {
+                    let cell = Cell::Memory(start.wrapping_add(i as u16));
+                    self.machine = self.machine.with_cell(cell, value);
+                }
+            }
+            ("label", [label, location]) => {
+                if !parser::is_label(label) {
+                    return Err(ParseError::InvalidArgument(label.to_string()).into());
+                }
+                let address = self.resolve(&command::location(location)?)?;
+                self.labels.insert(label.to_string(), address);
+            }
+            ("labels", []) => {
+                for (name, address) in &self.labels {
+                    writeln!(output, "{:<8} #{:04X}", name, address)?;
+                }
+            }
+            ("regs", []) => self.show_registers(output)?,
+            ("x", [location, count @ ..]) if count.len() <= 1 => {
+                let start = self.resolve(&command::location(location)?)?;
+                let count = match count {
+                    [n] => n
+                        .parse()
+                        .map_err(|_| ParseError::InvalidArgument(n.to_string()))?,
+                    _ => 1,
+                };
+                for i in 0..count {
+                    let cell = Cell::Memory(start.wrapping_add(i));
+                    let value = self.machine.cell(cell);
+                    writeln!(
+                        output,
+                        "{:<14} #{:04X} {:>6}",
+                        self.describe(cell),
+                        value,
+                        value as i16
+                    )?;
+                }
+            }
+            ("reset", []) => *self = Repl::default(),
+            ("h", []) | ("help", []) => writeln!(output, "{}", HELP)?,
+            ("q", []) | ("quit", []) => return Ok(false),
+            ("set" | "poke" | "label" | "x", []) => {
+                return Err(ParseError::MissingArgument(format!(":{}", name)).into())
+            }
+            ("set" | "poke" | "label" | "labels" | "regs" | "x" | "reset", args) => {
+                return Err(ParseError::InvalidArgument(args.join(" ")).into())
+            }
+            _ => return Err(ReplError::UnknownCommand(name.to_string())),
+        }
+        Ok(true)
+    }
+
+    fn show_registers(&self, output: &mut impl Write) -> io::Result<()> {
+        let machine = &self.machine;
+        for (i, value) in machine.gr().values().iter().enumerate() {
+            let separator = if i % 4 == 3 { "\n" } else { "  " };
+            write!(
+                output,
+                "GR{} #{:04X} {:>6}{}",
+                i, value, *value as i16, separator
+            )?;
+        }
+        writeln!(
+            output,
+            "SP  #{:04X}  OF {}  SF {}  ZF {}",
+            machine.sp(),
+            machine.of() as u8,
+            machine.sf() as u8,
+            machine.zf() as u8
+        )
+    }
+
+    /// 定義したラベルを番地に置き換えて、1行だけのプログラムにする
+    fn source(&self, line: &str) -> Result<String, ReplError> {
+        // ラベル欄を空けておく
+        let mut text = format!(" {}", line);
+        let (ast, _) = parser::parse(&text);
+        if let Some(statement) = ast.lines.first().and_then(|l| l.statement.as_ref()) {
+            if let Opecode::Start | Opecode::End | Opecode::Ds | Opecode::Dc = statement.opecode {
+                return Err(ReplError::NotInstruction);
+            }
+            // 後ろから置き換えれば前の位置はずれない
+            for operand in statement.operands.iter().rev() {
+                if let Value::Label(name) = &operand.value {
+                    let address = self.resolve(&Location::Label(name.clone()))?;
+                    let start = byte_index(&text, operand.span.start);
+                    let end = byte_index(&text, operand.span.end);
+                    text.replace_range(start..end, &format!("#{:04X}", address));
+                }
+            }
+        }
+        Ok(format!("REPL START\n{}\n END\n", text))
+    }
+
+    /// 命令を実行して、OUTの出力と書き換わった場所を表示する。
+    /// エラーのときはMachineを実行前のままにする
+    pub fn execute(
+        &mut self,
+        line: &str,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> Result<(), ReplError> {
+        let program = casl::assemble_at(&self.source(line)?, SCRATCH)
+            .map_err(|errors| ReplError::Assemble(errors[0].kind.clone()))?;
+        if self.machine.is_halted() {
+            return Err(ReplError::Halted);
+        }
+        let words = program
+            .kinds
+            .iter()
+            .take_while(|&&k| k == WordKind::Code)
+            .count();
+        // メモリの終わりを越えるものはアセンブラが受け付けないが、番地の計算は溢れないようにしておく
+        let code = SCRATCH as usize..SCRATCH as usize + words;
+
+        let mut before = self.machine.clone();
+        for (address, &word) in (SCRATCH..=u16::MAX).zip(&program.words) {
+            before = before.with_cell(Cell::Memory(address), word);
+        }
+        let mut machine = before.with_pr(SCRATCH);
+        // 書き込んだ場所。最初に書き込んだ順
+        let mut written = Vec::new();
+        let mut steps = 0;
+        while !machine.is_halted() && code.contains(&(machine.pr() as usize)) {
+            if steps == MAX_STEPS {
+                return Err(ReplError::StepLimit(MAX_STEPS));
+            }
+            if machine.needs_input() {
+                write!(output, "input> ")?;
+                output.flush()?;
+                // 入力の終わりならそのままINが終わりを読む
+                if let Some(line) = read_line(input)? {
+                    machine = machine.with_input(Some(line));
+                }
+            }
+            let (next, log) = machine.step_traced()?;
+            for access in log.iter().filter(|a| a.kind == AccessKind::Write) {
+                if !written.contains(&access.cell) {
+                    written.push(access.cell);
+                }
+            }
+            machine = next;
+            steps += 1;
+        }
+
+        for line in &machine.output()[before.output().len()..] {
+            writeln!(output, "{}", line)?;
+        }
+        // 作業領域と、SPより下の捨てたスタックは表示しない
+        let cells = written.into_iter().filter(|&cell| match cell {
+            Cell::Memory(address) => address >= machine.sp() && address < SCRATCH,
+            _ => true,
+        });
+        for cell in cells {
+            let (old, new) = (before.cell(cell), machine.cell(cell));
+            match cell {
+                _ if old == new => {}
+                Cell::Flag(_) => writeln!(output, "{:<5} {} -> {}", cell.to_string(), old, new)?,
+                _ => writeln!(
+                    output,
+                    "{:<5} #{:04X} -> #{:04X} ({})",
+                    self.describe(cell),
+                    old,
+                    new,
+                    new as i16
+                )?,
+            }
+        }
+        if machine.is_halted() {
+            writeln!(output, "Program halted")?;
+        } else if machine.pr() as usize != code.end {
+            writeln!(output, "Jumped to #{:04X}", machine.pr())?;
+        }
+        self.machine = machine;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::operations::RegisterNumber;
+
+    fn session(script: &str) -> (Repl, String) {
+        let mut repl = Repl::default();
+        let mut output = Vec::new();
+        let mut input = io::Cursor::new(script);
+        while let Some(line) = read_line(&mut input).unwrap() {
+            let result = match line.strip_prefix(':') {
+                Some(command) => repl.command(command, &mut output).map(|_| ()),
+                None => repl.execute(&line, &mut input, &mut output),
+            };
+            if let Err(e) = result {
+                writeln!(output, "{}", e).unwrap();
+            }
+        }
+        (repl, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn instructions() {
+        let (repl, output) = session(
+            "LAD GR1,5
+:label X #0200
+ST GR1,X
+SUBA GR1,=6
+CPA GR1,Y
+JMI #0300
+:set GR2 -1
+:poke X 1 #10
+:x X 2
+",
+        );
+        let machine = &repl.machine;
+        assert_eq!(machine.gr().get(RegisterNumber(1)), 0xFFFF);
+        assert_eq!(machine.gr().get(RegisterNumber(2)), 0xFFFF);
+        assert_eq!(machine.cell(Cell::Memory(0x201)), 0x10);
+        assert_eq!(
+            output,
+            "GR1   #0000 -> #0005 (5)
+#0200 (X) #0000 -> #0005 (5)
+GR1   #0005 -> #FFFF (-1)
+SF    0 -> 1
+Label `Y` is not defined
+Jumped to #0300
+#0200 (X)      #0001      1
+#0201          #0010     16
+"
+        );
+        assert!(!machine.zf());
+        assert!(machine.sf());
+        assert_eq!(machine.sp(), 0x100);
+    }
+
+    #[test]
+    fn too_long() {
+        // 命令とリテラルで258語になり、#FFFF を越える
+        let literal = "a".repeat(0x10000 - SCRATCH as usize);
+        let (repl, output) = session(&format!("LD GR1,='{}'\nLD GR2,='{}'\n", literal, "b"));
+        assert_eq!(
+            output.lines().next(),
+            Some("Program does not fit in memory")
+        );
+        assert_eq!(repl.machine.gr().get(RegisterNumber(1)), 0);
+        assert_eq!(repl.machine.gr().get(RegisterNumber(2)), 0x62);
+    }
+
+    #[test]
+    fn macros_and_halt() {
+        let (repl, output) = session(
+            ":label BUF #0200
+:label LEN #0210
+IN BUF,LEN
+hello
+OUT BUF,LEN
+RET
+NOP
+DC 3
+",
+        );
+        assert_eq!(repl.machine.output(), ["hello"]);
+        let lines: Vec<&str> = output.lines().collect();
+        // PUSHとPOPで戻したGR1, GR2は表示しない
+        assert_eq!(lines[0], "input> #0200 (BUF) #0000 -> #0068 (104)");
+        assert_eq!(lines[5], "#0210 (LEN) #0000 -> #0005 (5)");
+        assert_eq!(
+            &lines[6..],
+            [
+                "hello",
+                "Program halted",
+                "Machine has halted. Use :reset to start over",
+                "Only instructions can be executed. Use :poke to write data",
+            ]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! コンパクトなバイナリ形式。逆アセンブルは保存せず、読むときに語から求める
+//!
+//! 形式 (数値はすべてビッグエンディアン):
+//!
+//! | 内容 | 大きさ |
+//! | --- | --- |
+//! | マジック `FERSTRAC` | 8 byte |
+//! | バージョン (現在 1) | u16 |
+//!
+//! のあとに命令ごとに
+//!
+//! | 内容 | 大きさ |
+//! | --- | --- |
+//! | 命令の番号 | u64 |
+//! | PR | u16 |
+//! | 語数, 語 | u8, u16 × 語数 |
+//! | 変化したレジスタの数, 各 (番号 (0~7: GR, 8: SP), 前, 後) | u8, (u8, u16, u16) × 数 |
+//! | 変化したフラグの前と後 (bit0: OF, bit1: SF, bit2: ZF。変化しないものは0) | u8, u8 |
+//! | メモリへの書き込みの数, 各 (番地, 前, 後) | u16, (u16, u16, u16) × 数 |
+//! | 出力した行数, 各行の (バイト数 u32, UTF-8) | u8, ... |
+
+use super::{Change, Record, TraceError};
+use crate::casl::disasm::disassemble;
+use crate::core::access::Cell;
+use crate::core::operations::RegisterNumber;
+use crate::core::register::Flag;
+use std::io::{self, BufRead};
+
+pub const MAGIC: &[u8; 8] = b"FERSTRAC";
+const VERSION: u16 = 1;
+const SP: u8 = 8;
+
+fn flag_bit(flag: Flag) -> u8 {
+    match flag {
+        Flag::Overflow => 1,
+        Flag::Sign => 2,
+        Flag::Zero => 4,
+    }
+}
+
+pub fn write_header(output: &mut impl io::Write) -> io::Result<()> {
+    output.write_all(MAGIC)?;
+    output.write_all(&VERSION.to_be_bytes())
+}
+
+pub fn write(output: &mut impl io::Write, record: &Record) -> io::Result<()> {
+    output.write_all(&record.step.to_be_bytes())?;
+    output.write_all(&record.pr.to_be_bytes())?;
+    output.write_all(&[record.words.len() as u8])?;
+    for word in &record.words {
+        output.write_all(&word.to_be_bytes())?;
+    }
+
+    output.write_all(&[record.registers.len() as u8])?;
+    for c in &record.registers {
+        let number = match c.cell {
+            Cell::Register(r) => r.0,
+            _ => SP,
+        };
+        output.write_all(&[number])?;
+        output.write_all(&c.old.to_be_bytes())?;
+        output.write_all(&c.new.to_be_bytes())?;
+    }
+
+    let (mut old, mut new) = (0, 0);
+    for c in &record.flags {
+        if let Cell::Flag(flag) = c.cell {
+            old |= flag_bit(flag) * c.old as u8;
+            new |= flag_bit(flag) * c.new as u8;
+        }
+    }
+    output.write_all(&[old, new])?;
+
+    output.write_all(&(record.memory.len() as u16).to_be_bytes())?;
+    for c in &record.memory {
+        if let Cell::Memory(address) = c.cell {
+            output.write_all(&address.to_be_bytes())?;
+        }
+        output.write_all(&c.old.to_be_bytes())?;
+        output.write_all(&c.new.to_be_bytes())?;
+    }
+
+    output.write_all(&[record.output.len() as u8])?;
+    for line in &record.output {
+        output.write_all(&(line.len() as u32).to_be_bytes())?;
+        output.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_bytes<const N: usize>(input: &mut impl io::Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u8(input: &mut impl io::Read) -> io::Result<u8> {
+    Ok(read_bytes::<1>(input)?[0])
+}
+
+fn read_u16(input: &mut impl io::Read) -> io::Result<u16> {
+    Ok(u16::from_be_bytes(read_bytes(input)?))
+}
+
+fn read_record(input: &mut impl io::Read, step: u64) -> Result<Record, TraceError> {
+    let pr = read_u16(input)?;
+    let words = (0..read_u8(input)?)
+        .map(|_| read_u16(input))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut registers = Vec::new();
+    for _ in 0..read_u8(input)? {
+        let number = read_u8(input)?;
+        let cell = match number {
+            SP => Cell::Sp,
+            n => Cell::Register(
+                RegisterNumber::new(n as u16).map_err(|_| TraceError::InvalidRegister(n))?,
+            ),
+        };
+        registers.push(Change {
+            cell,
+            old: read_u16(input)?,
+            new: read_u16(input)?,
+        });
+    }
+
+    let [old, new] = read_bytes(input)?;
+    let flags = Flag::ALL
+        .iter()
+        .filter(|&&f| (old ^ new) & flag_bit(f) != 0)
+        .map(|&f| Change {
+            cell: Cell::Flag(f),
+            old: (old & flag_bit(f) != 0) as u16,
+            new: (new & flag_bit(f) != 0) as u16,
+        })
+        .collect();
+
+    let mut memory = Vec::new();
+    for _ in 0..read_u16(input)? {
+        memory.push(Change {
+            cell: Cell::Memory(read_u16(input)?),
+            old: read_u16(input)?,
+            new: read_u16(input)?,
+        });
+    }
+
+    let mut output = Vec::new();
+    for _ in 0..read_u8(input)? {
+        let len = u32::from_be_bytes(read_bytes(input)?);
+        let mut buf = vec![0; len as usize];
+        input.read_exact(&mut buf)?;
+        output.push(String::from_utf8(buf)?);
+    }
+
+    Ok(Record {
+        step,
+        pr,
+        disasm: disassemble(&words).0,
+        words,
+        registers,
+        flags,
+        memory,
+        output,
+    })
+}
+
+/// マジックから読む
+pub fn read(input: &mut impl BufRead) -> Result<Vec<Record>, TraceError> {
+    read_bytes::<8>(input)?;
+    let version = read_u16(input)?;
+    if version != VERSION {
+        return Err(TraceError::UnsupportedVersion(version));
+    }
+    let mut records = Vec::new();
+    // 記録の区切りで入力が終わったら最後
+    while !input.fill_buf()?.is_empty() {
+        let step = u64::from_be_bytes(read_bytes(input)?);
+        records.push(read_record(input, step)?);
+    }
+    Ok(records)
+}
@@ -0,0 +1,332 @@
+//! 2つのトレースを比べて、最初に食い違った命令を探す
+
+use super::{Change, Record};
+use crate::core::access::Cell;
+use std::fmt;
+
+/// 何をそろえて比べるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// n番目の命令どうしで、PR・命令・レジスタ・フラグ・メモリへの書き込み・出力を比べる
+    Steps,
+    /// メモリへの書き込みと出力の列だけを比べる。命令の並びが違うプログラムどうしで使う
+    Effects,
+}
+
+/// 外から見える出来事
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    /// (番地, 書いた値)
+    Write(u16, u16),
+    Output(String),
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Effect::Write(address, value) => write!(f, "#{:04X} <- #{:04X}", address, value),
+            Effect::Output(line) => write!(f, "OUT {:?}", line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    Pr(u16, u16),
+    Instruction(String, String),
+    /// レジスタかフラグの実行後の値。`None` は変化しなかった
+    Cell(Cell, Option<u16>, Option<u16>),
+    Memory(Vec<Change>, Vec<Change>),
+    Output(Vec<String>, Vec<String>),
+    /// `Effects` での食い違い。`None` はその側では出来事がもうない
+    Effect(Option<Effect>, Option<Effect>),
+    /// 片方だけが先に終わった。`true` なら左が終わった
+    Ended(bool),
+}
+
+fn value(value: &Option<u16>) -> String {
+    match value {
+        Some(v) => format!("#{:04X}", v),
+        None => "unchanged".to_string(),
+    }
+}
+
+fn writes(changes: &[Change]) -> String {
+    let writes: Vec<String> = changes
+        .iter()
+        .map(|c| format!("{} <- #{:04X}", c.cell, c.new))
+        .collect();
+    if writes.is_empty() {
+        "none".to_string()
+    } else {
+        writes.join(", ")
+    }
+}
+
+fn effect(effect: &Option<Effect>) -> String {
+    match effect {
+        Some(e) => e.to_string(),
+        None => "nothing".to_string(),
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Pr(l, r) => write!(f, "PR: #{:04X} / #{:04X}", l, r),
+            Difference::Instruction(l, r) => write!(f, "instruction: {} / {}", l, r),
+            Difference::Cell(cell, l, r) => write!(f, "{}: {} / {}", cell, value(l), value(r)),
+            Difference::Memory(l, r) => write!(f, "memory: {} / {}", writes(l), writes(r)),
+            Difference::Output(l, r) => write!(f, "output: {:?} / {:?}", l, r),
+            Difference::Effect(l, r) => write!(f, "{} / {}", effect(l), effect(r)),
+            Difference::Ended(true) => write!(f, "left trace ended"),
+            Difference::Ended(false) => write!(f, "right trace ended"),
+        }
+    }
+}
+
+/// 最初の食い違い。位置は記録の添字で、終わっていたら記録の数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub left: usize,
+    pub right: usize,
+    pub differences: Vec<Difference>,
+}
+
+/// 食い違いがなければ `None`
+pub fn diff(left: &[Record], right: &[Record], mode: Mode) -> Option<Divergence> {
+    match mode {
+        Mode::Steps => diff_steps(left, right),
+        Mode::Effects => diff_effects(left, right),
+    }
+}
+
+fn diff_steps(left: &[Record], right: &[Record]) -> Option<Divergence> {
+    for i in 0..left.len().max(right.len()) {
+        let differences = match (left.get(i), right.get(i)) {
+            (Some(l), Some(r)) => compare(l, r),
+            (l, _) => vec![Difference::Ended(l.is_none())],
+        };
+        if !differences.is_empty() {
+            return Some(Divergence {
+                left: i,
+                right: i,
+                differences,
+            });
+        }
+    }
+    None
+}
+
+fn compare(l: &Record, r: &Record) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    if l.pr != r.pr {
+        differences.push(Difference::Pr(l.pr, r.pr));
+    }
+    if l.words != r.words {
+        differences.push(Difference::Instruction(l.disasm.clone(), r.disasm.clone()));
+    }
+    let new_value =
+        |changes: &[Change], cell| changes.iter().rev().find(|c| c.cell == cell).map(|c| c.new);
+    let mut cells: Vec<Cell> = Vec::new();
+    for c in l
+        .registers
+        .iter()
+        .chain(&l.flags)
+        .chain(&r.registers)
+        .chain(&r.flags)
+    {
+        if !cells.contains(&c.cell) {
+            cells.push(c.cell);
+        }
+    }
+    for cell in cells {
+        let (lv, rv) = match cell {
+            Cell::Flag(_) => (new_value(&l.flags, cell), new_value(&r.flags, cell)),
+            _ => (new_value(&l.registers, cell), new_value(&r.registers, cell)),
+        };
+        if lv != rv {
+            differences.push(Difference::Cell(cell, lv, rv));
+        }
+    }
+    let written = |changes: &[Change]| -> Vec<(Cell, u16)> {
+        changes.iter().map(|c| (c.cell, c.new)).collect()
+    };
+    if written(&l.memory) != written(&r.memory) {
+        differences.push(Difference::Memory(l.memory.clone(), r.memory.clone()));
+    }
+    if l.output != r.output {
+        differences.push(Difference::Output(l.output.clone(), r.output.clone()));
+    }
+    differences
+}
+
+/// 出来事を (記録の添字, 出来事) の列にする
+fn effects(records: &[Record]) -> Vec<(usize, Effect)> {
+    let mut effects = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        for c in &record.memory {
+            if let Cell::Memory(address) = c.cell {
+                effects.push((i, Effect::Write(address, c.new)));
+            }
+        }
+        effects.extend(
+            record
+                .output
+                .iter()
+                .map(|line| (i, Effect::Output(line.clone()))),
+        );
+    }
+    effects
+}
+
+fn diff_effects(left: &[Record], right: &[Record]) -> Option<Divergence> {
+    let (l, r) = (effects(left), effects(right));
+    for i in 0..l.len().max(r.len()) {
+        let (le, re) = (l.get(i), r.get(i));
+        if le.map(|e| &e.1) == re.map(|e| &e.1) {
+            continue;
+        }
+        return Some(Divergence {
+            left: le.map_or(left.len(), |e| e.0),
+            right: re.map_or(right.len(), |e| e.0),
+            differences: vec![Difference::Effect(
+                le.map(|e| e.1.clone()),
+                re.map(|e| e.1.clone()),
+            )],
+        });
+    }
+    None
+}
+
+/// 食い違いの前 `context` 命令から食い違った命令までを逆アセンブルして並べる
+pub fn render(
+    left: &[Record],
+    right: &[Record],
+    divergence: &Divergence,
+    context: usize,
+) -> String {
+    let step = |records: &[Record], i: usize| {
+        records
+            .get(i)
+            .map_or("end".to_string(), |r| r.step.to_string())
+    };
+    let mut text = format!(
+        "Traces diverge at step {} (left) / {} (right)\n",
+        step(left, divergence.left),
+        step(right, divergence.right)
+    );
+    for d in &divergence.differences {
+        text.push_str(&format!("  {}\n", d));
+    }
+    for (name, records, at) in [
+        ("left", left, divergence.left),
+        ("right", right, divergence.right),
+    ] {
+        text.push_str(&format!("{}:\n", name));
+        for (i, record) in records
+            .iter()
+            .enumerate()
+            .take(at + 1)
+            .skip(at.saturating_sub(context))
+        {
+            let marker = if i == at { ">" } else { " " };
+            text.push_str(&format!(
+                "{} {:>6}  #{:04X}  {}\n",
+                marker, record.step, record.pr, record.disasm
+            ));
+        }
+        if at >= records.len() {
+            text.push_str(">    (end of trace)\n");
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Trace;
+    use super::*;
+    use crate::casl;
+
+    fn records(source: &str, input: &str) -> Vec<Record> {
+        let machine = casl::assemble(source)
+            .unwrap()
+            .load()
+            .with_input(vec![input.to_string()]);
+        Trace::new(machine).collect::<Result<_, _>>().unwrap()
+    }
+
+    // 1文字目を出力する
+    const REFERENCE: &str = "MAIN  START
+      IN    BUF,LEN
+      LAD   GR1,1
+      ST    GR1,LEN
+      OUT   BUF,LEN
+      RET
+BUF   DS    256
+LEN   DS    1
+      END";
+
+    // 2文字出力してしまう
+    const WRONG: &str = "MAIN  START
+      IN    BUF,LEN
+      LAD   GR1,2
+      ST    GR1,LEN
+      OUT   BUF,LEN
+      RET
+BUF   DS    256
+LEN   DS    1
+      END";
+
+    // 命令の並びは違うが同じことをする
+    const OTHER: &str = "MAIN  START
+      IN    BUF,LEN
+      LD    GR1,=1
+      ST    GR1,LEN
+      OUT   BUF,LEN
+      RET
+BUF   DS    256
+LEN   DS    1
+      END";
+
+    #[test]
+    fn steps() {
+        let reference = records(REFERENCE, "abc");
+        assert_eq!(diff(&reference, &reference, Mode::Steps), None);
+
+        let wrong = records(WRONG, "abc");
+        let d = diff(&reference, &wrong, Mode::Steps).unwrap();
+        // IN マクロの7命令のあとの LAD
+        assert_eq!(d.left, 7);
+        assert_eq!(d.differences.len(), 2);
+        assert_eq!(d.differences[1].to_string(), "GR1: #0001 / #0002");
+
+        let text = render(&reference, &wrong, &d, 2);
+        assert!(text.starts_with("Traces diverge at step 8 (left) / 8 (right)\n"));
+        assert!(text.contains(">      8  #010C  LAD   GR1,#0001\n"));
+        assert!(text.contains("       7  #010B  POP   GR1\n"));
+        assert!(!text.contains("     5  "));
+
+        let short = &reference[..5];
+        let d = diff(short, &reference, Mode::Steps).unwrap();
+        assert_eq!(d.differences, vec![Difference::Ended(true)]);
+        assert!(render(short, &reference, &d, 1).contains(">    (end of trace)\n"));
+    }
+
+    #[test]
+    fn effects() {
+        let reference = records(REFERENCE, "abc");
+        let other = records(OTHER, "abc");
+        assert!(diff(&reference, &other, Mode::Steps).is_some());
+        assert_eq!(diff(&reference, &other, Mode::Effects), None);
+
+        let wrong = records(WRONG, "abc");
+        let d = diff(&reference, &wrong, Mode::Effects).unwrap();
+        assert_eq!(d.left, 8);
+        assert_eq!(
+            d.differences[0].to_string(),
+            "#021D <- #0001 / #021D <- #0002"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! JSON Lines の形式。1行が1命令で、例えば
+//!
+//! ```text
+//! {"disasm":"ST    GR1,#0106","flags":{},"memory":[{"address":262,"new":3,"old":0}],"output":[],"pr":258,"registers":{},"step":2,"words":[4368,262]}
+//! ```
+//!
+//! `registers` と `flags` は名前 (`GR1`, `SP`, `ZF`) から変化前後の値の組への対応
+
+use super::{Change, Record, TraceError};
+use crate::core::access::Cell;
+use crate::core::operations::RegisterNumber;
+use crate::core::register::Flag;
+use serde_json::{json, Map, Value};
+use std::io::{self, BufRead};
+
+fn changes(changes: &[Change]) -> Value {
+    let map: Map<String, Value> = changes
+        .iter()
+        .map(|c| (c.cell.to_string(), json!([c.old, c.new])))
+        .collect();
+    Value::Object(map)
+}
+
+pub fn write(output: &mut impl io::Write, record: &Record) -> io::Result<()> {
+    let memory: Vec<Value> = record
+        .memory
+        .iter()
+        .map(|c| {
+            let address = match c.cell {
+                Cell::Memory(address) => address,
+                _ => unreachable!("only memory writes are listed"),
+            };
+            json!({ "address": address, "old": c.old, "new": c.new })
+        })
+        .collect();
+    let line = json!({
+        "step": record.step,
+        "pr": record.pr,
+        "words": record.words,
+        "disasm": record.disasm,
+        "registers": changes(&record.registers),
+        "flags": changes(&record.flags),
+        "memory": memory,
+        "output": record.output,
+    });
+    writeln!(output, "{}", line)
+}
+
+/// `GR1`, `SP`, `OF` など
+fn cell_from_name(name: &str) -> Option<Cell> {
+    if name == "SP" {
+        return Some(Cell::Sp);
+    }
+    if let Some(flag) = Flag::from_name(name) {
+        return Some(Cell::Flag(flag));
+    }
+    let n = name.strip_prefix("GR")?.parse::<u16>().ok()?;
+    RegisterNumber::new(n).ok().map(Cell::Register)
+}
+
+fn word(value: &Value) -> Option<u16> {
+    value
+        .as_u64()
+        .filter(|&n| n <= u16::MAX as u64)
+        .map(|n| n as u16)
+}
+
+fn parse_changes(value: &Value) -> Option<Vec<Change>> {
+    value
+        .as_object()?
+        .iter()
+        .map(|(name, pair)| {
+            Some(Change {
+                cell: cell_from_name(name)?,
+                old: word(pair.get(0)?)?,
+                new: word(pair.get(1)?)?,
+            })
+        })
+        .collect()
+}
+
+fn parse(value: &Value) -> Option<Record> {
+    let memory = value["memory"]
+        .as_array()?
+        .iter()
+        .map(|m| {
+            Some(Change {
+                cell: Cell::Memory(word(&m["address"])?),
+                old: word(&m["old"])?,
+                new: word(&m["new"])?,
+            })
+        })
+        .collect::<Option<_>>()?;
+    let output = value["output"]
+        .as_array()?
+        .iter()
+        .map(|line| line.as_str().map(str::to_string))
+        .collect::<Option<_>>()?;
+    Some(Record {
+        step: value["step"].as_u64()?,
+        pr: word(&value["pr"])?,
+        words: value["words"]
+            .as_array()?
+            .iter()
+            .map(word)
+            .collect::<Option<_>>()?,
+        disasm: value["disasm"].as_str()?.to_string(),
+        registers: parse_changes(&value["registers"])?,
+        flags: parse_changes(&value["flags"])?,
+        memory,
+        output,
+    })
+}
+
+/// 空行は読み飛ばす
+pub fn read(input: &mut impl BufRead) -> Result<Vec<Record>, TraceError> {
+    let mut records = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(&line)
+            .map_err(|e| TraceError::InvalidJson(i + 1, e.to_string()))?;
+        let record = parse(&value)
+            .ok_or_else(|| TraceError::InvalidJson(i + 1, "Not a trace record".to_string()))?;
+        records.push(record);
+    }
+    Ok(records)
+}
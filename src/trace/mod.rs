@@ -0,0 +1,255 @@
+//! 実行トレース。1命令ごとに何が変わったかを記録し、JSON Lines かバイナリで読み書きする
+
+pub mod binary;
+pub mod diff;
+pub mod json;
+
+use crate::casl::disasm::disassemble;
+use crate::core::access::{AccessKind, Cell};
+use crate::core::machine::{Machine, StepError};
+use std::io::{self, BufRead};
+
+/// 1つの場所の値の変化
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub cell: Cell,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// 1命令分の記録
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// 1始まりの命令の番号
+    pub step: u64,
+    pub pr: u16,
+    /// 命令の語 (1語か2語)
+    pub words: Vec<u16>,
+    pub disasm: String,
+    /// 値が変わったGR0~GR7とSP
+    pub registers: Vec<Change>,
+    /// 値が変わったフラグ (0か1)
+    pub flags: Vec<Change>,
+    /// メモリへの書き込み。同じ値を書いたものも含む
+    pub memory: Vec<Change>,
+    /// この命令がOUTで出力した行
+    pub output: Vec<String>,
+}
+
+impl Record {
+    /// `machine` で1命令実行し、実行後のMachineと記録を返す
+    pub fn step(machine: &Machine, step: u64) -> Result<(Machine, Record), StepError> {
+        let pr = machine.pr();
+        let head = [
+            machine.cell(Cell::Memory(pr)),
+            machine.cell(Cell::Memory(pr.wrapping_add(1))),
+        ];
+        let (disasm, len) = disassemble(&head);
+
+        let (next, log) = machine.step_traced()?;
+        let mut record = Record {
+            step,
+            pr,
+            words: head[..len].to_vec(),
+            disasm,
+            registers: Vec::new(),
+            flags: Vec::new(),
+            memory: Vec::new(),
+            output: next.output()[machine.output().len()..].to_vec(),
+        };
+        for access in log.iter().filter(|a| a.kind == AccessKind::Write) {
+            let change = Change {
+                cell: access.cell,
+                old: access.old,
+                new: access.new,
+            };
+            match access.cell {
+                Cell::Memory(_) => record.memory.push(change),
+                _ if access.old == access.new => {}
+                Cell::Flag(_) => record.flags.push(change),
+                Cell::Register(_) | Cell::Sp => record.registers.push(change),
+            }
+        }
+        Ok((next, record))
+    }
+}
+
+/// 停止するまで1命令ずつ実行して記録を返す
+pub struct Trace {
+    machine: Machine,
+    step: u64,
+    failed: bool,
+}
+
+impl Trace {
+    pub fn new(machine: Machine) -> Trace {
+        Trace {
+            machine,
+            step: 0,
+            failed: false,
+        }
+    }
+}
+
+impl Iterator for Trace {
+    type Item = Result<Record, StepError>;
+
+    /// 停止したか、エラーを返したあとは `None`
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.machine.is_halted() {
+            return None;
+        }
+        match Record::step(&self.machine, self.step + 1) {
+            Ok((machine, record)) => {
+                self.machine = machine;
+                self.step += 1;
+                Some(Ok(record))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// 1行に1命令のJSON
+    Json,
+    /// `binary` の形式
+    Binary,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" | "jsonl" => Some(Format::Json),
+            "binary" | "bin" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TraceError {
+    #[error("{0}")]
+    IOError(#[from] io::Error),
+    #[error("Line {0}: {1}")]
+    InvalidJson(usize, String),
+    #[error("Trace version {0} is not supported")]
+    UnsupportedVersion(u16),
+    #[error("Invalid register number {0}")]
+    InvalidRegister(u8),
+    #[error("{0}")]
+    InvalidText(#[from] std::string::FromUtf8Error),
+}
+
+/// 記録を `format` で書き出す
+pub struct Writer<W: io::Write> {
+    output: W,
+    format: Format,
+    started: bool,
+}
+
+impl<W: io::Write> Writer<W> {
+    pub fn new(output: W, format: Format) -> Writer<W> {
+        Writer {
+            output,
+            format,
+            started: false,
+        }
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self.format {
+            Format::Json => json::write(&mut self.output, record),
+            Format::Binary => {
+                if !self.started {
+                    binary::write_header(&mut self.output)?;
+                    self.started = true;
+                }
+                binary::write(&mut self.output, record)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// トレースをすべて読む。形式は先頭で判断する
+pub fn read(input: &mut impl BufRead) -> Result<Vec<Record>, TraceError> {
+    if input.fill_buf()?.starts_with(binary::MAGIC) {
+        binary::read(input)
+    } else {
+        json::read(input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::casl;
+    use crate::core::operations::RegisterNumber;
+
+    const SOURCE: &str = "MAIN  START
+      LD    GR1,=3
+      ST    GR1,X
+      OUT   MSG,LEN
+      RET
+X     DS    1
+MSG   DC    'OK'
+LEN   DC    2
+      END";
+
+    fn records() -> Vec<Record> {
+        let machine = casl::assemble(SOURCE).unwrap().load();
+        Trace::new(machine).collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn changes() {
+        let records = records();
+        let ld = &records[0];
+        assert_eq!((ld.step, ld.pr, ld.words.len()), (1, 0x100, 2));
+        assert!(ld.disasm.starts_with("LD    GR1,#"));
+        let gr1 = Cell::Register(RegisterNumber(1));
+        assert_eq!(
+            ld.registers,
+            vec![Change {
+                cell: gr1,
+                old: 0,
+                new: 3
+            }]
+        );
+        // LDはOFを0にするが、もともと0なので変化なし
+        assert!(ld.flags.is_empty());
+
+        let st = &records[1];
+        assert!(st.registers.is_empty());
+        assert_eq!(st.memory.len(), 1);
+        assert_eq!((st.memory[0].old, st.memory[0].new), (0, 3));
+
+        let out = records.iter().find(|r| !r.output.is_empty()).unwrap();
+        assert_eq!(out.output, vec!["OK".to_string()]);
+        let last = records.last().unwrap();
+        assert_eq!(last.disasm, "RET");
+        assert_eq!(records.len(), 10);
+    }
+
+    #[test]
+    fn round_trip() {
+        let records = records();
+        for &format in &[Format::Json, Format::Binary] {
+            let mut buf = Vec::new();
+            let mut writer = Writer::new(&mut buf, format);
+            for record in &records {
+                writer.write(record).unwrap();
+            }
+            assert_eq!(read(&mut io::Cursor::new(buf)).unwrap(), records);
+        }
+        assert!(read(&mut io::Cursor::new(Vec::new())).unwrap().is_empty());
+    }
+}
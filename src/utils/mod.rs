@@ -1 +1,2 @@
+pub mod message;
 pub mod to_pairs;
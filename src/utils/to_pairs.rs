@@ -15,14 +15,10 @@ impl<I: Iterator> Iterator for ToPair<I> {
     }
 }
 
-pub fn to_pairs<I: Iterator>(i: I) -> ToPair<I> {
-    ToPair::new(i)
-}
-
 pub trait ToPairBlanket: Iterator {
     ///
-    ///```
-    ///assert_eq!(vec![1,2,3,4].to_pairs().collect(), vec![(1,2), (3,4)])
+    ///```ignore
+    ///assert_eq!(vec![1,2,3,4].into_iter().to_pairs().collect::<Vec<_>>(), vec![(1,2), (3,4)])
     ///```
     ///
     fn to_pairs(&mut self) -> ToPair<&mut Self> {
@@ -30,6 +26,8 @@ pub trait ToPairBlanket: Iterator {
     }
 }
 
+impl<I> ToPairBlanket for I where I: Iterator {}
+
 #[cfg(test)]
 mod test {
     use super::ToPairBlanket;
@@ -44,5 +42,3 @@ mod test {
         )
     }
 }
-
-impl<I> ToPairBlanket for I where I: Iterator {}
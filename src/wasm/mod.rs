@@ -0,0 +1,231 @@
+//! wasm-bindgen でJavaScriptに公開するAPI。`wasm` フィーチャーで有効になる
+//!
+//! ```js
+//! const program = assemble(source);
+//! const machine = new Machine(program);
+//! machine.onOutput((line) => console.log(line));
+//! machine.onInput(() => prompt());  // null か undefined なら入力の終わり
+//! machine.addBreakpoint(program.addressOf("LOOP"));
+//! while (machine.run(100000) !== "halted") { ... }
+//! ```
+
+use crate::casl::{self, Program};
+use crate::core::runner::{Io, Runner};
+use js_sys::Function;
+use wasm_bindgen::prelude::*;
+
+/// アセンブルした結果
+#[wasm_bindgen(js_name = Program)]
+pub struct WasmProgram {
+    program: Program,
+}
+
+/// ソースをアセンブルする。エラーは1行に1つずつ `行:列: 内容` の形で投げる
+#[wasm_bindgen]
+pub fn assemble(source: &str) -> Result<WasmProgram, JsError> {
+    match casl::assemble(source) {
+        Ok(program) => Ok(WasmProgram { program }),
+        Err(errors) => {
+            let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            Err(JsError::new(&messages.join("\n")))
+        }
+    }
+}
+
+#[wasm_bindgen(js_class = Program)]
+impl WasmProgram {
+    /// 機械語。先頭は `origin` 番地
+    pub fn words(&self) -> Vec<u16> {
+        self.program.words.clone()
+    }
+
+    pub fn origin(&self) -> u16 {
+        self.program.origin
+    }
+
+    pub fn entry(&self) -> u16 {
+        self.program.entry
+    }
+
+    /// ラベルの番地
+    #[wasm_bindgen(js_name = addressOf)]
+    pub fn address_of(&self, label: &str) -> Option<u16> {
+        self.program.symbol(label).map(|s| s.address)
+    }
+
+    /// 番地の語を出力したソースの行 (0始まり)
+    #[wasm_bindgen(js_name = lineOf)]
+    pub fn line_of(&self, address: u16) -> Option<usize> {
+        self.program.line_of(address)
+    }
+}
+
+/// コールバックでINとOUTをする
+struct Callbacks<'a> {
+    input: Option<&'a Function>,
+    output: Option<&'a Function>,
+}
+
+impl Io for Callbacks<'_> {
+    fn read_line(&mut self) -> Option<String> {
+        self.input?.call0(&JsValue::NULL).ok()?.as_string()
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let Some(output) = self.output {
+            // コールバックの例外は実行を止めない
+            let _ = output.call1(&JsValue::NULL, &JsValue::from_str(line));
+        }
+    }
+}
+
+#[wasm_bindgen(js_name = Machine)]
+pub struct WasmMachine {
+    runner: Runner,
+    input: Option<Function>,
+    output: Option<Function>,
+}
+
+#[wasm_bindgen(js_class = Machine)]
+impl WasmMachine {
+    /// プログラムを読み込む
+    #[wasm_bindgen(constructor)]
+    pub fn new(program: &WasmProgram) -> WasmMachine {
+        WasmMachine {
+            runner: Runner::new(program.program.load()),
+            input: None,
+            output: None,
+        }
+    }
+
+    /// INで入力が必要になるたびに引数なしで呼ぶ。文字列以外を返すと入力の終わり
+    #[wasm_bindgen(js_name = onInput)]
+    pub fn on_input(&mut self, callback: Function) {
+        self.input = Some(callback);
+    }
+
+    /// OUTの1行ごとに呼ぶ
+    #[wasm_bindgen(js_name = onOutput)]
+    pub fn on_output(&mut self, callback: Function) {
+        self.output = Some(callback);
+    }
+
+    /// 1命令実行する
+    pub fn step(&mut self) -> Result<(), JsError> {
+        let mut io = Callbacks {
+            input: self.input.as_ref(),
+            output: self.output.as_ref(),
+        };
+        self.runner
+            .step(&mut io)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// 停止かブレークポイントまで最大 `max_steps` 命令実行する。
+    /// 止まった理由 `"halted"`, `"breakpoint"`, `"limit"` を返す
+    pub fn run(&mut self, max_steps: u32) -> Result<String, JsError> {
+        let mut io = Callbacks {
+            input: self.input.as_ref(),
+            output: self.output.as_ref(),
+        };
+        match self.runner.run(max_steps as u64, &mut io) {
+            Ok(stop) => Ok(stop.name().to_string()),
+            Err(e) => Err(JsError::new(&e.to_string())),
+        }
+    }
+
+    #[wasm_bindgen(js_name = addBreakpoint)]
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.runner.breakpoints.insert(address);
+    }
+
+    #[wasm_bindgen(js_name = removeBreakpoint)]
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.runner.breakpoints.remove(&address)
+    }
+
+    /// GR0~GR7
+    pub fn registers(&self) -> Vec<u16> {
+        self.runner.machine.gr().values().to_vec()
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.runner.machine.sp()
+    }
+
+    pub fn pr(&self) -> u16 {
+        self.runner.machine.pr()
+    }
+
+    pub fn of(&self) -> bool {
+        self.runner.machine.of()
+    }
+
+    pub fn sf(&self) -> bool {
+        self.runner.machine.sf()
+    }
+
+    pub fn zf(&self) -> bool {
+        self.runner.machine.zf()
+    }
+
+    pub fn halted(&self) -> bool {
+        self.runner.machine.is_halted()
+    }
+
+    /// `start` 番地から `len` 語。メモリの終わりで折り返す
+    pub fn memory(&self, start: u16, len: u16) -> Vec<u16> {
+        let mem = &self.runner.machine.mem;
+        (0..len)
+            .map(|i| mem.get(start.wrapping_add(i)).unwrap_or(0))
+            .collect()
+    }
+}
+
+/// JavaScriptを呼ばない部分だけを、ネイティブかwasmtime (wasm32-wasip1) で確かめる。
+/// コールバックとエラーはブラウザで tests/wasm.rs
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// GR1を3まで数えてから出力する
+    const COUNT: &str = "MAIN  START
+      LAD   GR1,0
+LOOP  LAD   GR1,1,GR1
+      CPA   GR1,=3
+      JMI   LOOP
+      OUT   MSG,LEN
+      RET
+MSG   DC    'ok'
+LEN   DC    2
+      END";
+
+    #[test]
+    fn run_and_breakpoints() {
+        let program = assemble(COUNT).unwrap();
+        let mut machine = WasmMachine::new(&program);
+        let start = program.entry();
+        let lp = program.address_of("LOOP").unwrap();
+
+        // 出発点のブレークポイントでは止まらない
+        machine.add_breakpoint(start);
+        machine.add_breakpoint(lp);
+        assert_eq!(machine.run(1000).unwrap(), "breakpoint");
+        assert_eq!((machine.pr(), machine.registers()[1]), (lp, 0));
+        assert_eq!(machine.run(1000).unwrap(), "breakpoint");
+        assert_eq!(machine.registers()[1], 1);
+
+        assert!(machine.remove_breakpoint(lp));
+        assert!(!machine.remove_breakpoint(lp));
+        assert_eq!(machine.run(2).unwrap(), "limit");
+        assert!(!machine.halted());
+        assert_eq!(machine.run(1000).unwrap(), "halted");
+        assert!(machine.halted());
+        assert_eq!(machine.registers()[1], 3);
+        assert_eq!(
+            machine.memory(program.address_of("MSG").unwrap(), 2),
+            [111, 107]
+        );
+        assert_eq!(machine.memory(0xFFFF, 3).len(), 3);
+    }
+}
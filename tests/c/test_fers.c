@@ -0,0 +1,86 @@
+/* Exercises include/fers.h. Built and run by tests/ffi.rs */
+#include <assert.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "fers.h"
+
+static const char *ECHO =
+    "MAIN  START\n"
+    "LOOP  IN    BUF,LEN\n"
+    "      LD    GR1,LEN\n"
+    "      JMI   FIN\n"
+    "      OUT   BUF,LEN\n"
+    "      JUMP  LOOP\n"
+    "FIN   RET\n"
+    "BUF   DS    16\n"
+    "LEN   DS    1\n"
+    "      END\n";
+
+struct lines {
+    const char **input;
+    int next;
+    char output[256];
+};
+
+static int read_line(void *user, char *buffer, size_t capacity) {
+    struct lines *lines = user;
+    const char *line = lines->input[lines->next];
+    if (line == NULL) {
+        return -1;
+    }
+    lines->next++;
+    size_t len = strlen(line);
+    assert(len <= capacity);
+    memcpy(buffer, line, len);
+    return (int)len;
+}
+
+static void write_line(void *user, const char *line, size_t len) {
+    struct lines *lines = user;
+    assert(strlen(line) == len);
+    strcat(lines->output, line);
+    strcat(lines->output, "\n");
+}
+
+int main(void) {
+    char error[256];
+    assert(fers_load("MAIN START\n LD GR9,1\n END\n", error, sizeof error) == NULL);
+    assert(strstr(error, "2:") == error);
+
+    FersMachine *machine = fers_load(ECHO, error, sizeof error);
+    assert(machine != NULL);
+    const char *input[] = {"hello", "C", NULL};
+    struct lines lines = {input, 0, ""};
+    fers_set_input(machine, read_line, &lines);
+    fers_set_output(machine, write_line, &lines);
+
+    assert(fers_get_register(machine, FERS_PR) == 0x100);
+    assert(fers_step(machine) == FERS_OK);
+    assert(fers_get_register(machine, FERS_SP) == 0xFF);
+
+    /* FIN is after 6 instructions of 2 words and IN, OUT of 12 words each */
+    uint16_t fin = 0x100 + 12 + 2 + 2 + 12 + 2;
+    fers_add_breakpoint(machine, fin);
+    assert(fers_run(machine, 1000) == FERS_BREAKPOINT);
+    assert(fers_get_register(machine, FERS_PR) == fin);
+    assert(strcmp(lines.output, "hello\nC\n") == 0);
+    assert(fers_get_register(machine, 1) == 0xFFFF);
+    assert(fers_get_register(machine, FERS_SF) == 1);
+
+    assert(fers_set_register(machine, 3, 42) == FERS_OK);
+    assert(fers_get_register(machine, 3) == 42);
+    assert(fers_set_register(machine, 99, 0) == FERS_ERROR);
+    fers_write_memory(machine, 0x8000, 0xBEEF);
+    assert(fers_read_memory(machine, 0x8000) == 0xBEEF);
+
+    assert(fers_remove_breakpoint(machine, fin));
+    assert(fers_run(machine, 1000) == FERS_HALTED);
+    assert(fers_halted(machine));
+    assert(fers_step(machine) == FERS_ERROR);
+    assert(strlen(fers_last_error(machine)) > 0);
+
+    fers_free(machine);
+    puts("ok");
+    return 0;
+}
@@ -0,0 +1,201 @@
+//! `fers` のサブコマンドと終了コードを、ビルドしたバイナリを実行して確かめる
+
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// OUTしてから定義されていないSVCを呼ぶ
+const FAILING: &str = "MAIN  START
+      OUT   X,L
+      SVC   9
+      RET
+X     DC    'hi'
+L     DC    2
+      END";
+
+const HELLO: &str = "MAIN  START
+      OUT   X,L
+      RET
+X     DC    'hi'
+L     DC    2
+      END";
+
+/// 促してから1行読んで、そのまま出力する
+const PROMPT: &str = "MAIN  START
+      OUT   P,PL
+      IN    BUF,LEN
+      OUT   BUF,LEN
+      RET
+P     DC    'name?'
+PL    DC    5
+BUF   DS    16
+LEN   DS    1
+      END";
+
+fn fers(args: &[&str]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fers"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"").unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn source(name: &str, text: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cli");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, text).unwrap();
+    path
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn usage_errors() {
+    let hello = source("usage.cas", HELLO);
+    let hello = hello.to_str().unwrap();
+    for args in [
+        &[][..],
+        &[hello],
+        &["debug"],
+        &["--trace", "json", hello],
+        &["trace", "xml", hello],
+        &["gdb", "port", hello],
+        &["diff", "--steps", "a", "b"],
+    ] {
+        let output = fers(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(stderr(&output).starts_with("usage: fers"));
+    }
+}
+
+#[test]
+fn runtime_errors() {
+    let failing = source("failing.cas", FAILING);
+    let failing = failing.to_str().unwrap();
+    for args in [
+        &["run", failing][..],
+        &["trace", "json", failing],
+        &["cycles", "default", failing],
+        &["profile", "table", failing],
+    ] {
+        let output = fers(args);
+        assert_eq!(output.status.code(), Some(3), "{:?}", args);
+        assert!(
+            stderr(&output).contains("SVC 9 is not defined"),
+            "{:?}",
+            args
+        );
+    }
+
+    // 止まるまでの集計は出す
+    let output = fers(&["profile", "table", failing]);
+    assert!(!output.stdout.is_empty());
+}
+
+#[test]
+fn io_errors() {
+    let missing = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cli/missing.cas");
+    let missing = missing.to_str().unwrap();
+    for args in [&["run", missing][..], &["trace", "json", missing]] {
+        let output = fers(args);
+        assert_eq!(output.status.code(), Some(6), "{:?}", args);
+        let message = stderr(&output);
+        assert!(
+            message.starts_with(&format!("{}: ", missing)),
+            "{}",
+            message
+        );
+        assert!(!message.contains("Os {"), "{}", message);
+    }
+}
+
+#[test]
+fn trace_and_diff() {
+    let hello = source("hello.cas", HELLO);
+    let failing = source("diverging.cas", FAILING);
+    let mut traces = Vec::new();
+    for (path, expected) in [(&hello, 0), (&failing, 3)] {
+        let output = fers(&["trace", "json", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(expected));
+        let trace = path.with_extension("trace");
+        fs::write(&trace, &output.stdout).unwrap();
+        traces.push(trace.to_str().unwrap().to_string());
+    }
+
+    let output = fers(&["diff", &traces[0], &traces[0]]);
+    assert_eq!(output.status.code(), Some(0));
+    let output = fers(&["diff", &traces[0], &traces[1]]);
+    assert_eq!(output.status.code(), Some(5));
+    let output = fers(&["diff", "--effects", &traces[0], &traces[0]]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn assembly_errors() {
+    let broken = source("broken.cas", "MAIN  START\n      LD    GR9,X\n      END");
+    let broken = broken.to_str().unwrap();
+    for args in [
+        &["run", broken][..],
+        &["asm", broken],
+        &["trace", "json", broken],
+    ] {
+        let output = fers(args);
+        assert_eq!(output.status.code(), Some(1), "{:?}", args);
+        assert!(stderr(&output).starts_with(&format!("{}:", broken)));
+    }
+}
+
+#[test]
+fn failing_cases() {
+    let hello = source("cases.cas", HELLO);
+    let cases = source(
+        "cases.toml",
+        "[[case]]\nname = \"wrong\"\noutput = [\"bye\"]\n",
+    );
+    let output = fers(&["test", hello.to_str().unwrap(), cases.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(5));
+    // 終了する前に結果を書き切る
+    assert!(String::from_utf8_lossy(&output.stdout).contains("FAIL wrong"));
+    assert!(stderr(&output).contains("1 of 1 cases failed"));
+}
+
+#[test]
+fn reads_input_lazily() {
+    let prompt = source("prompt.cas", PROMPT);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fers"))
+        .args(["run", prompt.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = io::BufReader::new(child.stdout.take().unwrap());
+
+    // 入力を閉じる前に促す行が出てくる
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        sender.send(line).unwrap();
+        let mut rest = String::new();
+        stdout.read_to_string(&mut rest).unwrap();
+        sender.send(rest).unwrap();
+    });
+    let line = receiver.recv_timeout(Duration::from_secs(10));
+    writeln!(stdin, "fers").unwrap();
+    drop(stdin);
+    assert_eq!(line.unwrap(), "name?\n");
+    assert_eq!(receiver.recv().unwrap(), "fers\n");
+    assert!(child.wait().unwrap().success());
+}
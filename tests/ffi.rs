@@ -0,0 +1,80 @@
+//! `tests/c/test_fers.c` を `cc` でビルドして、このクレートのcdylibとつないで動かす
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// libfers.so のあるディレクトリ。target/debug/deps/ffi-xxxx の2つ上
+fn lib_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().unwrap().parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn c_program() {
+    let dir = lib_dir();
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let binary = dir.join("test_fers");
+
+    // Cコンパイラがなければ失敗させる。黙って通すとCのAPIを確かめたことにならない
+    let status = Command::new("cc")
+        .arg(root.join("tests/c/test_fers.c"))
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg("-L")
+        .arg(&dir)
+        .arg(format!("-Wl,-rpath,{}", dir.display()))
+        .args(["-lfers", "-o"])
+        .arg(&binary)
+        .status()
+        .unwrap_or_else(|e| panic!("this test needs a C compiler as `cc`: {}", e));
+    assert!(status.success());
+
+    let output = Command::new(&binary).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n");
+}
+
+/// include/fers.h で宣言した関数と、cdylibが実際にエクスポートしている関数が一致する
+#[test]
+fn header_matches_exports() {
+    let output = Command::new("nm")
+        .args(["-D", "--defined-only"])
+        .arg(lib_dir().join("libfers.so"))
+        .output()
+        .unwrap_or_else(|e| panic!("this test needs `nm`: {}", e));
+    assert!(output.status.success());
+    let exported: BTreeSet<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [_, "T", name] if name.starts_with("fers_") => Some(name.to_string()),
+                _ => None,
+            },
+        )
+        .collect();
+
+    let header =
+        fs::read_to_string(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("include/fers.h"))
+            .unwrap();
+    // `fers_load(` のように直後に括弧が続く名前
+    let declared: BTreeSet<String> = header
+        .match_indices("fers_")
+        .filter_map(|(start, _)| {
+            let rest = &header[start..];
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))?;
+            rest[end..]
+                .starts_with('(')
+                .then(|| rest[..end].to_string())
+        })
+        .collect();
+
+    assert!(!exported.is_empty());
+    assert_eq!(declared, exported);
+}
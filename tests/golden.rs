@@ -0,0 +1,37 @@
+//! `tests/golden` の各プログラムをアセンブルして、同じ名前の .toml のケースで採点する。
+//! アセンブラ、命令の実行、入出力をまとめて確かめる
+
+use fers::grade::{self, Spec};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn golden() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut sources: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "cas"))
+        .collect();
+    sources.sort();
+    assert!(sources.len() >= 5);
+
+    let mut failed = Vec::new();
+    for path in &sources {
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let source = fs::read_to_string(path).unwrap();
+        let program = fers::assemble(&source)
+            .unwrap_or_else(|errors| panic!("{}: {:?}", path.display(), errors));
+        let spec = fs::read_to_string(path.with_extension("toml")).unwrap();
+        let spec = Spec::parse(&spec).unwrap_or_else(|e| panic!("{}.toml: {}", name, e));
+        let outcomes: Vec<_> = spec
+            .cases
+            .iter()
+            .map(|case| grade::grade(&program, case))
+            .collect();
+        if !outcomes.iter().all(grade::Outcome::passed) {
+            failed.push(format!("{}:\n{}", name, grade::report(&outcomes)));
+        }
+    }
+    assert!(failed.is_empty(), "{}", failed.join("\n"));
+}
@@ -0,0 +1,66 @@
+//! JavaScriptのAPIのテスト。Node.jsなしでヘッドレスブラウザの中で動かす
+//!
+//! ```sh
+//! wasm-pack test --headless --firefox -- --features wasm
+//! ```
+
+#![cfg(all(target_arch = "wasm32", feature = "wasm"))]
+
+use fers::wasm::{assemble, WasmMachine};
+use js_sys::{Array, Function};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+const ECHO: &str = "MAIN  START
+LOOP  IN    BUF,LEN
+      LD    GR1,LEN
+      JMI   FIN
+      OUT   BUF,LEN
+      JUMP  LOOP
+FIN   RET
+BUF   DS    16
+LEN   DS    1
+      END";
+
+#[wasm_bindgen_test]
+fn assemble_errors() {
+    assert!(assemble("MAIN START\n LD GR9,1\n END").is_err());
+    let program = assemble(ECHO).unwrap();
+    assert_eq!(program.origin(), 0x100);
+    assert_eq!(program.address_of("LOOP"), Some(0x100));
+    assert_eq!(program.line_of(0x100), Some(1));
+}
+
+#[wasm_bindgen_test]
+fn run_with_callbacks() {
+    let program = assemble(ECHO).unwrap();
+    let mut machine = WasmMachine::new(&program);
+
+    // 2行読んだら入力の終わり
+    let lines = Array::of2(&JsValue::from_str("hello"), &JsValue::from_str("wasm"));
+    let input = Function::new_with_args("", "return this.shift()").bind(&lines);
+    let output = Array::new();
+    let push = Function::new_with_args("line", "this.push(line)").bind(&output);
+    machine.on_input(input);
+    machine.on_output(push);
+
+    let fin = program.address_of("FIN").unwrap();
+    machine.add_breakpoint(fin);
+    assert_eq!(machine.run(1000).unwrap(), "breakpoint");
+    assert_eq!(machine.pr(), fin);
+    assert_eq!(output.length(), 2);
+    assert_eq!(output.get(1).as_string().unwrap(), "wasm");
+    // 入力の終わりでLENは-1
+    assert_eq!(machine.registers()[1], 0xFFFF);
+    assert_eq!(
+        machine.memory(program.address_of("BUF").unwrap(), 2),
+        [104, 101]
+    );
+
+    assert!(machine.remove_breakpoint(fin));
+    assert_eq!(machine.run(1000).unwrap(), "halted");
+    assert!(machine.halted());
+    assert!(machine.step().is_err());
+}